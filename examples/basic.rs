@@ -1,13 +1,17 @@
 use axum::{response::Html, routing::get, Router};
-use axum_markdown::MarkdownLayer;
+use axum_markdown::{MarkdownConfig, MarkdownLayer};
 
 #[allow(clippy::unwrap_used)]
 #[tokio::main]
 async fn main() {
+    // Build the tokenizer now rather than on the first markdown request
+    let config = MarkdownConfig::new();
+    axum_markdown::warm_up(&config);
+
     let app = Router::new()
         .route("/", get(index))
         .route("/about", get(about))
-        .layer(MarkdownLayer::new());
+        .layer(MarkdownLayer::with_config(config));
 
     let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
         .await
@@ -69,6 +69,18 @@
             inherit cargoArtifacts;
           });
 
+          # The same without the `tokens` feature, so that path keeps building
+          clippy-no-tokens = craneLib.cargoClippy (commonArgs // {
+            inherit cargoArtifacts;
+            cargoExtraArgs = "--no-default-features --features serve-dir";
+            cargoClippyExtraArgs = "--all-targets -- -D warnings";
+          });
+
+          tests-no-tokens = craneLib.cargoTest (commonArgs // {
+            inherit cargoArtifacts;
+            cargoExtraArgs = "--no-default-features --features serve-dir";
+          });
+
           audit = craneLib.cargoAudit {
             inherit src advisory-db;
           };
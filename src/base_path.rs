@@ -0,0 +1,107 @@
+//! Post-pass prefixing root-relative link destinations with the path a
+//! proxied service is served under, for
+//! [`MarkdownConfig::external_base_path`](crate::MarkdownConfig::external_base_path).
+
+use crate::{budget, entities};
+
+/// Prepend `base` (e.g. `/proxy`) to the root-relative destinations of the
+/// links, images, and link reference definitions in `markdown`: `/about`
+/// becomes `/proxy/about`.
+///
+/// Network-path (`//host/...`), absolute, and path-relative destinations
+/// are left as they are, as are fragments and queries, and code.
+pub fn prefix(markdown: &str, base: &str) -> String {
+    let base = base.trim_end_matches('/');
+    if base.is_empty() {
+        return markdown.to_string();
+    }
+    let base = if base.starts_with('/') {
+        base.to_string()
+    } else {
+        format!("/{base}")
+    };
+    let mut out = String::with_capacity(markdown.len());
+    for (i, line) in budget::scan(markdown).into_iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        if line.fence.is_some() || line.closes_fence {
+            out.push_str(line.text);
+        } else if let Some(at) = definition_destination(line.text) {
+            out.push_str(&line.text[..at]);
+            let rest = push_destination(&line.text[at..], &base, &mut out);
+            out.push_str(rest);
+        } else {
+            entities::split_code_spans(line.text, |text, code| {
+                if code {
+                    out.push_str(text);
+                } else {
+                    rewrite(text, &base, &mut out);
+                }
+            });
+        }
+    }
+    out
+}
+
+/// Offset of the destination in a link reference definition, `[1]: /about`.
+/// Text can't start with one: htmd escapes its brackets.
+fn definition_destination(line: &str) -> Option<usize> {
+    let trimmed = line.trim_start();
+    let (label, rest) = trimmed.strip_prefix('[')?.split_once("]:")?;
+    if label.is_empty() || label.contains('[') {
+        return None;
+    }
+    let destination = rest.trim_start();
+    Some(line.len() - destination.len())
+}
+
+/// Rewrite the destinations of the inline links and images in a stretch
+/// of text.
+fn rewrite(text: &str, base: &str, out: &mut String) {
+    let mut rest = text;
+    while let Some(at) = rest.find("](") {
+        let (before, after) = rest.split_at(at + 2);
+        out.push_str(before);
+        rest = push_destination(after, base, out);
+    }
+    out.push_str(rest);
+}
+
+/// Push the start of `destination`, prefixed with `base` if it is
+/// root-relative, returning the rest.
+fn push_destination<'a>(destination: &'a str, base: &str, out: &mut String) -> &'a str {
+    let (open, path) = destination
+        .strip_prefix('<')
+        .map_or(("", destination), |path| ("<", path));
+    out.push_str(open);
+    if path.starts_with('/') && !path.starts_with("//") {
+        out.push_str(base);
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefixes_root_relative_destinations() {
+        let md = "[About](/about) and ![logo](/img/logo.png \"Logo\") [x](</a b>)\n\n\
+                  [ext](https://example.com/) [net](//cdn.example.com/x) [rel](docs) [top](#top)\n\n\
+                  `[code](/code)`\n\n```\n[fenced](/fenced)\n```\n\n[1]: /defined\n[2]: https://example.com/";
+        assert_eq!(
+            prefix(md, "/proxy"),
+            "[About](/proxy/about) and ![logo](/proxy/img/logo.png \"Logo\") [x](</proxy/a b>)\n\n\
+             [ext](https://example.com/) [net](//cdn.example.com/x) [rel](docs) [top](#top)\n\n\
+             `[code](/code)`\n\n```\n[fenced](/fenced)\n```\n\n[1]: /proxy/defined\n[2]: https://example.com/"
+        );
+    }
+
+    #[test]
+    fn test_normalizes_base() {
+        assert_eq!(prefix("[a](/)", "proxy/"), "[a](/proxy/)");
+        assert_eq!(prefix("[a](/b)", "/"), "[a](/b)");
+        assert_eq!(prefix("[![i](/i.png)](/p)", "/x"), "[![i](/x/i.png)](/x/p)");
+    }
+}
@@ -0,0 +1,91 @@
+//! Post-pass normalizing the `>` prefixes of (nested) blockquotes.
+
+use crate::budget::fence_run;
+
+/// Rewrite every blockquote prefix as one `>` per nesting level followed by
+/// a single space, e.g. `>> text` two levels deep (htmd writes `> > text`).
+/// Blank lines inside a quote keep their `>`s, without the trailing space.
+///
+/// Fenced code inside a quote keeps its content verbatim: only the quote's
+/// own markers are rewritten, so a `>` in the code isn't taken for nesting.
+/// Fenced code outside any quote is left alone.
+pub fn normalize(markdown: &str) -> String {
+    // Depth and fence of the open fenced code block
+    let mut fence: Option<(usize, String)> = None;
+    let mut out: Vec<String> = Vec::new();
+
+    for line in markdown.split('\n') {
+        let rest = line.trim_start_matches(' ');
+        let indent = &line[..line.len() - rest.len()];
+        let (mut depth, mut content) = markers(rest, usize::MAX);
+
+        if let Some((fence_depth, open)) = &fence {
+            if depth < *fence_depth {
+                // The quote ended around the code block
+                fence = None;
+            } else {
+                (depth, content) = markers(rest, *fence_depth);
+                let trimmed = content.trim_start();
+                let closes = fence_run(trimmed).is_some_and(|run| {
+                    run.starts_with(open.as_str()) && trimmed[run.len()..].trim().is_empty()
+                });
+                if closes {
+                    fence = None;
+                }
+            }
+        } else if let Some(run) = fence_run(content.trim_start()) {
+            fence = Some((depth, run.to_string()));
+        }
+
+        out.push(match depth {
+            0 => line.to_string(),
+            _ if content.trim().is_empty() => format!("{indent}{}", ">".repeat(depth)),
+            _ => format!("{indent}{} {content}", ">".repeat(depth)),
+        });
+    }
+    out.join("\n")
+}
+
+/// Up to `max` leading `>` markers of `line` (each but the first may be
+/// indented up to three spaces, and takes one optional following space),
+/// and the text after them.
+fn markers(line: &str, max: usize) -> (usize, &str) {
+    let mut depth = 0;
+    let mut rest = line;
+    while depth < max {
+        let unindented = rest.trim_start_matches(' ');
+        let Some(after) = unindented.strip_prefix('>') else {
+            break;
+        };
+        if rest.len() - unindented.len() > 3 {
+            break;
+        }
+        depth += 1;
+        rest = after.strip_prefix(' ').unwrap_or(after);
+    }
+    (depth, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_quote_prefixes() {
+        let md = "> Outer\n> \n> > Inner\n> > \n> > Inner 2\n> \n> Back";
+        assert_eq!(
+            normalize(md),
+            "> Outer\n>\n>> Inner\n>>\n>> Inner 2\n>\n> Back"
+        );
+        assert_eq!(normalize("> > a\n>>b\n>  > c"), ">> a\n>> b\n>> c");
+    }
+
+    #[test]
+    fn test_code_in_quotes_kept_verbatim() {
+        let md = "> ```\n> > not nested\n> \n> ```\n\n```\n> prompt\n```";
+        assert_eq!(
+            normalize(md),
+            "> ```\n> > not nested\n>\n> ```\n\n```\n> prompt\n```"
+        );
+    }
+}
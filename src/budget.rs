@@ -0,0 +1,304 @@
+//! Truncation of markdown to a token budget.
+//!
+//! Cuts land on block boundaries where possible, so the result never ends
+//! inside a fenced code block or with a table header missing its separator.
+
+use crate::TokenCounter;
+use std::ops::Range;
+
+/// Cut `markdown` to at most `max_tokens` tokens including `notice`, which
+/// is appended after the cut. Returns the emitted text and its token count.
+///
+/// The cut backs off to the last block boundary unless that gives up more
+/// than `backoff_percent` of the budget, in which case it ends on a line
+/// boundary and closes any open code fence instead. If the notice alone
+/// exceeds the budget, the text is cut without it.
+pub fn truncate(
+    counter: &dyn TokenCounter,
+    markdown: &str,
+    max_tokens: usize,
+    notice: &str,
+    backoff_percent: u8,
+) -> (String, usize) {
+    let notice_tokens = counter.count(notice);
+    let Some(mut budget) = max_tokens.checked_sub(notice_tokens) else {
+        let text = safe_cut(counter, markdown, max_tokens, backoff_percent);
+        let tokens = counter.count(&text);
+        return (text, tokens);
+    };
+    loop {
+        let mut text = safe_cut(counter, markdown, budget, backoff_percent);
+        text.push_str(notice);
+        // Tokens can merge across the join, so the whole is recounted
+        let tokens = counter.count(&text);
+        if tokens <= max_tokens || budget == 0 {
+            return (text, tokens);
+        }
+        budget = budget.saturating_sub(tokens - max_tokens);
+    }
+}
+
+/// The longest prefix of `markdown` within `budget` tokens, cut as
+/// described for [`truncate`] and with trailing whitespace removed.
+fn safe_cut(
+    counter: &dyn TokenCounter,
+    markdown: &str,
+    budget: usize,
+    backoff_percent: u8,
+) -> String {
+    let prefix = counter.truncate(markdown, budget);
+    if prefix.len() == markdown.len() {
+        return prefix.trim_end().to_string();
+    }
+    let lines = scan(prefix);
+    // The final line is partial, or empty after a newline
+    let whole = &lines[..lines.len() - 1];
+
+    // The end of the last line outside any code block that is followed by
+    // a blank line or closes a fence
+    let boundary = whole
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(i, line)| {
+            line.fence.is_none()
+                && (line.closes_fence
+                    || whole
+                        .get(i + 1)
+                        .is_some_and(|next| next.blank && next.fence.is_none()))
+        })
+        .map_or(0, |(_, line)| line.end);
+    let dropped = counter.count(&prefix[boundary..]);
+    if dropped * 100 <= budget * usize::from(backoff_percent) {
+        return prefix[..boundary].trim_end().to_string();
+    }
+
+    // Too much to give up: end on the last whole line and repair it
+    let is_row = |i: usize| lines[i].fence.is_none() && lines[i].text.trim_start().starts_with('|');
+    let Some(mut last) = whole.len().checked_sub(1) else {
+        let broken = lines[0].fence.is_some() || is_row(0);
+        return if broken {
+            String::new()
+        } else {
+            prefix.trim_end().to_string()
+        };
+    };
+    // A table cut right after its header row would lose the separator
+    if is_row(last) && (last == 0 || !is_row(last - 1)) {
+        let Some(previous) = last.checked_sub(1) else {
+            return String::new();
+        };
+        last = previous;
+    }
+    let mut text = prefix[..lines[last].end].trim_end().to_string();
+    if let Some(fence) = &lines[last].fence {
+        text.push_str(if prefix.contains("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        });
+        text.push_str(fence);
+    }
+    text
+}
+
+/// Byte ranges of the top-level blocks of `markdown`: runs of lines
+/// separated by blank lines outside code fences.
+pub fn blocks(markdown: &str) -> Vec<Range<usize>> {
+    let mut blocks = Vec::new();
+    let mut current: Option<Range<usize>> = None;
+    for line in scan(markdown) {
+        if line.blank && line.fence.is_none() {
+            blocks.extend(current.take());
+        } else if let Some(block) = &mut current {
+            block.end = line.end;
+        } else {
+            current = Some(line.end - line.text.len()..line.end);
+        }
+    }
+    blocks.extend(current);
+    blocks
+}
+
+/// A line of a markdown prefix.
+pub struct Line<'a> {
+    pub text: &'a str,
+    /// Byte offset of the end of the line, excluding its newline.
+    pub end: usize,
+    pub blank: bool,
+    /// Whether the line closes a fenced code block.
+    pub closes_fence: bool,
+    /// The fence (with its indentation) of the code block open after this line.
+    pub fence: Option<String>,
+}
+
+pub fn scan(prefix: &str) -> Vec<Line<'_>> {
+    let mut lines = Vec::new();
+    let mut fence: Option<String> = None;
+    let mut offset = 0;
+    for text in prefix.split('\n') {
+        let end = offset + text.len();
+        offset = end + 1;
+        let trimmed = text.trim_start();
+        let run = fence_run(trimmed);
+        let mut closes_fence = false;
+        if let Some(open) = &fence {
+            let closes = run.is_some_and(|run| {
+                run.starts_with(open.trim_start()) && trimmed[run.len()..].trim().is_empty()
+            });
+            if closes {
+                fence = None;
+                closes_fence = true;
+            }
+        } else if let Some(run) = run {
+            fence = Some(format!("{}{run}", &text[..text.len() - trimmed.len()]));
+        }
+        lines.push(Line {
+            text,
+            end,
+            blank: trimmed.is_empty(),
+            closes_fence,
+            fence: fence.clone(),
+        });
+    }
+    lines
+}
+
+/// A leading run of three or more backticks or tildes.
+pub fn fence_run(trimmed: &str) -> Option<&str> {
+    let ch = trimmed.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let len = trimmed.len() - trimmed.trim_start_matches(ch).len();
+    (len >= 3).then(|| &trimmed[..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "tokens")]
+    use crate::TokenizerModel;
+
+    /// One token per character, truncated with the default binary search.
+    struct Chars;
+
+    impl TokenCounter for Chars {
+        fn count(&self, text: &str) -> usize {
+            text.chars().count()
+        }
+    }
+
+    #[test]
+    fn test_default_truncate_binary_search() {
+        assert_eq!(Chars.truncate("héllo wörld", 7), "héllo w");
+        assert_eq!(Chars.truncate("héllo", 9), "héllo");
+        assert_eq!(Chars.truncate("héllo", 0), "");
+    }
+
+    #[test]
+    fn test_truncate_fits_notice_in_budget() {
+        let (text, tokens) = truncate(&Chars, "one two three four", 12, " […]", 25);
+        assert_eq!(text, "one two […]");
+        assert_eq!(tokens, 11);
+        assert_eq!(
+            truncate(&Chars, "one two", 2, " […]", 25),
+            ("on".to_string(), 2)
+        );
+    }
+
+    #[test]
+    fn test_truncate_backs_off_to_block_boundary() {
+        let markdown = "Intro\n\n```rust\nlet a = 1;\nlet b = 2;\n```\n\nEnd";
+        assert_eq!(truncate(&Chars, markdown, 30, "", 100).0, "Intro");
+        assert_eq!(
+            truncate(&Chars, markdown, 42, "", 100).0,
+            "Intro\n\n```rust\nlet a = 1;\nlet b = 2;\n```"
+        );
+    }
+
+    #[test]
+    fn test_truncate_closes_fence_when_backoff_too_large() {
+        let markdown = "Intro\n\n  ~~~~\nlet a = 1;\nlet b = 2;\nlet c = 3;\n  ~~~~\n\nEnd";
+        assert_eq!(
+            truncate(&Chars, markdown, 45, "", 10).0,
+            "Intro\n\n  ~~~~\nlet a = 1;\nlet b = 2;\n  ~~~~"
+        );
+    }
+
+    #[test]
+    fn test_truncate_drops_table_header_without_separator() {
+        let markdown = "Intro text here\n| a | b |\n|---|---|\n| 1 | 2 |";
+        assert_eq!(truncate(&Chars, markdown, 26, "", 0).0, "Intro text here");
+        assert_eq!(
+            truncate(&Chars, markdown, 40, "", 0).0,
+            "Intro text here\n| a | b |\n|---|---|"
+        );
+    }
+
+    #[test]
+    fn test_blocks() {
+        let markdown = "\n# Title\n\nOne\ntwo\n\n\n```\ncode\n\nmore\n```\n  \nEnd\n";
+        let blocks: Vec<&str> = blocks(markdown).into_iter().map(|b| &markdown[b]).collect();
+        assert_eq!(
+            blocks,
+            ["# Title", "One\ntwo", "```\ncode\n\nmore\n```", "End"]
+        );
+    }
+
+    /// Open fences are closed, and every table has its separator row.
+    fn assert_well_formed(markdown: &str, context: &str) {
+        let lines = scan(markdown);
+        assert!(
+            lines.last().is_none_or(|line| line.fence.is_none()),
+            "unclosed fence in {context}: {markdown:?}"
+        );
+        for (i, line) in lines.iter().enumerate() {
+            let starts_table = line.fence.is_none()
+                && line.text.starts_with('|')
+                && (i == 0 || !lines[i - 1].text.starts_with('|'));
+            if starts_table {
+                assert!(
+                    lines
+                        .get(i + 1)
+                        .is_some_and(|next| next.text.starts_with("|-")),
+                    "table without separator in {context}: {markdown:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncate_always_well_formed() {
+        let blocks = [
+            "# Title",
+            "Some prose with a few words in it.",
+            "```rust\nfn main() {\n\n    println!(\"hi\");\n}\n```",
+            "| col | other |\n|---|---|\n| 1 | 2 |\n| 3 | 4 |",
+            "* item\n\n  ~~~\n  nested code\n  ~~~",
+        ];
+        // Every rotation of the blocks, cut at every budget and backoff
+        for start in 0..blocks.len() {
+            let markdown = (0..blocks.len() * 2)
+                .map(|i| blocks[(start + i) % blocks.len()])
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            for max in 0..Chars.count(&markdown) {
+                for backoff in [0, 25, 100] {
+                    let (text, tokens) = truncate(&Chars, &markdown, max, "\n\n[…]", backoff);
+                    assert!(tokens <= max || text.is_empty(), "{tokens} > {max}");
+                    assert_well_formed(&text, &format!("max {max}, backoff {backoff}"));
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "tokens")]
+    #[test]
+    fn test_tiktoken_truncate_keeps_whole_characters() {
+        let model = TokenizerModel::O200kBase;
+        let text = "Grüße aus 東京, 🦀 everywhere. ".repeat(20);
+        for max in 0..model.count(&text) {
+            let prefix = model.truncate(&text, max);
+            assert!(text.starts_with(prefix));
+            assert!(model.count(prefix) <= max, "max {max}");
+        }
+    }
+}
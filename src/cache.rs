@@ -0,0 +1,565 @@
+//! Caches of converted responses: by URL, skipping the inner service, and
+//! by upstream version, skipping conversion.
+
+use crate::{stats::Stats, CacheConfig};
+use bytes::Bytes;
+use http::{Extensions, HeaderMap, HeaderName, Response, StatusCode};
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// A converted response stored for reuse.
+#[derive(Debug, Clone)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    extensions: Extensions,
+    body: Bytes,
+    inserted_at: Instant,
+}
+
+/// Converted responses keyed by request path and query, expiring after a TTL.
+///
+/// Expired entries are dropped on lookup and swept on insert.
+#[derive(Debug)]
+pub struct UrlCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, CachedResponse>>,
+}
+
+impl UrlCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Rebuild the cached response for `key` if it is still fresh.
+    pub fn get(&self, key: &str) -> Option<Response<Bytes>> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = entries.get(key)?;
+        if entry.inserted_at.elapsed() >= self.ttl {
+            entries.remove(key);
+            return None;
+        }
+        let entry = entry.clone();
+        drop(entries);
+
+        let mut response = Response::new(entry.body);
+        *response.status_mut() = entry.status;
+        *response.headers_mut() = entry.headers;
+        *response.extensions_mut() = entry.extensions;
+        Some(response)
+    }
+
+    /// Store a converted response under `key`.
+    pub fn insert(
+        &self,
+        key: String,
+        status: StatusCode,
+        headers: HeaderMap,
+        extensions: Extensions,
+        body: Bytes,
+    ) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries.retain(|_, entry| entry.inserted_at.elapsed() < self.ttl);
+        entries.insert(
+            key,
+            CachedResponse {
+                status,
+                headers,
+                extensions,
+                body,
+                inserted_at: Instant::now(),
+            },
+        );
+    }
+}
+
+/// The caches shared by every service built from one layer, and its
+/// conversion counts.
+#[derive(Default)]
+pub struct Caches {
+    pub urls: Option<UrlCache>,
+    pub conversions: Option<Arc<dyn ConversionCache>>,
+    /// [`CacheConfig::ttl`] of the conversions.
+    pub ttl: Option<Duration>,
+    pub stats: Stats,
+}
+
+impl fmt::Debug for Caches {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Caches")
+            .field("urls", &self.urls)
+            .field("conversions", &self.conversions.as_ref().map(|_| ".."))
+            .field("ttl", &self.ttl)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+impl Caches {
+    /// Drop the `entries` of both caches.
+    pub fn invalidate(&self, entries: &Invalidation) {
+        if let Some(urls) = &self.urls {
+            urls.entries
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retain(|key, _| !entries.matches(key));
+        }
+        if let Some(conversions) = &self.conversions {
+            conversions.invalidate(entries);
+        }
+    }
+}
+
+/// Future returned by [`ConversionCache`] lookups and inserts.
+pub type CacheFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Storage for the conversions of [`MarkdownConfig::cache`], installed with
+/// [`MarkdownConfig::cache_backend`].
+///
+/// Backends only store and evict: the middleware checks that an entry is
+/// of the current upstream version and within its TTL, and replaces it
+/// otherwise. Keys start with the request path, followed by its query (if
+/// any) and space-separated per-request variants.
+///
+/// [`MarkdownConfig::cache`]: crate::MarkdownConfig::cache
+/// [`MarkdownConfig::cache_backend`]: crate::MarkdownConfig::cache_backend
+pub trait ConversionCache: Send + Sync {
+    /// The conversion stored under `key`, if any.
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<CachedConversion>>;
+
+    /// Store `conversion` under `key`, replacing any previous one.
+    fn insert(&self, key: String, conversion: CachedConversion) -> CacheFuture<'_, ()>;
+
+    /// Drop the `entries`, for [`MarkdownCacheHandle`](crate::MarkdownCacheHandle).
+    fn invalidate(&self, entries: &Invalidation);
+}
+
+/// Entries to drop from a cache, matched on the request path of their key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalidation {
+    /// The entries for this path, whatever their query string.
+    Path(String),
+    /// The entries for every path starting with this prefix.
+    Prefix(String),
+    /// Every entry.
+    All,
+}
+
+impl Invalidation {
+    /// Whether the entry stored under `key` is to be dropped.
+    #[must_use]
+    pub fn matches(&self, key: &str) -> bool {
+        let path = key.split([' ', '?']).next().unwrap_or(key);
+        match self {
+            Self::Path(p) => path == p,
+            Self::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            Self::All => true,
+        }
+    }
+}
+
+/// How conversion changed an upstream response's headers.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderChanges {
+    /// Headers set by conversion, with all their values.
+    set: HeaderMap,
+    /// Upstream headers conversion removed.
+    removed: Vec<HeaderName>,
+}
+
+impl HeaderChanges {
+    /// The changes turning `upstream` headers into `converted` ones.
+    pub fn between(upstream: &HeaderMap, converted: &HeaderMap) -> Self {
+        let mut set = HeaderMap::new();
+        for name in converted.keys() {
+            if !upstream.get_all(name).iter().eq(converted.get_all(name)) {
+                for value in converted.get_all(name) {
+                    set.append(name.clone(), value.clone());
+                }
+            }
+        }
+        let removed = upstream
+            .keys()
+            .filter(|name| !converted.contains_key(*name))
+            .cloned()
+            .collect();
+        Self { set, removed }
+    }
+
+    /// Make the same changes to another response's `headers`.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for name in self.removed.iter().chain(self.set.keys()) {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+
+    /// Bytes of the names and values of the changes.
+    fn size(&self) -> usize {
+        let set: usize = self
+            .set
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        set + self
+            .removed
+            .iter()
+            .map(|name| name.as_str().len())
+            .sum::<usize>()
+    }
+}
+
+/// A conversion stored by a [`ConversionCache`]: the markdown, and the
+/// headers and extensions conversion produced.
+#[derive(Debug, Clone)]
+pub struct CachedConversion {
+    /// The upstream `ETag`, or hash of the HTML, converted.
+    validator: String,
+    headers: HeaderChanges,
+    extensions: Extensions,
+    markdown: Bytes,
+    stored_at: Instant,
+}
+
+impl CachedConversion {
+    pub(crate) fn new(
+        validator: String,
+        headers: HeaderChanges,
+        extensions: Extensions,
+        markdown: Bytes,
+    ) -> Self {
+        Self {
+            validator,
+            headers,
+            extensions,
+            markdown,
+            stored_at: Instant::now(),
+        }
+    }
+
+    /// The converted markdown.
+    #[must_use]
+    pub const fn markdown(&self) -> &Bytes {
+        &self.markdown
+    }
+
+    /// Whether the conversion is of the `validator` version (if known)
+    /// and younger than `ttl` (if any).
+    pub(crate) fn is_current(&self, validator: Option<&str>, ttl: Option<Duration>) -> bool {
+        validator.is_none_or(|validator| self.validator == validator)
+            && ttl.is_none_or(|ttl| self.stored_at.elapsed() < ttl)
+    }
+
+    /// Apply the conversion to an upstream response's `parts`, returning
+    /// the markdown.
+    pub(crate) fn apply(self, parts: &mut http::response::Parts) -> Bytes {
+        self.headers.apply(&mut parts.headers);
+        parts.extensions.extend(self.extensions);
+        self.markdown
+    }
+
+    /// Bytes held by the conversion.
+    fn size(&self) -> usize {
+        self.validator.len() + self.markdown.len() + self.headers.size()
+    }
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    conversion: CachedConversion,
+    size: usize,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    entries: HashMap<String, MemoryEntry>,
+    bytes: usize,
+    /// Incremented on every use, to find the least recently used entry.
+    clock: u64,
+}
+
+/// The built-in [`ConversionCache`], in a map.
+///
+/// Beyond [`CacheConfig::max_entries`] or [`CacheConfig::max_bytes`], the
+/// least recently used entries are evicted. Entries past the
+/// [`CacheConfig::ttl`] are dropped on lookup.
+#[derive(Debug)]
+pub struct MemoryCache {
+    limits: CacheConfig,
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryCache {
+    /// An empty cache with the bounds of `limits`.
+    #[must_use]
+    pub fn new(limits: CacheConfig) -> Self {
+        Self {
+            limits,
+            state: Mutex::new(MemoryState::default()),
+        }
+    }
+}
+
+impl ConversionCache for MemoryCache {
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<CachedConversion>> {
+        let conversion = self
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key, self.limits.ttl);
+        Box::pin(std::future::ready(conversion))
+    }
+
+    fn insert(&self, key: String, conversion: CachedConversion) -> CacheFuture<'_, ()> {
+        let size = key.len() + conversion.size();
+        if size <= self.limits.max_bytes && self.limits.max_entries > 0 {
+            let entry = MemoryEntry {
+                conversion,
+                size,
+                last_used: 0,
+            };
+            self.state
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(key, entry, self.limits);
+        }
+        Box::pin(std::future::ready(()))
+    }
+
+    fn invalidate(&self, entries: &Invalidation) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|key| !entries.matches(key));
+    }
+}
+
+impl MemoryState {
+    fn get(&mut self, key: &str, ttl: Option<Duration>) -> Option<CachedConversion> {
+        if !self.entries.get(key)?.conversion.is_current(None, ttl) {
+            self.remove(key);
+            return None;
+        }
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.clock;
+        Some(entry.conversion.clone())
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.size;
+        }
+    }
+
+    fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        let bytes = &mut self.bytes;
+        self.entries.retain(|key, entry| {
+            let kept = keep(key);
+            if !kept {
+                *bytes -= entry.size;
+            }
+            kept
+        });
+    }
+
+    fn insert(&mut self, key: String, mut entry: MemoryEntry, limits: CacheConfig) {
+        self.clock += 1;
+        entry.last_used = self.clock;
+        self.bytes += entry.size;
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.bytes -= old.size;
+        }
+        while self.entries.len() > limits.max_entries || self.bytes > limits.max_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+pub mod tests {
+    use super::*;
+    use http::{header::CONTENT_TYPE, HeaderValue};
+
+    fn headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/markdown"));
+        headers
+    }
+
+    #[test]
+    fn test_get_returns_fresh_entry() {
+        let cache = UrlCache::new(Duration::from_secs(60));
+        cache.insert(
+            "/a".into(),
+            StatusCode::OK,
+            headers(),
+            Extensions::new(),
+            Bytes::from("# A"),
+        );
+
+        let response = cache.get("/a").unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/markdown");
+        assert_eq!(response.body(), "# A");
+
+        assert!(cache.get("/b").is_none());
+    }
+
+    #[test]
+    fn test_get_drops_expired_entry() {
+        let cache = UrlCache::new(Duration::ZERO);
+        cache.insert(
+            "/a".into(),
+            StatusCode::OK,
+            headers(),
+            Extensions::new(),
+            Bytes::from("# A"),
+        );
+
+        assert!(cache.get("/a").is_none());
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    fn conversion(markdown: &'static str) -> CachedConversion {
+        CachedConversion::new(
+            "1".into(),
+            HeaderChanges::default(),
+            Extensions::new(),
+            Bytes::from(markdown),
+        )
+    }
+
+    /// The behavior every [`ConversionCache`] must have, for a `cache`
+    /// with room for at least four small entries.
+    pub async fn backend_suite(cache: &dyn ConversionCache) {
+        let body = |conversion: Option<CachedConversion>| conversion.map(|c| c.markdown);
+        for key in [
+            "/docs/a",
+            "/docs/a?v=1 prefer",
+            "/docs/b",
+            "/docsets",
+            "/blog",
+        ] {
+            cache.insert(key.into(), conversion("# A")).await;
+        }
+        assert_eq!(body(cache.get("/docs/a").await), Some(Bytes::from("# A")));
+        assert!(cache.get("/docs/c").await.is_none());
+
+        cache.insert("/docs/a".into(), conversion("# A2")).await;
+        assert_eq!(body(cache.get("/docs/a").await), Some(Bytes::from("# A2")));
+
+        cache.invalidate(&Invalidation::Path("/docs/a".into()));
+        assert!(cache.get("/docs/a").await.is_none());
+        assert!(cache.get("/docs/a?v=1 prefer").await.is_none());
+        assert!(cache.get("/docs/b").await.is_some());
+
+        cache.invalidate(&Invalidation::Prefix("/docs/".into()));
+        assert!(cache.get("/docs/b").await.is_none());
+        assert!(cache.get("/docsets").await.is_some());
+        assert!(cache.get("/blog").await.is_some());
+
+        cache.invalidate(&Invalidation::All);
+        assert!(cache.get("/docsets").await.is_none());
+        assert!(cache.get("/blog").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend() {
+        backend_suite(&MemoryCache::new(CacheConfig::default())).await;
+    }
+
+    #[test]
+    fn test_conversion_is_current() {
+        let conversion = conversion("# A");
+        assert!(conversion.is_current(Some("1"), None));
+        assert!(conversion.is_current(None, Some(Duration::from_secs(60))));
+        assert!(!conversion.is_current(Some("2"), None));
+        assert!(!conversion.is_current(None, Some(Duration::ZERO)));
+    }
+
+    #[tokio::test]
+    async fn test_memory_evicts_least_recently_used() {
+        let cache = MemoryCache::new(CacheConfig {
+            max_entries: 2,
+            ..CacheConfig::default()
+        });
+        cache.insert("/a".into(), conversion("# A")).await;
+        cache.insert("/b".into(), conversion("# B")).await;
+        cache.get("/a").await.unwrap();
+        cache.insert("/c".into(), conversion("# C")).await;
+        assert!(cache.get("/b").await.is_none());
+        assert!(cache.get("/a").await.is_some());
+        assert!(cache.get("/c").await.is_some());
+
+        // Each entry is 2 + 1 + 3 bytes
+        let cache = MemoryCache::new(CacheConfig {
+            max_bytes: 12,
+            ..CacheConfig::default()
+        });
+        cache.insert("/a".into(), conversion("# A")).await;
+        cache.insert("/b".into(), conversion("# B")).await;
+        cache.insert("/c".into(), conversion("# C")).await;
+        assert!(cache.get("/a").await.is_none());
+        assert!(cache.get("/b").await.is_some());
+        assert_eq!(cache.state.lock().unwrap().bytes, 12);
+
+        // Too large to store at all
+        cache.insert("/d".into(), conversion("# Too large")).await;
+        assert!(cache.get("/d").await.is_none());
+
+        cache.invalidate(&Invalidation::All);
+        assert_eq!(cache.state.lock().unwrap().bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_memory_drops_expired() {
+        let cache = MemoryCache::new(CacheConfig::default().ttl(Duration::ZERO));
+        cache.insert("/a".into(), conversion("# A")).await;
+        assert!(cache.get("/a").await.is_none());
+        assert!(cache.state.lock().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn test_header_changes_replayed() {
+        let mut upstream = HeaderMap::new();
+        upstream.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        upstream.insert("content-length", HeaderValue::from_static("42"));
+        upstream.insert("x-kept", HeaderValue::from_static("1"));
+        let mut converted = headers();
+        converted.insert("x-kept", HeaderValue::from_static("1"));
+        converted.insert("x-markdown-tokens", HeaderValue::from_static("3"));
+
+        let changes = HeaderChanges::between(&upstream, &converted);
+        let mut next = upstream.clone();
+        next.insert("x-kept", HeaderValue::from_static("2"));
+        next.insert("set-cookie", HeaderValue::from_static("id=b"));
+        changes.apply(&mut next);
+        assert_eq!(next[CONTENT_TYPE], "text/markdown");
+        assert_eq!(next["x-markdown-tokens"], "3");
+        assert_eq!(next["x-kept"], "2");
+        assert_eq!(next["set-cookie"], "id=b");
+        assert!(!next.contains_key("content-length"));
+    }
+}
@@ -0,0 +1,242 @@
+//! The `Content-Signal` header: `yes`/`no` directives on whether content
+//! may be used for AI training, search, and AI input.
+
+use std::{fmt, str::FromStr};
+
+/// The directives the Content Signals Policy defines, in canonical order.
+const AI_TRAIN: &str = "ai-train";
+const SEARCH: &str = "search";
+const AI_INPUT: &str = "ai-input";
+
+/// A `Content-Signal` value, for [`MarkdownConfig::content_signal_typed`].
+///
+/// The default, `ai-train=yes, search=yes, ai-input=yes`, allows every
+/// use. Directives serialize in the policy's order, followed by any
+/// [`extension`](Self::extension)s in the order they were added:
+///
+/// ```
+/// # use axum_markdown::ContentSignal;
+/// let signal = ContentSignal::default().ai_train(false);
+/// assert_eq!(signal.to_string(), "ai-train=no, search=yes, ai-input=yes");
+/// assert_eq!("ai-train=no, search=yes, ai-input=yes".parse(), Ok(signal));
+/// assert!("ai-trian=yes".parse::<ContentSignal>().is_err());
+/// ```
+///
+/// [`MarkdownConfig::content_signal_typed`]: crate::MarkdownConfig::content_signal_typed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentSignal {
+    ai_train: Option<bool>,
+    search: Option<bool>,
+    ai_input: Option<bool>,
+    extensions: Vec<(String, bool)>,
+}
+
+impl Default for ContentSignal {
+    fn default() -> Self {
+        Self {
+            ai_train: Some(true),
+            search: Some(true),
+            ai_input: Some(true),
+            extensions: Vec::new(),
+        }
+    }
+}
+
+impl ContentSignal {
+    /// A signal with no directives, to add only the ones wanted.
+    #[must_use]
+    pub const fn empty() -> Self {
+        Self {
+            ai_train: None,
+            search: None,
+            ai_input: None,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// Set `ai-train`: whether the content may train or fine-tune models.
+    #[must_use]
+    pub const fn ai_train(mut self, allowed: bool) -> Self {
+        self.ai_train = Some(allowed);
+        self
+    }
+
+    /// Set `search`: whether the content may be indexed and shown in
+    /// search results.
+    #[must_use]
+    pub const fn search(mut self, allowed: bool) -> Self {
+        self.search = Some(allowed);
+        self
+    }
+
+    /// Set `ai-input`: whether the content may be fed to models answering
+    /// queries, as in retrieval-augmented generation.
+    #[must_use]
+    pub const fn ai_input(mut self, allowed: bool) -> Self {
+        self.ai_input = Some(allowed);
+        self
+    }
+
+    /// Set a directive the policy may define later, by its `key`: a
+    /// lowercase name such as `ai-summary`. A key already set, including
+    /// one of the three above, is reassigned.
+    #[must_use]
+    pub fn extension(mut self, key: impl Into<String>, allowed: bool) -> Self {
+        let key = key.into();
+        if let Some(known) = self.known(&key) {
+            *known = Some(allowed);
+        } else if let Some(entry) = self.extensions.iter_mut().find(|(k, _)| *k == key) {
+            entry.1 = allowed;
+        } else {
+            self.extensions.push((key, allowed));
+        }
+        self
+    }
+
+    /// Whether no directives are set.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.ai_train.is_none()
+            && self.search.is_none()
+            && self.ai_input.is_none()
+            && self.extensions.is_empty()
+    }
+
+    /// The field of a directive the policy defines.
+    fn known(&mut self, key: &str) -> Option<&mut Option<bool>> {
+        match key {
+            AI_TRAIN => Some(&mut self.ai_train),
+            SEARCH => Some(&mut self.search),
+            AI_INPUT => Some(&mut self.ai_input),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ContentSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let known = [
+            (AI_TRAIN, self.ai_train),
+            (SEARCH, self.search),
+            (AI_INPUT, self.ai_input),
+        ];
+        let directives = known
+            .into_iter()
+            .filter_map(|(key, allowed)| Some((key, allowed?)))
+            .chain(
+                self.extensions
+                    .iter()
+                    .map(|(key, allowed)| (key.as_str(), *allowed)),
+            );
+        for (i, (key, allowed)) in directives.enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{key}={}", if allowed { "yes" } else { "no" })?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the policy's directives; unknown ones are rejected, so that a
+/// misspelling can't slip into the header.
+impl FromStr for ContentSignal {
+    type Err = InvalidContentSignal;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut signal = Self::empty();
+        for directive in s.split(',') {
+            let directive = directive.trim();
+            let Some((key, value)) = directive.split_once('=') else {
+                return Err(InvalidContentSignal(format!(
+                    "expected `name=value`, found `{directive}`"
+                )));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let allowed = if value.eq_ignore_ascii_case("yes") {
+                true
+            } else if value.eq_ignore_ascii_case("no") {
+                false
+            } else {
+                return Err(InvalidContentSignal(format!(
+                    "`{key}` must be `yes` or `no`, found `{value}`"
+                )));
+            };
+            let Some(field) = signal.known(key) else {
+                return Err(InvalidContentSignal(format!("unknown directive `{key}`")));
+            };
+            if field.replace(allowed).is_some() {
+                return Err(InvalidContentSignal(format!("duplicate directive `{key}`")));
+            }
+        }
+        Ok(signal)
+    }
+}
+
+/// A `Content-Signal` string that failed to parse as a [`ContentSignal`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidContentSignal(String);
+
+impl fmt::Display for InvalidContentSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid Content-Signal: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidContentSignal {}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for value in [
+            "ai-train=yes, search=yes, ai-input=yes",
+            "ai-train=no, ai-input=yes",
+            "search=no",
+        ] {
+            let signal: ContentSignal = value.parse().unwrap();
+            assert_eq!(signal.to_string(), value);
+        }
+        let signal: Result<ContentSignal, _> = " search = NO ,ai-train=Yes".parse();
+        assert_eq!(
+            signal.map(|s| s.to_string()).as_deref(),
+            Ok("ai-train=yes, search=no")
+        );
+        assert_eq!(
+            ContentSignal::default().to_string(),
+            "ai-train=yes, search=yes, ai-input=yes"
+        );
+    }
+
+    #[test]
+    fn test_rejects_malformed() {
+        let err = |value: &str| value.parse::<ContentSignal>().err().map(|e| e.to_string());
+        assert_eq!(
+            err("ai-trian=yes").as_deref(),
+            Some("invalid Content-Signal: unknown directive `ai-trian`")
+        );
+        assert!(err("ai-train=maybe").is_some());
+        assert!(err("ai-train=yes, ai-train=no").is_some());
+        assert!(err("ai-train").is_some());
+        assert!(err("").is_some());
+        assert!(err("search=no,").is_some());
+    }
+
+    #[test]
+    fn test_builder() {
+        let signal = ContentSignal::empty()
+            .search(true)
+            .extension("ai-summary", false)
+            .extension("ai-train", false)
+            .extension("ai-summary", true);
+        assert_eq!(
+            signal.to_string(),
+            "ai-train=no, search=yes, ai-summary=yes"
+        );
+        assert!(ContentSignal::empty().is_empty());
+        assert!(!signal.is_empty());
+    }
+}
@@ -0,0 +1,292 @@
+//! Normalization of the date formats found in page metadata to RFC 3339,
+//! RFC 3339 formatting of timestamps, and HTTP-date parsing.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// Normalize `value` to RFC 3339 if it is an ISO 8601 / RFC 3339 date-time
+/// with an offset, a bare ISO 8601 date, or an RFC 2822 date. Anything else
+/// (including date-times without an offset) is returned trimmed but otherwise verbatim.
+pub fn normalize(value: &str) -> String {
+    let value = value.trim();
+    parse_iso(value)
+        .or_else(|| parse_rfc2822(value))
+        .unwrap_or_else(|| value.to_string())
+}
+
+/// Format `time` as an RFC 3339 UTC timestamp with second precision.
+/// Times before the Unix epoch are clamped to it.
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Seconds since the Unix epoch of an HTTP-date (RFC 9110 §5.6.7): an
+/// IMF-fixdate, or the obsolete RFC 850 and asctime formats, which
+/// recipients must accept too. Dates before the epoch are `None`.
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    let fields: Vec<&str> = value
+        .split([' ', ','])
+        .filter(|field| !field.is_empty())
+        .collect();
+    let (day, month, year, clock) = match fields.as_slice() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        [_, day, month, year, clock, "GMT"] if year.len() == 4 => {
+            (*day, *month, year.parse().ok()?, *clock)
+        }
+        // Sunday, 06-Nov-94 08:49:37 GMT
+        [_, date, clock, "GMT"] => {
+            let mut parts = date.split('-');
+            let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+            let year: u64 = year.parse().ok().filter(|_| year.len() == 2)?;
+            (
+                day,
+                month,
+                year + if year < 70 { 2000 } else { 1900 },
+                *clock,
+            )
+        }
+        // Sun Nov  6 08:49:37 1994
+        [_, month, day, clock, year] if year.len() == 4 => {
+            (*day, *month, year.parse().ok()?, *clock)
+        }
+        _ => return None,
+    };
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))? as u64 + 1;
+    let day: u64 = day.parse().ok().filter(|day| (1..=31).contains(day))?;
+    let mut clock = clock
+        .split(':')
+        .map(|field| field.parse::<u64>().ok().filter(|_| field.len() == 2));
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    if clock.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    Some(days_from_civil(year, month, day)? * 86_400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Days from 1970-01-01 to a proleptic Gregorian date, the inverse of
+/// [`civil_from_days`].
+fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+    let year = if month <= 2 {
+        year.checked_sub(1)?
+    } else {
+        year
+    };
+    let era = year / 400;
+    let yoe = year % 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    (era * 146_097 + doe).checked_sub(719_468)
+}
+
+/// Proleptic Gregorian date of the day `days` after 1970-01-01
+/// (Howard Hinnant's `civil_from_days`).
+const fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// `YYYY-MM-DD`, or `YYYY-MM-DD[T ]HH:MM[:SS[.frac]](Z|±HH[:]MM)`.
+fn parse_iso(value: &str) -> Option<String> {
+    let (date, time) = match value.split_once(['T', 't', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+    let [year, month, day] = split_numbers(date, '-')?;
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let date = format_date(year.parse().ok()?, month.parse().ok()?, day.parse().ok()?)?;
+    let Some(time) = time else {
+        return Some(date);
+    };
+
+    let (clock, offset) = if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+        (clock, "Z".to_string())
+    } else {
+        let sign = time.rfind(['+', '-'])?;
+        (&time[..sign], format_offset(&time[sign..])?)
+    };
+    let (clock, fraction) = match clock.split_once('.') {
+        Some((clock, fraction))
+            if !fraction.is_empty() && fraction.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            (clock, Some(fraction))
+        }
+        Some(_) => return None,
+        None => (clock, None),
+    };
+    let mut time = format_clock(clock)?;
+    if let Some(fraction) = fraction {
+        time.push('.');
+        time.push_str(fraction);
+    }
+    Some(format!("{date}T{time}{offset}"))
+}
+
+/// `[Day, ]D Mon YYYY HH:MM[:SS] (±HHMM|GMT|UT|Z)`.
+fn parse_rfc2822(value: &str) -> Option<String> {
+    let value = value.split_once(',').map_or(value, |(_, rest)| rest);
+    let fields: Vec<&str> = value.split_ascii_whitespace().collect();
+    let [day, month, year, clock, zone] = fields.as_slice() else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))?;
+    let date = format_date(
+        year.parse().ok().filter(|_| year.len() == 4)?,
+        u32::try_from(month + 1).ok()?,
+        day.parse().ok()?,
+    )?;
+    let offset = match zone.to_ascii_uppercase().as_str() {
+        "GMT" | "UT" | "UTC" | "Z" => "Z".to_string(),
+        zone => format_offset(zone)?,
+    };
+    Some(format!("{date}T{}{offset}", format_clock(clock)?))
+}
+
+fn split_numbers(s: &str, separator: char) -> Option<[&str; 3]> {
+    let mut parts = s.split(separator);
+    let numbers = [parts.next()?, parts.next()?, parts.next()?];
+    let valid = parts.next().is_none()
+        && numbers
+            .iter()
+            .all(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+    valid.then_some(numbers)
+}
+
+fn format_date(year: u32, month: u32, day: u32) -> Option<String> {
+    ((1..=12).contains(&month) && (1..=31).contains(&day))
+        .then(|| format!("{year:04}-{month:02}-{day:02}"))
+}
+
+/// `HH:MM` or `HH:MM:SS` as `HH:MM:SS`.
+fn format_clock(clock: &str) -> Option<String> {
+    let fields: Vec<&str> = clock.split(':').collect();
+    let (hour, minute, second) = match fields.as_slice() {
+        [h, m] => (h, m, "00"),
+        [h, m, s] => (h, m, *s),
+        _ => return None,
+    };
+    let digits = |s: &str| {
+        (s.len() == 2 && s.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| s.parse::<u32>().ok())
+            .flatten()
+    };
+    let (hour, minute, second) = (digits(hour)?, digits(minute)?, digits(second)?);
+    // 60 allows for leap seconds
+    (hour < 24 && minute < 60 && second <= 60).then(|| format!("{hour:02}:{minute:02}:{second:02}"))
+}
+
+/// `±HH:MM` or `±HHMM` as `±HH:MM`.
+fn format_offset(offset: &str) -> Option<String> {
+    let (sign, rest) = offset.split_at_checked(1)?;
+    if sign != "+" && sign != "-" {
+        return None;
+    }
+    let digits = rest.replacen(':', "", 1);
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = digits.split_at(2);
+    (hours < "24" && minutes < "60").then(|| format!("{sign}{hours}:{minutes}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalizes_iso_variants() {
+        for (input, expected) in [
+            ("2024-03-05T10:20:30Z", "2024-03-05T10:20:30Z"),
+            ("2024-03-05t10:20:30.123z", "2024-03-05T10:20:30.123Z"),
+            ("2024-03-05 10:20+0100", "2024-03-05T10:20:00+01:00"),
+            ("2024-03-05T10:20:30-05:30", "2024-03-05T10:20:30-05:30"),
+            (" 2024-03-05 ", "2024-03-05"),
+        ] {
+            assert_eq!(normalize(input), expected, "input {input:?}");
+        }
+    }
+
+    #[test]
+    fn test_normalizes_rfc2822() {
+        assert_eq!(
+            normalize("Tue, 5 Mar 2024 10:20:30 +0200"),
+            "2024-03-05T10:20:30+02:00"
+        );
+        assert_eq!(normalize("5 mar 2024 10:20 GMT"), "2024-03-05T10:20:00Z");
+    }
+
+    #[test]
+    fn test_format_rfc3339() {
+        use std::time::Duration;
+        for (secs, expected) in [
+            (0, "1970-01-01T00:00:00Z"),
+            (951_782_400, "2000-02-29T00:00:00Z"),
+            (1_717_243_200, "2024-06-01T12:00:00Z"),
+            (4_102_444_799, "2099-12-31T23:59:59Z"),
+        ] {
+            assert_eq!(
+                format_rfc3339(UNIX_EPOCH + Duration::from_secs(secs)),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_passes_through_unparseable() {
+        for input in [
+            "March 5th, 2024",
+            "2024-03-05T10:20:30",
+            "2024-13-05T10:20:30Z",
+            "2024-03-05T25:20:30Z",
+        ] {
+            assert_eq!(normalize(input), input, "input {input:?}");
+        }
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        // 1994-11-06T08:49:37Z, in each of RFC 9110's formats
+        for value in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(parse_http_date(value), Some(784_111_777), "{value}");
+        }
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_http_date("Tue, 13 Oct 2026 08:00:00 GMT"),
+            Some(1_791_878_400)
+        );
+        for value in [
+            "Wed, 31 Dec 1969 23:59:59 GMT",
+            "Sun, 06 Nov 1994 08:49:37 +0000",
+            "Sun, 06 Nov 1994 8:49:37 GMT",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "yesterday",
+        ] {
+            assert_eq!(parse_http_date(value), None, "{value}");
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Rendering of `<dl>` definition lists, which GFM has no syntax for.
+//!
+//! The `<dt>` and `<dd>` handlers wrap their converted content in control
+//! character markers; the `<dl>` handler then reads its entries back out of
+//! its own content, ignoring the whitespace between them. Nested lists are
+//! rendered (and their markers consumed) before the enclosing one.
+
+use crate::DefinitionListStyle;
+use markup5ever_rcdom::{Handle, NodeData};
+
+const TERM: char = '\u{1}';
+const DEFINITION: char = '\u{3}';
+const END: char = '\u{2}';
+
+/// Output of the `<dt>` handler.
+pub fn term(node: &Handle, content: &str) -> String {
+    wrap(node, TERM, content)
+}
+
+/// Output of the `<dd>` handler.
+pub fn definition(node: &Handle, content: &str) -> String {
+    wrap(node, DEFINITION, content)
+}
+
+/// Mark up `content` for the enclosing `<dl>`, or pass it through as a
+/// paragraph when there is none.
+fn wrap(node: &Handle, marker: char, content: &str) -> String {
+    let content = content.trim();
+    if in_list(node) {
+        format!("{marker}{content}{END}")
+    } else {
+        format!("\n\n{content}\n\n")
+    }
+}
+
+/// Output of the `<dl>` handler: its entries rendered in `style`.
+pub fn list(content: &str, style: DefinitionListStyle) -> String {
+    let mut out = String::new();
+    let mut after_definition = false;
+    let mut rest = content;
+    while let Some(start) = rest.find([TERM, DEFINITION]) {
+        let marker = rest[start..].chars().next().unwrap_or(TERM);
+        let body = &rest[start + marker.len_utf8()..];
+        let end = body.find(END).unwrap_or(body.len());
+        let text = &body[..end];
+        rest = body.get(end + END.len_utf8()..).unwrap_or("");
+
+        if marker == TERM {
+            if after_definition {
+                out.push('\n');
+            }
+            out.push_str("**");
+            out.push_str(text);
+            out.push_str("**\n");
+            after_definition = false;
+        } else {
+            let prefix = match style {
+                DefinitionListStyle::BoldTermParagraph => ": ",
+                DefinitionListStyle::Indented => "  ",
+            };
+            out.push_str(prefix);
+            out.push_str(&indent(text));
+            out.push('\n');
+            after_definition = true;
+        }
+    }
+    format!("\n\n{}\n\n", out.trim_end())
+}
+
+/// Indent every line after the first by two spaces, so multi-paragraph
+/// definitions stay attached to their marker.
+fn indent(text: &str) -> String {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            if i == 0 || line.is_empty() {
+                line.to_string()
+            } else {
+                format!("  {line}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Whether `node` has a `<dl>` ancestor (directly, or through the `<div>`
+/// wrappers HTML allows around groups).
+fn in_list(node: &Handle) -> bool {
+    let mut current = parent(node);
+    while let Some(node) = current {
+        if matches!(&node.data, NodeData::Element { name, .. } if name.local.as_ref() == "dl") {
+            return true;
+        }
+        current = parent(&node);
+    }
+    false
+}
+
+fn parent(node: &Handle) -> Option<Handle> {
+    let weak = node.parent.take();
+    let parent = weak.as_ref().and_then(std::rc::Weak::upgrade);
+    node.parent.set(weak);
+    parent
+}
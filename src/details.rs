@@ -0,0 +1,77 @@
+//! Rendering of `<details>` disclosure widgets, whose hidden content GFM
+//! has no way to collapse.
+//!
+//! As with definition lists, the `<summary>` handler wraps its converted
+//! content in control character markers, which the enclosing `<details>`
+//! handler reads back out of its own content. Nested widgets are rendered
+//! (and their markers consumed) before the enclosing one.
+
+use crate::DetailsHandling;
+use html5ever::serialize::{serialize, SerializeOpts, TraversalScope};
+use markup5ever_rcdom::{Handle, NodeData, SerializableHandle};
+
+const SUMMARY: char = '\u{1}';
+const END: char = '\u{2}';
+
+/// Output of the `<summary>` handler: marked up for the enclosing
+/// `<details>`, or passed through as a paragraph when there is none.
+pub fn summary(node: &Handle, content: &str) -> String {
+    let content = content.trim();
+    if is_child_of(node, "details") {
+        format!("{SUMMARY}{content}{END}")
+    } else {
+        format!("\n\n{content}\n\n")
+    }
+}
+
+/// Output of the `<details>` handler for `Expand` and `SummaryOnly`: the
+/// summary in bold, followed by the body unless only the summary is wanted.
+pub fn details(content: &str, handling: DetailsHandling) -> String {
+    let (summary, body) = content.find(SUMMARY).map_or_else(
+        || (None, content.to_string()),
+        |start| {
+            let rest = &content[start + SUMMARY.len_utf8()..];
+            let end = rest.find(END).unwrap_or(rest.len());
+            let body = format!(
+                "{}{}",
+                &content[..start],
+                rest.get(end + END.len_utf8()..).unwrap_or("")
+            );
+            (Some(&rest[..end]), body)
+        },
+    );
+
+    let mut out = String::from("\n\n");
+    if let Some(summary) = summary.filter(|summary| !summary.is_empty()) {
+        out.push_str("**");
+        out.push_str(summary);
+        out.push_str("**\n\n");
+    }
+    if handling == DetailsHandling::Expand {
+        out.push_str(body.trim());
+    }
+    format!("{}\n\n", out.trim_end())
+}
+
+/// Output of the `<details>` handler for `KeepHtml`: the element serialized
+/// back to HTML, which markdown renderers pass through as an HTML block.
+pub fn html(node: &Handle) -> Option<String> {
+    let mut out = Vec::new();
+    let opts = SerializeOpts {
+        traversal_scope: TraversalScope::IncludeNode,
+        ..SerializeOpts::default()
+    };
+    serialize(&mut out, &SerializableHandle::from(node.clone()), opts).ok()?;
+    Some(format!("\n\n{}\n\n", String::from_utf8(out).ok()?.trim()))
+}
+
+/// Whether `node` is a direct child of a `tag` element, as a `<summary>`
+/// must be of a `<details>`.
+pub fn is_child_of(node: &Handle, tag: &str) -> bool {
+    let weak = node.parent.take();
+    let parent = weak.as_ref().and_then(std::rc::Weak::upgrade);
+    node.parent.set(weak);
+    parent.is_some_and(|parent| {
+        matches!(&parent.data, NodeData::Element { name, .. } if name.local.as_ref() == tag)
+    })
+}
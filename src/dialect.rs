@@ -0,0 +1,220 @@
+//! Rendering of the constructs GFM has and `CommonMark` lacks, for the
+//! [`MarkdownDialect`]: tables, strikethrough, and task list checkboxes.
+//!
+//! GFM gets pipe tables, `~~` and `[x]`; `CommonMark` gets an HTML block,
+//! inline `<del>`, and escaped brackets. As with `<details>`, the
+//! `<td>`/`<th>` and `<tr>` handlers wrap their
+//! converted content in control character markers, which the enclosing
+//! `<table>` handler reads back out of its own content to lay out the rows.
+//!
+//! [`MarkdownDialect`]: crate::MarkdownDialect
+
+use crate::{details, MarkdownDialect};
+use markup5ever_rcdom::{Handle, NodeData};
+
+const CELL: char = '\u{6}';
+const CELL_END: char = '\u{7}';
+const ROW: char = '\u{e}';
+const ROW_END: char = '\u{f}';
+
+/// Output of the `<td>` and `<th>` handlers: the content on one line, as a
+/// pipe table cell must be.
+pub fn cell(content: &str) -> String {
+    let content = content
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace('|', "\\|");
+    format!("{CELL}{content}{CELL_END}")
+}
+
+/// Output of the `<tr>` handler.
+pub fn row(content: &str) -> String {
+    format!("{ROW}{content}{ROW_END}")
+}
+
+/// Output of the `<table>` handler for GFM: a pipe table whose first row
+/// is the header, preceded by the caption, if any.
+pub fn pipe_table(node: &Handle, content: &str) -> String {
+    let mut rows: Vec<Vec<&str>> = Vec::new();
+    let mut caption = String::new();
+    let mut rest = content;
+    while let Some(start) = rest.find(ROW) {
+        caption.push_str(&rest[..start]);
+        let after = &rest[start + ROW.len_utf8()..];
+        let end = after.find(ROW_END).unwrap_or(after.len());
+        rows.push(cells(&after[..end]));
+        rest = after.get(end + ROW_END.len_utf8()..).unwrap_or("");
+    }
+    caption.push_str(rest);
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    if columns == 0 {
+        return content.to_string();
+    }
+
+    let mut out = String::from("\n\n");
+    let caption = caption.split_whitespace().collect::<Vec<_>>().join(" ");
+    if !caption.is_empty() {
+        out.push_str(&caption);
+        out.push_str("\n\n");
+    }
+    let mut alignments = alignments(node);
+    alignments.resize(columns, "---");
+    for (i, cells) in rows.iter().enumerate() {
+        out.push('|');
+        for column in 0..columns {
+            out.push(' ');
+            out.push_str(cells.get(column).copied().unwrap_or(""));
+            out.push_str(" |");
+        }
+        out.push('\n');
+        if i == 0 {
+            out.push_str("| ");
+            out.push_str(&alignments.join(" | "));
+            out.push_str(" |\n");
+        }
+    }
+    out.push('\n');
+    out
+}
+
+/// Output of the `<table>` handler for `CommonMark`: the table serialized
+/// back to HTML, without the blank lines that would end the HTML block.
+pub fn html_block(node: &Handle) -> Option<String> {
+    let html = details::html(node)?;
+    let lines: Vec<&str> = html
+        .trim()
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    Some(format!("\n\n{}\n\n", lines.join("\n")))
+}
+
+/// Output of the `<del>`, `<s>`, and `<strike>` handlers.
+pub fn strikethrough(content: &str, dialect: MarkdownDialect) -> String {
+    let text = content.trim();
+    if text.is_empty() {
+        return content.to_string();
+    }
+    match dialect {
+        MarkdownDialect::Gfm => format!("~~{text}~~"),
+        MarkdownDialect::CommonMark => format!("<del>{text}</del>"),
+    }
+}
+
+/// Output of the `<input>` handler: a checkbox's state as a task list
+/// marker, or for `CommonMark` as literal brackets. Other inputs are
+/// dropped, as the converter would.
+pub fn checkbox(node: &Handle, dialect: MarkdownDialect) -> String {
+    let is_checkbox =
+        attr(node, "type").is_some_and(|kind| kind.trim().eq_ignore_ascii_case("checkbox"));
+    if !is_checkbox {
+        return String::new();
+    }
+    let mark = if attr(node, "checked").is_some() {
+        'x'
+    } else {
+        ' '
+    };
+    match dialect {
+        MarkdownDialect::Gfm => format!("[{mark}] "),
+        MarkdownDialect::CommonMark => format!("\\[{mark}\\] "),
+    }
+}
+
+/// The cells of a row's content.
+fn cells(row: &str) -> Vec<&str> {
+    let mut cells = Vec::new();
+    let mut rest = row;
+    while let Some(start) = rest.find(CELL) {
+        let after = &rest[start + CELL.len_utf8()..];
+        let end = after.find(CELL_END).unwrap_or(after.len());
+        cells.push(&after[..end]);
+        rest = after.get(end + CELL_END.len_utf8()..).unwrap_or("");
+    }
+    cells
+}
+
+/// The delimiter row cells for the columns of a table, from the `align`
+/// attribute or `text-align` style of its first row's cells.
+fn alignments(table: &Handle) -> Vec<&'static str> {
+    let Some(first) = first_row(table) else {
+        return Vec::new();
+    };
+    let cells = first.children.borrow();
+    cells
+        .iter()
+        .filter(|child| matches!(element_name(child), Some("td" | "th")))
+        .map(|cell| {
+            let style = attr(cell, "style").unwrap_or_default().to_ascii_lowercase();
+            let align = attr(cell, "align")
+                .map(|align| align.trim().to_ascii_lowercase())
+                .or_else(|| {
+                    style.split(';').find_map(|declaration| {
+                        let (property, value) = declaration.split_once(':')?;
+                        (property.trim() == "text-align").then(|| value.trim().to_string())
+                    })
+                });
+            match align.as_deref() {
+                Some("left") => ":---",
+                Some("center") => ":---:",
+                Some("right") => "---:",
+                _ => "---",
+            }
+        })
+        .collect()
+}
+
+/// The table's first `<tr>`, outside any nested table.
+fn first_row(node: &Handle) -> Option<Handle> {
+    node.children
+        .borrow()
+        .iter()
+        .find_map(|child| match element_name(child) {
+            Some("tr") => Some(child.clone()),
+            Some("thead" | "tbody" | "tfoot") => first_row(child),
+            _ => None,
+        })
+}
+
+fn element_name(node: &Handle) -> Option<&str> {
+    match &node.data {
+        NodeData::Element { name, .. } => Some(name.local.as_ref()),
+        _ => None,
+    }
+}
+
+fn attr(node: &Handle, name: &str) -> Option<String> {
+    match &node.data {
+        NodeData::Element { attrs, .. } => attrs
+            .borrow()
+            .iter()
+            .find(|a| a.name.local.as_ref() == name)
+            .map(|a| a.value.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cells_are_one_line() {
+        let row = row(&format!("\n{}\n{}", cell("\n\na\n\nb | c\n\n"), cell("")));
+        assert_eq!(
+            cells(row.trim_start_matches(ROW).trim_end_matches(ROW_END)),
+            ["a b \\| c", ""]
+        );
+    }
+
+    #[test]
+    fn test_strikethrough() {
+        assert_eq!(strikethrough(" gone ", MarkdownDialect::Gfm), "~~gone~~");
+        assert_eq!(
+            strikethrough("gone", MarkdownDialect::CommonMark),
+            "<del>gone</del>"
+        );
+        assert_eq!(strikethrough(" ", MarkdownDialect::Gfm), " ");
+    }
+}
@@ -0,0 +1,87 @@
+//! RFC 9530 `Content-Digest` and RFC 3230 `Digest` for converted
+//! responses, whose upstream digests no longer describe the body.
+
+use crate::DigestAlgorithm;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bytes::Bytes;
+use http::{HeaderMap, HeaderValue, Response, StatusCode};
+use sha2::{Digest, Sha256, Sha512};
+
+/// Headers digesting the inner service's body: RFC 9530's, RFC 3230's
+/// `Digest`, and the obsolete `Content-MD5`.
+const UPSTREAM: [&str; 4] = ["content-digest", "repr-digest", "digest", "content-md5"];
+
+/// Remove the inner service's digests from converted `headers`.
+pub fn strip(headers: &mut HeaderMap) {
+    for name in UPSTREAM {
+        headers.remove(name);
+    }
+}
+
+/// Replace the digests of a converted response with a `Content-Digest` of
+/// the body it sends, when `content` is set, and a `Digest` of the whole
+/// markdown, when `instance` is. Responses without content (`304` and
+/// `416`) get neither, and partial ones no `Digest`.
+pub fn apply(
+    response: Response<Bytes>,
+    content: Option<DigestAlgorithm>,
+    instance: Option<DigestAlgorithm>,
+) -> Response<Bytes> {
+    let (mut parts, body) = response.into_parts();
+    strip(&mut parts.headers);
+    let sends_content = matches!(parts.status, StatusCode::OK | StatusCode::PARTIAL_CONTENT);
+    if let Some(algorithm) = content.filter(|_| sends_content) {
+        if let Ok(value) = HeaderValue::from_str(&field(&body, algorithm)) {
+            parts.headers.insert("content-digest", value);
+        }
+    }
+    if let Some(algorithm) = instance.filter(|_| parts.status == StatusCode::OK) {
+        if let Ok(value) = HeaderValue::from_str(&instance_digest(&body, algorithm)) {
+            parts.headers.insert("digest", value);
+        }
+    }
+    Response::from_parts(parts, body)
+}
+
+/// The `Content-Digest` dictionary member for `body`: the algorithm's name
+/// and the digest as a structured-field byte sequence (`:base64:`).
+fn field(body: &[u8], algorithm: DigestAlgorithm) -> String {
+    format!("{}=:{}:", algorithm.name(), encode(body, algorithm))
+}
+
+/// The `Digest` value for `body`: the algorithm's name and the base64
+/// digest.
+fn instance_digest(body: &[u8], algorithm: DigestAlgorithm) -> String {
+    format!("{}={}", algorithm.name(), encode(body, algorithm))
+}
+
+/// The base64 digest of `body`.
+fn encode(body: &[u8], algorithm: DigestAlgorithm) -> String {
+    match algorithm {
+        DigestAlgorithm::Sha256 => BASE64_STANDARD.encode(Sha256::digest(body)),
+        DigestAlgorithm::Sha512 => BASE64_STANDARD.encode(Sha512::digest(body)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field() {
+        // RFC 9530 Appendix B
+        assert_eq!(
+            field(b"{\"hello\": \"world\"}", DigestAlgorithm::Sha256),
+            "sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:"
+        );
+        assert_eq!(
+            field(b"{\"hello\": \"world\"}", DigestAlgorithm::Sha512),
+            "sha-512=:WZDPaVn/7XgHaAy8pmojAkGWoRx2UFChF41A2svX+TaPm+AbwAgBWnrI\
+             iYllu7BNNyealdVLvRwEmTHWXvJwew==:"
+        );
+        assert_eq!(
+            instance_digest(b"{\"hello\": \"world\"}", DigestAlgorithm::Sha256),
+            "sha-256=X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE="
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Post-pass decoding HTML character references left in the markdown's
+//! text, e.g. from double-encoded HTML, and replacing non-breaking spaces.
+
+use crate::budget;
+use html5ever::data::NAMED_ENTITIES;
+
+/// With `references`, decode the character references (`&amp;`, `&#169;`,
+/// `&#xa9;`) in the text of `markdown`; with `nbsp_to_space`, write its
+/// non-breaking spaces as plain spaces.
+///
+/// Fenced code blocks, code spans, and raw HTML blocks (up to the next
+/// blank line) are left verbatim. So are `&lt;` and
+/// `&gt;`, which renderers display as `<` and `>` anyway, whereas decoding
+/// them could turn text into HTML tags.
+pub fn normalize(markdown: &str, references: bool, nbsp_to_space: bool) -> String {
+    let options = Options {
+        references,
+        nbsp_to_space,
+    };
+    let mut out = String::with_capacity(markdown.len());
+    let mut html_block = false;
+    for (i, line) in budget::scan(markdown).into_iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let code = line.fence.is_some() || line.closes_fence;
+        html_block = !code && !line.blank && (html_block || opens_html_block(line.text));
+        if code || html_block {
+            out.push_str(line.text);
+        } else {
+            decode_line(line.text, options, &mut out);
+        }
+    }
+    out
+}
+
+/// Whether `line` starts a block of raw HTML: a tag, closing tag, or
+/// comment.
+fn opens_html_block(line: &str) -> bool {
+    let mut chars = line.trim_start().chars();
+    chars.next() == Some('<')
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '/' || c == '!')
+}
+
+/// Decode a line outside fenced code, skipping its code spans.
+fn decode_line(line: &str, options: Options, out: &mut String) {
+    split_code_spans(line, |text, code| {
+        if code {
+            out.push_str(text);
+        } else {
+            decode_text(text, options, out);
+        }
+    });
+}
+
+/// Pass the stretches of `line` to `each` in order, with whether they are
+/// code spans (backticks included). An unclosed backtick run is text.
+pub fn split_code_spans(line: &str, mut each: impl FnMut(&str, bool)) {
+    let mut rest = line;
+    while let Some(start) = rest.find('`') {
+        let ticks = rest[start..].len() - rest[start..].trim_start_matches('`').len();
+        let after = &rest[start + ticks..];
+        // A span closes at the next backtick run of the same length
+        let mut offset = 0;
+        let close = loop {
+            let Some(at) = after[offset..].find('`') else {
+                break None;
+            };
+            let run_start = offset + at;
+            let run = after[run_start..].len() - after[run_start..].trim_start_matches('`').len();
+            if run == ticks {
+                break Some(run_start + run);
+            }
+            offset = run_start + run;
+        };
+        each(&rest[..start], false);
+        let end = start + ticks + close.unwrap_or(0);
+        each(&rest[start..end], close.is_some());
+        rest = &rest[end..];
+    }
+    each(rest, false);
+}
+
+#[derive(Clone, Copy)]
+struct Options {
+    references: bool,
+    nbsp_to_space: bool,
+}
+
+fn decode_text(text: &str, options: Options, out: &mut String) {
+    let Options {
+        references,
+        nbsp_to_space,
+    } = options;
+    let mut rest = text;
+    let stops: &[char] = if references {
+        &['&', '\u{a0}']
+    } else {
+        &['\u{a0}']
+    };
+    while let Some(at) = rest.find(stops) {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        if let Some(after) = rest.strip_prefix('\u{a0}') {
+            out.push(if nbsp_to_space { ' ' } else { '\u{a0}' });
+            rest = after;
+            continue;
+        }
+        let Some((decoded, len)) = reference(rest) else {
+            out.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        for c in decoded {
+            out.push(if nbsp_to_space && c == '\u{a0}' {
+                ' '
+            } else {
+                c
+            });
+        }
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+}
+
+/// The characters of the reference `text` starts with, and its length.
+fn reference(text: &str) -> Option<(impl Iterator<Item = char>, usize)> {
+    let end = text.find(';')?;
+    let body = &text[1..end];
+    let (first, second) = if let Some(number) = body.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        // `from_u32` rejects surrogates; NUL is a parse error in HTML
+        (char::from_u32(code).filter(|&c| c != '\0')?, None)
+    } else {
+        if !body.bytes().all(|b| b.is_ascii_alphanumeric()) || matches!(body, "lt" | "gt") {
+            return None;
+        }
+        let &(first, second) = NAMED_ENTITIES.get(&text[1..=end])?;
+        (
+            char::from_u32(first)?,
+            char::from_u32(second).filter(|_| second != 0),
+        )
+    };
+    Some((std::iter::once(first).chain(second), end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decodes_text_not_code() {
+        let md = "Tom &amp; Jerry&nbsp;&#169;&#xA9; &lt;b&gt; &unknown; & co\n\n\
+                  `a&amp;b` and ``x ` &amp;``\n\n```\na &amp;&nbsp;b\n```\n\n&quot;end&quot;";
+        assert_eq!(
+            normalize(md, true, false),
+            "Tom & Jerry\u{a0}©© &lt;b&gt; &unknown; & co\n\n\
+             `a&amp;b` and ``x ` &amp;``\n\n```\na &amp;&nbsp;b\n```\n\n\"end\""
+        );
+        assert_eq!(
+            normalize("&#0; &#xD800; &#;", true, false),
+            "&#0; &#xD800; &#;"
+        );
+    }
+
+    #[test]
+    fn test_nbsp_to_space() {
+        let md = "a&nbsp;b\u{a0}c `d\u{a0}e`\n\n```\nf\u{a0}g\n```";
+        assert_eq!(
+            normalize(md, true, true),
+            "a b c `d\u{a0}e`\n\n```\nf\u{a0}g\n```"
+        );
+        assert_eq!(
+            normalize(md, true, false),
+            "a\u{a0}b\u{a0}c `d\u{a0}e`\n\n```\nf\u{a0}g\n```"
+        );
+        assert_eq!(normalize("&amp;&nbsp;\u{a0}", false, true), "&amp;&nbsp; ");
+    }
+
+    #[test]
+    fn test_html_blocks_kept_verbatim() {
+        let md = "<details><summary>A &amp; B</summary>\n&amp;\n\n&amp; <b>x</b>";
+        assert_eq!(
+            normalize(md, true, false),
+            "<details><summary>A &amp; B</summary>\n&amp;\n\n& <b>x</b>"
+        );
+    }
+
+    #[test]
+    fn test_unclosed_code_span_is_text() {
+        assert_eq!(normalize("a ` b &amp; c", true, false), "a ` b & c");
+    }
+}
@@ -0,0 +1,100 @@
+//! Approximate token counting for [`Estimation::Approximate`](crate::Estimation).
+//!
+//! BPE encoders split text into pieces (words, digit groups, punctuation
+//! runs) before merging bytes within each piece, so counting those pieces,
+//! with long pieces worth several tokens, tracks the exact count far better
+//! than a flat bytes-per-token ratio on code and tables. The weights are
+//! tuned against `o200k_base` on prose, code, and table-heavy markdown.
+
+use crate::TokenCounter;
+
+/// Letters per token in a long word.
+const LETTERS: usize = 8;
+/// Digits per token (BPE encoders group at most three).
+const DIGITS: usize = 3;
+/// Punctuation characters per token.
+const PUNCTUATION: usize = 2;
+/// Spaces that make an indentation run a token of its own.
+const INDENT: usize = 5;
+/// From here up, characters (CJK and the like) are a token each, not words.
+const IDEOGRAPHS: char = '\u{2e80}';
+
+/// The heuristic counter.
+pub struct Approximate;
+
+impl TokenCounter for Approximate {
+    fn count(&self, text: &str) -> usize {
+        let mut pieces = 0;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            let mut run: usize = 1;
+            let mut extend = |same: fn(char) -> bool| {
+                while chars.next_if(|&next| same(next)).is_some() {
+                    run += 1;
+                }
+            };
+            pieces += if c >= IDEOGRAPHS {
+                1
+            } else if c.is_alphabetic() {
+                extend(|c| c.is_alphabetic() && c < IDEOGRAPHS);
+                run.div_ceil(LETTERS)
+            } else if c.is_numeric() {
+                extend(char::is_numeric);
+                run.div_ceil(DIGITS)
+            } else if c == ' ' {
+                extend(|c| c == ' ');
+                usize::from(run >= INDENT)
+            } else if c.is_whitespace() {
+                extend(|c| c.is_whitespace() && c != ' ');
+                1
+            } else {
+                extend(|c| !c.is_alphanumeric() && !c.is_whitespace());
+                run.div_ceil(PUNCTUATION)
+            };
+        }
+        // Pieces slightly outnumber tokens, which merge across some
+        // boundaries (e.g. a leading space or punctuation into a word)
+        pieces * 7 / 8
+    }
+}
+
+#[cfg(all(test, feature = "tokens"))]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::TokenizerModel;
+    use std::fmt::Write;
+
+    #[test]
+    fn test_estimate_tracks_o200k() {
+        let prose = "Exact BPE counting on every response is the single biggest CPU line \
+            item from this middleware in our profiles, and our consumers only need a \
+            ballpark to decide whether to fetch. Headings, lists, and **emphasis** \
+            are common, as are [links](https://example.com/docs/getting-started).\n\n"
+            .repeat(20);
+        let code = format!("# Budget\n\n```rust\n{}\n```\n", include_str!("budget.rs"));
+        let mut table = String::from("| id | name | link | price |\n| --- | --- | --- | --- |\n");
+        for i in 0..60 {
+            writeln!(
+                table,
+                "| {i} | item-{i} | [link](https://example.com/items/{i}) | 3.{i}5 |"
+            )
+            .unwrap();
+        }
+        let cjk = "このミドルウェアは、HTMLの応答をマークダウンに変換し、\
+            トークン数をヘッダーで返します。\n"
+            .repeat(10);
+
+        for (name, text) in [
+            ("prose", prose),
+            ("code", code),
+            ("table", table),
+            ("cjk", cjk),
+        ] {
+            let exact = TokenizerModel::O200kBase.count(&text);
+            let approximate = Approximate.count(&text);
+            let error = approximate.abs_diff(exact) * 100 / exact;
+            assert!(error <= 20, "{name}: {approximate} vs {exact} exact");
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Strong `ETag`s for converted responses (RFC 9110 §8.8.3), the inner
+//! service's validators they replace, and `If-None-Match` against them.
+//! `If-Modified-Since` is evaluated against the `Last-Modified` of
+//! converted responses, which is the HTML's.
+
+use crate::{date, ETagPolicy, MarkdownConfig};
+use bytes::Bytes;
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE, ETAG, LAST_MODIFIED},
+    HeaderMap, HeaderValue, Response, StatusCode,
+};
+use siphasher::sip128::SipHasher;
+
+/// A strong entity tag for `body`: its 128-bit `SipHash`, in hex.
+pub fn strong(body: &[u8]) -> HeaderValue {
+    let hash = u128::from(SipHasher::new().hash(body));
+    HeaderValue::from_str(&format!("\"{hash:032x}\"")).unwrap_or(HeaderValue::from_static("\"\""))
+}
+
+/// Copy the inner service's `ETag` and `Last-Modified` into the configured
+/// `x-original-*` headers.
+fn preserve_original(headers: &mut HeaderMap, config: &MarkdownConfig) {
+    for (header, name) in [
+        (ETAG, &config.original_etag_header),
+        (LAST_MODIFIED, &config.original_last_modified_header),
+    ] {
+        if let Some((name, value)) = name.clone().zip(headers.get(header).cloned()) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Set the `ETag` of a converted response with `body` per
+/// [`MarkdownConfig::etag`], keeping the original validators first.
+pub fn apply(headers: &mut HeaderMap, body: &[u8], config: &MarkdownConfig) {
+    preserve_original(headers, config);
+    let replace = match config.etag {
+        ETagPolicy::Keep => false,
+        ETagPolicy::Replace => headers.contains_key(ETAG),
+        ETagPolicy::Always => true,
+    };
+    if replace {
+        headers.insert(ETAG, strong(body));
+    }
+}
+
+/// Recompute an `ETag` set by [`apply`] for a new `body`, e.g. one page.
+pub fn refresh(headers: &mut HeaderMap, body: &[u8], policy: ETagPolicy) {
+    if policy != ETagPolicy::Keep && headers.contains_key(ETAG) {
+        headers.insert(ETAG, strong(body));
+    }
+}
+
+/// The opaque tags of an `If-None-Match` entity-tag list, without any
+/// `W/` prefix, so that comparing them is the weak comparison. Parsing
+/// stops at the first malformed member.
+fn opaque_tags(value: &str) -> impl Iterator<Item = &str> {
+    let mut rest = value;
+    std::iter::from_fn(move || {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        let tag = rest.strip_prefix("W/").unwrap_or(rest);
+        let end = tag.strip_prefix('"')?.find('"')? + 2;
+        rest = &tag[end..];
+        Some(&tag[..end])
+    })
+}
+
+/// Whether `If-None-Match` field lines match the current `etag`
+/// (RFC 9110 §13.1.2): `*` matches any current representation, a list
+/// matches if any member is weakly equal to `etag`.
+fn none_match(if_none_match: &[HeaderValue], etag: Option<&HeaderValue>) -> bool {
+    let etag = etag.and_then(|etag| etag.to_str().ok()).map(|etag| {
+        let etag = etag.trim();
+        etag.strip_prefix("W/").unwrap_or(etag)
+    });
+    if_none_match
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| {
+            value.trim() == "*" || etag.is_some_and(|etag| opaque_tags(value).any(|t| t == etag))
+        })
+}
+
+/// Turn a `200 OK` response into an empty `304 Not Modified` one if the
+/// request's `If-None-Match` matches its `ETag`. Other responses, and
+/// requests without `If-None-Match`, pass through.
+pub fn not_modified(response: Response<Bytes>, if_none_match: &[HeaderValue]) -> Response<Bytes> {
+    if response.status() != StatusCode::OK
+        || if_none_match.is_empty()
+        || !none_match(if_none_match, response.headers().get(ETAG))
+    {
+        return response;
+    }
+    into_not_modified(response)
+}
+
+/// Turn a `200 OK` response into an empty `304 Not Modified` one if it was
+/// last modified no later than the request's `If-Modified-Since`
+/// (RFC 9110 §13.1.3). Responses without a valid `Last-Modified`, and
+/// requests without a valid `If-Modified-Since`, pass through.
+pub fn not_modified_since(
+    response: Response<Bytes>,
+    if_modified_since: Option<&HeaderValue>,
+) -> Response<Bytes> {
+    let parse = |value: Option<&HeaderValue>| date::parse_http_date(value?.to_str().ok()?);
+    let unmodified = parse(response.headers().get(LAST_MODIFIED))
+        .zip(parse(if_modified_since))
+        .is_some_and(|(modified, since)| modified <= since);
+    if response.status() != StatusCode::OK || !unmodified {
+        return response;
+    }
+    into_not_modified(response)
+}
+
+/// The empty `304 Not Modified` for a response, keeping its validators.
+fn into_not_modified(response: Response<Bytes>) -> Response<Bytes> {
+    let (mut parts, _) = response.into_parts();
+    parts.status = StatusCode::NOT_MODIFIED;
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.remove(CONTENT_TYPE);
+    Response::from_parts(parts, Bytes::new())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strong_etag_format() {
+        let etag = strong(b"# Hello");
+        let etag = etag.to_str().unwrap_or_default();
+        assert_eq!(etag.len(), 34);
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert!(etag[1..33].bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(strong(b"# Hello"), strong(b"# Hello"));
+        assert_ne!(strong(b"# Hello"), strong(b"# Hello!"));
+    }
+
+    #[test]
+    fn test_none_match_weak_comparison() {
+        let matches = |values: &[&'static str], etag: Option<&'static str>| {
+            let values: Vec<_> = values.iter().map(|v| HeaderValue::from_static(v)).collect();
+            none_match(&values, etag.map(HeaderValue::from_static).as_ref())
+        };
+        let etag = Some("\"abc\"");
+        assert!(matches(&["\"abc\""], etag));
+        assert!(matches(&["W/\"abc\""], etag));
+        assert!(matches(&["\"abc\""], Some("W/\"abc\"")));
+        assert!(matches(&["\"x\", W/\"abc\""], etag));
+        assert!(matches(&["\"x\"", "\"abc\""], etag));
+        assert!(matches(&["\"a,b\", \"abc\""], etag));
+        assert!(matches(&["*"], etag));
+        assert!(matches(&["*"], None));
+        assert!(!matches(&["\"abcd\", \"ab\""], etag));
+        assert!(!matches(&["abc"], etag));
+        assert!(!matches(&["\"abc\""], None));
+        assert!(!matches(&["\"abc"], etag));
+    }
+
+    #[test]
+    fn test_not_modified_since() {
+        let response = || {
+            Response::builder()
+                .header(LAST_MODIFIED, "Tue, 13 Oct 2026 08:00:00 GMT")
+                .header(CONTENT_TYPE, "text/markdown")
+                .body(Bytes::from_static(b"# Hello"))
+                .unwrap()
+        };
+        let status = |since: Option<&'static str>| {
+            let since = since.map(HeaderValue::from_static);
+            not_modified_since(response(), since.as_ref()).status()
+        };
+        assert_eq!(
+            status(Some("Tue, 13 Oct 2026 08:00:00 GMT")),
+            StatusCode::NOT_MODIFIED
+        );
+        assert_eq!(
+            status(Some("Wed, 14 Oct 2026 08:00:00 GMT")),
+            StatusCode::NOT_MODIFIED
+        );
+        assert_eq!(
+            status(Some("Tue, 13 Oct 2026 07:59:59 GMT")),
+            StatusCode::OK
+        );
+        assert_eq!(status(Some("not a date")), StatusCode::OK);
+        assert_eq!(status(None), StatusCode::OK);
+
+        let since = HeaderValue::from_static("Wed, 14 Oct 2026 08:00:00 GMT");
+        let response = not_modified_since(response(), Some(&since));
+        assert!(!response.headers().contains_key(CONTENT_TYPE));
+        assert_eq!(
+            response.headers()[LAST_MODIFIED],
+            "Tue, 13 Oct 2026 08:00:00 GMT"
+        );
+        assert!(response.body().is_empty());
+    }
+}
@@ -0,0 +1,68 @@
+//! Post-pass switching backtick code fences to tildes.
+
+/// Rewrite fenced code blocks whose content contains a run of three or more
+/// backticks to use tilde fences, leaving other blocks alone.
+///
+/// This relies on htmd's output shape: blocks are opened and closed by the
+/// same fence on lines of their own, and htmd lengthens a backtick fence
+/// beyond three exactly when the content contains a backtick run.
+pub fn tildes_for_backtick_content(markdown: &str) -> String {
+    let lines: Vec<&str> = markdown.split('\n').collect();
+    let mut out: Vec<String> = Vec::with_capacity(lines.len());
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        let ticks = trimmed.len() - trimmed.trim_start_matches('`').len();
+        let close = format!("{indent}{}", &trimmed[..ticks]);
+        let end = (ticks >= 3)
+            .then(|| lines[i + 1..].iter().position(|l| *l == close))
+            .flatten()
+            .map(|offset| i + 1 + offset);
+
+        let Some(end) = end else {
+            out.push(line.to_string());
+            i += 1;
+            continue;
+        };
+        let content = &lines[i + 1..end];
+        let has_backtick_run = content.iter().any(|l| l.contains("```"));
+        if has_backtick_run {
+            let longest_tilde_run = content
+                .iter()
+                .flat_map(|l| l.split(|c| c != '~'))
+                .map(str::len)
+                .max()
+                .unwrap_or(0);
+            let fence = format!("{indent}{}", "~".repeat(longest_tilde_run.max(2) + 1));
+            out.push(format!("{fence}{}", &trimmed[ticks..]));
+            out.extend(content.iter().map(ToString::to_string));
+            out.push(fence);
+        } else {
+            out.extend(lines[i..=end].iter().map(ToString::to_string));
+        }
+        i = end + 1;
+    }
+    out.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_switches_only_blocks_with_backtick_runs() {
+        let markdown = "````md\na\n```\n~~~~\nb\n````\n\n```rust\nlet x = `y`;\n```\n\n*   item\n\n    ````\n    ```\n    ````";
+        assert_eq!(
+            tildes_for_backtick_content(markdown),
+            "~~~~~md\na\n```\n~~~~\nb\n~~~~~\n\n```rust\nlet x = `y`;\n```\n\n*   item\n\n    ~~~\n    ```\n    ~~~"
+        );
+    }
+
+    #[test]
+    fn test_leaves_unclosed_fences() {
+        let markdown = "````\nnever closed";
+        assert_eq!(tildes_for_backtick_content(markdown), markdown);
+    }
+}
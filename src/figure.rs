@@ -0,0 +1,50 @@
+//! Rendering of `<figure>` elements, whose `<figcaption>` the converter
+//! would otherwise run into the image.
+//!
+//! As with `<details>`, the `<figcaption>` handler wraps its converted
+//! content in control character markers, which the enclosing `<figure>`
+//! handler reads back out of its own content: the caption becomes an
+//! italic line after the figure, wherever it appeared in the HTML.
+
+use crate::details;
+use markup5ever_rcdom::Handle;
+
+const CAPTION: char = '\u{4}';
+const END: char = '\u{5}';
+
+/// Output of the `<figcaption>` handler: marked up for the enclosing
+/// `<figure>`, or passed through as a paragraph when there is none.
+pub fn caption(node: &Handle, content: &str) -> String {
+    let content = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    if details::is_child_of(node, "figure") {
+        format!("{CAPTION}{content}{END}")
+    } else {
+        format!("\n\n{content}\n\n")
+    }
+}
+
+/// Output of the `<figure>` handler: the figure's content, then its
+/// caption in italics.
+pub fn figure(content: &str) -> String {
+    let (caption, body) = content.find(CAPTION).map_or_else(
+        || (None, content.to_string()),
+        |start| {
+            let rest = &content[start + CAPTION.len_utf8()..];
+            let end = rest.find(END).unwrap_or(rest.len());
+            let body = format!(
+                "{}{}",
+                &content[..start],
+                rest.get(end + END.len_utf8()..).unwrap_or("")
+            );
+            (Some(&rest[..end]), body)
+        },
+    );
+
+    let mut out = format!("\n\n{}", body.trim());
+    if let Some(caption) = caption.filter(|caption| !caption.is_empty()) {
+        out.push_str("\n\n*");
+        out.push_str(caption);
+        out.push('*');
+    }
+    format!("{}\n\n", out.trim_end())
+}
@@ -0,0 +1,339 @@
+//! YAML front matter rendering.
+
+use crate::{
+    json_ld, metadata::PageMetadata, BreadcrumbPlacement, JsonLdPlacement, MarkdownConfig,
+    SourcePlacement,
+};
+use http::Uri;
+use std::fmt::Write;
+
+/// A front matter value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// A string, rendered double-quoted.
+    String(String),
+    /// A list of strings, each rendered double-quoted.
+    List(Vec<String>),
+    /// A list of items rendered verbatim, e.g. JSON objects (which are valid
+    /// YAML flow syntax). The caller is responsible for their validity.
+    RawList(Vec<String>),
+}
+
+/// What a [`MarkdownConfig::front_matter_fields`] callback can see of the page.
+#[derive(Debug, Clone, Copy)]
+pub struct FrontMatterContext<'a> {
+    metadata: &'a PageMetadata,
+    uri: &'a Uri,
+    url: &'a str,
+    html: &'a str,
+}
+
+impl<'a> FrontMatterContext<'a> {
+    pub(crate) const fn new(
+        metadata: &'a PageMetadata,
+        uri: &'a Uri,
+        url: &'a str,
+        html: &'a str,
+    ) -> Self {
+        Self {
+            metadata,
+            uri,
+            url,
+            html,
+        }
+    }
+
+    /// The request URI.
+    #[must_use]
+    pub const fn uri(&self) -> &'a Uri {
+        self.uri
+    }
+
+    /// The page URL: absolute when [`MarkdownConfig::base_url`] is set or
+    /// the request has a `Host`, otherwise just the path and query.
+    #[must_use]
+    pub const fn url(&self) -> &'a str {
+        self.url
+    }
+
+    /// The full HTML document, for extracting anything the accessors below
+    /// don't cover.
+    #[must_use]
+    pub const fn html(&self) -> &'a str {
+        self.html
+    }
+
+    /// Text of the first `<title>`.
+    #[must_use]
+    pub fn title(&self) -> Option<&'a str> {
+        self.metadata.title.as_deref()
+    }
+
+    /// Content of `<meta name="description">`.
+    #[must_use]
+    pub fn description(&self) -> Option<&'a str> {
+        self.metadata.description.as_deref()
+    }
+
+    /// The canonical URL, resolved against the page URL.
+    #[must_use]
+    pub fn canonical(&self) -> Option<&'a str> {
+        self.metadata.canonical.as_deref()
+    }
+
+    /// The `lang` attribute of `<html>`.
+    #[must_use]
+    pub fn lang(&self) -> Option<&'a str> {
+        self.metadata.lang.as_deref()
+    }
+
+    /// Content of the first `<meta>` whose `name` (or `property`) is `name`,
+    /// compared case-insensitively.
+    #[must_use]
+    pub fn meta(&self, name: &str) -> Option<&'a str> {
+        self.metadata
+            .meta
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, content)| content.as_str())
+    }
+}
+
+/// An ordered set of front matter fields.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrontMatter {
+    fields: Vec<(String, Value)>,
+}
+
+impl FrontMatter {
+    /// Build the front matter for a page from its extracted metadata, then
+    /// merge in any fields from [`MarkdownConfig::front_matter_fields`].
+    pub fn from_metadata(context: &FrontMatterContext<'_>, config: &MarkdownConfig) -> Self {
+        let metadata = context.metadata;
+        let mut front_matter = Self::default();
+        if let Some(title) = &metadata.title {
+            front_matter.insert("title", Value::String(title.clone()));
+        }
+        if let Some(description) = &metadata.description {
+            front_matter.insert("description", Value::String(description.clone()));
+        }
+        if let Some(published) = metadata.published() {
+            front_matter.insert("published", Value::String(published));
+        }
+        if let Some(modified) = metadata.modified() {
+            front_matter.insert("modified", Value::String(modified));
+        }
+        if config.breadcrumbs == Some(BreadcrumbPlacement::FrontMatter) {
+            if let Some(trail) = metadata.breadcrumbs() {
+                front_matter.insert("breadcrumbs", Value::List(trail));
+            }
+        }
+        if let Some(canonical) = &metadata.canonical {
+            front_matter.insert("canonical", Value::String(canonical.clone()));
+        }
+        for (property, content) in &metadata.social {
+            let allowed = config
+                .social_properties
+                .as_ref()
+                .is_none_or(|allow| allow.iter().any(|p| p == property));
+            if allowed {
+                // `og:image` → `og.image`, keeping the namespace visible in the key
+                front_matter.append(&property.replace(':', "."), content);
+            }
+        }
+        if let Some(types) = config
+            .json_ld_types
+            .as_ref()
+            .filter(|_| config.json_ld_placement == JsonLdPlacement::FrontMatter)
+        {
+            let items: Vec<String> = json_ld::select(&metadata.json_ld, types)
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            if !items.is_empty() {
+                front_matter.insert("json_ld", Value::RawList(items));
+            }
+        }
+        if config.source_attribution.is_some()
+            && config.source_placement == SourcePlacement::FrontMatter
+        {
+            front_matter.insert("source", Value::String(context.url.to_string()));
+            front_matter.insert("retrieved_at", Value::String(config.retrieved_at_now()));
+        }
+        if let Some(fields) = &config.front_matter_fields {
+            for (key, value) in fields(context) {
+                front_matter.insert(&key, value);
+            }
+        }
+        front_matter
+    }
+
+    /// Set `key` to `value`, replacing any existing value for the key.
+    pub fn insert(&mut self, key: &str, value: Value) {
+        if let Some(field) = self.fields.iter_mut().find(|(k, _)| k == key) {
+            field.1 = value;
+        } else {
+            self.fields.push((key.to_string(), value));
+        }
+    }
+
+    /// Add `value` under `key`, turning the field into a list if the key repeats.
+    pub fn append(&mut self, key: &str, value: &str) {
+        let Some((_, existing)) = self.fields.iter_mut().find(|(k, _)| k == key) else {
+            self.fields
+                .push((key.to_string(), Value::String(value.to_string())));
+            return;
+        };
+        match existing {
+            Value::String(first) => {
+                *existing = Value::List(vec![std::mem::take(first), value.to_string()]);
+            }
+            Value::List(items) => items.push(value.to_string()),
+            Value::RawList(_) => *existing = Value::String(value.to_string()),
+        }
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Render as a `---`-delimited YAML block, including the trailing newline.
+    pub fn render(&self) -> String {
+        let mut yaml = String::from("---\n");
+        for (key, value) in &self.fields {
+            push_key(&mut yaml, key);
+            yaml.push(':');
+            match value {
+                Value::String(s) => {
+                    yaml.push(' ');
+                    push_quoted(&mut yaml, s);
+                }
+                Value::List(items) => {
+                    for item in items {
+                        yaml.push_str("\n  - ");
+                        push_quoted(&mut yaml, item);
+                    }
+                }
+                Value::RawList(items) => {
+                    for item in items {
+                        yaml.push_str("\n  - ");
+                        yaml.push_str(item);
+                    }
+                }
+            }
+            yaml.push('\n');
+        }
+        yaml.push_str("---\n");
+        yaml
+    }
+}
+
+/// Append a mapping key, quoting it unless it is a simple identifier.
+fn push_key(out: &mut String, key: &str) {
+    let plain = key.starts_with(|c: char| c.is_ascii_alphabetic())
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if plain {
+        out.push_str(key);
+    } else {
+        push_quoted(out, key);
+    }
+}
+
+/// Append `value` as a YAML double-quoted scalar.
+///
+/// Double quoting sidesteps YAML's plain-scalar rules entirely (colons,
+/// leading indicators, `yes`/`no` booleans), so only quotes, backslashes,
+/// and control characters need escaping.
+fn push_quoted(out: &mut String, value: &str) {
+    out.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04X}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(title: &str) -> String {
+        let mut front_matter = FrontMatter::default();
+        front_matter.insert("title", Value::String(title.to_string()));
+        front_matter.render()
+    }
+
+    #[test]
+    fn test_render_plain() {
+        assert_eq!(render("Hello"), "---\ntitle: \"Hello\"\n---\n");
+    }
+
+    #[test]
+    fn test_render_escapes_quotes_and_colons() {
+        assert_eq!(
+            render(r#"Rust: the "fast" \ safe"#),
+            "---\ntitle: \"Rust: the \\\"fast\\\" \\\\ safe\"\n---\n"
+        );
+    }
+
+    #[test]
+    fn test_render_escapes_control_characters() {
+        assert_eq!(render("a\tb\u{7}"), "---\ntitle: \"a\\tb\\u0007\"\n---\n");
+    }
+
+    #[test]
+    fn test_render_keeps_unicode() {
+        assert_eq!(render("Café — 東京"), "---\ntitle: \"Café — 東京\"\n---\n");
+    }
+
+    #[test]
+    fn test_insert_replaces_existing_key() {
+        let mut front_matter = FrontMatter::default();
+        front_matter.insert("title", Value::String("a".to_string()));
+        front_matter.insert("title", Value::String("b".to_string()));
+        assert_eq!(front_matter.render(), "---\ntitle: \"b\"\n---\n");
+    }
+
+    #[test]
+    fn test_append_repeated_key_becomes_list() {
+        let mut front_matter = FrontMatter::default();
+        front_matter.append("og.image", "a.png");
+        front_matter.append("og.type", "article");
+        front_matter.append("og.image", "b.png");
+        assert_eq!(
+            front_matter.render(),
+            "---\nog.image:\n  - \"a.png\"\n  - \"b.png\"\nog.type: \"article\"\n---\n"
+        );
+    }
+
+    #[test]
+    fn test_render_raw_list() {
+        let mut front_matter = FrontMatter::default();
+        front_matter.insert(
+            "json_ld",
+            Value::RawList(vec![r#"{"@type":"Article"}"#.to_string()]),
+        );
+        assert_eq!(
+            front_matter.render(),
+            "---\njson_ld:\n  - {\"@type\":\"Article\"}\n---\n"
+        );
+    }
+
+    #[test]
+    fn test_render_quotes_unusual_keys() {
+        let mut front_matter = FrontMatter::default();
+        front_matter.append("odd key: yes", "v");
+        assert_eq!(front_matter.render(), "---\n\"odd key: yes\": \"v\"\n---\n");
+    }
+}
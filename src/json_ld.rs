@@ -0,0 +1,145 @@
+//! JSON-LD structured data selection.
+
+use serde_json::Value;
+
+/// Parse the body of a `<script type="application/ld+json">` block into
+/// `items`. Malformed blocks are skipped; top-level arrays and `@graph`
+/// containers are flattened into their members.
+pub fn parse_block(block: &str, items: &mut Vec<Value>) {
+    if let Ok(value) = serde_json::from_str::<Value>(block) {
+        flatten(value, items);
+    }
+}
+
+fn flatten(value: Value, items: &mut Vec<Value>) {
+    match value {
+        Value::Array(values) => {
+            for value in values {
+                flatten(value, items);
+            }
+        }
+        Value::Object(mut object) => match object.remove("@graph") {
+            Some(graph) => flatten(graph, items),
+            None => items.push(Value::Object(object)),
+        },
+        _ => {}
+    }
+}
+
+/// Whether an item's `@type` (a string or an array of strings) is `ty`.
+pub fn has_type(item: &Value, ty: &str) -> bool {
+    match item.get("@type") {
+        Some(Value::String(t)) => t == ty,
+        Some(Value::Array(ts)) => ts.iter().any(|t| t.as_str() == Some(ty)),
+        _ => false,
+    }
+}
+
+/// The items whose `@type` is in `types`, with `@context` removed.
+pub fn select(items: &[Value], types: &[String]) -> Vec<Value> {
+    items
+        .iter()
+        .filter(|item| types.iter().any(|ty| has_type(item, ty)))
+        .map(|item| {
+            let mut item = item.clone();
+            if let Value::Object(object) = &mut item {
+                object.remove("@context");
+            }
+            item
+        })
+        .collect()
+}
+
+/// Names in the first `BreadcrumbList`'s `itemListElement`, ordered by
+/// `position`. Each name is the element's `name` or its `item.name`.
+pub fn breadcrumbs(items: &[Value]) -> Option<Vec<String>> {
+    let list = items.iter().find(|item| has_type(item, "BreadcrumbList"))?;
+    let mut elements: Vec<&Value> = match list.get("itemListElement")? {
+        Value::Array(elements) => elements.iter().collect(),
+        element => vec![element],
+    };
+    // Stable, so elements without a position keep their order
+    elements.sort_by(|a, b| position(a).total_cmp(&position(b)));
+    let names: Vec<String> = elements
+        .into_iter()
+        .filter_map(|element| {
+            let name = element
+                .get("name")
+                .or_else(|| element.get("item")?.get("name"))?
+                .as_str()?
+                .trim();
+            (!name.is_empty()).then(|| name.to_string())
+        })
+        .collect();
+    (!names.is_empty()).then_some(names)
+}
+
+/// A list element's `position`, which may be a number or a numeric string.
+fn position(element: &Value) -> f64 {
+    match element.get("position") {
+        Some(Value::Number(n)) => n.as_f64().unwrap_or(f64::MAX),
+        Some(Value::String(s)) => s.trim().parse().unwrap_or(f64::MAX),
+        _ => f64::MAX,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_skips_malformed_and_flattens() {
+        let mut items = Vec::new();
+        parse_block(r#"{"@type": "Article", "headline": "A"}"#, &mut items);
+        parse_block(r#"{"@type": "Article", "headline": "#, &mut items);
+        parse_block(
+            r#"[{"@type": "Product"}, {"@graph": [{"@type": "FAQPage"}]}]"#,
+            &mut items,
+        );
+        assert_eq!(
+            items,
+            [
+                json!({"@type": "Article", "headline": "A"}),
+                json!({"@type": "Product"}),
+                json!({"@type": "FAQPage"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_by_type() {
+        let items = [
+            json!({"@context": "https://schema.org", "@type": "Article", "headline": "A"}),
+            json!({"@type": ["Thing", "Product"], "name": "P"}),
+            json!({"@type": "Organization", "name": "O"}),
+        ];
+        let types = ["Article".to_string(), "Product".to_string()];
+        assert_eq!(
+            select(&items, &types),
+            [
+                json!({"@type": "Article", "headline": "A"}),
+                json!({"@type": ["Thing", "Product"], "name": "P"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_breadcrumbs_ordered_by_position() {
+        let items = [
+            json!({"@type": "Article"}),
+            json!({"@type": "BreadcrumbList", "itemListElement": [
+                {"position": 3, "name": "Getting Started"},
+                {"position": "1", "item": {"@id": "/", "name": "Home"}},
+                {"position": 2, "name": "Docs"},
+                {"position": 4, "name": " "},
+            ]}),
+        ];
+        assert_eq!(
+            breadcrumbs(&items).unwrap(),
+            ["Home", "Docs", "Getting Started"]
+        );
+        assert_eq!(breadcrumbs(&items[..1]), None);
+    }
+}
@@ -1,38 +1,625 @@
 #![doc = include_str!("../README.md")]
 
-use axum::body::{to_bytes, Body};
+mod base_path;
+mod blockquote;
+mod budget;
+mod cache;
+mod content_signal;
+mod date;
+mod definition_list;
+mod details;
+mod dialect;
+mod digest;
+mod entities;
+mod estimate;
+mod etag;
+mod fence;
+mod figure;
+mod front_matter;
+mod json_ld;
+mod links;
+mod list_indent;
+mod math;
+mod metadata;
+#[cfg(feature = "moka")]
+mod moka_cache;
+mod negotiate;
+mod page;
+mod range;
+mod read;
+mod sections;
+#[cfg(feature = "serve-dir")]
+mod serve_dir;
+mod sitemap;
+mod stats;
+mod summary;
+mod template;
+mod toc;
+mod url;
+mod whitespace;
+
+use axum::body::Body;
+use base64::{prelude::BASE64_STANDARD, Engine};
 use bytes::Bytes;
+pub use cache::{CacheFuture, CachedConversion, ConversionCache, Invalidation, MemoryCache};
+use cache::{Caches, HeaderChanges, UrlCache};
+pub use content_signal::{ContentSignal, InvalidContentSignal};
+use front_matter::FrontMatter;
+pub use front_matter::{FrontMatterContext, Value as FrontMatterValue};
 use http::{
-    header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, VARY},
-    HeaderMap, HeaderValue, Request, Response,
+    header::{
+        ACCEPT_RANGES, AUTHORIZATION, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LANGUAGE,
+        CONTENT_LENGTH, CONTENT_LOCATION, CONTENT_RANGE, CONTENT_SECURITY_POLICY, CONTENT_TYPE,
+        COOKIE, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LINK, ORIGIN, RANGE, REFERER,
+        SET_COOKIE, TE, TRAILER, TRANSFER_ENCODING, VARY, WARNING, X_FRAME_OPTIONS,
+        X_XSS_PROTECTION,
+    },
+    HeaderMap, HeaderName, HeaderValue, Method, Request, Response, Uri,
 };
+use http_body_util::BodyExt;
+use metadata::PageMetadata;
+#[cfg(feature = "moka")]
+pub use moka_cache::MokaCache;
+pub use negotiate::{negotiate, MediaType};
 use pin_project_lite::pin_project;
+use read::{read_body, Unread};
+#[cfg(feature = "serve-dir")]
+pub use serve_dir::serve_dir;
+pub use sitemap::markdown_sitemap_route;
+pub use stats::ConversionStats;
+use stats::Timings;
+#[cfg(feature = "tokens")]
+use std::sync::LazyLock;
 use std::{
+    borrow::Cow,
+    fmt::{self, Write as _},
     future::Future,
+    ops::Range,
     pin::Pin,
-    sync::{Arc, LazyLock},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::SyncSender,
+        Arc,
+    },
     task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
 };
 
-#[allow(clippy::expect_used)] // Critical to middleware — no meaningful recovery if tokenizer fails
-static BPE: LazyLock<tiktoken_rs::CoreBPE> =
-    LazyLock::new(|| tiktoken_rs::o200k_base().expect("failed to initialize o200k_base tokenizer"));
 use tower::{Layer, Service};
 
+// One encoder per model per process, built on first use. `None` if it
+// failed to build, in which case tokens go uncounted rather than panicking.
+#[cfg(feature = "tokens")]
+static O200K_BASE: LazyLock<Option<tiktoken_rs::CoreBPE>> =
+    LazyLock::new(|| TokenizerModel::O200kBase.load());
+#[cfg(feature = "tokens")]
+static CL100K_BASE: LazyLock<Option<tiktoken_rs::CoreBPE>> =
+    LazyLock::new(|| TokenizerModel::Cl100kBase.load());
+#[cfg(feature = "tokens")]
+static P50K_BASE: LazyLock<Option<tiktoken_rs::CoreBPE>> =
+    LazyLock::new(|| TokenizerModel::P50kBase.load());
+#[cfg(feature = "tokens")]
+static P50K_EDIT: LazyLock<Option<tiktoken_rs::CoreBPE>> =
+    LazyLock::new(|| TokenizerModel::P50kEdit.load());
+#[cfg(feature = "tokens")]
+static R50K_BASE: LazyLock<Option<tiktoken_rs::CoreBPE>> =
+    LazyLock::new(|| TokenizerModel::R50kBase.load());
+
+/// Callback producing extra front matter fields; see [`MarkdownConfig::front_matter_fields`].
+pub type FrontMatterFields =
+    Arc<dyn Fn(&FrontMatterContext<'_>) -> Vec<(String, FrontMatterValue)> + Send + Sync>;
+
+/// Callback producing the `{retrieved_at}` timestamp; see [`MarkdownConfig::retrieved_at`].
+pub type RetrievedAt = Arc<dyn Fn() -> String + Send + Sync>;
+
+/// Callback rewriting the finished markdown; see [`MarkdownConfig::post_process`].
+pub type PostProcess = Arc<dyn Fn(String) -> String + Send + Sync>;
+
+/// Callback building the response to a failed conversion; see
+/// [`MarkdownConfig::error_responder`].
+pub type ErrorResponder = Arc<dyn Fn(ConversionError) -> Response<Body> + Send + Sync>;
+
+/// Future returned by [`LinkResolver::resolve`].
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = Option<String>> + Send + 'a>>;
+
+/// Default template for [`MarkdownConfig::source_attribution`].
+pub const DEFAULT_SOURCE_TEMPLATE: &str = "Source: {url} (retrieved {retrieved_at})";
+
 /// Configuration for the markdown conversion middleware.
-#[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)] // Independent feature toggles, not a state machine
+#[derive(Clone)]
 pub struct MarkdownConfig {
     /// Maximum HTML body size (in bytes) to attempt conversion on. Default: 1MB.
     pub max_body_size: usize,
-    /// Optional value for the `Content-Signal` response header.
+    /// Maximum body sizes for particular `Content-Type`s, in place of
+    /// `max_body_size`: the first type the response's `Content-Type`
+    /// contains, ignoring ASCII case, sets its limit. Default: empty.
+    pub max_body_sizes: Vec<(String, usize)>,
+    /// Behavior for HTML responses over the body size limit. Default:
+    /// [`OversizeBehavior::Error`].
+    pub oversize_behavior: OversizeBehavior,
+    /// Optional value for the `Content-Signal` response header. Default:
+    /// `ai-train=yes, search=yes, ai-input=yes`.
     pub content_signal: Option<String>,
+    /// Name of the `Content-Signal` header. Default: `content-signal`.
+    pub content_signal_header_name: HeaderName,
+    /// Send the Content-Signal on every response, not only converted ones.
+    /// Default: false.
+    pub signal_all_responses: bool,
+    /// Whose Content-Signal a response keeps when the inner service set
+    /// one. Default: [`SignalPrecedence::Handler`].
+    pub content_signal_precedence: SignalPrecedence,
+    /// `X-Robots-Tag` header value, e.g. `noarchive`. Default: `None`.
+    pub x_robots_tag: Option<HeaderValue>,
+    /// Send the `X-Robots-Tag` on every response, not only converted ones.
+    /// Default: false.
+    pub robots_tag_all_responses: bool,
+    /// Name of the token count header. Default: `x-markdown-tokens`.
+    pub token_header_name: HeaderName,
+    /// What to do when an HTML response has an empty body. Default: [`EmptyBodyBehavior::EmitEmpty`].
+    pub empty_body_behavior: EmptyBodyBehavior,
+    /// Prepend a YAML front matter block with the page title and description. Default: false.
+    pub front_matter: bool,
+    /// Emit an empty `---` block when the page has no metadata. Default: false.
+    pub empty_front_matter: bool,
+    /// With front matter enabled, drop the first `<h1>` if it repeats the title. Default: true.
+    pub strip_duplicate_title: bool,
+    /// Open Graph/Twitter `<meta>` properties to include in front matter
+    /// (e.g. `og:image`). `None` includes every property found. Default: `None`.
+    pub social_properties: Option<Vec<String>>,
+    /// Response header used to advertise the page's canonical URL. Default: `None`.
+    pub canonical_header: Option<CanonicalHeader>,
+    /// `Content-Location` of converted responses. Default: `None`.
+    pub content_location: Option<ContentLocation>,
+    /// Advertise the other representation in a `Link: rel="alternate"`
+    /// header, locating the markdown one. Default: `None`.
+    pub alternate_link: Option<ContentLocation>,
+    /// Externally visible origin (e.g. `https://example.com`) used to build
+    /// absolute URLs. Default: `None` (derived from the request's `Host`).
+    pub base_url: Option<String>,
+    /// Path (e.g. `/proxy`) the service is served under externally,
+    /// prepended to the root-relative link and image destinations of the
+    /// markdown. Default: `None`.
+    pub external_base_path: Option<String>,
+    /// Treat `Prefer: return=markdown` as a request for markdown. Default: false.
+    pub prefer_header: bool,
+    /// Path suffix, such as `.md`, that requests markdown for the path
+    /// without it. Default: `None`.
+    pub markdown_suffix: Option<String>,
+    /// Whether the suffix or the `Accept` header decides when they
+    /// disagree. Default: [`SuffixPrecedence::SuffixWins`].
+    pub suffix_precedence: SuffixPrecedence,
+    /// Answer markdown requests that rule out UTF-8 with `406 Not
+    /// Acceptable`. Default: false (they get UTF-8 markdown anyway).
+    pub strict_negotiation: bool,
+    /// Convert only for requests whose `Origin` or `Referer` is this
+    /// server. Default: false.
+    pub require_same_origin: bool,
+    /// Serve converted responses from a per-layer cache keyed by path and
+    /// query for this long, without calling the inner service. Default: `None`.
+    pub cache_ttl: Option<Duration>,
+    /// Reuse conversions of unchanged upstream HTML, within these bounds.
+    /// Default: `None` (disabled).
+    pub cache: Option<CacheConfig>,
+    /// Where [`cache`](Self::cache) conversions are stored. Default: `None`
+    /// (a [`MemoryCache`]).
+    pub cache_backend: Option<Arc<dyn ConversionCache>>,
+    /// JSON-LD `@type`s (e.g. `Article`) to extract from
+    /// `<script type="application/ld+json">`. Default: `None` (disabled).
+    pub json_ld_types: Option<Vec<String>>,
+    /// Where extracted JSON-LD goes. Default: [`JsonLdPlacement::FrontMatter`].
+    pub json_ld_placement: JsonLdPlacement,
+    /// Channel that receives a [`ConversionError`] for each failed conversion. Default: `None`.
+    pub error_sender: Option<SyncSender<ConversionError>>,
+    /// Body format of the `502` response to a failed conversion. Default:
+    /// [`ErrorFormat::PlainText`].
+    pub error_format: ErrorFormat,
+    /// What a response gets when its HTML fails to convert. Default:
+    /// [`ErrorPolicy::BadGateway`].
+    pub on_error: ErrorPolicy,
+    /// Callback building the response to a failed conversion, in place of
+    /// the `502`. Default: `None`.
+    pub error_responder: Option<ErrorResponder>,
+    /// Time allowed to convert a body and count its tokens, after which the
+    /// conversion fails per [`on_error`](Self::on_error). Default: `None`
+    /// (unbounded).
+    pub conversion_timeout: Option<Duration>,
+    /// Converter used in place of htmd, to inject failures and delays.
+    #[cfg(test)]
+    stub_converter: Option<tests::StubConverter>,
+    /// Strip byte order marks and leading/trailing whitespace from the
+    /// converted markdown. Default: true.
+    pub trim_output: bool,
+    /// Extra front matter fields computed per page. Default: `None`.
+    pub front_matter_fields: Option<FrontMatterFields>,
+    /// Template for a source attribution footer. Default: `None` (disabled).
+    pub source_attribution: Option<String>,
+    /// Where the source attribution goes. Default: [`SourcePlacement::Footer`].
+    pub source_placement: SourcePlacement,
+    /// Timestamp source for `{retrieved_at}`. Default: `None` (the current
+    /// time as an RFC 3339 UTC timestamp).
+    pub retrieved_at: Option<RetrievedAt>,
+    /// Rewrites the markdown before it is counted and sent. Default: `None`.
+    pub post_process: Option<PostProcess>,
+    /// Start the markdown with a `<!-- canonical: url -->` comment. Default: false.
+    pub canonical_comment: bool,
+    /// End the markdown with the original HTML in a base64
+    /// `<!-- source-html-base64: ... -->` comment. Default: false.
+    pub embed_source_html: bool,
+    /// Insert a table of contents built from the headings. Default: `None`.
+    pub table_of_contents: Option<TocOptions>,
+    /// End headings with a `{#slug}` anchor. Default: false.
+    pub heading_anchors: bool,
+    /// Where to render the page's breadcrumb trail. Default: `None` (disabled).
+    pub breadcrumbs: Option<BreadcrumbPlacement>,
+    /// Honor `X-Markdown-Dry-Run: true` requests. Default: false.
+    pub dry_run_header: bool,
+    /// Serve the HTML of markdown requests, annotated with the markdown's
+    /// `x-markdown-tokens`. Default: false.
+    pub shadow_mode: bool,
+    /// Encoding used for `x-markdown-tokens`. Default: [`TokenizerModel::O200kBase`].
+    pub tokenizer: TokenizerModel,
+    /// Append `; model=<encoding>` to `x-markdown-tokens`. Default: false.
+    pub token_header_model: bool,
+    /// Fence style for code blocks. Default: [`CodeFence::Backtick`].
+    pub code_fence: CodeFence,
+    /// Links above which a document gets reference-style links. Default:
+    /// `None` (always inline).
+    pub inline_link_threshold: Option<usize>,
+    /// Count tokens and emit `x-markdown-tokens`. Default: true.
+    pub count_tokens: bool,
+    /// Custom token counter, used instead of [`tokenizer`](Self::tokenizer). Default: `None`.
+    pub token_counter: Option<Arc<dyn TokenCounter>>,
+    /// Exact or approximate token counts. Default: [`Estimation::Exact`].
+    pub token_estimation: Estimation,
+    /// How inline `<svg>` elements are converted. Default: [`SvgHandling::Keep`].
+    pub svg_handling: SvgHandling,
+    /// Collapse the HTML's insignificant whitespace before converting it.
+    /// Default: false.
+    pub normalize_html_whitespace: bool,
+    /// Fetches same-origin linked pages to inline after the content. Default: `None`.
+    pub link_resolver: Option<Arc<dyn LinkResolver>>,
+    /// Bounds on link inlining. Default: [`LinkLimits::default`].
+    pub link_limits: LinkLimits,
+    /// Tokenizers a request may select with `X-Token-Model`. Default: empty
+    /// (the header is ignored).
+    pub token_models: Vec<TokenizerModel>,
+    /// Further counters, by name, reported in `x-markdown-tokens-by-model`.
+    /// Default: empty.
+    pub additional_tokenizers: Vec<(String, Arc<dyn TokenCounter>)>,
+    /// Count the original HTML's tokens into `x-html-tokens`. Default: false.
+    pub html_tokens: bool,
+    /// Report the HTML's size in `x-original-content-length`. Default: false.
+    pub original_content_length: bool,
+    /// Report the markdown's size relative to the HTML's in
+    /// `x-markdown-ratio`. Default: false.
+    pub compression_ratio: bool,
+    /// Flag converted responses with `Warning: 214`. Default: false.
+    pub transformation_warning: bool,
+    /// Set `Content-Language` from the page's `<html lang>`. Default: false.
+    pub propagate_language: bool,
+    /// Headers removed from converted responses. Default: empty.
+    pub strip_headers: Vec<HeaderName>,
+    /// Headers added to converted responses. Default: empty.
+    pub extra_headers: HeaderMap,
+    /// Send the `extra_headers` on every response, not only converted ones.
+    /// Default: false.
+    pub extra_headers_all_responses: bool,
+    /// Report the conversion's duration in `x-markdown-duration-ms`.
+    /// Default: false.
+    pub duration_header: bool,
+    /// How `<dl>` definition lists are rendered. Default: `None` (left to
+    /// the converter, which runs terms and definitions together).
+    pub definition_lists: Option<DefinitionListStyle>,
+    /// How `<details>` disclosure widgets are rendered. Default: `None`
+    /// (left to the converter, which keeps their text as plain paragraphs).
+    pub details_handling: Option<DetailsHandling>,
+    /// How `MathML` `<math>` elements and TeX `\(..\)`/`\[..\]` delimiters
+    /// are rendered. Default: `None` (left to the converter, which keeps
+    /// only `MathML`'s text and escapes the delimiters).
+    pub math: Option<MathNotation>,
+    /// Markdown flavor for tables, strikethrough, and task lists. Default:
+    /// `None` (left to the converter, which keeps only their text).
+    pub dialect: Option<MarkdownDialect>,
+    /// How `<hr>` thematic breaks are written. Default: `None` (left to the
+    /// converter, which writes `* * *`).
+    pub thematic_break: Option<ThematicBreak>,
+    /// Line terminator of the markdown. Default: [`LineEnding::Lf`].
+    pub line_ending: LineEnding,
+    /// Size in bytes above which text is tokenized on the blocking thread
+    /// pool. Default: 64 KiB.
+    pub blocking_token_threshold: usize,
+    /// Send `x-markdown-tokens` as a trailer rather than a header. Default: false.
+    pub tokens_as_trailer: bool,
+    /// Response `Content-Type`s that are converted, matched as
+    /// case-insensitive substrings. Default: `["text/html"]`.
+    pub convertible_types: Vec<String>,
+    /// Token budget the markdown is truncated to. Default: `None` (unlimited).
+    pub max_tokens: Option<usize>,
+    /// Text appended to truncated markdown. Default: `"\n\n[Truncated]"`.
+    pub truncation_notice: String,
+    /// Ceiling on a request's `X-Max-Tokens` budget. Default: `None` (the
+    /// header is ignored).
+    pub max_tokens_header: Option<usize>,
+    /// Spaces per list nesting level, widened where a parent's marker needs
+    /// more. Default: 2.
+    pub list_indent: usize,
+    /// Write blockquote prefixes as one `>` per nesting level. Default: true.
+    pub normalize_blockquotes: bool,
+    /// Decode HTML character references left in text. Default: true.
+    pub decode_entities: bool,
+    /// Write non-breaking spaces in text as plain spaces. Default: false.
+    pub nbsp_to_space: bool,
+    /// Pass redirect responses through unconverted. Default: true.
+    pub pass_through_redirects: bool,
+    /// `ETag` of converted responses. Default: [`ETagPolicy::Replace`].
+    pub etag: ETagPolicy,
+    /// Answer `If-Modified-Since` against converted responses'
+    /// `Last-Modified`. Default: false (left to the inner service).
+    pub if_modified_since: bool,
+    /// Header repeating the inner service's `ETag` on converted responses.
+    /// Default: `x-original-etag`.
+    pub original_etag_header: Option<HeaderName>,
+    /// Header repeating the inner service's `Last-Modified` on converted
+    /// responses. Default: `x-original-last-modified`.
+    pub original_last_modified_header: Option<HeaderName>,
+    /// `Content-Digest` of converted responses. Default: `None` (the inner
+    /// service's digests are removed and none is added).
+    pub content_digest: Option<DigestAlgorithm>,
+    /// RFC 3230 `Digest` of converted responses. Default: `None`.
+    pub digest: Option<DigestAlgorithm>,
+    /// Serve a JSON metadata summary for `Accept:
+    /// application/vnd.page-summary+json`. Default: false.
+    pub page_summary: bool,
+    /// Most of the token budget, in percent, that truncation gives up to
+    /// end on a block boundary. Default: 25.
+    pub truncation_backoff: u8,
+    /// Token window for pages selected with `X-Markdown-Page`. Default:
+    /// `None` (pagination is off).
+    pub page_tokens: Option<usize>,
+    /// Count tokens per `#`/`##` section into a [`SectionTokens`] response
+    /// extension. Default: false.
+    pub section_tokens: bool,
+    /// Longest `x-markdown-section-tokens` header to send. Default: `None`
+    /// (no header).
+    pub section_tokens_header: Option<usize>,
+}
+
+#[cfg_attr(test, allow(clippy::missing_fields_in_debug))] // The test-only stub converter
+impl fmt::Debug for MarkdownConfig {
+    #[allow(clippy::too_many_lines)] // One entry per field
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MarkdownConfig")
+            .field("max_body_size", &self.max_body_size)
+            .field("max_body_sizes", &self.max_body_sizes)
+            .field("oversize_behavior", &self.oversize_behavior)
+            .field("content_signal", &self.content_signal)
+            .field(
+                "content_signal_header_name",
+                &self.content_signal_header_name,
+            )
+            .field("signal_all_responses", &self.signal_all_responses)
+            .field("content_signal_precedence", &self.content_signal_precedence)
+            .field("x_robots_tag", &self.x_robots_tag)
+            .field("robots_tag_all_responses", &self.robots_tag_all_responses)
+            .field("token_header_name", &self.token_header_name)
+            .field("empty_body_behavior", &self.empty_body_behavior)
+            .field("front_matter", &self.front_matter)
+            .field("empty_front_matter", &self.empty_front_matter)
+            .field("strip_duplicate_title", &self.strip_duplicate_title)
+            .field("social_properties", &self.social_properties)
+            .field("canonical_header", &self.canonical_header)
+            .field("content_location", &self.content_location)
+            .field("alternate_link", &self.alternate_link)
+            .field("base_url", &self.base_url)
+            .field("external_base_path", &self.external_base_path)
+            .field("prefer_header", &self.prefer_header)
+            .field("markdown_suffix", &self.markdown_suffix)
+            .field("suffix_precedence", &self.suffix_precedence)
+            .field("strict_negotiation", &self.strict_negotiation)
+            .field("require_same_origin", &self.require_same_origin)
+            .field("cache_ttl", &self.cache_ttl)
+            .field("cache", &self.cache)
+            .field("cache_backend", &self.cache_backend.as_ref().map(|_| ".."))
+            .field("json_ld_types", &self.json_ld_types)
+            .field("json_ld_placement", &self.json_ld_placement)
+            .field("error_sender", &self.error_sender)
+            .field("error_format", &self.error_format)
+            .field("on_error", &self.on_error)
+            .field(
+                "error_responder",
+                &self.error_responder.as_ref().map(|_| ".."),
+            )
+            .field("conversion_timeout", &self.conversion_timeout)
+            .field("trim_output", &self.trim_output)
+            .field(
+                "front_matter_fields",
+                &self.front_matter_fields.as_ref().map(|_| ".."),
+            )
+            .field("source_attribution", &self.source_attribution)
+            .field("source_placement", &self.source_placement)
+            .field("retrieved_at", &self.retrieved_at.as_ref().map(|_| ".."))
+            .field("post_process", &self.post_process.as_ref().map(|_| ".."))
+            .field("canonical_comment", &self.canonical_comment)
+            .field("embed_source_html", &self.embed_source_html)
+            .field("table_of_contents", &self.table_of_contents)
+            .field("heading_anchors", &self.heading_anchors)
+            .field("breadcrumbs", &self.breadcrumbs)
+            .field("dry_run_header", &self.dry_run_header)
+            .field("shadow_mode", &self.shadow_mode)
+            .field("tokenizer", &self.tokenizer)
+            .field("token_header_model", &self.token_header_model)
+            .field("code_fence", &self.code_fence)
+            .field("inline_link_threshold", &self.inline_link_threshold)
+            .field("count_tokens", &self.count_tokens)
+            .field("svg_handling", &self.svg_handling)
+            .field("normalize_html_whitespace", &self.normalize_html_whitespace)
+            .field("link_resolver", &self.link_resolver.as_ref().map(|_| ".."))
+            .field("link_limits", &self.link_limits)
+            .field("token_models", &self.token_models)
+            .field(
+                "additional_tokenizers",
+                &self
+                    .additional_tokenizers
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("html_tokens", &self.html_tokens)
+            .field("original_content_length", &self.original_content_length)
+            .field("compression_ratio", &self.compression_ratio)
+            .field("transformation_warning", &self.transformation_warning)
+            .field("propagate_language", &self.propagate_language)
+            .field("strip_headers", &self.strip_headers)
+            .field("extra_headers", &self.extra_headers)
+            .field(
+                "extra_headers_all_responses",
+                &self.extra_headers_all_responses,
+            )
+            .field("duration_header", &self.duration_header)
+            .field("definition_lists", &self.definition_lists)
+            .field("details_handling", &self.details_handling)
+            .field("math", &self.math)
+            .field("dialect", &self.dialect)
+            .field("thematic_break", &self.thematic_break)
+            .field("line_ending", &self.line_ending)
+            .field("blocking_token_threshold", &self.blocking_token_threshold)
+            .field("tokens_as_trailer", &self.tokens_as_trailer)
+            .field("convertible_types", &self.convertible_types)
+            .field("max_tokens", &self.max_tokens)
+            .field("truncation_notice", &self.truncation_notice)
+            .field("max_tokens_header", &self.max_tokens_header)
+            .field("list_indent", &self.list_indent)
+            .field("normalize_blockquotes", &self.normalize_blockquotes)
+            .field("decode_entities", &self.decode_entities)
+            .field("nbsp_to_space", &self.nbsp_to_space)
+            .field("pass_through_redirects", &self.pass_through_redirects)
+            .field("etag", &self.etag)
+            .field("if_modified_since", &self.if_modified_since)
+            .field("original_etag_header", &self.original_etag_header)
+            .field(
+                "original_last_modified_header",
+                &self.original_last_modified_header,
+            )
+            .field("content_digest", &self.content_digest)
+            .field("digest", &self.digest)
+            .field("page_summary", &self.page_summary)
+            .field("truncation_backoff", &self.truncation_backoff)
+            .field("page_tokens", &self.page_tokens)
+            .field("section_tokens", &self.section_tokens)
+            .field("section_tokens_header", &self.section_tokens_header)
+            .field("token_counter", &self.token_counter.as_ref().map(|_| ".."))
+            .field("token_estimation", &self.token_estimation)
+            .finish()
+    }
 }
 
 impl Default for MarkdownConfig {
+    #[allow(clippy::too_many_lines)] // One entry per field
     fn default() -> Self {
         Self {
             max_body_size: 1024 * 1024,
-            content_signal: Some("ai-train=yes, search=yes, ai-input=yes".to_string()),
+            max_body_sizes: Vec::new(),
+            oversize_behavior: OversizeBehavior::default(),
+            content_signal: Some(ContentSignal::default().to_string()),
+            content_signal_header_name: HeaderName::from_static("content-signal"),
+            signal_all_responses: false,
+            content_signal_precedence: SignalPrecedence::default(),
+            x_robots_tag: None,
+            robots_tag_all_responses: false,
+            token_header_name: HeaderName::from_static("x-markdown-tokens"),
+            empty_body_behavior: EmptyBodyBehavior::default(),
+            front_matter: false,
+            empty_front_matter: false,
+            strip_duplicate_title: true,
+            social_properties: None,
+            canonical_header: None,
+            content_location: None,
+            alternate_link: None,
+            base_url: None,
+            external_base_path: None,
+            prefer_header: false,
+            markdown_suffix: None,
+            suffix_precedence: SuffixPrecedence::default(),
+            strict_negotiation: false,
+            require_same_origin: false,
+            cache_ttl: None,
+            cache: None,
+            cache_backend: None,
+            json_ld_types: None,
+            json_ld_placement: JsonLdPlacement::default(),
+            error_sender: None,
+            error_format: ErrorFormat::default(),
+            on_error: ErrorPolicy::default(),
+            error_responder: None,
+            conversion_timeout: None,
+            #[cfg(test)]
+            stub_converter: None,
+            trim_output: true,
+            front_matter_fields: None,
+            source_attribution: None,
+            source_placement: SourcePlacement::default(),
+            retrieved_at: None,
+            post_process: None,
+            canonical_comment: false,
+            embed_source_html: false,
+            table_of_contents: None,
+            heading_anchors: false,
+            breadcrumbs: None,
+            dry_run_header: false,
+            shadow_mode: false,
+            tokenizer: TokenizerModel::default(),
+            token_header_model: false,
+            code_fence: CodeFence::default(),
+            inline_link_threshold: None,
+            count_tokens: true,
+            svg_handling: SvgHandling::default(),
+            normalize_html_whitespace: false,
+            link_resolver: None,
+            link_limits: LinkLimits::default(),
+            token_models: Vec::new(),
+            additional_tokenizers: Vec::new(),
+            html_tokens: false,
+            original_content_length: false,
+            compression_ratio: false,
+            transformation_warning: false,
+            propagate_language: false,
+            strip_headers: Vec::new(),
+            extra_headers: HeaderMap::new(),
+            extra_headers_all_responses: false,
+            duration_header: false,
+            definition_lists: None,
+            details_handling: None,
+            math: None,
+            dialect: None,
+            thematic_break: None,
+            line_ending: LineEnding::default(),
+            blocking_token_threshold: 64 * 1024,
+            tokens_as_trailer: false,
+            convertible_types: vec!["text/html".to_string()],
+            max_tokens: None,
+            truncation_notice: "\n\n[Truncated]".to_string(),
+            max_tokens_header: None,
+            list_indent: 2,
+            normalize_blockquotes: true,
+            decode_entities: true,
+            nbsp_to_space: false,
+            pass_through_redirects: true,
+            etag: ETagPolicy::default(),
+            if_modified_since: false,
+            original_etag_header: Some(HeaderName::from_static("x-original-etag")),
+            original_last_modified_header: Some(HeaderName::from_static(
+                "x-original-last-modified",
+            )),
+            content_digest: None,
+            digest: None,
+            page_summary: false,
+            truncation_backoff: 25,
+            page_tokens: None,
+            section_tokens: false,
+            section_tokens_header: None,
+            token_counter: None,
+            token_estimation: Estimation::default(),
         }
     }
 }
@@ -45,570 +632,8834 @@ impl MarkdownConfig {
     }
 
     /// Set the maximum body size for conversion.
+    ///
+    /// A handler can raise or lower the limit for a single response by
+    /// inserting a [`MaxBodySizeOverride`] into its extensions.
     #[must_use]
     pub const fn max_body_size(mut self, size: usize) -> Self {
         self.max_body_size = size;
         self
     }
 
-    /// Set the Content-Signal header value.
+    /// Set the maximum body size for responses whose `Content-Type`
+    /// contains `content_type`, ignoring ASCII case, in place of
+    /// [`max_body_size`](Self::max_body_size).
+    ///
+    /// Limits are matched in the order they were added, so add narrower
+    /// types first. A [`MaxBodySizeOverride`] still takes precedence.
+    #[must_use]
+    pub fn max_body_size_for(mut self, content_type: impl Into<String>, size: usize) -> Self {
+        self.max_body_sizes.push((content_type.into(), size));
+        self
+    }
+
+    /// Set the behavior for HTML responses over the body size limit.
+    ///
+    /// With [`OversizeBehavior::Passthrough`], reading stops once past the
+    /// limit, and the response goes out unconverted: the bytes read, then
+    /// the rest of the stream, with the inner service's headers. It is
+    /// still reported to the [`error_sender`](Self::error_sender).
+    #[must_use]
+    pub const fn oversize_behavior(mut self, behavior: OversizeBehavior) -> Self {
+        self.oversize_behavior = behavior;
+        self
+    }
+
+    /// Set the Content-Signal header value, sent as is.
+    /// [`try_content_signal`](Self::try_content_signal) validates it first.
     #[must_use]
     pub fn content_signal(mut self, signal: impl Into<String>) -> Self {
         self.content_signal = Some(signal.into());
         self
     }
 
+    /// Set the Content-Signal header value from a string, such as
+    /// `ai-train=no, search=yes`, sent re-serialized in canonical form.
+    ///
+    /// # Errors
+    ///
+    /// If `signal` is not a list of known `yes`/`no` directives (see
+    /// [`ContentSignal`]); a misspelled directive is an error rather than
+    /// a header clients will ignore.
+    pub fn try_content_signal(
+        mut self,
+        signal: impl AsRef<str>,
+    ) -> Result<Self, InvalidContentSignal> {
+        let signal: ContentSignal = signal.as_ref().parse()?;
+        self.content_signal = Some(signal.to_string());
+        Ok(self)
+    }
+
+    /// Set the Content-Signal header value.
+    #[must_use]
+    #[allow(clippy::needless_pass_by_value)] // Owned, like the other setters' values
+    pub fn content_signal_typed(mut self, signal: ContentSignal) -> Self {
+        self.content_signal = Some(signal.to_string());
+        self
+    }
+
     /// Disable the Content-Signal header.
     #[must_use]
     pub fn no_content_signal(mut self) -> Self {
         self.content_signal = None;
         self
     }
-}
-
-/// Tower layer that wraps services with markdown content negotiation.
-#[derive(Debug, Clone)]
-pub struct MarkdownLayer {
-    config: Arc<MarkdownConfig>,
-}
 
-impl MarkdownLayer {
-    /// Create a new `MarkdownLayer` with default configuration.
+    /// Send the Content-Signal on every response passing through the layer:
+    /// HTML and other passthrough responses as well as converted ones.
+    ///
+    /// Crawlers mostly fetch the HTML, so this is how they see the policy.
+    /// A Content-Signal the inner service set is kept unless
+    /// [`content_signal_precedence`](Self::content_signal_precedence) says
+    /// otherwise.
     #[must_use]
-    pub fn new() -> Self {
-        Self {
-            config: Arc::new(MarkdownConfig::default()),
-        }
+    pub const fn signal_all_responses(mut self, enabled: bool) -> Self {
+        self.signal_all_responses = enabled;
+        self
     }
 
-    /// Create a new `MarkdownLayer` with the given configuration.
+    /// Choose whether a Content-Signal set by the inner service is kept, or
+    /// replaced with the configured one.
     #[must_use]
-    pub fn with_config(config: MarkdownConfig) -> Self {
-        Self {
-            config: Arc::new(config),
-        }
+    pub const fn content_signal_precedence(mut self, precedence: SignalPrecedence) -> Self {
+        self.content_signal_precedence = precedence;
+        self
     }
-}
 
-impl Default for MarkdownLayer {
-    fn default() -> Self {
-        Self::new()
+    /// Send `X-Robots-Tag: value` on converted responses, e.g.
+    /// `noarchive, max-snippet:200`, or `googlebot: noindex` for one crawler.
+    ///
+    /// An `X-Robots-Tag` the inner service set is kept, with this one added
+    /// as a further field line.
+    ///
+    /// ```rust
+    /// # use axum_markdown::MarkdownConfig;
+    /// let config = MarkdownConfig::new()
+    ///     .x_robots_tag("noarchive, max-snippet:200")?
+    ///     .robots_tag_all_responses(true);
+    /// # Ok::<_, http::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If `value` is not a valid header value.
+    pub fn x_robots_tag<V>(mut self, value: V) -> Result<Self, http::Error>
+    where
+        V: TryInto<HeaderValue>,
+        V::Error: Into<http::Error>,
+    {
+        self.x_robots_tag = Some(value.try_into().map_err(Into::into)?);
+        Ok(self)
     }
-}
-
-impl<S> Layer<S> for MarkdownLayer {
-    type Service = MarkdownService<S>;
 
-    fn layer(&self, inner: S) -> Self::Service {
-        MarkdownService {
-            inner,
-            config: Arc::clone(&self.config),
-        }
+    /// Send the [`x_robots_tag`](Self::x_robots_tag) on every response
+    /// passing through the layer, not only converted ones.
+    #[must_use]
+    pub const fn robots_tag_all_responses(mut self, enabled: bool) -> Self {
+        self.robots_tag_all_responses = enabled;
+        self
     }
-}
-
-/// Tower service that performs markdown content negotiation.
-#[derive(Debug, Clone)]
-pub struct MarkdownService<S> {
-    inner: S,
-    config: Arc<MarkdownConfig>,
-}
 
-impl<S> Service<Request<Body>> for MarkdownService<S>
-where
-    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
-    S::Future: Send + 'static,
-    S::Error: Send + 'static,
-{
-    type Response = Response<Body>;
-    type Error = S::Error;
-    type Future = MarkdownFuture<S::Future, S::Error>;
+    /// Send the Content-Signal header as `name`.
+    ///
+    /// # Errors
+    ///
+    /// If `name` is not a valid header name.
+    pub fn content_signal_header_name<N>(mut self, name: N) -> Result<Self, http::Error>
+    where
+        N: TryInto<HeaderName>,
+        N::Error: Into<http::Error>,
+    {
+        self.content_signal_header_name = name.try_into().map_err(Into::into)?;
+        Ok(self)
+    }
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
+    /// Send the token count as `name` (e.g. `x-llm-token-count`) instead of
+    /// `x-markdown-tokens`, in the headers, the
+    /// [`tokens_as_trailer`](Self::tokens_as_trailer) trailer, and pages.
+    /// The other `x-markdown-tokens-*` headers keep their names.
+    ///
+    /// ```
+    /// # use axum_markdown::MarkdownConfig;
+    /// let config = MarkdownConfig::new().token_header_name("x-llm-token-count")?;
+    /// assert!(MarkdownConfig::new().token_header_name("token count").is_err());
+    /// # Ok::<_, http::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If `name` is not a valid header name.
+    pub fn token_header_name<N>(mut self, name: N) -> Result<Self, http::Error>
+    where
+        N: TryInto<HeaderName>,
+        N::Error: Into<http::Error>,
+    {
+        self.token_header_name = name.try_into().map_err(Into::into)?;
+        Ok(self)
     }
 
-    fn call(&mut self, req: Request<Body>) -> Self::Future {
-        let convert = wants_markdown(req.headers());
-        let config = Arc::clone(&self.config);
-        let future = self.inner.call(req);
+    /// Set the behavior for HTML responses with an empty body.
+    #[must_use]
+    pub const fn empty_body_behavior(mut self, behavior: EmptyBodyBehavior) -> Self {
+        self.empty_body_behavior = behavior;
+        self
+    }
 
-        MarkdownFuture {
-            state: FutureState::Pending {
-                future,
-                convert,
-                config,
-            },
-        }
+    /// Enable or disable YAML front matter extracted from the page `<head>`.
+    ///
+    /// The block contains `title` (from `<title>`), `description` (from
+    /// `<meta name="description">`), `published`/`modified` dates (from
+    /// `article:*_time`, `<meta name="date">`, JSON-LD, or `<article><time>`,
+    /// normalized to RFC 3339 where parseable), and Open Graph/Twitter properties (see
+    /// [`social_properties`](Self::social_properties)) when present, and counts towards
+    /// `x-markdown-tokens`. The `<head>` itself is left out of the markdown body.
+    #[must_use]
+    pub const fn front_matter(mut self, enabled: bool) -> Self {
+        self.front_matter = enabled;
+        self
     }
-}
 
-pin_project! {
-    /// Future returned by `MarkdownService`.
-    pub struct MarkdownFuture<F, E> {
-        #[pin]
-        state: FutureState<F, E>,
+    /// Emit an empty front matter block when no metadata was found,
+    /// instead of omitting it.
+    #[must_use]
+    pub const fn empty_front_matter(mut self, enabled: bool) -> Self {
+        self.empty_front_matter = enabled;
+        self
     }
-}
 
-pin_project! {
-    #[project = FutureStateProj]
-    enum FutureState<F, E> {
-        Pending {
-            #[pin]
-            future: F,
-            convert: bool,
-            config: Arc<MarkdownConfig>,
-        },
-        Converting {
-            #[pin]
-            future: Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>,
-        },
+    /// Drop the first `<h1>` from the body when it duplicates the front matter title.
+    #[must_use]
+    pub const fn strip_duplicate_title(mut self, enabled: bool) -> Self {
+        self.strip_duplicate_title = enabled;
+        self
     }
-}
 
-impl<F, E> Future for MarkdownFuture<F, E>
-where
-    F: Future<Output = Result<Response<Body>, E>>,
-    E: Send + 'static,
-{
-    type Output = Result<Response<Body>, E>;
+    /// Limit the Open Graph/Twitter properties copied into front matter.
+    ///
+    /// By default every `og:*`, `article:*`, `twitter:*` (and other Open Graph
+    /// namespace) `<meta>` tag is included under a dotted key such as
+    /// `og.image`; repeated properties become a YAML list. Pass an empty list
+    /// to include none.
+    #[must_use]
+    pub fn social_properties<I, P>(mut self, properties: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.social_properties = Some(properties.into_iter().map(Into::into).collect());
+        self
+    }
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        loop {
-            let this = self.as_mut().project();
-            match this.state.project() {
-                FutureStateProj::Pending {
-                    future,
-                    convert,
-                    config,
-                } => {
-                    let response = match future.poll(cx) {
-                        Poll::Ready(Ok(resp)) => resp,
-                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-                        Poll::Pending => return Poll::Pending,
-                    };
+    /// Advertise the page's `<link rel="canonical">` URL in a response header.
+    ///
+    /// This works independently of front matter (where the URL appears as
+    /// `canonical:`). Relative URLs are resolved against the page URL.
+    #[must_use]
+    pub const fn canonical_header(mut self, header: CanonicalHeader) -> Self {
+        self.canonical_header = Some(header);
+        self
+    }
 
-                    if !*convert || !is_html_response(&response) {
-                        // Pass through, but still add Vary: Accept
-                        let response = append_vary(response);
-                        return Poll::Ready(Ok(response));
-                    }
+    /// Send `Content-Location` on converted responses, telling caches and
+    /// clients they got the markdown representation of the resource, found
+    /// at `location`.
+    ///
+    /// The value is the request's path and query (less `markdown-page`),
+    /// percent-encoded where needed. A canonical URL sent with
+    /// [`CanonicalHeader::ContentLocation`] takes its place.
+    #[must_use]
+    pub fn content_location(mut self, location: ContentLocation) -> Self {
+        self.content_location = Some(location);
+        self
+    }
 
-                    let config = Arc::clone(config);
-                    let converting =
-                        Box::pin(async move { convert_response(response, &config).await });
+    /// Advertise each response's other representation with a `Link`
+    /// header, appended to any the inner service set.
+    ///
+    /// HTML responses that would be converted, passed through to clients
+    /// that didn't ask for markdown, point crawlers at the markdown found at
+    /// `location`, built as for [`content_location`](Self::content_location):
+    /// `Link: </docs/intro.md>; rel="alternate"; type="text/markdown"`.
+    /// Converted responses point back at the HTML, at the request URI:
+    /// `Link: </docs/intro>; rel="alternate"; type="text/html"`.
+    #[must_use]
+    pub fn alternate_link(mut self, location: ContentLocation) -> Self {
+        self.alternate_link = Some(location);
+        self
+    }
 
-                    self.as_mut()
-                        .project()
-                        .state
-                        .set(FutureState::Converting { future: converting });
-                }
-                FutureStateProj::Converting { future } => {
-                    return future.poll(cx);
-                }
-            }
-        }
+    /// Set the externally visible origin used to build absolute URLs,
+    /// e.g. `https://example.com`. Useful behind proxies where the `Host`
+    /// header and scheme seen by the server differ from the public ones.
+    #[must_use]
+    pub fn base_url(mut self, url: impl Into<String>) -> Self {
+        self.base_url = Some(url.into());
+        self
     }
-}
 
-/// Check if the Accept header explicitly contains `text/markdown`.
-fn wants_markdown(headers: &HeaderMap) -> bool {
-    headers.get_all(ACCEPT).iter().any(|val| {
-        val.to_str().ok().is_some_and(|s| {
-            s.split(',')
-                .any(|part| part.split(';').next().unwrap_or("").trim() == "text/markdown")
-        })
-    })
-}
+    /// Prefix the root-relative links and images of the markdown with
+    /// `path`, for a proxied upstream served under it externally: with
+    /// `/proxy`, `[About](/about)` becomes `[About](/proxy/about)`.
+    ///
+    /// Only destinations starting with a single `/` change; they are not
+    /// made absolute as with [`base_url`](Self::base_url). Raw HTML and
+    /// code in the markdown are left as they are.
+    #[must_use]
+    pub fn external_base_path(mut self, path: impl Into<String>) -> Self {
+        self.external_base_path = Some(path.into());
+        self
+    }
 
-/// Check if a response has a `text/html` content type.
-fn is_html_response(response: &Response<Body>) -> bool {
-    response
-        .headers()
-        .get(CONTENT_TYPE)
-        .and_then(|v| v.to_str().ok())
-        .is_some_and(|ct| ct.contains("text/html"))
-}
+    /// Also convert when the request carries `Prefer: return=markdown` (RFC 7240).
+    ///
+    /// Honored preferences are acknowledged with `Preference-Applied:
+    /// return=markdown`, and `Prefer` is added to `Vary`.
+    #[must_use]
+    pub const fn prefer_header(mut self, enabled: bool) -> Self {
+        self.prefer_header = enabled;
+        self
+    }
 
-/// Append `Accept` to the `Vary` header of a response.
-fn append_vary(mut response: Response<Body>) -> Response<Body> {
-    let headers = response.headers_mut();
+    /// Serve markdown for paths ending in `suffix`, such as `.md`: a request
+    /// for `/docs/intro.md` is passed to the inner service as `/docs/intro`
+    /// and its HTML converted, whatever the `Accept` header. `/docs/index.md`
+    /// is `/docs/`, as with [`ContentLocation::PathSuffix`].
+    ///
+    /// A request whose `Accept` prefers `text/html`, as a browser's does,
+    /// still gets markdown; see
+    /// [`suffix_precedence`](Self::suffix_precedence).
+    ///
+    /// The path is rewritten before the inner service sees it, so the
+    /// layer must wrap the router rather than be added with
+    /// `Router::layer`, whose middleware runs after routing:
+    ///
+    /// ```
+    /// # use axum::{Router, response::Html, routing::get};
+    /// # use axum_markdown::{MarkdownConfig, MarkdownLayer};
+    /// use tower::Layer;
+    ///
+    /// let router = Router::new().route("/docs/intro", get(|| async { Html("<h1>Intro</h1>") }));
+    /// let app = MarkdownLayer::with_config(MarkdownConfig::new().markdown_suffix(".md")).layer(router);
+    /// # let _: axum_markdown::MarkdownService<Router> = app;
+    /// ```
+    #[must_use]
+    pub fn markdown_suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.markdown_suffix = Some(suffix.into());
+        self
+    }
 
-    let existing_values: Vec<String> = headers
-        .get_all(VARY)
-        .iter()
-        .filter_map(|v| v.to_str().ok().map(String::from))
-        .collect();
+    /// Choose whether a [`markdown_suffix`](Self::markdown_suffix) request
+    /// whose `Accept` header prefers `text/html` gets markdown or HTML.
+    #[must_use]
+    pub const fn suffix_precedence(mut self, precedence: SuffixPrecedence) -> Self {
+        self.suffix_precedence = precedence;
+        self
+    }
 
-    if existing_values.is_empty() {
-        headers.insert(VARY, HeaderValue::from_static("Accept"));
-    } else {
-        let already_has_accept = existing_values.iter().any(|s| {
-            s.split(',')
-                .any(|p| p.trim().eq_ignore_ascii_case("accept"))
-        });
+    /// Refuse markdown requests whose `Accept-Charset`, or the `charset`
+    /// of their `text/markdown` range, rules out UTF-8, with an empty
+    /// `406 Not Acceptable`.
+    ///
+    /// Markdown is only ever served as UTF-8. By default such requests get
+    /// it anyway, which clients can usually make sense of, rather than an
+    /// error; the inner service is not called for refused requests.
+    #[must_use]
+    pub const fn strict_negotiation(mut self, enabled: bool) -> Self {
+        self.strict_negotiation = enabled;
+        self
+    }
 
-        let combined = existing_values.join(", ");
-        let new_val = if already_has_accept {
-            combined
-        } else {
-            format!("{combined}, Accept")
-        };
+    /// Convert only for requests from the server's own pages: those whose
+    /// `Origin`, or without one `Referer`, has the scheme and host of the
+    /// request URL (see [`base_url`](Self::base_url)). Other markdown
+    /// requests, including ones carrying neither header, get the HTML.
+    ///
+    /// This keeps the layer from serving as an HTML-to-markdown proxy for
+    /// arbitrary pages behind a reverse proxy. Responses vary on `Origin`
+    /// and `Referer`.
+    #[must_use]
+    pub const fn require_same_origin(mut self, enabled: bool) -> Self {
+        self.require_same_origin = enabled;
+        self
+    }
 
-        if let Ok(hv) = HeaderValue::from_str(&new_val) {
-            // insert replaces all existing Vary headers with the consolidated one
-            headers.insert(VARY, hv);
-        }
+    /// Cache converted responses by host, request path, and query for `ttl`.
+    ///
+    /// While an entry is fresh, markdown requests for the same URL are
+    /// answered from the cache without calling the inner service at all, so
+    /// only enable this for pages that change slowly and don't vary by user.
+    /// Only `GET` and `HEAD` requests without `Authorization` or `Cookie`
+    /// are answered and stored; responses marked `Cache-Control: private`
+    /// or `no-store` aren't stored, and `Set-Cookie` never is.
+    /// The cache is shared by every service built from one [`MarkdownLayer`].
+    /// Entries are not bounded in number beyond expiring after `ttl`.
+    #[must_use]
+    pub const fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
     }
 
-    response
-}
+    /// Cache conversions in memory, keyed by request path and query plus
+    /// the upstream `ETag` (or, without one, a hash of the HTML).
+    ///
+    /// The inner service is still called, but when it returns the same
+    /// version of a page, the stored markdown, token counts, and headers
+    /// are reused without converting or counting again. A new version
+    /// replaces the entry. Responses report `x-markdown-cache: HIT` or
+    /// `MISS`. The cache is shared by every service built from one
+    /// [`MarkdownLayer`], and can be invalidated through its
+    /// [`cache_handle`](MarkdownLayer::cache_handle).
+    #[must_use]
+    pub const fn cache(mut self, limits: CacheConfig) -> Self {
+        self.cache = Some(limits);
+        self
+    }
 
-/// Convert an HTML response to markdown.
-async fn convert_response<E>(
-    response: Response<Body>,
-    config: &MarkdownConfig,
-) -> Result<Response<Body>, E> {
-    let (mut parts, body) = response.into_parts();
+    /// Store [`cache`](Self::cache) conversions in `backend` instead of the
+    /// built-in [`MemoryCache`], which also enables the cache. The backend
+    /// then owns eviction; [`CacheConfig::ttl`] still applies when set with
+    /// [`cache`](Self::cache).
+    #[must_use]
+    pub fn cache_backend(mut self, backend: Arc<dyn ConversionCache>) -> Self {
+        self.cache_backend = Some(backend);
+        self
+    }
 
-    let Ok(body_bytes) = to_bytes(body, config.max_body_size).await else {
-        // Body too large or read error — the original body is consumed so we
-        // cannot forward it. Return a 502 to signal the failure rather than
-        // silently sending an empty 200.
-        let mut response = Response::new(Body::from(
-            "Markdown conversion failed: response body too large or unreadable",
-        ));
-        *response.status_mut() = http::StatusCode::BAD_GATEWAY;
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/plain; charset=utf-8"),
-        );
-        return Ok(append_vary(response));
-    };
+    /// Extract JSON-LD items whose `@type` is one of `types`.
+    ///
+    /// Items from every `<script type="application/ld+json">` block are
+    /// collected (arrays and `@graph` containers are flattened, `@context` is
+    /// dropped); blocks that fail to parse are skipped. See
+    /// [`json_ld_placement`](Self::json_ld_placement) for where they end up.
+    #[must_use]
+    pub fn json_ld_types<I, T>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.json_ld_types = Some(types.into_iter().map(Into::into).collect());
+        self
+    }
 
-    let html = String::from_utf8_lossy(&body_bytes);
-    let Ok(markdown) = htmd::convert(&html) else {
-        // Conversion failed — return 502 rather than serving raw HTML
-        // with a text/markdown Content-Type (which would be a lie and
-        // a potential XSS vector in markdown renderers).
-        let mut response = Response::new(Body::from(
-            "Markdown conversion failed: unable to convert HTML to markdown",
-        ));
-        *response.status_mut() = http::StatusCode::BAD_GATEWAY;
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/plain; charset=utf-8"),
-        );
-        return Ok(append_vary(response));
-    };
+    /// Set where extracted JSON-LD is placed in the markdown.
+    #[must_use]
+    pub const fn json_ld_placement(mut self, placement: JsonLdPlacement) -> Self {
+        self.json_ld_placement = placement;
+        self
+    }
 
-    // Count tokens
-    let token_count = BPE.encode_with_special_tokens(&markdown).len();
+    /// Report failed conversions to `sender`.
+    ///
+    /// Errors are sent with `try_send`, so a full or disconnected channel
+    /// never stalls the request; such errors are dropped instead.
+    #[must_use]
+    pub fn error_sender(mut self, sender: SyncSender<ConversionError>) -> Self {
+        self.error_sender = Some(sender);
+        self
+    }
 
-    // Update headers
-    parts.headers.insert(
-        CONTENT_TYPE,
-        HeaderValue::from_static("text/markdown; charset=utf-8"),
-    );
-    parts.headers.remove(CONTENT_LENGTH);
+    /// Set the body format of the `502 Bad Gateway` response sent when a
+    /// conversion fails.
+    #[must_use]
+    pub const fn error_format(mut self, format: ErrorFormat) -> Self {
+        self.error_format = format;
+        self
+    }
 
-    if let Ok(hv) = HeaderValue::from_str(&token_count.to_string()) {
-        parts.headers.insert("x-markdown-tokens", hv);
+    /// Set what a response gets when htmd fails to convert its HTML, or
+    /// the [`conversion_timeout`](Self::conversion_timeout) expires.
+    ///
+    /// Only conversion failures follow the policy. A body too large follows
+    /// [`oversize_behavior`](Self::oversize_behavior), an unreadable one
+    /// is lost partway and gets a `502`, and an empty body follows
+    /// [`empty_body_behavior`](Self::empty_body_behavior). Failures are
+    /// reported to the [`error_sender`](Self::error_sender) either way.
+    #[must_use]
+    pub const fn on_error(mut self, policy: ErrorPolicy) -> Self {
+        self.on_error = policy;
+        self
     }
 
-    if let Some(ref signal) = config.content_signal {
-        if let Ok(hv) = HeaderValue::from_str(signal) {
-            parts.headers.insert("content-signal", hv);
-        }
+    /// Fail conversions that take longer than `timeout`, per
+    /// [`on_error`](Self::on_error): a `502` saying the conversion timed
+    /// out, or the buffered HTML.
+    ///
+    /// The timeout covers converting the body and counting its tokens,
+    /// from the end of the body read: a slow upstream is left to
+    /// timeouts in front of the service. With a timeout, htmd and the
+    /// tokenizers run on the blocking thread pool whatever the
+    /// [`blocking_token_threshold`](Self::blocking_token_threshold), and
+    /// the Tokio runtime needs its time driver enabled. Work that times
+    /// out can't be interrupted, and finishes there in the background.
+    #[must_use]
+    pub const fn conversion_timeout(mut self, timeout: Duration) -> Self {
+        self.conversion_timeout = Some(timeout);
+        self
     }
 
-    let markdown_bytes = Bytes::from(markdown);
-    let mut response = Response::from_parts(parts, Body::from(markdown_bytes));
-    response = append_vary(response);
+    /// Build the response to a failed conversion with `responder`, in place
+    /// of the `502` in [`error_format`](Self::error_format), e.g. to follow a
+    /// platform's own error format.
+    ///
+    /// The [`ConversionError`] carries the request URL and the inner
+    /// service's status and headers. Its response is sent as it is, apart
+    /// from `Vary: Accept`. Conversion failures under
+    /// [`ErrorPolicy::PassthroughHtml`] still pass the HTML through.
+    ///
+    /// ```rust
+    /// # use std::sync::Arc;
+    /// # use axum::response::IntoResponse;
+    /// # use axum_markdown::MarkdownConfig;
+    /// let config = MarkdownConfig::new().error_responder(Arc::new(|error| {
+    ///     (http::StatusCode::SERVICE_UNAVAILABLE, error.to_string()).into_response()
+    /// }));
+    /// ```
+    #[must_use]
+    pub fn error_responder(mut self, responder: ErrorResponder) -> Self {
+        self.error_responder = Some(responder);
+        self
+    }
 
-    Ok(response)
+    /// Strip any UTF-8 byte order mark and leading/trailing whitespace from
+    /// the converted body, before front matter is added and tokens are counted.
+    #[must_use]
+    pub const fn trim_output(mut self, enabled: bool) -> Self {
+        self.trim_output = enabled;
+        self
+    }
+
+    /// Add app-specific front matter fields computed from each page.
+    ///
+    /// The callback runs when front matter is enabled, after the built-in
+    /// fields are collected. New keys are appended; a key that matches a
+    /// built-in field replaces its value.
+    #[must_use]
+    pub fn front_matter_fields(mut self, fields: FrontMatterFields) -> Self {
+        self.front_matter_fields = Some(fields);
+        self
+    }
+
+    /// Record where the markdown came from, using `template` (e.g.
+    /// [`DEFAULT_SOURCE_TEMPLATE`]).
+    ///
+    /// As a footer, the rendered template is appended after a `---` rule;
+    /// `{url}` (the page URL, see [`base_url`](Self::base_url)),
+    /// `{retrieved_at}`, and `{title}` are substituted. In front matter, the
+    /// template is unused and `source` and `retrieved_at` fields are added
+    /// instead. Either way it counts towards `x-markdown-tokens`.
+    #[must_use]
+    pub fn source_attribution(mut self, template: impl Into<String>) -> Self {
+        self.source_attribution = Some(template.into());
+        self
+    }
+
+    /// Set where the source attribution is placed.
+    #[must_use]
+    pub const fn source_placement(mut self, placement: SourcePlacement) -> Self {
+        self.source_placement = placement;
+        self
+    }
+
+    /// Override how the `{retrieved_at}` timestamp is produced, e.g. to
+    /// change its format or to make it deterministic in tests.
+    #[must_use]
+    pub fn retrieved_at(mut self, retrieved_at: RetrievedAt) -> Self {
+        self.retrieved_at = Some(retrieved_at);
+        self
+    }
+
+    /// Rewrite every converted document with `post_process`, e.g. to
+    /// redact secrets.
+    ///
+    /// It runs last, after the built-in passes, front matter, and the
+    /// canonical comment, and before token counting, so
+    /// `x-markdown-tokens`, [`max_tokens`](Self::max_tokens), and the
+    /// `ETag` all describe its output.
+    ///
+    /// ```rust
+    /// # use std::sync::Arc;
+    /// # use axum_markdown::MarkdownConfig;
+    /// let config = MarkdownConfig::new()
+    ///     .post_process(Arc::new(|markdown| markdown.replace("hunter2", "[redacted]")));
+    /// ```
+    #[must_use]
+    pub fn post_process(mut self, post_process: PostProcess) -> Self {
+        self.post_process = Some(post_process);
+        self
+    }
+
+    /// Start every converted document with `<!-- canonical: url -->`, where
+    /// `url` is the request URL (see [`base_url`](Self::base_url)).
+    ///
+    /// Renderers hide the comment while downstream indexes can read it.
+    /// It precedes the front matter, so readers that only recognize front
+    /// matter on the very first line won't find it when both are enabled.
+    #[must_use]
+    pub const fn canonical_comment(mut self, enabled: bool) -> Self {
+        self.canonical_comment = enabled;
+        self
+    }
+
+    /// End every converted document with the original HTML, base64-encoded
+    /// (so it cannot close the comment early) in a
+    /// `<!-- source-html-base64: ... -->` comment, from which archives can
+    /// recover the source.
+    ///
+    /// The comment is added after token counting: `x-markdown-tokens` and
+    /// [`max_tokens`](Self::max_tokens) cover the markdown alone. It is left
+    /// out when the encoded HTML would exceed the body size limit
+    /// ([`max_body_size`](Self::max_body_size), the limit for the response's
+    /// `Content-Type`, or its per-response override).
+    #[must_use]
+    pub const fn embed_source_html(mut self, enabled: bool) -> Self {
+        self.embed_source_html = enabled;
+        self
+    }
+
+    /// Insert a table of contents: a nested list of links to the document's
+    /// headings, placed after the opening heading (or at the top of the body,
+    /// below any front matter).
+    ///
+    /// Links use GitHub-style slugs of the heading text (htmd does not keep
+    /// heading `id`s), and entries are plain text with inline formatting removed.
+    #[must_use]
+    pub const fn table_of_contents(mut self, options: TocOptions) -> Self {
+        self.table_of_contents = Some(options);
+        self
+    }
+
+    /// End each heading with an attribute carrying its GitHub-style slug,
+    /// `## Getting Started {#getting-started}`, so sections of the markdown
+    /// can be linked to. Slugs are lowercase with spaces as hyphens, and
+    /// repeats are numbered `-1`, `-2`, matching the
+    /// [`table_of_contents`](Self::table_of_contents) links.
+    #[must_use]
+    pub const fn heading_anchors(mut self, enabled: bool) -> Self {
+        self.heading_anchors = enabled;
+        self
+    }
+
+    /// Carry the page's breadcrumb trail over into the markdown.
+    ///
+    /// The trail comes from a JSON-LD `BreadcrumbList` if there is one,
+    /// otherwise from a `<nav aria-label="breadcrumb">` or schema.org
+    /// `BreadcrumbList` microdata.
+    #[must_use]
+    pub const fn breadcrumbs(mut self, placement: BreadcrumbPlacement) -> Self {
+        self.breadcrumbs = Some(placement);
+        self
+    }
+
+    /// Let requests carrying `X-Markdown-Dry-Run: true` ask what the
+    /// middleware would do, for debugging negotiation.
+    ///
+    /// Such responses pass through unconverted, annotated with
+    /// `X-Markdown-Would-Convert: true|false` and `X-Markdown-Decision`:
+    /// `accept-header`, `path-suffix`, `prefer-header`, or `page-summary` when
+    /// converting, `not-requested`, `cross-origin`, `redirect`, or `not-html`
+    /// when not. The cache is bypassed, and
+    /// `X-Markdown-Dry-Run` is added to `Vary`.
+    #[must_use]
+    pub const fn dry_run_header(mut self, enabled: bool) -> Self {
+        self.dry_run_header = enabled;
+        self
+    }
+
+    /// Convert responses to markdown requests only to count their tokens,
+    /// for sizing the feature before rolling it out.
+    ///
+    /// Such responses keep their HTML body and headers, with the
+    /// `x-markdown-tokens` the markdown would have had added. The caches
+    /// are bypassed, and a failed conversion serves the HTML unannotated.
+    #[must_use]
+    pub const fn shadow_mode(mut self, enabled: bool) -> Self {
+        self.shadow_mode = enabled;
+        self
+    }
+
+    /// Set the tiktoken encoding used to count tokens.
+    ///
+    /// Each encoding is built once per process, the first time it is used.
+    #[must_use]
+    #[cfg_attr(
+        not(feature = "tokens"),
+        deprecated(note = "has no effect without the `tokens` feature")
+    )]
+    pub const fn tokenizer(mut self, model: TokenizerModel) -> Self {
+        self.tokenizer = model;
+        self
+    }
+
+    /// Name the encoding in the header, as `x-markdown-tokens: 345; model=cl100k_base`.
+    #[must_use]
+    pub const fn token_header_model(mut self, enabled: bool) -> Self {
+        self.token_header_model = enabled;
+        self
+    }
+
+    /// Enable or disable token counting.
+    ///
+    /// Tokenizing is the most expensive step for large documents, so
+    /// disabling it when no consumer reads `x-markdown-tokens` cuts
+    /// conversion latency. The header is then omitted, and since encoders
+    /// are built lazily, a process that never counts never builds one.
+    #[must_use]
+    pub const fn count_tokens(mut self, enabled: bool) -> Self {
+        self.count_tokens = enabled;
+        self
+    }
+
+    /// Count tokens with `counter` instead of a tiktoken encoding.
+    ///
+    /// The counter is shared, never rebuilt: by every service of the
+    /// layer, by clones of the config, and by any other layer given the
+    /// same `Arc` (see [`MarkdownLayer::with_token_counter`]), so an
+    /// expensive tokenizer is loaded once. The built-in encodings are
+    /// process-wide already. [`token_header_model`](Self::token_header_model)
+    /// has no effect with a custom counter.
+    #[must_use]
+    pub fn token_counter(mut self, counter: Arc<dyn TokenCounter>) -> Self {
+        self.token_counter = Some(counter);
+        self
+    }
+
+    /// Choose between exact and approximate token counts.
+    ///
+    /// [`Estimation::Approximate`] replaces the tokenizer (and any
+    /// [`token_counter`](Self::token_counter) or `X-Token-Model` choice) with
+    /// a heuristic that is typically within 15% of `o200k_base`, for every
+    /// count: `x-markdown-tokens`, `x-html-tokens`, and the budgets of
+    /// [`max_tokens`](Self::max_tokens) and
+    /// [`page_tokens`](Self::page_tokens). Approximate counts are marked with
+    /// `x-markdown-tokens-estimated: true`.
+    #[must_use]
+    pub const fn token_estimation(mut self, estimation: Estimation) -> Self {
+        self.token_estimation = estimation;
+        self
+    }
+
+    /// Let requests pick the tokenizer for their `x-markdown-tokens` count
+    /// with `X-Token-Model: <name>` (e.g. `cl100k_base`), from `models`.
+    ///
+    /// Unknown or unlisted names fall back to the configured counter. The
+    /// encoding used is echoed in `x-markdown-tokens-model`, and responses
+    /// vary on `X-Token-Model`.
+    #[must_use]
+    #[cfg_attr(
+        not(feature = "tokens"),
+        deprecated(note = "has no effect without the `tokens` feature")
+    )]
+    pub fn token_models<I>(mut self, models: I) -> Self
+    where
+        I: IntoIterator<Item = TokenizerModel>,
+    {
+        self.token_models = models.into_iter().collect();
+        self
+    }
+
+    /// Also count the markdown with each of `models`, reporting the counts
+    /// as `x-markdown-tokens-by-model: cl100k_base=1310, p50k_base=1402`,
+    /// for clients that budget for several model families.
+    /// `x-markdown-tokens` remains the primary count.
+    ///
+    /// The markdown is decoded once for all of them, and counted on the
+    /// blocking pool past
+    /// [`blocking_token_threshold`](Self::blocking_token_threshold). They
+    /// count the whole document, so the header is left off pages (see
+    /// [`page_tokens`](Self::page_tokens)), and they are skipped with
+    /// [`Estimation::Approximate`].
+    #[must_use]
+    #[cfg_attr(
+        not(feature = "tokens"),
+        deprecated(note = "has no effect without the `tokens` feature"),
+        allow(unused_mut)
+    )]
+    pub fn additional_tokenizers<I>(mut self, models: I) -> Self
+    where
+        I: IntoIterator<Item = TokenizerModel>,
+    {
+        #[cfg(feature = "tokens")]
+        self.additional_tokenizers
+            .extend(models.into_iter().map(|model| {
+                (
+                    model.name().to_string(),
+                    Arc::new(model) as Arc<dyn TokenCounter>,
+                )
+            }));
+        #[cfg(not(feature = "tokens"))]
+        drop(models.into_iter());
+        self
+    }
+
+    /// Also count the markdown with `counter`, reported under `name` in
+    /// `x-markdown-tokens-by-model` (see
+    /// [`additional_tokenizers`](Self::additional_tokenizers)). `name` is
+    /// a structured-field key: lowercase, starting with a letter.
+    #[must_use]
+    pub fn additional_token_counter(
+        mut self,
+        name: impl Into<String>,
+        counter: Arc<dyn TokenCounter>,
+    ) -> Self {
+        self.additional_tokenizers.push((name.into(), counter));
+        self
+    }
+
+    /// Also report the token count of the original HTML body, with the same
+    /// tokenizer, in `x-html-tokens`.
+    ///
+    /// This roughly doubles the tokenization work per conversion.
+    #[must_use]
+    pub const fn html_tokens(mut self, enabled: bool) -> Self {
+        self.html_tokens = enabled;
+        self
+    }
+
+    /// Report the size in bytes of the HTML a converted response replaced,
+    /// in `x-original-content-length`, for bandwidth accounting.
+    ///
+    /// The size is the body as read, not the inner service's
+    /// `Content-Length`; a declared length that disagrees is logged as a
+    /// warning.
+    #[must_use]
+    pub const fn original_content_length(mut self, enabled: bool) -> Self {
+        self.original_content_length = enabled;
+        self
+    }
+
+    /// Report the size of the markdown relative to the HTML it replaced in
+    /// `x-markdown-ratio`, e.g. `0.183`, for a per-response measure of the
+    /// savings.
+    ///
+    /// Both sizes are in bytes, the HTML's measured as for
+    /// [`original_content_length`](Self::original_content_length), and the
+    /// markdown's before any pagination or range. An empty HTML body gives
+    /// `inf`.
+    #[must_use]
+    pub const fn compression_ratio(mut self, enabled: bool) -> Self {
+        self.compression_ratio = enabled;
+        self
+    }
+
+    /// Flag converted responses as transformed by an intermediary, with
+    /// `Warning: 214 axum-markdown "Transformation Applied"` (RFC 7234
+    /// §5.5.7).
+    ///
+    /// The header is added after any `Warning` the inner service sent.
+    /// Passthrough and error responses don't get it.
+    #[must_use]
+    pub const fn transformation_warning(mut self, enabled: bool) -> Self {
+        self.transformation_warning = enabled;
+        self
+    }
+
+    /// Give converted responses the language of their page, as
+    /// `Content-Language` from the `<html lang>` attribute.
+    ///
+    /// A `Content-Language` the inner service sent is kept as it is. Pages
+    /// without a `lang`, or with one that isn't a language tag, get none.
+    #[must_use]
+    pub const fn propagate_language(mut self, enabled: bool) -> Self {
+        self.propagate_language = enabled;
+        self
+    }
+
+    /// Report the wall-clock time spent converting a response in
+    /// `x-markdown-duration-ms`, e.g. `1.482`, to tell the middleware's
+    /// latency from the inner service's.
+    ///
+    /// The time runs from reading the HTML body to the converted response's
+    /// headers, including waits for the blocking pool. Responses served
+    /// from a cache don't get the header. [`MarkdownLayer::stats`] splits the
+    /// time between reading, conversion, and token counting.
+    #[must_use]
+    pub const fn duration_header(mut self, enabled: bool) -> Self {
+        self.duration_header = enabled;
+        self
+    }
+
+    /// Remove `headers` from converted responses, replacing any set before.
+    /// Passthrough responses keep them.
+    #[must_use]
+    pub fn strip_headers(mut self, headers: impl IntoIterator<Item = HeaderName>) -> Self {
+        self.strip_headers = headers.into_iter().collect();
+        self
+    }
+
+    /// Remove the headers that only apply to an HTML document from converted
+    /// responses: `Content-Security-Policy`, `X-Frame-Options`,
+    /// `X-XSS-Protection`, and `Permissions-Policy`. A markdown body isn't
+    /// rendered as a page, so they do nothing there.
+    ///
+    /// Shorthand for [`strip_headers`](Self::strip_headers) with those.
+    #[must_use]
+    pub fn strip_html_only_headers(self) -> Self {
+        self.strip_headers([
+            CONTENT_SECURITY_POLICY,
+            X_FRAME_OPTIONS,
+            X_XSS_PROTECTION,
+            HeaderName::from_static("permissions-policy"),
+        ])
+    }
+
+    /// Add `headers` to converted responses, replacing any set before, e.g.
+    /// an `x-served-by` marker for edge rules. Passthrough responses get
+    /// them too with [`extra_headers_all_responses`](Self::extra_headers_all_responses).
+    ///
+    /// They are set after the middleware's own headers and the inner
+    /// service's, replacing those of the same name; a name listed more
+    /// than once gets each of its values. Headers that describe the
+    /// markdown body itself, `Content-Type`, `Content-Length`,
+    /// `Content-Encoding`, `Content-Range`, and `Transfer-Encoding`, can't
+    /// be set: they are left out, with a warning logged.
+    #[must_use]
+    pub fn extra_headers(
+        mut self,
+        headers: impl IntoIterator<Item = (HeaderName, HeaderValue)>,
+    ) -> Self {
+        self.extra_headers.clear();
+        for (name, value) in headers {
+            self = self.add_extra_header(name, value);
+        }
+        self
+    }
+
+    /// Add `name: value` to the [`extra_headers`](Self::extra_headers),
+    /// after those set before.
+    ///
+    /// ```rust
+    /// # use axum_markdown::MarkdownConfig;
+    /// let config = MarkdownConfig::new()
+    ///     .extra_header("x-robots-tag", "noindex")?
+    ///     .extra_header("x-served-by", "markdown-layer")?;
+    /// # Ok::<_, http::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If `name` is not a valid header name or `value` not a valid value.
+    pub fn extra_header<K, V>(self, name: K, value: V) -> Result<Self, http::Error>
+    where
+        K: TryInto<HeaderName>,
+        K::Error: Into<http::Error>,
+        V: TryInto<HeaderValue>,
+        V::Error: Into<http::Error>,
+    {
+        let name = name.try_into().map_err(Into::into)?;
+        let value = value.try_into().map_err(Into::into)?;
+        Ok(self.add_extra_header(name, value))
+    }
+
+    /// Send the [`extra_headers`](Self::extra_headers) on every response
+    /// passing through the layer, not only converted ones.
+    #[must_use]
+    pub const fn extra_headers_all_responses(mut self, enabled: bool) -> Self {
+        self.extra_headers_all_responses = enabled;
+        self
+    }
+
+    fn add_extra_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        if BODY_HEADERS.contains(&name) {
+            tracing::warn!(header = %name, "extra header describes the body, ignoring it");
+        } else {
+            self.extra_headers.append(name, value);
+        }
+        self
+    }
+
+    /// Render `<dl>` definition lists in `style`.
+    #[must_use]
+    pub const fn definition_lists(mut self, style: DefinitionListStyle) -> Self {
+        self.definition_lists = Some(style);
+        self
+    }
+
+    /// Render `<details>` disclosure widgets with `handling`.
+    #[must_use]
+    pub const fn details_handling(mut self, handling: DetailsHandling) -> Self {
+        self.details_handling = Some(handling);
+        self
+    }
+
+    /// Render math as `$..$` inline and display math in `notation`.
+    ///
+    /// A `<math>` element's `application/x-tex` annotation is used as its
+    /// source when present; otherwise its presentation `MathML` is written
+    /// out as TeX. `display="block"` elements and `\[..\]` on a line of
+    /// their own become blocks, other math stays inline.
+    #[must_use]
+    pub const fn math(mut self, notation: MathNotation) -> Self {
+        self.math = Some(notation);
+        self
+    }
+
+    /// Render tables, strikethrough, and task list checkboxes for
+    /// `dialect`: GFM pipe tables, `~~text~~` and `[x]`, or for a strict
+    /// `CommonMark` renderer, which has none of them, HTML blocks, inline
+    /// `<del>`, and literal brackets.
+    #[must_use]
+    pub const fn dialect(mut self, dialect: MarkdownDialect) -> Self {
+        self.dialect = Some(dialect);
+        self
+    }
+
+    /// Write `<hr>` thematic breaks as `style`, e.g. `***` to keep them
+    /// apart from front matter delimiters.
+    #[must_use]
+    pub const fn thematic_break(mut self, style: ThematicBreak) -> Self {
+        self.thematic_break = Some(style);
+        self
+    }
+
+    /// End the lines of the markdown with `ending`, e.g.
+    /// [`LineEnding::CrLf`] for Windows tools that require it.
+    ///
+    /// It is applied last, after [`post_process`](Self::post_process), so
+    /// token counts and digests are of the body as sent. The
+    /// [`truncation_notice`](Self::truncation_notice) follows it too.
+    #[must_use]
+    pub const fn line_ending(mut self, ending: LineEnding) -> Self {
+        self.line_ending = ending;
+        self
+    }
+
+    /// Tokenize documents larger than `bytes` with `tokio::task::spawn_blocking`
+    /// rather than on the async worker, so large pages don't stall other
+    /// requests. Smaller documents are counted inline.
+    ///
+    /// If the blocking task fails, the token count header is omitted.
+    /// Outside a Tokio runtime everything is counted inline.
+    #[must_use]
+    pub const fn blocking_token_threshold(mut self, bytes: usize) -> Self {
+        self.blocking_token_threshold = bytes;
+        self
+    }
+
+    /// Deliver `x-markdown-tokens` as an HTTP trailer after the body,
+    /// declared up front in a `Trailer` header.
+    ///
+    /// The body is then sent chunked over HTTP/1.1, without a
+    /// `Content-Length`. HTTP/1.1 requests without `TE: trailers` keep the
+    /// header, since hyper only sends them trailers when asked to.
+    #[must_use]
+    pub const fn tokens_as_trailer(mut self, enabled: bool) -> Self {
+        self.tokens_as_trailer = enabled;
+        self
+    }
+
+    /// Set the response content types that are converted, replacing the
+    /// default `text/html`.
+    ///
+    /// A response is converted when its `Content-Type` contains any of
+    /// `types`, ignoring ASCII case, so `text/html` also covers
+    /// `text/html; charset=utf-8` and `text/html+custom`.
+    #[must_use]
+    pub fn convertible_types<I, T>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.convertible_types = types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Truncate markdown longer than `tokens` tokens to fit, ending it with
+    /// the [`truncation_notice`](Self::truncation_notice).
+    ///
+    /// The notice counts toward the budget. Truncated responses report
+    /// the emitted count in `x-markdown-tokens`, plus
+    /// `x-markdown-truncated: true` and the untruncated count in
+    /// `x-markdown-original-tokens`. The cut is found with
+    /// [`TokenCounter::truncate`].
+    #[must_use]
+    pub const fn max_tokens(mut self, tokens: usize) -> Self {
+        self.max_tokens = Some(tokens);
+        self
+    }
+
+    /// Let requests set their own [`max_tokens`](Self::max_tokens) budget
+    /// with `X-Max-Tokens: <n>`, clamped to `ceiling`.
+    ///
+    /// Zero or unparseable values are ignored, leaving the configured
+    /// budget (if any) in place. Responses vary on `X-Max-Tokens`.
+    #[must_use]
+    pub const fn max_tokens_header(mut self, ceiling: usize) -> Self {
+        self.max_tokens_header = Some(ceiling);
+        self
+    }
+
+    /// Set the indentation of nested lists: each level is indented by
+    /// `spaces`, with a single space after every list marker. Children of
+    /// an ordered item are indented at least to its content, past `1. `.
+    #[must_use]
+    pub const fn list_indent(mut self, spaces: usize) -> Self {
+        self.list_indent = spaces;
+        self
+    }
+
+    /// Rewrite blockquote prefixes so each nesting level adds exactly one
+    /// `>`, followed by a single space: `>> text` two levels deep, where
+    /// htmd writes `> > text`. Blank lines inside a quote keep their `>`s.
+    /// Fenced code inside a quote is left verbatim.
+    #[must_use]
+    pub const fn normalize_blockquotes(mut self, enabled: bool) -> Self {
+        self.normalize_blockquotes = enabled;
+        self
+    }
+
+    /// Decode character references such as `&amp;` or `&#169;` that are
+    /// still in the markdown's text, as double-encoded HTML leaves them.
+    /// Code blocks and code spans are left verbatim, as are `&lt;` and
+    /// `&gt;`, so text never turns into HTML tags.
+    #[must_use]
+    pub const fn decode_entities(mut self, enabled: bool) -> Self {
+        self.decode_entities = enabled;
+        self
+    }
+
+    /// Write non-breaking spaces (`&nbsp;`) in text as plain spaces. Code
+    /// blocks and code spans keep theirs.
+    #[must_use]
+    pub const fn nbsp_to_space(mut self, enabled: bool) -> Self {
+        self.nbsp_to_space = enabled;
+        self
+    }
+
+    /// Pass `301`, `302`, `303`, `307`, and `308` responses through
+    /// unconverted, even with an HTML body and markdown requested, so the
+    /// body stays the one that accompanies the `Location` header.
+    #[must_use]
+    pub const fn pass_through_redirects(mut self, enabled: bool) -> Self {
+        self.pass_through_redirects = enabled;
+        self
+    }
+
+    /// Set how converted responses get their `ETag`.
+    ///
+    /// An inner service's `ETag` validates the HTML, which clients of the
+    /// markdown never see, so by default it is replaced with a strong
+    /// `ETag` hashed from the markdown bytes. Each page of a
+    /// [`page_tokens`](Self::page_tokens) document gets its own.
+    #[must_use]
+    pub const fn etag(mut self, policy: ETagPolicy) -> Self {
+        self.etag = policy;
+        self
+    }
+
+    /// Have the middleware answer `If-Modified-Since` on converted
+    /// responses with `304 Not Modified`.
+    ///
+    /// Converted responses keep the HTML's `Last-Modified`, since the
+    /// markdown changes only when the HTML does. `If-Modified-Since` is
+    /// always forwarded, so an inner service can answer `304` itself, and
+    /// its bodiless responses pass through unconverted. This is for inner
+    /// services that ignore the header, and for conversions served from
+    /// the [`cache_ttl`](Self::cache_ttl) cache without asking the inner
+    /// service, which otherwise always get a `200`. Either way, an
+    /// `If-None-Match` takes precedence (RFC 9110 §13.2.2):
+    /// `If-Modified-Since` is then ignored, and not forwarded.
+    #[must_use]
+    pub const fn if_modified_since(mut self, enabled: bool) -> Self {
+        self.if_modified_since = enabled;
+        self
+    }
+
+    /// Set the header that carries the inner service's `ETag`, verbatim
+    /// (weak or strong), on converted responses, so they can be correlated
+    /// with the HTML they came from. `None` leaves it out.
+    #[must_use]
+    pub fn original_etag_header(mut self, name: Option<HeaderName>) -> Self {
+        self.original_etag_header = name;
+        self
+    }
+
+    /// Set the header that carries the inner service's `Last-Modified` on
+    /// converted responses. `None` leaves it out.
+    #[must_use]
+    pub fn original_last_modified_header(mut self, name: Option<HeaderName>) -> Self {
+        self.original_last_modified_header = name;
+        self
+    }
+
+    /// Send an RFC 9530 `Content-Digest` of the markdown, e.g.
+    /// `sha-256=:...:`, on converted responses.
+    ///
+    /// The digest covers the bytes each response carries: one page of a
+    /// [`page_tokens`](Self::page_tokens) document, or the part a `Range`
+    /// asked for. The inner service's `Content-Digest`, `Repr-Digest`,
+    /// `Digest`, and `Content-MD5` describe the HTML, so converted
+    /// responses never keep them.
+    #[must_use]
+    pub const fn content_digest(mut self, algorithm: DigestAlgorithm) -> Self {
+        self.content_digest = Some(algorithm);
+        self
+    }
+
+    /// Send an RFC 3230 `Digest` of the markdown, e.g. `sha-256=...`, on
+    /// converted responses, for caches and clients that predate
+    /// [`content_digest`](Self::content_digest).
+    ///
+    /// The digest is of the whole markdown document, so it is left off
+    /// `206 Partial Content` responses, whose body is only part of one.
+    /// It is independent of the [`etag`](Self::etag).
+    #[must_use]
+    pub const fn digest(mut self, algorithm: DigestAlgorithm) -> Self {
+        self.digest = Some(algorithm);
+        self
+    }
+
+    /// Serve just the page's metadata to requests that prefer
+    /// `application/vnd.page-summary+json` (see [`negotiate`]): a JSON
+    /// object with the page's `url`, `title`, `description`, `canonical`
+    /// URL, and `lang`, and its Open Graph and Twitter card `properties`
+    /// (as filtered by [`social_properties`](Self::social_properties)),
+    /// e.g. `{"title": "Guide", "properties": {"og:title": "The Guide"}, ...}`.
+    ///
+    /// Missing fields are `null`, and a property the page repeats is an
+    /// array. Summaries bypass the cache. To put the same metadata at the
+    /// top of the markdown instead, use [`front_matter`](Self::front_matter).
+    #[must_use]
+    pub const fn page_summary(mut self, enabled: bool) -> Self {
+        self.page_summary = enabled;
+        self
+    }
+
+    /// Set how much of the token budget, in percent, truncation may give up
+    /// to cut at a block boundary (before a blank line, or after a code
+    /// fence) rather than mid-block.
+    ///
+    /// When backing off would cost more, the cut ends on a line boundary
+    /// instead: an open code fence is closed, and a table header left
+    /// without its separator row is dropped.
+    #[must_use]
+    pub const fn truncation_backoff(mut self, percent: u8) -> Self {
+        self.truncation_backoff = percent;
+        self
+    }
+
+    /// Let requests page through long documents in windows of `tokens`
+    /// tokens, selecting a page (from 1) with `X-Markdown-Page: <n>` or a
+    /// `markdown-page=<n>` query parameter.
+    ///
+    /// Pages break between blocks; a block larger than the window gets a
+    /// page to itself. Responses carry `x-markdown-page`,
+    /// `x-markdown-total-pages`, and a `Link: rel="next"` to the following
+    /// page, and vary on `X-Markdown-Page`. A page past the end gets an
+    /// empty `416 Range Not Satisfiable` response with
+    /// `x-markdown-total-pages`.
+    ///
+    /// The document is paginated after conversion and any
+    /// [`max_tokens`](Self::max_tokens) truncation. With
+    /// [`cache_ttl`](Self::cache_ttl) set, every page is cut from one cached
+    /// conversion; only the paging itself is recomputed per request.
+    #[must_use]
+    pub const fn page_tokens(mut self, tokens: usize) -> Self {
+        self.page_tokens = Some(tokens);
+        self
+    }
+
+    /// Count the tokens of each top-level section (split on `#` and `##`
+    /// headings outside code fences) into a [`SectionTokens`] list
+    /// response extension, for layers wrapping this one.
+    ///
+    /// Each token is attributed to the section it starts in, so the counts
+    /// sum to `x-markdown-tokens`. They describe the whole document after
+    /// any [`max_tokens`](Self::max_tokens) truncation, even when a request
+    /// gets one page or range of it.
+    #[must_use]
+    pub const fn section_tokens(mut self, enabled: bool) -> Self {
+        self.section_tokens = enabled;
+        self
+    }
+
+    /// Also send the [`section_tokens`](Self::section_tokens) counts as
+    /// `x-markdown-section-tokens`, a JSON array of `[heading, tokens]`
+    /// pairs (`null` for any text before the first heading), e.g.
+    /// `[["Intro",120],["Usage",480]]`. The header is left out when it would
+    /// be longer than `max_len` bytes.
+    #[must_use]
+    pub const fn section_tokens_header(mut self, max_len: usize) -> Self {
+        self.section_tokens = true;
+        self.section_tokens_header = Some(max_len);
+        self
+    }
+
+    /// Set the text appended to markdown truncated by [`max_tokens`](Self::max_tokens).
+    #[must_use]
+    pub fn truncation_notice(mut self, notice: impl Into<String>) -> Self {
+        self.truncation_notice = notice.into();
+        self
+    }
+
+    /// The [`selected_counter`](Self::selected_counter), if it is available.
+    fn counter(&self, requested: Option<TokenizerModel>) -> Option<Arc<dyn TokenCounter>> {
+        self.selected_counter(requested)
+            .filter(|counter| counter.is_available())
+    }
+
+    /// Whether conversions may need the configured tokenizer: to count, or
+    /// to truncate or paginate by tokens, and not by estimate.
+    fn uses_tokenizer(&self) -> bool {
+        let truncates = self.max_tokens.is_some()
+            || self.max_tokens_header.is_some()
+            || self.page_tokens.is_some();
+        (self.count_tokens || self.html_tokens || truncates)
+            && self.token_estimation != Estimation::Approximate
+    }
+
+    /// Whether the request's counter exists but is unavailable.
+    fn counter_unavailable(&self, requested: Option<TokenizerModel>) -> bool {
+        self.selected_counter(requested)
+            .is_some_and(|counter| !counter.is_available())
+    }
+
+    /// The `requested` tokenizer, or the configured counter without one.
+    /// Without the `tokens` feature, only a custom counter counts.
+    #[cfg_attr(feature = "tokens", allow(clippy::unnecessary_wraps))]
+    fn selected_counter(&self, requested: Option<TokenizerModel>) -> Option<Arc<dyn TokenCounter>> {
+        if self.token_estimation == Estimation::Approximate {
+            return Some(Arc::new(estimate::Approximate));
+        }
+        match (requested, &self.token_counter) {
+            #[cfg(feature = "tokens")]
+            (Some(model), _) => Some(Arc::new(model)),
+            (_, Some(counter)) => Some(Arc::clone(counter)),
+            #[cfg(feature = "tokens")]
+            (None, None) => Some(Arc::new(self.tokenizer)),
+            #[cfg(not(feature = "tokens"))]
+            (_, None) => None,
+        }
+    }
+
+    /// Run `f` on `text` with the request's [`counter`](Self::counter), on
+    /// the blocking thread pool when `text` is over
+    /// [`blocking_token_threshold`](Self::blocking_token_threshold).
+    /// `None` if there is no counter or the blocking task fails.
+    async fn with_counter<T, F>(
+        &self,
+        text: Bytes,
+        requested: Option<TokenizerModel>,
+        f: F,
+    ) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn TokenCounter, &str) -> T + Send + 'static,
+    {
+        let counter = self.counter(requested)?;
+        let len = text.len();
+        self.offload(len, move || f(&*counter, &String::from_utf8_lossy(&text)))
+            .await
+    }
+
+    /// Run `run`, on the blocking thread pool when `len` is over
+    /// [`blocking_token_threshold`](Self::blocking_token_threshold) or a
+    /// [`conversion_timeout`](Self::conversion_timeout) must be able to
+    /// expire meanwhile.
+    async fn offload<T, F>(&self, len: usize, run: F) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let offload = len > self.blocking_token_threshold || self.conversion_timeout.is_some();
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) if offload => runtime.spawn_blocking(run).await.ok(),
+            _ => Some(run()),
+        }
+    }
+
+    /// The tiktoken encoding [`token_count`](Self::token_count) uses, if any.
+    fn token_model(&self, requested: Option<TokenizerModel>) -> Option<TokenizerModel> {
+        if cfg!(feature = "tokens") && self.token_estimation == Estimation::Exact {
+            requested.or_else(|| self.token_counter.is_none().then_some(self.tokenizer))
+        } else {
+            None
+        }
+    }
+
+    /// Set how code blocks are fenced.
+    #[must_use]
+    pub const fn code_fence(mut self, fence: CodeFence) -> Self {
+        self.code_fence = fence;
+        self
+    }
+
+    /// Switch documents with more than `links` links to reference-style
+    /// links: `[text][1]` in the text, with the `[1]: url` definitions
+    /// collected at the end. Documents with `links` or fewer keep inline
+    /// links.
+    #[must_use]
+    pub const fn inline_link_threshold(mut self, links: usize) -> Self {
+        self.inline_link_threshold = Some(links);
+        self
+    }
+
+    /// Set how inline `<svg>` elements are converted.
+    #[must_use]
+    pub const fn svg_handling(mut self, handling: SvgHandling) -> Self {
+        self.svg_handling = handling;
+        self
+    }
+
+    /// Collapse the insignificant whitespace of the HTML before converting
+    /// it, as a browser would render it: runs of whitespace in text become
+    /// one space, and whitespace next to block-level tags is dropped.
+    ///
+    /// Deeply indented HTML then converts as its minified form does, without
+    /// stray spaces and blank lines. `<pre>`, `<textarea>`, `<script>`, and
+    /// `<style>` contents are kept as they are.
+    #[must_use]
+    pub const fn normalize_html_whitespace(mut self, enabled: bool) -> Self {
+        self.normalize_html_whitespace = enabled;
+        self
+    }
+
+    /// Inline the content of same-origin pages linked from each converted
+    /// page, fetched through `resolver`, under a `## Linked pages` section.
+    ///
+    /// Only links on the page itself are followed (a depth of 1), within
+    /// [`link_limits`](Self::link_limits).
+    #[must_use]
+    pub fn link_resolver(mut self, resolver: Arc<dyn LinkResolver>) -> Self {
+        self.link_resolver = Some(resolver);
+        self
+    }
+
+    /// Set the bounds on link inlining.
+    #[must_use]
+    pub const fn link_limits(mut self, limits: LinkLimits) -> Self {
+        self.link_limits = limits;
+        self
+    }
+
+    /// The `{retrieved_at}` timestamp for a response converted now.
+    fn retrieved_at_now(&self) -> String {
+        self.retrieved_at.as_ref().map_or_else(
+            || date::format_rfc3339(SystemTime::now()),
+            |retrieved_at| retrieved_at(),
+        )
+    }
+
+    /// The fence of [`MathNotation::Fenced`] math blocks.
+    fn math_fence(&self) -> &'static str {
+        if self.code_fence == CodeFence::Tilde {
+            "~~~"
+        } else {
+            "```"
+        }
+    }
+
+    /// Whether conversion needs a metadata pass over the HTML.
+    const fn needs_metadata(&self) -> bool {
+        self.front_matter
+            || self.canonical_header.is_some()
+            || self.json_ld_types.is_some()
+            || self.source_attribution.is_some()
+            || self.breadcrumbs.is_some()
+            || self.link_resolver.is_some()
+            || self.inline_link_threshold.is_some()
+            || self.propagate_language
+    }
+}
+
+/// Behavior for HTML responses whose body is empty (zero bytes).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmptyBodyBehavior {
+    /// Convert as usual, producing an empty markdown body with a token count of 0.
+    #[default]
+    EmitEmpty,
+    /// Return the original (empty) HTML response unchanged, apart from `Vary: Accept`.
+    Passthrough,
+    /// Return a 502 Bad Gateway, as for other conversion failures.
+    Error,
+}
+
+/// Behavior for HTML responses over the body size limit, set with
+/// [`MarkdownConfig::oversize_behavior`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OversizeBehavior {
+    /// Return a 502 Bad Gateway, as for other conversion failures.
+    #[default]
+    Error,
+    /// Return the original HTML response unchanged, apart from `Vary: Accept`.
+    Passthrough,
+}
+
+/// Response header carrying the canonical URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanonicalHeader {
+    /// `Link: <url>; rel="canonical"`, appended to any existing `Link` headers.
+    Link,
+    /// `Content-Location: url`.
+    ContentLocation,
+}
+
+/// Where [`MarkdownConfig::content_location`] points converted responses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentLocation {
+    /// The request URI itself.
+    RequestUri,
+    /// The request path with a suffix such as `.md` appended:
+    /// `/docs/intro.md?lang=en`. Paths ending in `/` get `index.md`.
+    PathSuffix(String),
+    /// The request URI with a query parameter such as `format=md` added:
+    /// `/docs/intro?lang=en&format=md`.
+    QueryParam(String),
+}
+
+impl ContentLocation {
+    /// The location of the markdown for a request to `path_and_query`.
+    fn resolve(&self, path_and_query: &str) -> String {
+        let uri = page::strip_param(path_and_query);
+        let (path, query) = uri
+            .split_once('?')
+            .map_or((uri.as_str(), None), |(path, query)| (path, Some(query)));
+        let location = match (self, query) {
+            (Self::RequestUri, _) => uri.clone(),
+            (Self::PathSuffix(suffix), query) => {
+                let index = if path.ends_with('/') { "index" } else { "" };
+                let query = query.map(|query| format!("?{query}")).unwrap_or_default();
+                format!("{path}{index}{suffix}{query}")
+            }
+            (Self::QueryParam(param), Some(query)) if query.split('&').any(|p| p == param) => {
+                uri.clone()
+            }
+            (Self::QueryParam(param), Some(query)) => format!("{path}?{query}&{param}"),
+            (Self::QueryParam(param), None) => format!("{path}?{param}"),
+        };
+        url::encode(&location)
+    }
+}
+
+/// Which wins when a [`MarkdownConfig::markdown_suffix`] request has an
+/// `Accept` header preferring `text/html`, for
+/// [`MarkdownConfig::suffix_precedence`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SuffixPrecedence {
+    /// The suffix: the request gets markdown.
+    #[default]
+    SuffixWins,
+    /// The `Accept` header: the request gets the HTML. An `Accept` that
+    /// ranks markdown as high as HTML, such as `*/*`, still gets markdown.
+    AcceptWins,
+}
+
+/// Markdown flavor of [`MarkdownConfig::dialect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkdownDialect {
+    /// GitHub Flavored Markdown: pipe tables, `~~strikethrough~~`, and
+    /// `[x]` task list items.
+    Gfm,
+    /// Strict `CommonMark`: tables are kept as HTML blocks, strikethrough
+    /// as inline `<del>`, and checkboxes as literal `\[x\]`.
+    CommonMark,
+}
+
+/// Thematic break marker of [`MarkdownConfig::thematic_break`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThematicBreak {
+    /// `---`
+    Dashes,
+    /// `***`
+    Asterisks,
+    /// `___`
+    Underscores,
+}
+
+impl ThematicBreak {
+    const fn marker(self) -> &'static str {
+        match self {
+            Self::Dashes => "\n\n---\n\n",
+            Self::Asterisks => "\n\n***\n\n",
+            Self::Underscores => "\n\n___\n\n",
+        }
+    }
+}
+
+/// Line terminator of [`MarkdownConfig::line_ending`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    /// `\n`, as htmd writes.
+    #[default]
+    Lf,
+    /// `\r\n`
+    CrLf,
+}
+
+impl LineEnding {
+    /// `text` with its lines ended this way. Lines already ending in
+    /// `\r\n` are left as they are.
+    fn apply(self, text: &str) -> String {
+        match self {
+            Self::Lf => text.to_string(),
+            Self::CrLf => {
+                let mut out = String::with_capacity(text.len() + text.len() / 32);
+                let mut previous = None;
+                for c in text.chars() {
+                    if c == '\n' && previous != Some('\r') {
+                        out.push('\r');
+                    }
+                    out.push(c);
+                    previous = Some(c);
+                }
+                out
+            }
+        }
+    }
+}
+
+/// Placement of extracted JSON-LD items.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JsonLdPlacement {
+    /// A `json_ld` list in the YAML front matter, one JSON object per item.
+    /// Requires [`MarkdownConfig::front_matter`]; nothing is emitted without it.
+    #[default]
+    FrontMatter,
+    /// A fenced `json` code block holding an array of the items, appended
+    /// to the end of the document.
+    CodeBlock,
+}
+
+/// Fence style for fenced code blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodeFence {
+    /// ```` ``` ````, lengthened when the code itself contains backtick runs.
+    #[default]
+    Backtick,
+    /// `~~~` for every block.
+    Tilde,
+    /// `~~~` for blocks whose code contains a run of three or more
+    /// backticks, ```` ``` ```` otherwise.
+    Auto,
+}
+
+/// `ETag` handling for converted responses, set with [`MarkdownConfig::etag`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ETagPolicy {
+    /// Leave the inner service's `ETag` in place.
+    Keep,
+    /// Replace the inner service's `ETag`, if any, with a strong one of the
+    /// markdown.
+    #[default]
+    Replace,
+    /// Give every converted response a strong `ETag` of its markdown.
+    Always,
+}
+
+/// Which Content-Signal wins when the inner service sets its own, for
+/// [`MarkdownConfig::content_signal_precedence`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SignalPrecedence {
+    /// Keep the inner service's header.
+    #[default]
+    Handler,
+    /// Replace it with the configured one.
+    Layer,
+}
+
+/// Hash algorithm of the [`MarkdownConfig::content_digest`] and
+/// [`MarkdownConfig::digest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    /// SHA-256, as `sha-256`.
+    Sha256,
+    /// SHA-512, as `sha-512`.
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    /// The algorithm's key in `Content-Digest` and `Digest`.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha-256",
+            Self::Sha512 => "sha-512",
+        }
+    }
+}
+
+/// How token counts are computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Estimation {
+    /// Counted by the tokenizer.
+    #[default]
+    Exact,
+    /// Estimated from the text's words, digits, and punctuation, far more
+    /// cheaply than tokenizing it.
+    Approximate,
+}
+
+/// Conversion of inline `<svg>` elements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SvgHandling {
+    /// Left out of the markdown entirely.
+    Drop,
+    /// Replaced with the text of its `<title>` and `<desc>` children, like
+    /// an image's alt text. SVGs with neither are dropped.
+    TitleAsText,
+    /// Converted like any other element, keeping the SVG's text inline.
+    #[default]
+    Keep,
+}
+
+/// Rendering of `<dl>` definition lists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefinitionListStyle {
+    /// `**term**` lines followed by `: definition` lines, as in PHP
+    /// Markdown Extra and Pandoc.
+    BoldTermParagraph,
+    /// `**term**` lines followed by their definitions indented two spaces.
+    Indented,
+}
+
+/// Rendering of `<details>` disclosure widgets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetailsHandling {
+    /// The `<summary>` as a bold line, followed by the hidden content.
+    Expand,
+    /// Just the `<summary>`, as a bold line; the hidden content is dropped.
+    SummaryOnly,
+    /// The element kept as raw HTML, which renders as a collapsible widget.
+    KeepHtml,
+}
+
+/// Rendering of display math, for [`MarkdownConfig::math`]. Inline math
+/// is always written between single `$`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MathNotation {
+    /// Blocks between `$$` lines, as GitHub and Pandoc read them.
+    Dollars,
+    /// ```` ```math ```` fenced blocks, using the [`MarkdownConfig::code_fence`]
+    /// character.
+    Fenced,
+}
+
+/// A tiktoken encoding for counting tokens.
+///
+/// Counting requires the default-on `tokens` feature; without it, models
+/// can still be named but never count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TokenizerModel {
+    /// GPT-4o and later.
+    #[default]
+    O200kBase,
+    /// GPT-4 and GPT-3.5.
+    Cl100kBase,
+    /// Codex and `text-davinci-002`/`-003`.
+    P50kBase,
+    /// `text-davinci-edit-001` and `code-davinci-edit-001`.
+    P50kEdit,
+    /// GPT-3 (`davinci`).
+    R50kBase,
+}
+
+impl TokenizerModel {
+    /// The model whose [`name`](Self::name) is `name`, ignoring ASCII case
+    /// and surrounding whitespace.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Self::O200kBase,
+            Self::Cl100kBase,
+            Self::P50kBase,
+            Self::P50kEdit,
+            Self::R50kBase,
+        ]
+        .into_iter()
+        .find(|model| model.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// The encoding's tiktoken name, e.g. `cl100k_base`.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::O200kBase => "o200k_base",
+            Self::Cl100kBase => "cl100k_base",
+            Self::P50kBase => "p50k_base",
+            Self::P50kEdit => "p50k_edit",
+            Self::R50kBase => "r50k_base",
+        }
+    }
+
+    #[cfg(feature = "tokens")]
+    fn encoder(self) -> Option<&'static tiktoken_rs::CoreBPE> {
+        match self {
+            Self::O200kBase => O200K_BASE.as_ref(),
+            Self::Cl100kBase => CL100K_BASE.as_ref(),
+            Self::P50kBase => P50K_BASE.as_ref(),
+            Self::P50kEdit => P50K_EDIT.as_ref(),
+            Self::R50kBase => R50K_BASE.as_ref(),
+        }
+    }
+
+    #[cfg(feature = "tokens")]
+    fn load(self) -> Option<tiktoken_rs::CoreBPE> {
+        #[cfg(test)]
+        tests::TOKENIZER_LOADS[self as usize].fetch_add(1, Ordering::Relaxed);
+        let encoder = match self {
+            Self::O200kBase => tiktoken_rs::o200k_base(),
+            Self::Cl100kBase => tiktoken_rs::cl100k_base(),
+            Self::P50kBase => tiktoken_rs::p50k_base(),
+            Self::P50kEdit => tiktoken_rs::p50k_edit(),
+            Self::R50kBase => tiktoken_rs::r50k_base(),
+        };
+        self.loaded(encoder)
+    }
+
+    /// The encoder, or `None` after logging why it failed to build. Each
+    /// encoding loads once, so this logs at most once per model.
+    #[cfg(feature = "tokens")]
+    fn loaded<E: fmt::Display>(
+        self,
+        encoder: Result<tiktoken_rs::CoreBPE, E>,
+    ) -> Option<tiktoken_rs::CoreBPE> {
+        encoder
+            .map_err(|err| {
+                tracing::error!(
+                    tokenizer = self.name(),
+                    "failed to initialize tokenizer, token counts are unavailable: {err}"
+                );
+            })
+            .ok()
+    }
+}
+
+#[cfg(feature = "tokens")]
+impl TokenCounter for TokenizerModel {
+    fn count(&self, text: &str) -> usize {
+        self.encoder()
+            .map_or(0, |encoder| encoder.encode_with_special_tokens(text).len())
+    }
+
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> &'a str {
+        let Some(encoder) = self.encoder() else {
+            return text;
+        };
+        let tokens = encoder.encode_with_special_tokens(text);
+        if tokens.len() <= max_tokens {
+            return text;
+        }
+        // A cut inside a multi-byte character doesn't decode; back off to
+        // the last whole one. Decoding is lossless, so the prefix is verbatim.
+        (0..=max_tokens)
+            .rev()
+            .find_map(|n| encoder.decode(tokens[..n].to_vec()).ok())
+            .and_then(|prefix| text.get(..prefix.len()))
+            .unwrap_or("")
+    }
+
+    fn is_available(&self) -> bool {
+        self.encoder().is_some()
+    }
+
+    // tiktoken-rs only exposes per-token bytes through its `_`-prefixed API
+    #[allow(clippy::used_underscore_items)]
+    fn count_parts(&self, text: &str, ends: &[usize]) -> Vec<usize> {
+        let mut counts = vec![0; ends.len()];
+        let (Some(encoder), Some(last)) = (self.encoder(), ends.len().checked_sub(1)) else {
+            return counts;
+        };
+        let text = text.get(..ends[last]).unwrap_or(text);
+        let (mut part, mut offset) = (0, 0);
+        for token in encoder._decode_native_and_split(encoder.encode_with_special_tokens(text)) {
+            while part < last && offset >= ends[part] {
+                part += 1;
+            }
+            counts[part] += 1;
+            offset += token.len();
+        }
+        counts
+    }
+}
+
+/// Counts the tokens reported in `x-markdown-tokens`.
+///
+/// [`TokenizerModel`] implements this with tiktoken; install another
+/// implementation (e.g. for a Llama tokenizer) with
+/// [`MarkdownConfig::token_counter`].
+pub trait TokenCounter: Send + Sync {
+    /// Number of tokens in `text`.
+    fn count(&self, text: &str) -> usize;
+
+    /// The longest prefix of `text` with at most `max_tokens` tokens, for
+    /// [`MarkdownConfig::max_tokens`].
+    ///
+    /// The default binary-searches the prefixes ending on a character
+    /// boundary with [`count`](Self::count); override it if the tokenizer
+    /// can decode a token prefix directly.
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> &'a str {
+        if self.count(text) <= max_tokens {
+            return text;
+        }
+        let boundaries: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+        // The prefix ending at `boundaries[lo]` fits; the one at `hi` (or the whole text) doesn't
+        let (mut lo, mut hi) = (0, boundaries.len());
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if self.count(&text[..boundaries[mid]]) <= max_tokens {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        &text[..boundaries[lo]]
+    }
+
+    /// Whether the counter can count, e.g. `false` if its tokenizer failed
+    /// to initialize. Conversions skip an unavailable counter: responses go
+    /// out uncounted and untruncated, with `x-markdown-tokens-unavailable:
+    /// true` in place of `x-markdown-tokens`. Default: true.
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    /// Tokens in each consecutive part of `text` ending at the byte offsets
+    /// `ends`, for [`MarkdownConfig::section_tokens`]. The counts sum to
+    /// [`count`](Self::count) of the text up to the last end.
+    ///
+    /// The default counts each prefix and takes the differences; override
+    /// it if the tokenizer can attribute tokens to positions in one pass.
+    fn count_parts(&self, text: &str, ends: &[usize]) -> Vec<usize> {
+        let mut counted = 0;
+        ends.iter()
+            .map(|&end| {
+                let prefix = self.count(text.get(..end).unwrap_or(text));
+                let part = prefix.saturating_sub(counted);
+                counted = counted.max(prefix);
+                part
+            })
+            .collect()
+    }
+}
+
+/// Fetches linked pages for [`MarkdownConfig::link_resolver`].
+pub trait LinkResolver: Send + Sync {
+    /// The HTML of the page at the absolute (or, without a `Host`,
+    /// path-only) `url`, or `None` to leave the link out.
+    fn resolve<'a>(&'a self, url: &'a str) -> ResolveFuture<'a>;
+}
+
+/// Options for [`MarkdownConfig::link_limits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkLimits {
+    /// Most links resolved per page, in document order. Default: 5.
+    pub max_links: usize,
+    /// Largest linked page inlined, in bytes of HTML; larger pages are
+    /// skipped. Default: 256 KiB.
+    pub max_page_bytes: usize,
+}
+
+impl Default for LinkLimits {
+    fn default() -> Self {
+        Self {
+            max_links: 5,
+            max_page_bytes: 256 * 1024,
+        }
+    }
+}
+
+/// Bounds of the [`MarkdownConfig::cache`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Most conversions kept. Default: 1024.
+    pub max_entries: usize,
+    /// Most bytes of markdown and headers kept; larger conversions are not
+    /// cached. Default: 64 MiB.
+    pub max_bytes: usize,
+    /// How long a conversion is kept. Default: `None` (until evicted or
+    /// invalidated).
+    pub ttl: Option<Duration>,
+}
+
+impl CacheConfig {
+    /// Expire conversions `ttl` after they are stored.
+    ///
+    /// Until then, a response without an `ETag` is served from the cache
+    /// without reading or hashing its HTML, so changes to such pages show
+    /// only after the TTL, or once invalidated with a
+    /// [`MarkdownCacheHandle`]. Responses with an `ETag` are still checked
+    /// against it.
+    ///
+    /// Only `GET` and `HEAD` requests without `Authorization` or `Cookie`
+    /// use the cache at all, and responses marked `Cache-Control: private`
+    /// or `no-store` are neither served from it nor stored, so one user's
+    /// page is never served to another.
+    #[must_use]
+    pub const fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 1024,
+            max_bytes: 64 * 1024 * 1024,
+            ttl: None,
+        }
+    }
+}
+
+/// Response extension overriding [`MarkdownConfig::max_body_size`] for
+/// that response only.
+///
+/// ```rust
+/// use axum::response::{Html, IntoResponse};
+/// use axum_markdown::MaxBodySizeOverride;
+///
+/// async fn archive() -> impl IntoResponse {
+///     let html = Html("<h1>Every post ever</h1>");
+///     (axum::Extension(MaxBodySizeOverride(16 * 1024 * 1024)), html)
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxBodySizeOverride(pub usize);
+
+/// Token count of a section of a converted document, in the response
+/// extension added by [`MarkdownConfig::section_tokens`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionTokens {
+    /// The heading's text with inline formatting removed; `None` for the
+    /// text before the first heading.
+    pub heading: Option<String>,
+    /// Tokens in the section, heading included.
+    pub tokens: usize,
+    /// Where the section is in the converted markdown.
+    pub byte_range: Range<usize>,
+}
+
+/// Options for [`MarkdownConfig::table_of_contents`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TocOptions {
+    /// Deepest heading level listed (`3` lists `#` to `###`). Default: 3.
+    pub max_depth: u8,
+    /// Minimum number of entries for a table to be inserted. Default: 3.
+    pub min_headings: usize,
+}
+
+impl Default for TocOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: 3,
+            min_headings: 3,
+        }
+    }
+}
+
+/// Placement of the breadcrumb trail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreadcrumbPlacement {
+    /// A `Home › Docs › Page` line at the top of the body.
+    Line,
+    /// A `breadcrumbs` list in the front matter. Requires
+    /// [`MarkdownConfig::front_matter`]; nothing is emitted without it.
+    FrontMatter,
+}
+
+/// Placement of the source attribution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SourcePlacement {
+    /// A footer at the end of the document.
+    #[default]
+    Footer,
+    /// `source` and `retrieved_at` front matter fields. Requires
+    /// [`MarkdownConfig::front_matter`]; nothing is emitted without it.
+    FrontMatter,
+}
+
+/// Body format of the response to a failed conversion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorFormat {
+    /// A one-line `text/plain` message.
+    #[default]
+    PlainText,
+    /// An RFC 7807 `application/problem+json` object with `type`, `title`,
+    /// `status`, and `detail` members. The `type` is a URN per
+    /// [`ConversionErrorKind`], e.g. `urn:axum-markdown:body-too-large`.
+    ProblemJson,
+}
+
+/// The response to HTML that failed to convert, for
+/// [`MarkdownConfig::on_error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// A `502 Bad Gateway`, in [`MarkdownConfig::error_format`].
+    #[default]
+    BadGateway,
+    /// The original HTML response, with its headers and a `Content-Length`
+    /// for the buffered body, apart from `Vary: Accept`. The client didn't
+    /// refuse HTML, it only preferred markdown.
+    PassthroughHtml,
+}
+
+/// A failed conversion, as delivered to [`MarkdownConfig::error_sender`]
+/// and [`MarkdownConfig::error_responder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversionError {
+    /// URL of the request whose response failed to convert.
+    pub url: String,
+    /// What went wrong.
+    pub kind: ConversionErrorKind,
+    /// Status of the inner service's response.
+    pub status: http::StatusCode,
+    /// Headers of the inner service's response.
+    pub headers: HeaderMap,
+}
+
+impl ConversionError {
+    fn new(
+        config: &MarkdownConfig,
+        request: &RequestContext,
+        kind: ConversionErrorKind,
+        parts: &http::response::Parts,
+    ) -> Self {
+        Self {
+            url: request.url(config),
+            kind,
+            status: parts.status,
+            headers: parts.headers.clone(),
+        }
+    }
+}
+
+/// Why a conversion failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConversionErrorKind {
+    /// The HTML body exceeded [`MarkdownConfig::max_body_size`] (or the
+    /// limit for its `Content-Type`).
+    BodyTooLarge {
+        /// The limit exceeded, in bytes.
+        limit: usize,
+    },
+    /// The HTML body could not be read.
+    BodyUnreadable,
+    /// The HTML body was empty and [`EmptyBodyBehavior::Error`] is configured.
+    EmptyBody,
+    /// htmd failed to convert the HTML.
+    ConversionFailed,
+    /// Conversion took longer than [`MarkdownConfig::conversion_timeout`].
+    Timeout {
+        /// The timeout exceeded.
+        limit: Duration,
+    },
+}
+
+impl ConversionErrorKind {
+    const fn reason(self) -> &'static str {
+        match self {
+            Self::BodyTooLarge { .. } => "response body too large",
+            Self::BodyUnreadable => "response body unreadable",
+            Self::EmptyBody => "response body is empty",
+            Self::ConversionFailed => "unable to convert HTML to markdown",
+            Self::Timeout { .. } => "conversion timed out",
+        }
+    }
+
+    /// The problem `type` and `title` for [`ErrorFormat::ProblemJson`].
+    const fn problem(self) -> (&'static str, &'static str) {
+        match self {
+            Self::BodyTooLarge { .. } => (
+                "urn:axum-markdown:body-too-large",
+                "Response body too large",
+            ),
+            Self::BodyUnreadable => (
+                "urn:axum-markdown:body-unreadable",
+                "Response body unreadable",
+            ),
+            Self::EmptyBody => ("urn:axum-markdown:empty-body", "Response body is empty"),
+            Self::ConversionFailed => (
+                "urn:axum-markdown:conversion-failed",
+                "Markdown conversion failed",
+            ),
+            Self::Timeout { .. } => (
+                "urn:axum-markdown:conversion-timed-out",
+                "Markdown conversion timed out",
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "markdown conversion of {} failed: {}",
+            self.url,
+            self.kind.reason()
+        )
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// Initialize the tokenizers `config` may count with: the configured
+/// [`tokenizer`](MarkdownConfig::tokenizer) (unless a custom
+/// [`token_counter`](MarkdownConfig::token_counter) replaces it) and every
+/// [`token_models`](MarkdownConfig::token_models) entry.
+///
+/// Tokenizers are otherwise built on first use, which adds hundreds of
+/// milliseconds to the first conversion. Call this at startup to pay that
+/// cost before serving traffic; it blocks until the tokenizers are ready.
+/// Without the `tokens` feature there is nothing to build, and this does
+/// nothing.
+#[cfg_attr(not(feature = "tokens"), allow(clippy::missing_const_for_fn))]
+pub fn warm_up(config: &MarkdownConfig) {
+    #[cfg(feature = "tokens")]
+    {
+        if !config.uses_tokenizer() {
+            return;
+        }
+        let default = config.token_counter.is_none().then_some(config.tokenizer);
+        for model in default
+            .into_iter()
+            .chain(config.token_models.iter().copied())
+        {
+            model.encoder();
+        }
+    }
+    #[cfg(not(feature = "tokens"))]
+    let _ = config;
+}
+
+/// Initialize the token counters `config` may count with, as [`warm_up`]
+/// does, and check that each is available, for a readiness probe.
+///
+/// Beyond the tokenizers `warm_up` builds, this checks a custom
+/// [`token_counter`](MarkdownConfig::token_counter) and the
+/// [`additional_tokenizers`](MarkdownConfig::additional_tokenizers). The
+/// checks run on the blocking thread pool.
+///
+/// ```rust
+/// # use axum_markdown::{ensure_ready, MarkdownConfig};
+/// # async fn probe() -> Result<(), axum_markdown::InitError> {
+/// ensure_ready(&MarkdownConfig::new()).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// If a counter failed to initialize; the conversions it serves would go
+/// out uncounted.
+pub async fn ensure_ready(config: &MarkdownConfig) -> Result<(), InitError> {
+    let mut counters: Vec<(String, Arc<dyn TokenCounter>)> = Vec::new();
+    if config.uses_tokenizer() {
+        if let Some(counter) = config.selected_counter(None) {
+            let name = config
+                .token_model(None)
+                .map_or("token_counter", TokenizerModel::name);
+            counters.push((name.to_string(), counter));
+        }
+        #[cfg(feature = "tokens")]
+        counters.extend(config.token_models.iter().map(|&model| {
+            (
+                model.name().to_string(),
+                Arc::new(model) as Arc<dyn TokenCounter>,
+            )
+        }));
+    }
+    counters.extend(config.additional_tokenizers.iter().cloned());
+
+    let check = move || {
+        counters
+            .into_iter()
+            .filter(|(_, counter)| !counter.is_available())
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>()
+    };
+    match config.offload(usize::MAX, check).await {
+        Some(unavailable) if unavailable.is_empty() => Ok(()),
+        Some(unavailable) => Err(InitError(format!(
+            "unavailable: {}",
+            unavailable.join(", ")
+        ))),
+        None => Err(InitError("a token counter panicked".to_string())),
+    }
+}
+
+/// A token counter that failed to initialize, from [`ensure_ready`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitError(String);
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "token counter initialization failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for InitError {}
+
+/// A read-only [`MarkdownConfig`] behind an `Arc`, cheap to clone into
+/// application state and tasks, and dereferencing to the config.
+///
+/// ```rust
+/// # use axum::Router;
+/// # use axum_markdown::{MarkdownConfig, MarkdownLayer, SharedMarkdownConfig};
+/// let config = SharedMarkdownConfig::from(MarkdownConfig::new().front_matter(true));
+/// let app: Router = Router::new().layer(MarkdownLayer::with_shared_config(config.clone()));
+/// assert!(config.front_matter);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SharedMarkdownConfig(Arc<MarkdownConfig>);
+
+impl std::ops::Deref for SharedMarkdownConfig {
+    type Target = MarkdownConfig;
+
+    fn deref(&self) -> &MarkdownConfig {
+        &self.0
+    }
+}
+
+impl AsRef<MarkdownConfig> for SharedMarkdownConfig {
+    fn as_ref(&self) -> &MarkdownConfig {
+        &self.0
+    }
+}
+
+impl From<MarkdownConfig> for SharedMarkdownConfig {
+    fn from(config: MarkdownConfig) -> Self {
+        Self(Arc::new(config))
+    }
+}
+
+impl From<Arc<MarkdownConfig>> for SharedMarkdownConfig {
+    fn from(config: Arc<MarkdownConfig>) -> Self {
+        Self(config)
+    }
+}
+
+impl From<SharedMarkdownConfig> for Arc<MarkdownConfig> {
+    fn from(config: SharedMarkdownConfig) -> Self {
+        config.0
+    }
+}
+
+/// Tower layer that wraps services with markdown content negotiation.
+#[derive(Debug, Clone)]
+pub struct MarkdownLayer {
+    config: Arc<MarkdownConfig>,
+    cache: Arc<Caches>,
+}
+
+impl MarkdownLayer {
+    /// Create a new `MarkdownLayer` with default configuration.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_config(MarkdownConfig::default())
+    }
+
+    /// Create a new `MarkdownLayer` with the given configuration.
+    #[must_use]
+    pub fn with_config(config: MarkdownConfig) -> Self {
+        Self::with_shared_config(config)
+    }
+
+    /// Create a `MarkdownLayer` from a configuration shared with the rest of
+    /// the application (see [`SharedMarkdownConfig`]), without copying it.
+    ///
+    /// Each layer still has its own caches.
+    #[must_use]
+    pub fn with_shared_config(config: impl Into<SharedMarkdownConfig>) -> Self {
+        let SharedMarkdownConfig(config) = config.into();
+        Self {
+            cache: Arc::new(Caches {
+                urls: config.cache_ttl.map(UrlCache::new),
+                conversions: config.cache_backend.clone().or_else(|| {
+                    let cache = MemoryCache::new(config.cache?);
+                    Some(Arc::new(cache) as Arc<dyn ConversionCache>)
+                }),
+                ttl: config.cache.and_then(|cache| cache.ttl),
+                stats: stats::Stats::default(),
+            }),
+            config,
+        }
+    }
+
+    /// The layer's configuration.
+    #[must_use]
+    pub fn config(&self) -> SharedMarkdownConfig {
+        SharedMarkdownConfig(Arc::clone(&self.config))
+    }
+
+    /// Create a `MarkdownLayer` with the given configuration, counting
+    /// tokens with `counter` (see [`MarkdownConfig::token_counter`]).
+    ///
+    /// Layers built from clones of one `Arc` share the counter, so a
+    /// tokenizer loaded once serves all of them:
+    ///
+    /// ```rust
+    /// # use std::sync::Arc;
+    /// # use axum::Router;
+    /// # use axum_markdown::{MarkdownConfig, MarkdownLayer, TokenCounter};
+    /// struct Words;
+    /// impl TokenCounter for Words {
+    ///     fn count(&self, text: &str) -> usize {
+    ///         text.split_whitespace().count()
+    ///     }
+    /// }
+    ///
+    /// let counter: Arc<dyn TokenCounter> = Arc::new(Words);
+    /// let docs: Router = Router::new()
+    ///     .layer(MarkdownLayer::with_token_counter(MarkdownConfig::new(), Arc::clone(&counter)));
+    /// let blog: Router = Router::new().layer(MarkdownLayer::with_token_counter(
+    ///     MarkdownConfig::new().front_matter(true),
+    ///     counter,
+    /// ));
+    /// ```
+    #[must_use]
+    pub fn with_token_counter(config: MarkdownConfig, counter: Arc<dyn TokenCounter>) -> Self {
+        Self::with_config(config.token_counter(counter))
+    }
+
+    /// The layer's conversion counts so far, across every service built
+    /// from it.
+    ///
+    /// ```rust
+    /// # use axum_markdown::{ConversionStats, MarkdownLayer};
+    /// let layer = MarkdownLayer::new();
+    /// assert_eq!(layer.stats(), ConversionStats::default());
+    /// ```
+    #[must_use]
+    pub fn stats(&self) -> ConversionStats {
+        self.cache.stats.snapshot()
+    }
+
+    /// A handle to invalidate the layer's caches, shared with every service
+    /// built from it: the [`cache`](MarkdownConfig::cache) of conversions
+    /// and the [`cache_ttl`](MarkdownConfig::cache_ttl) one of responses.
+    #[must_use]
+    pub fn cache_handle(&self) -> MarkdownCacheHandle {
+        MarkdownCacheHandle {
+            caches: Arc::clone(&self.cache),
+        }
+    }
+}
+
+/// Invalidates the caches of a [`MarkdownLayer`], from
+/// [`MarkdownLayer::cache_handle`]. Cheap to clone, and usable from any
+/// thread; does nothing for caches that aren't enabled.
+///
+/// ```rust
+/// use axum::{routing::post, Router};
+/// use axum_markdown::{CacheConfig, MarkdownConfig, MarkdownLayer};
+///
+/// let layer = MarkdownLayer::with_config(MarkdownConfig::new().cache(CacheConfig::default()));
+/// let handle = layer.cache_handle();
+/// let app: Router = Router::new()
+///     .route("/admin/purge", post(move || async move { handle.invalidate_prefix("/docs/") }))
+///     .layer(layer);
+/// ```
+#[derive(Debug, Clone)]
+pub struct MarkdownCacheHandle {
+    caches: Arc<Caches>,
+}
+
+impl MarkdownCacheHandle {
+    /// Drop the entries for `path`, whatever their query string.
+    pub fn invalidate(&self, path: &str) {
+        self.caches
+            .invalidate(&Invalidation::Path(path.to_string()));
+    }
+
+    /// Drop the entries for every path starting with `prefix`.
+    pub fn invalidate_prefix(&self, prefix: &str) {
+        self.caches
+            .invalidate(&Invalidation::Prefix(prefix.to_string()));
+    }
+
+    /// Drop every entry.
+    pub fn clear(&self) {
+        self.caches.invalidate(&Invalidation::All);
+    }
+}
+
+impl Default for MarkdownLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Layer<S> for MarkdownLayer {
+    type Service = MarkdownService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MarkdownService {
+            inner,
+            config: Arc::clone(&self.config),
+            cache: Arc::clone(&self.cache),
+        }
+    }
+}
+
+/// Tower service that performs markdown content negotiation.
+#[derive(Debug, Clone)]
+pub struct MarkdownService<S> {
+    inner: S,
+    config: Arc<MarkdownConfig>,
+    cache: Arc<Caches>,
+}
+
+impl<S> MarkdownService<S> {
+    /// The conversion counts of the layer this service was built from; see
+    /// [`MarkdownLayer::stats`].
+    #[must_use]
+    pub fn stats(&self) -> ConversionStats {
+        self.cache.stats.snapshot()
+    }
+}
+
+impl<S> Service<Request<Body>> for MarkdownService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = MarkdownFuture<S::Future, S::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let config = Arc::clone(&self.config);
+        let suffixed = config
+            .markdown_suffix
+            .as_deref()
+            .is_some_and(|suffix| strip_markdown_suffix(&mut req, suffix));
+        let mut request = RequestContext::new(&req, &config);
+        let accept_markdown = !request.summary && wants_markdown(req.headers());
+        let suffix_markdown = suffixed
+            && !request.summary
+            && (config.suffix_precedence == SuffixPrecedence::SuffixWins
+                || !prefers_html(req.headers()));
+        let requested =
+            request.prefer_markdown || accept_markdown || suffix_markdown || request.summary;
+        let cross_origin = requested
+            && config.require_same_origin
+            && !is_same_origin(req.headers(), &request, &config);
+        let convert = requested && !cross_origin;
+
+        if config.dry_run_header && is_dry_run(req.headers()) {
+            request.dry_run = Some(if cross_origin {
+                "cross-origin"
+            } else if request.summary {
+                "page-summary"
+            } else if accept_markdown {
+                "accept-header"
+            } else if suffix_markdown {
+                "path-suffix"
+            } else if request.prefer_markdown {
+                "prefer-header"
+            } else {
+                "not-requested"
+            });
+        } else if convert && !request.summary && !config.shadow_mode {
+            if config.strict_negotiation && !negotiate::utf8_acceptable(req.headers()) {
+                let response = append_vary_token(append_vary(not_acceptable()), "Accept-Charset");
+                return MarkdownFuture {
+                    state: FutureState::Cached {
+                        future: std::future::ready(Ok(finish(response, &config))),
+                    },
+                };
+            }
+            // A byte range refers to the markdown, so the inner service must
+            // produce the whole HTML document. Ranges conditional on
+            // `If-Range` can't be validated against the markdown; serve it whole.
+            let range = req.headers_mut().remove(RANGE);
+            request.range = range.filter(|_| !req.headers().contains_key(IF_RANGE));
+            // Likewise, `If-None-Match` is evaluated against the markdown's
+            // `ETag`, after conversion
+            if matches!(*req.method(), Method::GET | Method::HEAD) {
+                request.if_none_match = req
+                    .headers()
+                    .get_all(IF_NONE_MATCH)
+                    .iter()
+                    .cloned()
+                    .collect();
+                req.headers_mut().remove(IF_NONE_MATCH);
+                // ...which makes the inner service's `If-Modified-Since`
+                // answer moot
+                if request.if_none_match.is_empty() {
+                    request.if_modified_since = req
+                        .headers()
+                        .get(IF_MODIFIED_SINCE)
+                        .filter(|_| config.if_modified_since)
+                        .cloned();
+                } else {
+                    req.headers_mut().remove(IF_MODIFIED_SINCE);
+                }
+            }
+
+            if let Some(response) = self
+                .cache
+                .urls
+                .as_ref()
+                .filter(|_| request.shareable)
+                .and_then(|c| c.get(&request.cache_key()))
+            {
+                if request.page.is_some() {
+                    // Paging counts tokens, so it may need the blocking pool
+                    let paging = Box::pin(async move {
+                        let response = page::apply(response, &config, &request).await;
+                        Ok(finish(respond(response, &config, &request), &config))
+                    });
+                    return MarkdownFuture {
+                        state: FutureState::Converting { future: paging },
+                    };
+                }
+                let response = respond(response, &config, &request);
+                return MarkdownFuture {
+                    state: FutureState::Cached {
+                        future: std::future::ready(Ok(finish(response, &config))),
+                    },
+                };
+            }
+        }
+
+        let cache = Arc::clone(&self.cache);
+        let future = self.inner.call(req);
+
+        MarkdownFuture {
+            state: FutureState::Pending {
+                future,
+                convert,
+                config,
+                request,
+                cache,
+            },
+        }
+    }
+}
+
+pin_project! {
+    /// Future returned by `MarkdownService`.
+    pub struct MarkdownFuture<F, E> {
+        #[pin]
+        state: FutureState<F, E>,
+    }
+}
+
+pin_project! {
+    #[project = FutureStateProj]
+    enum FutureState<F, E> {
+        Pending {
+            #[pin]
+            future: F,
+            convert: bool,
+            config: Arc<MarkdownConfig>,
+            request: RequestContext,
+            cache: Arc<Caches>,
+        },
+        Cached {
+            #[pin]
+            future: std::future::Ready<Result<Response<Body>, E>>,
+        },
+        Converting {
+            #[pin]
+            future: Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>,
+        },
+    }
+}
+
+impl<F, E> Future for MarkdownFuture<F, E>
+where
+    F: Future<Output = Result<Response<Body>, E>>,
+    E: Send + 'static,
+{
+    type Output = Result<Response<Body>, E>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            let this = self.as_mut().project();
+            match this.state.project() {
+                FutureStateProj::Pending {
+                    future,
+                    convert,
+                    config,
+                    request,
+                    cache,
+                } => {
+                    let response = match future.poll(cx) {
+                        Poll::Ready(Ok(resp)) => resp,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    };
+
+                    // An inner MarkdownLayer already handled this response
+                    if response.extensions().get::<AlreadyProcessed>().is_some() {
+                        return Poll::Ready(Ok(response));
+                    }
+
+                    if let Some(decision) = request.dry_run {
+                        let response = report_dry_run(response, decision, config);
+                        return Poll::Ready(Ok(finish(append_vary(response), config)));
+                    }
+
+                    if !*convert || !is_convertible(&response, config) {
+                        // Pass through, but still add Vary: Accept
+                        let response = link_markdown(response, config, request);
+                        let response = finish(append_vary(response), config);
+                        return Poll::Ready(Ok(response));
+                    }
+
+                    let config = Arc::clone(config);
+                    let request = std::mem::take(request);
+                    let cache = Arc::clone(cache);
+                    let converting = Box::pin(async move {
+                        if request.summary {
+                            let response = summary::respond(response, &config, &request).await;
+                            return Ok(finish(response, &config));
+                        }
+                        if config.shadow_mode {
+                            let response = shadow(response, &config, &request).await;
+                            return Ok(finish(response, &config));
+                        }
+                        convert_response(response, &config, &request, &cache)
+                            .await
+                            .map(|response| finish(response, &config))
+                    });
+
+                    self.as_mut()
+                        .project()
+                        .state
+                        .set(FutureState::Converting { future: converting });
+                }
+                FutureStateProj::Converting { future } => {
+                    return future.poll(cx);
+                }
+                FutureStateProj::Cached { future } => {
+                    return future.poll(cx);
+                }
+            }
+        }
+    }
+}
+
+/// The parts of the request still needed once the inner service has responded.
+#[derive(Debug, Clone, Default)]
+#[allow(clippy::struct_excessive_bools)] // Independent facts about the request
+struct RequestContext {
+    uri: Uri,
+    host: Option<HeaderValue>,
+    /// The request asked for markdown via an honored `Prefer` header.
+    prefer_markdown: bool,
+    /// `Range` header to apply to the markdown, taken off the inner request.
+    range: Option<HeaderValue>,
+    /// `If-None-Match` field lines to evaluate against the markdown's
+    /// `ETag`, taken off the inner request.
+    if_none_match: Vec<HeaderValue>,
+    /// `If-Modified-Since` to evaluate against the converted response's
+    /// `Last-Modified`, with [`MarkdownConfig::if_modified_since`].
+    if_modified_since: Option<HeaderValue>,
+    /// For dry-run requests, the negotiation decision to report.
+    dry_run: Option<&'static str>,
+    /// Tokenizer selected with an allow-listed `X-Token-Model`.
+    tokenizer: Option<TokenizerModel>,
+    /// Token budget from an honored `X-Max-Tokens`, already clamped.
+    max_tokens: Option<usize>,
+    /// Page selected with `X-Markdown-Page` or `markdown-page`, when paginating.
+    page: Option<usize>,
+    /// The request prefers a [`MarkdownConfig::page_summary`] to markdown.
+    summary: bool,
+    /// The request is a `GET` or `HEAD` without credentials, which the
+    /// [`MarkdownConfig::cache_ttl`] cache may answer and store.
+    shareable: bool,
+    /// The client reads trailers: it speaks HTTP/2 or sent `TE: trailers`.
+    accepts_trailers: bool,
+}
+
+impl RequestContext {
+    fn new<B>(req: &Request<B>, config: &MarkdownConfig) -> Self {
+        Self {
+            uri: req.uri().clone(),
+            host: req.headers().get(HOST).cloned(),
+            prefer_markdown: config.prefer_header && prefers_markdown(req.headers()),
+            range: None,
+            if_none_match: Vec::new(),
+            if_modified_since: None,
+            dry_run: None,
+            tokenizer: req
+                .headers()
+                .get("x-token-model")
+                .and_then(|v| v.to_str().ok())
+                .and_then(TokenizerModel::from_name)
+                .filter(|model| config.token_models.contains(model)),
+            max_tokens: config.max_tokens_header.and_then(|ceiling| {
+                let tokens = req.headers().get("x-max-tokens")?.to_str().ok()?;
+                let tokens = tokens.trim().parse::<usize>().ok().filter(|&t| t > 0)?;
+                Some(tokens.min(ceiling))
+            }),
+            page: config.page_tokens.and_then(|_| {
+                let header = req.headers().get("x-markdown-page");
+                header.map_or_else(
+                    || req.uri().query().and_then(page::from_query),
+                    |value| value.to_str().ok().and_then(page::parse),
+                )
+            }),
+            summary: config.page_summary
+                && negotiate(
+                    req.headers(),
+                    &[
+                        MediaType::HTML,
+                        MediaType::MARKDOWN,
+                        MediaType::PAGE_SUMMARY,
+                    ],
+                ) == Some(MediaType::PAGE_SUMMARY),
+            shareable: matches!(*req.method(), Method::GET | Method::HEAD)
+                && !req.headers().contains_key(AUTHORIZATION)
+                && !req.headers().contains_key(COOKIE),
+            accepts_trailers: req.version() >= http::Version::HTTP_2
+                || req
+                    .headers()
+                    .get_all(TE)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .any(|coding| {
+                        let name = coding.split(';').next().unwrap_or_default();
+                        name.trim().eq_ignore_ascii_case("trailers")
+                    }),
+        }
+    }
+
+    /// The request's path and query.
+    fn path_and_query(&self) -> &str {
+        self.uri.path_and_query().map_or("/", |pq| pq.as_str())
+    }
+
+    /// Key under which the converted response is cached.
+    fn cache_key(&self) -> String {
+        let mut key = page::strip_param(self.uri.path_and_query().map_or("/", |pq| pq.as_str()));
+        // The representation differs per tokenizer and budget, so each
+        // selection gets its own entry
+        if let Some(model) = self.tokenizer {
+            key.push(' ');
+            key.push_str(model.name());
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            let _ = write!(key, " max={max_tokens}");
+        }
+        if self.prefer_markdown {
+            key.push_str(" prefer");
+        }
+        if let Some(host) = self.host.as_ref().and_then(|h| h.to_str().ok()) {
+            let _ = write!(key, " host={host}");
+        }
+        key
+    }
+
+    /// The URL of the request, as absolute as the available information allows.
+    fn url(&self, config: &MarkdownConfig) -> String {
+        let path = self.uri.path_and_query().map_or("/", |pq| pq.as_str());
+        if let Some(base) = &config.base_url {
+            return format!("{}{path}", base.trim_end_matches('/'));
+        }
+        if self.uri.authority().is_some() {
+            return self.uri.to_string();
+        }
+        self.host
+            .as_ref()
+            .and_then(|h| h.to_str().ok())
+            .map_or_else(|| path.to_string(), |host| format!("http://{host}{path}"))
+    }
+}
+
+/// Whether the request's `Origin`, or without one its `Referer`, is the
+/// origin of the request URL, for [`MarkdownConfig::require_same_origin`].
+fn is_same_origin(headers: &HeaderMap, request: &RequestContext, config: &MarkdownConfig) -> bool {
+    headers
+        .get(ORIGIN)
+        .or_else(|| headers.get(REFERER))
+        .and_then(|source| source.to_str().ok())
+        .is_some_and(|source| {
+            url::has_authority(source) && url::same_origin(source, &request.url(config))
+        })
+}
+
+/// Response extension recording that a `MarkdownService` has already
+/// handled the response, so nested layers don't convert or touch `Vary` twice.
+#[derive(Debug, Clone, Copy)]
+struct AlreadyProcessed;
+
+/// Final touches applied to every response leaving the middleware.
+fn finish(mut response: Response<Body>, config: &MarkdownConfig) -> Response<Body> {
+    if config.prefer_header {
+        response = append_vary_token(response, "Prefer");
+    }
+    if config.dry_run_header {
+        response = append_vary_token(response, "X-Markdown-Dry-Run");
+    }
+    if !config.token_models.is_empty() {
+        response = append_vary_token(response, "X-Token-Model");
+    }
+    if config.max_tokens_header.is_some() {
+        response = append_vary_token(response, "X-Max-Tokens");
+    }
+    if config.page_tokens.is_some() {
+        response = append_vary_token(response, "X-Markdown-Page");
+    }
+    if config.require_same_origin {
+        response = append_vary_token(append_vary_token(response, "Origin"), "Referer");
+    }
+    if config.signal_all_responses {
+        set_content_signal(response.headers_mut(), config);
+    }
+    if config.robots_tag_all_responses {
+        append_robots_tag(response.headers_mut(), config);
+    }
+    if config.extra_headers_all_responses {
+        set_extra_headers(response.headers_mut(), config);
+    }
+    response.extensions_mut().insert(AlreadyProcessed);
+    response
+}
+
+/// The response to a markdown request refused by
+/// [`MarkdownConfig::strict_negotiation`].
+fn not_acceptable() -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = http::StatusCode::NOT_ACCEPTABLE;
+    response
+}
+
+/// Check if the request asks for a dry run (`X-Markdown-Dry-Run: true`).
+fn is_dry_run(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-markdown-dry-run")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Annotate an unconverted response with what conversion would have done.
+fn report_dry_run(
+    mut response: Response<Body>,
+    decision: &'static str,
+    config: &MarkdownConfig,
+) -> Response<Body> {
+    let would_convert = decision != "not-requested";
+    let (would_convert, decision) = if would_convert && is_passed_redirect(&response, config) {
+        (false, "redirect")
+    } else if would_convert && !is_convertible(&response, config) {
+        (false, "not-html")
+    } else {
+        (would_convert, decision)
+    };
+    let headers = response.headers_mut();
+    headers.insert(
+        "x-markdown-would-convert",
+        HeaderValue::from_static(if would_convert { "true" } else { "false" }),
+    );
+    headers.insert("x-markdown-decision", HeaderValue::from_static(decision));
+    response
+}
+
+/// Check if markdown is the best match for the Accept header. The HTML is
+/// offered first, so a tie (as with `*/*`) leaves the response unconverted.
+fn wants_markdown(headers: &HeaderMap) -> bool {
+    negotiate(headers, &[MediaType::HTML, MediaType::MARKDOWN]) == Some(MediaType::MARKDOWN)
+}
+
+/// Check if the Accept header ranks HTML above markdown. Markdown is
+/// offered first, so a tie goes to it.
+fn prefers_html(headers: &HeaderMap) -> bool {
+    negotiate(headers, &[MediaType::MARKDOWN, MediaType::HTML]) == Some(MediaType::HTML)
+}
+
+/// Take [`MarkdownConfig::markdown_suffix`] off the request's path,
+/// returning whether it had it. `index` before the suffix is dropped too, so
+/// `/docs/index.md` becomes `/docs/`.
+fn strip_markdown_suffix<B>(req: &mut Request<B>, suffix: &str) -> bool {
+    let Some(stem) = req
+        .uri()
+        .path()
+        .strip_suffix(suffix)
+        .filter(|stem| !stem.is_empty() && !stem.ends_with('/'))
+    else {
+        return false;
+    };
+    let stem = stem
+        .strip_suffix("index")
+        .filter(|stem| stem.ends_with('/'))
+        .unwrap_or(stem);
+    let path_and_query = req
+        .uri()
+        .query()
+        .map_or_else(|| stem.to_string(), |query| format!("{stem}?{query}"));
+    let mut parts = req.uri().clone().into_parts();
+    let Ok(path_and_query) = path_and_query.parse() else {
+        return false;
+    };
+    parts.path_and_query = Some(path_and_query);
+    let Ok(uri) = Uri::from_parts(parts) else {
+        return false;
+    };
+    *req.uri_mut() = uri;
+    true
+}
+
+/// Check if the Prefer header contains `return=markdown` (RFC 7240).
+fn prefers_markdown(headers: &HeaderMap) -> bool {
+    headers.get_all("prefer").iter().any(|val| {
+        val.to_str().is_ok_and(|s| {
+            s.split(',').any(|pref| {
+                let pref = pref.split(';').next().unwrap_or("");
+                pref.split_once('=').is_some_and(|(name, value)| {
+                    name.trim().eq_ignore_ascii_case("return")
+                        && value
+                            .trim()
+                            .trim_matches('"')
+                            .eq_ignore_ascii_case("markdown")
+                })
+            })
+        })
+    })
+}
+
+/// Check if a redirect response is to pass through unconverted, per
+/// [`MarkdownConfig::pass_through_redirects`].
+fn is_passed_redirect(response: &Response<Body>, config: &MarkdownConfig) -> bool {
+    config.pass_through_redirects
+        && matches!(response.status().as_u16(), 301 | 302 | 303 | 307 | 308)
+}
+
+/// Check if a status never has content: `1xx`, `204`, `205`, and `304`.
+fn is_bodiless(status: http::StatusCode) -> bool {
+    status.is_informational()
+        || matches!(
+            status,
+            http::StatusCode::NO_CONTENT
+                | http::StatusCode::RESET_CONTENT
+                | http::StatusCode::NOT_MODIFIED
+        )
+}
+
+/// Check if a response has one of [`MarkdownConfig::convertible_types`]
+/// (and isn't a redirect passed through, or a bodiless status like a `304`
+/// whose `Content-Type` describes a body it doesn't have).
+fn is_convertible(response: &Response<Body>, config: &MarkdownConfig) -> bool {
+    !is_passed_redirect(response, config)
+        && !is_bodiless(response.status())
+        && response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|ct| {
+                let ct = ct.to_ascii_lowercase();
+                config
+                    .convertible_types
+                    .iter()
+                    .any(|t| ct.contains(&t.to_ascii_lowercase()))
+            })
+}
+
+/// With [`MarkdownConfig::alternate_link`], append a `Link` to the markdown
+/// representation of a passed-through response that would be converted.
+fn link_markdown(
+    mut response: Response<Body>,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+) -> Response<Body> {
+    if let Some(location) = &config.alternate_link {
+        if !config.shadow_mode && is_convertible(&response, config) {
+            let link = alternate_link(&location.resolve(request.path_and_query()), "text/markdown");
+            if let Ok(hv) = HeaderValue::from_str(&link) {
+                response.headers_mut().append(LINK, hv);
+            }
+        }
+    }
+    response
+}
+
+/// A `Link` header value for the alternate representation at `uri`.
+fn alternate_link(uri: &str, media_type: &str) -> String {
+    format!("<{uri}>; rel=\"alternate\"; type=\"{media_type}\"")
+}
+
+/// Append `Accept` to the `Vary` header of a response.
+fn append_vary(response: Response<Body>) -> Response<Body> {
+    append_vary_token(response, "Accept")
+}
+
+/// Append a header name to the `Vary` header of a response, unless already listed.
+///
+/// The `Vary` field lines are consolidated into one, listing each name once,
+/// in the order first seen, in canonical case (`Accept-Encoding`). A `*`
+/// stands alone, as it already varies on everything.
+fn append_vary_token(mut response: Response<Body>, token: &'static str) -> Response<Body> {
+    let headers = response.headers_mut();
+
+    let mut names: Vec<String> = Vec::new();
+    let listed = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','));
+    for name in listed.chain([token]) {
+        let name = name.trim_matches([' ', '\t']);
+        if !name.is_empty() && !names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+            names.push(canonical_case(name));
+        }
+    }
+    if names.iter().any(|name| name == "*") {
+        names = vec!["*".to_string()];
+    }
+
+    if let Ok(hv) = HeaderValue::from_str(&names.join(", ")) {
+        // insert replaces all existing Vary headers with the consolidated one
+        headers.insert(VARY, hv);
+    }
+
+    response
+}
+
+/// A header name with each `-`-separated word capitalized.
+fn canonical_case(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut start = true;
+    for c in name.chars() {
+        out.push(if start {
+            c.to_ascii_uppercase()
+        } else {
+            c.to_ascii_lowercase()
+        });
+        start = c == '-';
+    }
+    out
+}
+
+/// Build the htmd converter for a response.
+///
+/// With front matter enabled, the `<head>` is rendered as front matter
+/// rather than leaking into the body. With JSON-LD extraction enabled,
+/// `<script>` bodies are dropped so the raw JSON doesn't appear as text.
+fn html_converter(
+    config: &MarkdownConfig,
+    metadata: Option<&PageMetadata>,
+) -> htmd::HtmlToMarkdown {
+    let mut builder = htmd::HtmlToMarkdown::builder();
+
+    let referenced = config
+        .inline_link_threshold
+        .zip(metadata)
+        .is_some_and(|(threshold, metadata)| metadata.links.len() > threshold);
+    if config.code_fence == CodeFence::Tilde || referenced {
+        let mut options = htmd::options::Options::default();
+        if config.code_fence == CodeFence::Tilde {
+            options.code_block_fence = htmd::options::CodeBlockFence::Tildes;
+        }
+        if referenced {
+            options.link_style = htmd::options::LinkStyle::Referenced;
+        }
+        builder = builder.options(options);
+    }
+
+    if config.json_ld_types.is_some() {
+        builder = builder.skip_tags(vec!["script"]);
+    }
+
+    match config.svg_handling {
+        SvgHandling::Drop => builder = builder.skip_tags(vec!["svg"]),
+        SvgHandling::TitleAsText => {
+            builder = builder.add_handler(vec!["svg"], |element: htmd::Element| {
+                metadata::svg_text(element.node)
+            });
+        }
+        SvgHandling::Keep => {}
+    }
+
+    if let Some(style) = config.definition_lists {
+        builder = builder
+            .add_handler(vec!["dt"], |element: htmd::Element| {
+                Some(definition_list::term(element.node, element.content))
+            })
+            .add_handler(vec!["dd"], |element: htmd::Element| {
+                Some(definition_list::definition(element.node, element.content))
+            })
+            .add_handler(vec!["dl"], move |element: htmd::Element| {
+                Some(definition_list::list(element.content, style))
+            });
+    }
+
+    match config.details_handling {
+        Some(DetailsHandling::KeepHtml) => {
+            builder = builder.add_handler(vec!["details"], |element: htmd::Element| {
+                details::html(element.node)
+            });
+        }
+        Some(handling) => {
+            builder = builder
+                .add_handler(vec!["summary"], |element: htmd::Element| {
+                    Some(details::summary(element.node, element.content))
+                })
+                .add_handler(vec!["details"], move |element: htmd::Element| {
+                    Some(details::details(element.content, handling))
+                });
+        }
+        None => {}
+    }
+
+    builder = builder
+        .add_handler(vec!["figcaption"], |element: htmd::Element| {
+            Some(figure::caption(element.node, element.content))
+        })
+        .add_handler(vec!["figure"], |element: htmd::Element| {
+            Some(figure::figure(element.content))
+        });
+
+    if let Some(dialect) = config.dialect {
+        builder = dialect_handlers(builder, dialect);
+    }
+
+    if let Some(style) = config.thematic_break {
+        builder = builder.add_handler(vec!["hr"], move |_: htmd::Element| {
+            Some(style.marker().to_string())
+        });
+    }
+
+    if let Some(notation) = config.math {
+        let fence = config.math_fence();
+        builder = builder.add_handler(vec!["math"], move |element: htmd::Element| {
+            Some(math::element(element.node, notation, fence))
+        });
+    }
+
+    if let Some(metadata) = metadata.filter(|_| config.front_matter) {
+        builder = builder.skip_tags(vec!["head"]);
+
+        if config.strip_duplicate_title && metadata.h1_duplicates_title() {
+            let pending = AtomicBool::new(true);
+            builder = builder.add_handler(vec!["h1"], move |element: htmd::Element| {
+                if pending.swap(false, Ordering::Relaxed) {
+                    return None;
+                }
+                Some(format!("\n\n# {}\n\n", element.content.trim_ascii()))
+            });
+        }
+    }
+
+    builder.build()
+}
+
+/// Add the handlers of tables, strikethrough, and checkboxes for
+/// [`MarkdownConfig::dialect`].
+fn dialect_handlers(
+    builder: htmd::HtmlToMarkdownBuilder,
+    dialect: MarkdownDialect,
+) -> htmd::HtmlToMarkdownBuilder {
+    let builder = match dialect {
+        MarkdownDialect::Gfm => builder
+            .add_handler(vec!["td", "th"], |element: htmd::Element| {
+                Some(dialect::cell(element.content))
+            })
+            .add_handler(vec!["tr"], |element: htmd::Element| {
+                Some(dialect::row(element.content))
+            })
+            .add_handler(vec!["table"], |element: htmd::Element| {
+                Some(dialect::pipe_table(element.node, element.content))
+            }),
+        MarkdownDialect::CommonMark => builder
+            .add_handler(vec!["table"], |element: htmd::Element| {
+                dialect::html_block(element.node)
+            }),
+    };
+    builder
+        .add_handler(vec!["del", "s", "strike"], move |element: htmd::Element| {
+            Some(dialect::strikethrough(element.content, dialect))
+        })
+        .add_handler(vec!["input"], move |element: htmd::Element| {
+            Some(dialect::checkbox(element.node, dialect))
+        })
+}
+
+/// Send a [`ConversionError`] to the configured channel, if any, without blocking.
+fn report_error(config: &MarkdownConfig, error: &ConversionError) {
+    if let Some(sender) = &config.error_sender {
+        let _ = sender.try_send(error.clone());
+    }
+}
+
+/// The response to HTML that failed to convert for `kind`, per
+/// [`MarkdownConfig::on_error`]. It is never the HTML with a markdown
+/// `Content-Type`, which would be a lie and a potential XSS vector in
+/// markdown renderers.
+fn conversion_failed(
+    mut parts: http::response::Parts,
+    html: &Bytes,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+    kind: ConversionErrorKind,
+) -> Response<Body> {
+    match config.on_error {
+        ErrorPolicy::BadGateway => bad_gateway(config, request, kind, &parts),
+        ErrorPolicy::PassthroughHtml => {
+            report_error(config, &ConversionError::new(config, request, kind, &parts));
+            parts.headers.remove("x-original-content-length");
+            parts
+                .headers
+                .insert(CONTENT_LENGTH, HeaderValue::from(html.len()));
+            append_vary(Response::from_parts(parts, Body::from(html.clone())))
+        }
+    }
+}
+
+/// Report a failed conversion of the response with `parts` and build its
+/// 502 response, in [`MarkdownConfig::error_format`], or the
+/// [`MarkdownConfig::error_responder`]'s response.
+fn bad_gateway(
+    config: &MarkdownConfig,
+    request: &RequestContext,
+    kind: ConversionErrorKind,
+    parts: &http::response::Parts,
+) -> Response<Body> {
+    let error = ConversionError::new(config, request, kind, parts);
+    report_error(config, &error);
+    if let Some(responder) = &config.error_responder {
+        return append_vary(responder(error));
+    }
+    let status = http::StatusCode::BAD_GATEWAY;
+    let (content_type, body) = match config.error_format {
+        ErrorFormat::PlainText => {
+            let message = match kind {
+                // The body is consumed either way, so these share a message
+                ConversionErrorKind::BodyTooLarge { .. } | ConversionErrorKind::BodyUnreadable => {
+                    "response body too large or unreadable"
+                }
+                kind => kind.reason(),
+            };
+            (
+                "text/plain; charset=utf-8",
+                format!("Markdown conversion failed: {message}"),
+            )
+        }
+        ErrorFormat::ProblemJson => {
+            let (kind_type, title) = kind.problem();
+            let problem = serde_json::json!({
+                "type": kind_type,
+                "title": title,
+                "status": status.as_u16(),
+                "detail": error.to_string(),
+            });
+            ("application/problem+json", problem.to_string())
+        }
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    append_vary(response)
+}
+
+/// Add the breadcrumb line, front matter, JSON-LD code block, and source
+/// footer (when enabled) around the body.
+fn add_metadata_blocks(
+    markdown: &mut String,
+    metadata: &PageMetadata,
+    html: &str,
+    request: &RequestContext,
+    config: &MarkdownConfig,
+) {
+    let url = request.url(config);
+    if config.breadcrumbs == Some(BreadcrumbPlacement::Line) {
+        if let Some(trail) = metadata.breadcrumbs() {
+            let mut line = trail.join(" › ");
+            if !markdown.is_empty() {
+                line.push_str("\n\n");
+            }
+            markdown.insert_str(0, &line);
+        }
+    }
+
+    if config.front_matter {
+        let context = FrontMatterContext::new(metadata, &request.uri, &url, html);
+        let front_matter = FrontMatter::from_metadata(&context, config);
+        if !front_matter.is_empty() || config.empty_front_matter {
+            let mut block = front_matter.render();
+            if !markdown.is_empty() {
+                block.push('\n');
+            }
+            markdown.insert_str(0, &block);
+        }
+    }
+
+    if let Some(types) = config
+        .json_ld_types
+        .as_ref()
+        .filter(|_| config.json_ld_placement == JsonLdPlacement::CodeBlock)
+    {
+        let items = json_ld::select(&metadata.json_ld, types);
+        if let (false, Ok(json)) = (items.is_empty(), serde_json::to_string_pretty(&items)) {
+            if !markdown.is_empty() {
+                markdown.push_str("\n\n");
+            }
+            markdown.push_str("```json\n");
+            markdown.push_str(&json);
+            markdown.push_str("\n```");
+        }
+    }
+
+    if let Some(template) = config
+        .source_attribution
+        .as_deref()
+        .filter(|_| config.source_placement == SourcePlacement::Footer)
+    {
+        let footer = template::render(
+            template,
+            &[
+                ("url", &url),
+                ("retrieved_at", &config.retrieved_at_now()),
+                ("title", metadata.title.as_deref().unwrap_or_default()),
+            ],
+        );
+        if !markdown.is_empty() {
+            markdown.push_str("\n\n");
+        }
+        markdown.push_str("---\n");
+        markdown.push_str(&footer);
+    }
+}
+
+/// The `Warning` of [`MarkdownConfig::transformation_warning`]. The agent
+/// is a pseudonym, a token, so it can't carry a `/version`.
+const TRANSFORMATION_APPLIED: &str = concat!(
+    "214 ",
+    env!("CARGO_PKG_NAME"),
+    " \"Transformation Applied\""
+);
+
+/// Headers describing the markdown body, which
+/// [`MarkdownConfig::extra_headers`] can't set.
+const BODY_HEADERS: [HeaderName; 5] = [
+    CONTENT_TYPE,
+    CONTENT_LENGTH,
+    CONTENT_ENCODING,
+    CONTENT_RANGE,
+    TRANSFER_ENCODING,
+];
+
+/// Set the headers of a converted response (apart from `Vary`).
+fn set_markdown_headers(
+    headers: &mut HeaderMap,
+    tokens: &TokenCounts,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+    metadata: Option<&PageMetadata>,
+) {
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/markdown; charset=utf-8"),
+    );
+    headers.remove(CONTENT_LENGTH);
+    for name in &config.strip_headers {
+        headers.remove(name);
+    }
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if config.transformation_warning {
+        headers.append(WARNING, HeaderValue::from_static(TRANSFORMATION_APPLIED));
+    }
+
+    if let Some(token_count) = tokens.markdown {
+        if let Ok(hv) = HeaderValue::from_str(&tokens_value(token_count, config, request)) {
+            headers.insert(config.token_header_name.clone(), hv);
+        }
+        if config.token_estimation == Estimation::Approximate {
+            headers.insert(
+                "x-markdown-tokens-estimated",
+                HeaderValue::from_static("true"),
+            );
+        }
+        let model = config.token_model(request.tokenizer);
+        if let Some(model) = model.filter(|_| !config.token_models.is_empty()) {
+            headers.insert(
+                "x-markdown-tokens-model",
+                HeaderValue::from_static(model.name()),
+            );
+        }
+    }
+
+    let by_model = tokens
+        .by_model
+        .iter()
+        .map(|(name, count)| format!("{name}={count}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Some(Ok(hv)) = (!by_model.is_empty()).then(|| HeaderValue::from_str(&by_model)) {
+        headers.insert("x-markdown-tokens-by-model", hv);
+    }
+
+    if tokens.markdown.is_none()
+        && config.count_tokens
+        && config.counter_unavailable(request.tokenizer)
+    {
+        headers.insert(
+            "x-markdown-tokens-unavailable",
+            HeaderValue::from_static("true"),
+        );
+    }
+
+    if let Some(untruncated) = tokens.untruncated {
+        headers.insert("x-markdown-truncated", HeaderValue::from_static("true"));
+        headers.insert("x-markdown-original-tokens", HeaderValue::from(untruncated));
+    }
+    if let Some(html_tokens) = tokens.html {
+        headers.insert("x-html-tokens", HeaderValue::from(html_tokens));
+    }
+
+    if config.propagate_language {
+        set_content_language(headers, metadata);
+    }
+
+    if request.prefer_markdown {
+        headers.insert(
+            "preference-applied",
+            HeaderValue::from_static("return=markdown"),
+        );
+    }
+
+    if let Some(location) = &config.content_location {
+        if let Ok(hv) = HeaderValue::from_str(&location.resolve(request.path_and_query())) {
+            headers.insert(CONTENT_LOCATION, hv);
+        }
+    }
+
+    if config.alternate_link.is_some() {
+        let html = ContentLocation::RequestUri.resolve(request.path_and_query());
+        if let Ok(hv) = HeaderValue::from_str(&alternate_link(&html, "text/html")) {
+            headers.append(LINK, hv);
+        }
+    }
+
+    set_canonical(headers, config, metadata);
+    set_content_signal(headers, config);
+    if !config.robots_tag_all_responses {
+        append_robots_tag(headers, config);
+    }
+    if !config.extra_headers_all_responses {
+        set_extra_headers(headers, config);
+    }
+}
+
+/// Point to the page's canonical URL as [`MarkdownConfig::canonical_header`]
+/// says.
+fn set_canonical(
+    headers: &mut HeaderMap,
+    config: &MarkdownConfig,
+    metadata: Option<&PageMetadata>,
+) {
+    if let (Some(header), Some(canonical)) = (
+        config.canonical_header,
+        metadata.and_then(|m| m.canonical.as_deref()),
+    ) {
+        if let Ok(hv) = HeaderValue::from_str(canonical) {
+            match header {
+                CanonicalHeader::Link => {
+                    if let Ok(link) =
+                        HeaderValue::from_str(&format!("<{canonical}>; rel=\"canonical\""))
+                    {
+                        headers.append(LINK, link);
+                    }
+                }
+                CanonicalHeader::ContentLocation => {
+                    headers.insert(CONTENT_LOCATION, hv);
+                }
+            }
+        }
+    }
+}
+
+/// Set [`MarkdownConfig::extra_headers`], replacing the headers of their
+/// names.
+fn set_extra_headers(headers: &mut HeaderMap, config: &MarkdownConfig) {
+    for name in config.extra_headers.keys() {
+        headers.remove(name);
+    }
+    for (name, value) in &config.extra_headers {
+        headers.append(name, value.clone());
+    }
+}
+
+/// Set `Content-Language` to the page's `<html lang>`, unless the inner
+/// service set it.
+fn set_content_language(headers: &mut HeaderMap, metadata: Option<&PageMetadata>) {
+    if headers.contains_key(CONTENT_LANGUAGE) {
+        return;
+    }
+    let lang = metadata
+        .and_then(|m| m.lang.as_deref())
+        .filter(|lang| lang.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-'));
+    if let Some(Ok(hv)) = lang.map(HeaderValue::from_str) {
+        headers.insert(CONTENT_LANGUAGE, hv);
+    }
+}
+
+/// Add the configured `X-Robots-Tag` to `headers`, after any the inner
+/// service set.
+fn append_robots_tag(headers: &mut HeaderMap, config: &MarkdownConfig) {
+    if let Some(value) = &config.x_robots_tag {
+        headers.append("x-robots-tag", value.clone());
+    }
+}
+
+/// Set the configured Content-Signal in `headers`, unless the inner service
+/// set one that takes precedence.
+fn set_content_signal(headers: &mut HeaderMap, config: &MarkdownConfig) {
+    let name = &config.content_signal_header_name;
+    if config.content_signal_precedence == SignalPrecedence::Handler && headers.contains_key(name) {
+        return;
+    }
+    if let Some(signal) = config.content_signal.as_ref().filter(|s| !s.is_empty()) {
+        if let Ok(hv) = HeaderValue::from_str(signal) {
+            headers.insert(name.clone(), hv);
+        }
+    }
+}
+
+/// Apply the configured markdown post-passes to freshly converted markdown.
+fn postprocess(markdown: &mut String, config: &MarkdownConfig) {
+    *markdown = list_indent::normalize(markdown, config.list_indent);
+
+    if config.normalize_blockquotes {
+        *markdown = blockquote::normalize(markdown);
+    }
+
+    if let Some(base) = &config.external_base_path {
+        *markdown = base_path::prefix(markdown, base);
+    }
+
+    if config.decode_entities || config.nbsp_to_space {
+        *markdown = entities::normalize(markdown, config.decode_entities, config.nbsp_to_space);
+    }
+
+    if let Some(notation) = config.math {
+        *markdown = math::delimiters(markdown, notation, config.math_fence());
+    }
+
+    if config.code_fence == CodeFence::Auto {
+        *markdown = fence::tildes_for_backtick_content(markdown);
+    }
+
+    if config.trim_output {
+        *markdown = markdown
+            .trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}')
+            .to_string();
+    }
+
+    if let Some(options) = &config.table_of_contents {
+        toc::insert(markdown, options);
+    }
+
+    if config.heading_anchors {
+        *markdown = toc::anchor(markdown);
+    }
+}
+
+/// The `x-markdown-tokens` value for `count` tokens, naming the tokenizer
+/// with [`MarkdownConfig::token_header_model`].
+fn tokens_value(count: usize, config: &MarkdownConfig, request: &RequestContext) -> String {
+    config
+        .token_model(request.tokenizer)
+        .filter(|_| config.token_header_model)
+        .map_or_else(
+            || count.to_string(),
+            |model| format!("{count}; model={}", model.name()),
+        )
+}
+
+/// With [`MarkdownConfig::tokens_as_trailer`], move `x-markdown-tokens`
+/// from the headers of a converted response into a trailer, if the client
+/// reads trailers.
+fn tokens_trailer(
+    response: Response<Body>,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+) -> Response<Body> {
+    if !config.tokens_as_trailer || !request.accepts_trailers {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let name = &config.token_header_name;
+    let Some(tokens) = parts.headers.remove(name) else {
+        return Response::from_parts(parts, body);
+    };
+    parts
+        .headers
+        .insert(TRAILER, HeaderValue::from(name.clone()));
+    let mut trailers = HeaderMap::new();
+    trailers.insert(name.clone(), tokens);
+    let body = body.with_trailers(std::future::ready(Some(Ok(trailers))));
+    Response::from_parts(parts, Body::new(Unsized(body)))
+}
+
+/// A body that doesn't declare its size, so that hyper sends it chunked
+/// over HTTP/1.1: the only framing that carries trailers.
+struct Unsized<B>(B);
+
+impl<B: http_body::Body + Unpin> http_body::Body for Unsized<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.get_mut().0).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_end_stream()
+    }
+}
+
+/// Token counts reported on a converted response.
+#[derive(Debug, Default)]
+struct TokenCounts {
+    /// For `x-markdown-tokens`.
+    markdown: Option<usize>,
+    /// The markdown's count before truncation, if it was truncated.
+    untruncated: Option<usize>,
+    /// For `x-html-tokens`.
+    html: Option<usize>,
+    /// For `x-markdown-tokens-by-model`.
+    by_model: Vec<(String, usize)>,
+}
+
+/// Count the markdown's tokens and apply [`MarkdownConfig::max_tokens`],
+/// returning the markdown to emit.
+async fn count_tokens(
+    markdown: Bytes,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+) -> (Bytes, TokenCounts) {
+    let counts = |markdown: Option<usize>, untruncated| TokenCounts {
+        markdown: markdown.filter(|_| config.count_tokens),
+        untruncated,
+        ..TokenCounts::default()
+    };
+    let max_tokens = request.max_tokens.or(config.max_tokens);
+    if !config.count_tokens && max_tokens.is_none() {
+        return (markdown, TokenCounts::default());
+    }
+    let total = config
+        .with_counter(markdown.clone(), request.tokenizer, |counter, text| {
+            counter.count(text)
+        })
+        .await;
+    let Some(total) = total else {
+        return (markdown, TokenCounts::default());
+    };
+    let Some(max_tokens) = max_tokens.filter(|max| total > *max) else {
+        return (markdown, counts(Some(total), None));
+    };
+
+    let notice = config.line_ending.apply(&config.truncation_notice);
+    let backoff = config.truncation_backoff;
+    let truncated = config
+        .with_counter(markdown.clone(), request.tokenizer, move |counter, text| {
+            budget::truncate(counter, text, max_tokens, &notice, backoff)
+        })
+        .await;
+    match truncated {
+        Some((text, count)) => (Bytes::from(text), counts(Some(count), Some(total))),
+        None => (markdown, counts(Some(total), None)),
+    }
+}
+
+/// Count `markdown` with each of [`MarkdownConfig::additional_tokenizers`]
+/// that is available, in one pass.
+async fn count_additional(markdown: Bytes, config: &MarkdownConfig) -> Vec<(String, usize)> {
+    if !config.count_tokens
+        || config.token_estimation == Estimation::Approximate
+        || config.additional_tokenizers.is_empty()
+    {
+        return Vec::new();
+    }
+    let counters = config.additional_tokenizers.clone();
+    config
+        .offload(markdown.len(), move || {
+            let text = String::from_utf8_lossy(&markdown);
+            counters
+                .iter()
+                .filter(|(_, counter)| counter.is_available())
+                .map(|(name, counter)| (name.clone(), counter.count(&text)))
+                .collect()
+        })
+        .await
+        .unwrap_or_default()
+}
+
+/// Set `x-original-content-length` to the `measured` length of the HTML,
+/// warning if the inner service declared a different `Content-Length`.
+fn set_original_length(headers: &mut HeaderMap, measured: usize) {
+    let declared = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.trim().parse::<usize>().ok());
+    if let Some(declared) = declared.filter(|&declared| declared != measured) {
+        tracing::warn!(
+            declared,
+            measured,
+            "HTML body length differs from its Content-Length, reporting the measured length"
+        );
+    }
+    headers.insert("x-original-content-length", HeaderValue::from(measured));
+}
+
+/// Set `x-markdown-duration-ms` to the time since `started`.
+fn set_duration(headers: &mut HeaderMap, started: Instant) {
+    let millis = format!("{:.3}", started.elapsed().as_secs_f64() * 1000.0);
+    if let Ok(hv) = HeaderValue::from_str(&millis) {
+        headers.insert("x-markdown-duration-ms", hv);
+    }
+}
+
+/// Set `x-markdown-ratio` to the markdown's size over the HTML's.
+fn set_compression_ratio(headers: &mut HeaderMap, markdown: usize, original: usize) {
+    let ratio = if original == 0 {
+        "inf".to_string()
+    } else {
+        #[allow(clippy::cast_precision_loss)] // Three decimals are reported
+        let ratio = markdown as f64 / original as f64;
+        format!("{ratio:.3}")
+    };
+    if let Ok(hv) = HeaderValue::from_str(&ratio) {
+        headers.insert("x-markdown-ratio", hv);
+    }
+}
+
+/// The response to a body that couldn't be read within `limit` bytes: its
+/// HTML passed through, per [`MarkdownConfig::oversize_behavior`], or a
+/// `502`.
+fn unread(
+    unread: Unread,
+    parts: http::response::Parts,
+    limit: usize,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+) -> Response<Body> {
+    match unread {
+        Unread::TooLarge(body) if config.oversize_behavior == OversizeBehavior::Passthrough => {
+            let kind = ConversionErrorKind::BodyTooLarge { limit };
+            report_error(config, &ConversionError::new(config, request, kind, &parts));
+            append_vary(Response::from_parts(parts, body))
+        }
+        Unread::TooLarge(_) => {
+            let kind = ConversionErrorKind::BodyTooLarge { limit };
+            bad_gateway(config, request, kind, &parts)
+        }
+        Unread::Unreadable => {
+            bad_gateway(config, request, ConversionErrorKind::BodyUnreadable, &parts)
+        }
+    }
+}
+
+/// `markdown` followed by a comment carrying `html` in base64, unless the
+/// encoding would be longer than `limit`.
+fn embed_source_html(markdown: &Bytes, html: &[u8], limit: usize) -> Bytes {
+    let encoded = BASE64_STANDARD.encode(html);
+    if encoded.len() > limit {
+        return markdown.clone();
+    }
+    let mut out = markdown.to_vec();
+    if !out.is_empty() {
+        out.extend_from_slice(if out.ends_with(b"\n") { b"\n" } else { b"\n\n" });
+    }
+    out.extend_from_slice(b"<!-- source-html-base64: ");
+    out.extend_from_slice(encoded.as_bytes());
+    out.extend_from_slice(b" -->");
+    Bytes::from(out)
+}
+
+/// Add [`MarkdownConfig::section_tokens`] counts of `markdown` to a
+/// converted response.
+async fn add_section_tokens(
+    parts: &mut http::response::Parts,
+    markdown: Bytes,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+) {
+    let Some(sections) = sections::count(markdown, config, request).await else {
+        return;
+    };
+    let header = config
+        .section_tokens_header
+        .and_then(|max_len| sections::header(&sections, max_len))
+        .and_then(|value| HeaderValue::from_str(&value).ok());
+    if let Some(hv) = header {
+        parts.headers.insert("x-markdown-section-tokens", hv);
+    }
+    parts.extensions.insert(sections);
+}
+
+/// The body size limit for a response: its [`MaxBodySizeOverride`], the
+/// [`MarkdownConfig::max_body_sizes`] entry for its `Content-Type`, or
+/// [`MarkdownConfig::max_body_size`].
+fn body_limit(parts: &http::response::Parts, config: &MarkdownConfig) -> usize {
+    if let Some(limit) = parts.extensions.get::<MaxBodySizeOverride>() {
+        return limit.0;
+    }
+    let content_type = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    config
+        .max_body_sizes
+        .iter()
+        .find(|(t, _)| content_type.contains(&t.to_ascii_lowercase()))
+        .map_or(config.max_body_size, |&(_, limit)| limit)
+}
+
+/// Where a conversion goes in the [`MarkdownConfig::cache`].
+struct CacheSlot<'a> {
+    cache: &'a dyn ConversionCache,
+    ttl: Option<Duration>,
+    key: String,
+    /// The upstream `ETag`, or a hash of the HTML once read.
+    validator: Option<String>,
+    /// The upstream headers, to record what conversion changes.
+    upstream: HeaderMap,
+}
+
+impl<'a> CacheSlot<'a> {
+    /// The slot for a `200 OK` upstream response, if the cache is enabled
+    /// and the response may be shared, as in [`cache_url`].
+    fn new(
+        caches: &'a Caches,
+        parts: &http::response::Parts,
+        request: &RequestContext,
+    ) -> Option<Self> {
+        let cache = caches.conversions.as_deref()?;
+        if parts.status != http::StatusCode::OK || !request.shareable || is_private(&parts.headers)
+        {
+            return None;
+        }
+        let key = request.cache_key();
+        let validator = parts
+            .headers
+            .get(http::header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from);
+        Some(Self {
+            cache,
+            ttl: caches.ttl,
+            key,
+            validator,
+            upstream: parts.headers.clone(),
+        })
+    }
+
+    /// Apply the stored conversion, if current, to `parts`, returning its
+    /// markdown.
+    ///
+    /// Before the HTML is read, without an `ETag`, only an expiring cache
+    /// can answer: it trusts any fresh entry.
+    async fn hit(&self, parts: &mut http::response::Parts) -> Option<Bytes> {
+        if self.validator.is_none() && self.ttl.is_none() {
+            return None;
+        }
+        let conversion = self.cache.get(&self.key).await?;
+        if !conversion.is_current(self.validator.as_deref(), self.ttl) {
+            return None;
+        }
+        let markdown = conversion.apply(parts);
+        parts
+            .headers
+            .insert("x-markdown-cache", HeaderValue::from_static("HIT"));
+        Some(markdown)
+    }
+
+    /// Identify the version by a hash of `html` if the `ETag` didn't, and
+    /// look it up if [`hit`](Self::hit) couldn't before.
+    async fn hit_read(&mut self, parts: &mut http::response::Parts, html: &[u8]) -> Option<Bytes> {
+        if self.validator.is_some() {
+            return None;
+        }
+        let hash = etag::strong(html);
+        self.validator = Some(hash.to_str().unwrap_or_default().to_string());
+        if self.ttl.is_some() {
+            return None;
+        }
+        self.hit(parts).await
+    }
+
+    /// Store the conversion into `parts` and `markdown`.
+    async fn store(self, parts: &mut http::response::Parts, markdown: &Bytes) {
+        let mut extensions = http::Extensions::new();
+        if let Some(sections) = parts.extensions.get::<Vec<SectionTokens>>() {
+            extensions.insert(sections.clone());
+        }
+        let conversion = CachedConversion::new(
+            self.validator.unwrap_or_default(),
+            HeaderChanges::between(&self.upstream, &parts.headers),
+            extensions,
+            markdown.clone(),
+        );
+        self.cache.insert(self.key, conversion).await;
+        parts
+            .headers
+            .insert("x-markdown-cache", HeaderValue::from_static("MISS"));
+    }
+}
+
+/// Serve a converted response: the requested page, or a `304`, or range.
+async fn deliver(
+    response: Response<Bytes>,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+) -> Response<Body> {
+    let response = page::apply(response, config, request).await;
+    respond(response, config, request)
+}
+
+/// Answer a converted response's conditional headers and `Range`, then give it
+/// its digests and token count trailer.
+fn respond(
+    response: Response<Bytes>,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+) -> Response<Body> {
+    let response = etag::not_modified(response, &request.if_none_match);
+    let response = etag::not_modified_since(response, request.if_modified_since.as_ref());
+    let response = range::apply(response, request.range.as_ref());
+    let response = digest::apply(response, config.content_digest, config.digest);
+    append_vary(tokens_trailer(response.map(Body::from), config, request))
+}
+
+/// Serve an HTML response as is for [`MarkdownConfig::shadow_mode`], with
+/// the `x-markdown-tokens` of its conversion.
+async fn shadow(
+    response: Response<Body>,
+    config: &Arc<MarkdownConfig>,
+    request: &RequestContext,
+) -> Response<Body> {
+    let (parts, body) = response.into_parts();
+    let limit = body_limit(&parts, config);
+    let body = match read_body(body, limit).await {
+        Ok(body) => body,
+        Err(error) => return unread(error, parts, limit, config, request),
+    };
+
+    let mut converting = Response::new(Body::from(body.clone()));
+    *converting.status_mut() = parts.status;
+    *converting.headers_mut() = parts.headers.clone();
+    *converting.extensions_mut() = parts.extensions.clone();
+    let request = RequestContext {
+        page: None,
+        ..request.clone()
+    };
+    let Ok::<_, std::convert::Infallible>(converted) =
+        convert_response(converting, config, &request, &Caches::default()).await;
+
+    let mut response = Response::from_parts(parts, Body::from(body));
+    let name = &config.token_header_name;
+    if let Some(tokens) = converted.headers().get(name) {
+        response.headers_mut().insert(name.clone(), tokens.clone());
+    }
+    append_vary(response)
+}
+
+/// Finish converted `markdown`: the post-passes, inlined links, metadata
+/// blocks, canonical comment, and finally [`MarkdownConfig::post_process`].
+async fn complete_markdown(
+    mut markdown: String,
+    metadata: Option<&PageMetadata>,
+    html: &str,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+) -> String {
+    postprocess(&mut markdown, config);
+
+    if let Some((resolver, metadata)) = config.link_resolver.as_deref().zip(metadata) {
+        links::inline(
+            &mut markdown,
+            resolver,
+            metadata,
+            &request.url(config),
+            config,
+        )
+        .await;
+    }
+
+    if let Some(metadata) = metadata {
+        add_metadata_blocks(&mut markdown, metadata, html, request, config);
+    }
+
+    if config.canonical_comment {
+        // Percent-encoding leaves no `>` that could close the comment early
+        let mut comment = format!("<!-- canonical: {} -->", url::encode(&request.url(config)));
+        if !markdown.is_empty() {
+            comment.push('\n');
+        }
+        markdown.insert_str(0, &comment);
+    }
+
+    if let Some(post_process) = &config.post_process {
+        markdown = post_process(markdown);
+    }
+    if config.line_ending == LineEnding::CrLf {
+        markdown = config.line_ending.apply(&markdown);
+    }
+    markdown
+}
+
+/// Convert an HTML response to markdown.
+async fn convert_response<E>(
+    response: Response<Body>,
+    config: &Arc<MarkdownConfig>,
+    request: &RequestContext,
+    caches: &Caches,
+) -> Result<Response<Body>, E> {
+    let (mut parts, body) = response.into_parts();
+    let max_body_size = body_limit(&parts, config);
+
+    let mut slot = CacheSlot::new(caches, &parts, request);
+    if let Some(slot) = &slot {
+        if let Some(markdown) = slot.hit(&mut parts).await {
+            return Ok(deliver(Response::from_parts(parts, markdown), config, request).await);
+        }
+    }
+
+    let started = Instant::now();
+    let body_bytes = match read_body(body, max_body_size).await {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            caches.stats.failed();
+            return Ok(unread(error, parts, max_body_size, config, request));
+        }
+    };
+
+    if body_bytes.is_empty() {
+        match config.empty_body_behavior {
+            EmptyBodyBehavior::EmitEmpty => {}
+            EmptyBodyBehavior::Passthrough => {
+                return Ok(append_vary(Response::from_parts(parts, Body::empty())));
+            }
+            EmptyBodyBehavior::Error => {
+                caches.stats.failed();
+                let kind = ConversionErrorKind::EmptyBody;
+                return Ok(bad_gateway(config, request, kind, &parts));
+            }
+        }
+    }
+
+    let original_length = body_bytes.len();
+    if config.original_content_length {
+        set_original_length(&mut parts.headers, original_length);
+    }
+
+    if let Some(slot) = &mut slot {
+        if let Some(markdown) = slot.hit_read(&mut parts, &body_bytes).await {
+            return Ok(deliver(Response::from_parts(parts, markdown), config, request).await);
+        }
+    }
+
+    let read = Instant::now();
+    let conversion = convert_and_count(&body_bytes, &mut parts, config, request);
+    let conversion = match config.conversion_timeout {
+        Some(limit) => tokio::time::timeout(limit, conversion)
+            .await
+            .unwrap_or(Err(ConversionErrorKind::Timeout { limit })),
+        None => conversion.await,
+    };
+    let Conversion {
+        metadata,
+        markdown: mut markdown_bytes,
+        tokens,
+        converted,
+    } = match conversion {
+        Ok(conversion) => conversion,
+        Err(kind) => {
+            caches.stats.failed();
+            return Ok(conversion_failed(parts, &body_bytes, config, request, kind));
+        }
+    };
+
+    if config.embed_source_html {
+        markdown_bytes = embed_source_html(&markdown_bytes, &body_bytes, max_body_size);
+    }
+    etag::apply(&mut parts.headers, &markdown_bytes, config);
+    if config.compression_ratio {
+        set_compression_ratio(&mut parts.headers, markdown_bytes.len(), original_length);
+    }
+    caches.stats.converted(
+        tokens.markdown,
+        Timings {
+            read: read - started,
+            conversion: converted - read,
+            tokenization: converted.elapsed(),
+        },
+    );
+
+    set_markdown_headers(
+        &mut parts.headers,
+        &tokens,
+        config,
+        request,
+        metadata.as_ref(),
+    );
+
+    if let Some(cache) = caches.urls.as_ref() {
+        cache_url(cache, &parts, &markdown_bytes, request);
+    }
+    if let Some(slot) = slot {
+        slot.store(&mut parts, &markdown_bytes).await;
+    }
+    // Set after caching: a cached response wasn't converted again
+    if config.duration_header {
+        set_duration(&mut parts.headers, started);
+    }
+    let response = Response::from_parts(parts, markdown_bytes);
+    Ok(deliver(response, config, request).await)
+}
+
+/// A converted body, before delivery.
+struct Conversion {
+    metadata: Option<PageMetadata>,
+    markdown: Bytes,
+    tokens: TokenCounts,
+    /// When conversion finished and token counting started.
+    converted: Instant,
+}
+
+/// Convert the HTML `body` and count its tokens: the work
+/// [`MarkdownConfig::conversion_timeout`] bounds.
+async fn convert_and_count(
+    body: &Bytes,
+    parts: &mut http::response::Parts,
+    config: &Arc<MarkdownConfig>,
+    request: &RequestContext,
+) -> Result<Conversion, ConversionErrorKind> {
+    let html = String::from_utf8_lossy(body);
+    let (metadata, markdown) = if config.conversion_timeout.is_some() {
+        // On the blocking pool, so that the timeout can expire meanwhile
+        let (html, config, request) = (html.to_string(), Arc::clone(config), request.clone());
+        tokio::task::spawn_blocking(move || html_to_markdown(&html, &config, &request))
+            .await
+            .map_err(|_| ConversionErrorKind::ConversionFailed)?
+    } else {
+        html_to_markdown(&html, config, request)
+    };
+    let markdown = markdown.ok_or(ConversionErrorKind::ConversionFailed)?;
+
+    let markdown = complete_markdown(markdown, metadata.as_ref(), &html, config, request).await;
+    let converted = Instant::now();
+
+    let (markdown, mut tokens) = count_tokens(Bytes::from(markdown), config, request).await;
+    tokens.by_model = count_additional(markdown.clone(), config).await;
+    if config.section_tokens {
+        add_section_tokens(parts, markdown.clone(), config, request).await;
+    }
+    if config.html_tokens {
+        let html = match html {
+            Cow::Borrowed(_) => body.clone(),
+            Cow::Owned(html) => Bytes::from(html),
+        };
+        tokens.html = config
+            .with_counter(html, request.tokenizer, |counter, html| counter.count(html))
+            .await;
+    }
+    Ok(Conversion {
+        metadata,
+        markdown,
+        tokens,
+        converted,
+    })
+}
+
+/// Convert `html`, extracting its metadata when the configuration needs it.
+/// The markdown is `None` if conversion failed.
+fn html_to_markdown(
+    html: &str,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+) -> (Option<PageMetadata>, Option<String>) {
+    let metadata = config.needs_metadata().then(|| {
+        let mut metadata = PageMetadata::extract(html);
+        metadata.resolve_urls(&request.url(config));
+        metadata
+    });
+    let markdown = convert_html(html, config, metadata.as_ref());
+    (metadata, markdown)
+}
+
+/// Convert `html` with htmd, or `None` if it failed.
+fn convert_html(
+    html: &str,
+    config: &MarkdownConfig,
+    metadata: Option<&PageMetadata>,
+) -> Option<String> {
+    #[cfg(test)]
+    if let Some(stub) = &config.stub_converter {
+        return stub(html);
+    }
+    let converter = html_converter(config, metadata);
+    let markdown = if config.normalize_html_whitespace {
+        converter.convert(&whitespace::normalize(html))
+    } else {
+        converter.convert(html)
+    };
+    markdown.ok()
+}
+
+/// Store a `200 OK` converted response in the [`MarkdownConfig::cache_ttl`]
+/// cache, unless it answers a request that isn't shareable or is marked
+/// `private` or `no-store`. Its `Set-Cookie` fields aren't stored.
+fn cache_url(
+    cache: &UrlCache,
+    parts: &http::response::Parts,
+    markdown: &Bytes,
+    request: &RequestContext,
+) {
+    if parts.status != http::StatusCode::OK || !request.shareable || is_private(&parts.headers) {
+        return;
+    }
+    let mut headers = parts.headers.clone();
+    headers.remove(SET_COOKIE);
+    let mut extensions = http::Extensions::new();
+    if let Some(sections) = parts.extensions.get::<Vec<SectionTokens>>() {
+        extensions.insert(sections.clone());
+    }
+    cache.insert(
+        request.cache_key(),
+        parts.status,
+        headers,
+        extensions,
+        markdown.clone(),
+    );
 }
 
-#[cfg(test)]
-#[allow(clippy::unwrap_used)]
-mod tests {
-    use super::*;
-    use axum::{routing::get, Router};
-    use http::StatusCode;
-    use tower::ServiceExt;
+/// Whether `headers` carry `Cache-Control: private` or `no-store`.
+fn is_private(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.split('=').next().unwrap_or_default().trim())
+        .any(|name| name.eq_ignore_ascii_case("private") || name.eq_ignore_ascii_case("no-store"))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use axum::{body::to_bytes, routing::get, Router};
+    use http::{header::ACCEPT, StatusCode};
+    #[cfg(feature = "tokens")]
+    use std::sync::atomic::AtomicUsize;
+    use tower::ServiceExt;
+
+    /// Times each [`TokenizerModel`] has been built, indexed by discriminant.
+    #[cfg(feature = "tokens")]
+    pub static TOKENIZER_LOADS: [AtomicUsize; 5] = [const { AtomicUsize::new(0) }; 5];
+
+    /// A converter standing in for htmd, set with [`stub_converter`].
+    pub type StubConverter = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+    /// `config` converting with `stub` in place of htmd.
+    fn stub_converter(
+        mut config: MarkdownConfig,
+        stub: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> MarkdownConfig {
+        config.stub_converter = Some(Arc::new(stub));
+        config
+    }
+
+    fn html_response() -> &'static str {
+        "<html><body><h1>Hello</h1><p>World</p></body></html>"
+    }
+
+    fn app() -> Router {
+        app_with(MarkdownConfig::new())
+    }
+
+    fn app_with(config: MarkdownConfig) -> Router {
+        Router::new()
+            .route("/", get(|| async { axum::response::Html(html_response()) }))
+            .route(
+                "/json",
+                get(|| async { axum::Json(serde_json::json!({"key": "value"})) }),
+            )
+            .layer(MarkdownLayer::with_config(config))
+    }
+
+    #[test]
+    fn test_wants_markdown_basic() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("text/markdown"));
+        assert!(wants_markdown(&headers));
+    }
+
+    #[test]
+    fn test_wants_markdown_with_params() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("text/markdown; charset=utf-8"),
+        );
+        assert!(wants_markdown(&headers));
+    }
+
+    #[test]
+    fn test_wants_markdown_multiple_types() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("text/markdown, text/html, application/json"),
+        );
+        assert!(wants_markdown(&headers));
+    }
+
+    #[test]
+    fn test_wants_markdown_multiple_types_html_first() {
+        // Before RFC 7231 negotiation, any listed `text/markdown` got
+        // markdown; now the HTML listed first wins the tie
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("text/html, text/markdown, application/json"),
+        );
+        assert!(!wants_markdown(&headers));
+    }
+
+    #[test]
+    fn test_does_not_want_markdown_preferred_html() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("text/html, text/markdown;q=0.9"),
+        );
+        assert!(!wants_markdown(&headers));
+    }
+
+    #[test]
+    fn test_does_not_want_markdown_html() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("text/html"));
+        assert!(!wants_markdown(&headers));
+    }
+
+    #[test]
+    fn test_does_not_want_markdown_wildcard() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("*/*"));
+        assert!(!wants_markdown(&headers));
+    }
+
+    #[test]
+    fn test_does_not_want_markdown_text_wildcard() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("text/*"));
+        assert!(!wants_markdown(&headers));
+    }
+
+    #[tokio::test]
+    async fn test_html_passthrough_without_accept() {
+        let app = app();
+
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let ct = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(ct.contains("text/html"));
+
+        // Should still have Vary: Accept
+        let vary = response.headers().get(VARY).unwrap().to_str().unwrap();
+        assert!(vary.contains("Accept"));
+    }
+
+    #[tokio::test]
+    async fn test_converts_html_to_markdown() {
+        let app = app();
+
+        let req = Request::builder()
+            .uri("/")
+            .header(ACCEPT, "text/markdown")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let ct = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert_eq!(ct, "text/markdown; charset=utf-8");
+
+        // Should have token count header
+        #[cfg(feature = "tokens")]
+        {
+            let tokens: usize = response
+                .headers()
+                .get("x-markdown-tokens")
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!(tokens > 0);
+        }
+        #[cfg(not(feature = "tokens"))]
+        assert!(response.headers().get("x-markdown-tokens").is_none());
+
+        // Should have Content-Signal header
+        let signal = response
+            .headers()
+            .get("content-signal")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert_eq!(signal, "ai-train=yes, search=yes, ai-input=yes");
+
+        // Should have Vary: Accept
+        let vary = response.headers().get(VARY).unwrap().to_str().unwrap();
+        assert!(vary.contains("Accept"));
+
+        // Body should be markdown
+        let body = to_bytes(response.into_body(), 1024 * 1024).await.unwrap();
+        let md = String::from_utf8(body.to_vec()).unwrap();
+        assert!(md.contains("# Hello"));
+        assert!(md.contains("World"));
+    }
+
+    #[tokio::test]
+    async fn test_non_html_passthrough() {
+        let app = app();
+
+        let req = Request::builder()
+            .uri("/json")
+            .header(ACCEPT, "text/markdown")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let ct = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(ct.contains("application/json"));
+    }
+
+    #[tokio::test]
+    async fn test_body_too_large_returns_502() {
+        let config = MarkdownConfig::new().max_body_size(10); // 10 bytes max
+        let app = Router::new()
+            .route("/", get(|| async {
+                axum::response::Html("<html><body><h1>This body is definitely larger than 10 bytes</h1></body></html>")
+            }))
+            .layer(MarkdownLayer::with_config(config));
+
+        let req = Request::builder()
+            .uri("/")
+            .header(ACCEPT, "text/markdown")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let ct = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(ct.contains("text/plain"));
+        let vary = response.headers().get(VARY).unwrap().to_str().unwrap();
+        assert!(vary.contains("Accept"));
+
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("too large"));
+    }
+
+    #[tokio::test]
+    async fn test_max_body_size_per_content_type() {
+        let html = "<h1>This body is definitely larger than 10 bytes</h1>";
+        let app = Router::new()
+            .route(
+                "/html",
+                get(move || async move { axum::response::Html(html) }),
+            )
+            .route(
+                "/docs",
+                get(move || async move { ([(CONTENT_TYPE, "application/vnd.docs+html")], html) }),
+            )
+            .layer(MarkdownLayer::with_config(
+                MarkdownConfig::new()
+                    .convertible_types(["text/html", "application/vnd.docs+html"])
+                    .max_body_size(10)
+                    .max_body_size_for("vnd.docs", 20)
+                    .max_body_size_for("TEXT/HTML", 1024),
+            ));
+
+        let response = app
+            .clone()
+            .oneshot(markdown_request_to("/html"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body_string(response).await,
+            "# This body is definitely larger than 10 bytes"
+        );
+
+        let response = app.oneshot(markdown_request_to("/docs")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_max_body_size_override() {
+        let html =
+            "<html><body><h1>This body is definitely larger than 10 bytes</h1></body></html>";
+        let app = Router::new()
+            .route(
+                "/default",
+                get(move || async move { axum::response::Html(html) }),
+            )
+            .route(
+                "/raised",
+                get(move || async move {
+                    (
+                        axum::Extension(MaxBodySizeOverride(1024)),
+                        axum::response::Html(html),
+                    )
+                }),
+            )
+            .layer(MarkdownLayer::with_config(
+                MarkdownConfig::new().max_body_size(10),
+            ));
+
+        let response = app
+            .clone()
+            .oneshot(markdown_request_to("/default"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let response = app.oneshot(markdown_request_to("/raised")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body_string(response).await,
+            "# This body is definitely larger than 10 bytes"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oversize_passthrough() {
+        const HTML: &str = "<html><body><h1>Too large</h1><p>Passed through</p></body></html>";
+        let (sender, receiver) = std::sync::mpsc::sync_channel(4);
+        let app = |limit: usize| {
+            Router::new()
+                .route(
+                    "/",
+                    get(|| async {
+                        Response::builder()
+                            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                            .header(CONTENT_LENGTH, HTML.len())
+                            .header("x-custom", "kept")
+                            .body(Body::from(HTML))
+                            .unwrap()
+                    }),
+                )
+                .layer(MarkdownLayer::with_config(
+                    MarkdownConfig::new()
+                        .max_body_size(limit)
+                        .oversize_behavior(OversizeBehavior::Passthrough)
+                        .error_sender(sender.clone()),
+                ))
+        };
+
+        // One byte over the limit
+        let response = app(HTML.len() - 1)
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        let html = app(HTML.len() - 1)
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers(), html.headers());
+        assert_eq!(
+            response.headers()[CONTENT_LENGTH],
+            HTML.len().to_string().as_str()
+        );
+        assert_eq!(response.headers()["x-custom"], "kept");
+        assert_eq!(response.headers()[VARY], "Accept");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, HTML.as_bytes());
+        assert_eq!(
+            receiver.try_recv().map(|e| e.kind),
+            Ok(ConversionErrorKind::BodyTooLarge {
+                limit: HTML.len() - 1
+            })
+        );
+
+        // Exactly at the limit converts as before
+        let response = app(HTML.len()).oneshot(markdown_request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/markdown; charset=utf-8"
+        );
+        assert_eq!(body_string(response).await, "# Too large\n\nPassed through");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_body_too_large_reports_error() {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(8);
+        let config = MarkdownConfig::new().max_body_size(10).error_sender(sender);
+        let response = convert_request(
+            config,
+            "<p>This body is definitely larger than 10 bytes</p>",
+            markdown_request_to("/docs/page"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            [ConversionError {
+                url: "http://example.com/docs/page".to_string(),
+                kind: ConversionErrorKind::BodyTooLarge { limit: 10 },
+                status: StatusCode::OK,
+                headers: HeaderMap::from_iter([(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/html; charset=utf-8")
+                )]),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_format_problem_json() {
+        let config = MarkdownConfig::new()
+            .max_body_size(10)
+            .error_format(ErrorFormat::ProblemJson);
+        let response = convert_request(
+            config,
+            "<p>This body is definitely larger than 10 bytes</p>",
+            markdown_request_to("/docs/page"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        let problem: serde_json::Value =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(
+            problem,
+            serde_json::json!({
+                "type": "urn:axum-markdown:body-too-large",
+                "title": "Response body too large",
+                "status": 502,
+                "detail": "markdown conversion of http://example.com/docs/page failed: \
+                           response body too large",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_responder() {
+        let responder: ErrorResponder = Arc::new(|error: ConversionError| {
+            let (status, limit) = match error.kind {
+                ConversionErrorKind::BodyTooLarge { limit } => {
+                    (StatusCode::PAYLOAD_TOO_LARGE, Some(limit))
+                }
+                _ => (StatusCode::INTERNAL_SERVER_ERROR, None),
+            };
+            let problem = serde_json::json!({
+                "type": "https://errors.example.com/markdown",
+                "title": "Markdown unavailable",
+                "status": status.as_u16(),
+                "instance": error.url,
+                "upstream_status": error.status.as_u16(),
+                "upstream_type": error.headers[CONTENT_TYPE].to_str().unwrap(),
+                "limit": limit,
+            });
+            let mut response = Response::new(Body::from(problem.to_string()));
+            *response.status_mut() = status;
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/problem+json"),
+            );
+            response
+        });
+        let config = || MarkdownConfig::new().error_responder(responder.clone());
+
+        let response = convert_request(
+            config().max_body_size(10),
+            "<p>This body is definitely larger than 10 bytes</p>",
+            markdown_request_to("/docs/page"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        assert_eq!(response.headers()[VARY], "Accept");
+        let problem: serde_json::Value =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(
+            problem,
+            serde_json::json!({
+                "type": "https://errors.example.com/markdown",
+                "title": "Markdown unavailable",
+                "status": 413,
+                "instance": "http://example.com/docs/page",
+                "upstream_status": 200,
+                "upstream_type": "text/html; charset=utf-8",
+                "limit": 10,
+            })
+        );
+
+        let config = config().empty_body_behavior(EmptyBodyBehavior::Error);
+        let response = convert_request(config, "", markdown_request_to("/page")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+    }
+
+    #[tokio::test]
+    async fn test_error_sender_never_blocks() {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(0);
+        let config = MarkdownConfig::new()
+            .empty_body_behavior(EmptyBodyBehavior::Error)
+            .error_sender(sender);
+        // A rendezvous channel with no waiting receiver: `send` would block forever
+        let response = convert_request(config, "", markdown_request_to("/page")).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_content_signal_setters() {
+        let signal = |config| async {
+            let response = convert_with(config, html_response()).await;
+            response.headers()["content-signal"].clone()
+        };
+        let config = MarkdownConfig::new().content_signal("ai-train=no, custom=1");
+        assert_eq!(signal(config).await, "ai-train=no, custom=1");
+
+        let config = MarkdownConfig::new()
+            .try_content_signal(" search = NO ,ai-train=Yes")
+            .unwrap();
+        assert_eq!(signal(config).await, "ai-train=yes, search=no");
+        let error = MarkdownConfig::new()
+            .try_content_signal("ai-trian=yes")
+            .err();
+        assert_eq!(
+            error.map(|e| e.to_string()).as_deref(),
+            Some("invalid Content-Signal: unknown directive `ai-trian`")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_custom_config_no_signal() {
+        let config = MarkdownConfig::new().no_content_signal();
+        let app = Router::new()
+            .route("/", get(|| async { axum::response::Html(html_response()) }))
+            .layer(MarkdownLayer::with_config(config));
+
+        let req = Request::builder()
+            .uri("/")
+            .header(ACCEPT, "text/markdown")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("content-signal").is_none());
+    }
+
+    #[test]
+    fn test_append_vary_preserves_multiple_vary_headers() {
+        let mut response = Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::empty())
+            .unwrap();
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("Cookie"));
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+
+        let response = append_vary(response);
+
+        let vary = response.headers().get(VARY).unwrap().to_str().unwrap();
+        assert!(
+            vary.contains("Cookie"),
+            "Vary should contain Cookie, got: {vary}"
+        );
+        assert!(
+            vary.contains("Accept-Encoding"),
+            "Vary should contain Accept-Encoding, got: {vary}"
+        );
+        assert!(
+            vary.contains("Accept"),
+            "Vary should contain Accept, got: {vary}"
+        );
+    }
+
+    #[test]
+    fn test_append_vary_normalizes() {
+        let vary = |values: &[&'static str]| {
+            let mut response = Response::new(Body::empty());
+            for value in values {
+                response
+                    .headers_mut()
+                    .append(VARY, HeaderValue::from_static(value));
+            }
+            let response = append_vary(response);
+            let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+            assert_eq!(vary.len(), 1, "{vary:?}");
+            vary[0].to_str().unwrap().to_string()
+        };
+        let canonical = "Accept-Encoding, Cookie, Accept";
+        assert_eq!(vary(&["accept-encoding,  COOKIE"]), canonical);
+        assert_eq!(vary(&["Accept-Encoding\t,\tcookie ,"]), canonical);
+        assert_eq!(
+            vary(&["ACCEPT-ENCODING", " Cookie", "accept-encoding"]),
+            canonical
+        );
+        assert_eq!(
+            vary(&["accept-encoding, aCCept,cookie"]),
+            "Accept-Encoding, Accept, Cookie"
+        );
+        assert_eq!(vary(&[",, ,"]), "Accept");
+        assert_eq!(vary(&["Cookie, *"]), "*");
+        assert_eq!(vary(&[]), "Accept");
+    }
+
+    #[test]
+    fn test_append_vary_multiple_headers_already_has_accept() {
+        let mut response = Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::empty())
+            .unwrap();
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("Cookie"));
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("Accept"));
+
+        let response = append_vary(response);
+
+        let vary = response.headers().get(VARY).unwrap().to_str().unwrap();
+        assert!(
+            vary.contains("Cookie"),
+            "Vary should contain Cookie, got: {vary}"
+        );
+        // Should not duplicate Accept — check that the consolidated value
+        // has exactly one "Accept" token (not inside another word)
+        let accept_count = vary
+            .split(',')
+            .filter(|p| p.trim().eq_ignore_ascii_case("accept"))
+            .count();
+        assert_eq!(
+            accept_count, 1,
+            "Accept should appear exactly once, got: {vary}"
+        );
+    }
+
+    fn empty_html_app(behavior: EmptyBodyBehavior) -> Router {
+        let config = MarkdownConfig::new().empty_body_behavior(behavior);
+        Router::new()
+            .route("/", get(|| async { axum::response::Html("") }))
+            .layer(MarkdownLayer::with_config(config))
+    }
+
+    fn markdown_request() -> Request<Body> {
+        Request::builder()
+            .uri("/")
+            .header(ACCEPT, "text/markdown")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_empty_body_emit_empty() {
+        let app = empty_html_app(EmptyBodyBehavior::EmitEmpty);
+
+        let response = app.oneshot(markdown_request()).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let ct = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert_eq!(ct, "text/markdown; charset=utf-8");
+        #[cfg(feature = "tokens")]
+        assert_eq!(response.headers().get("x-markdown-tokens").unwrap(), "0");
+
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_empty_body_passthrough() {
+        let app = empty_html_app(EmptyBodyBehavior::Passthrough);
+
+        let response = app.oneshot(markdown_request()).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let ct = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(ct.contains("text/html"));
+        assert!(response.headers().get("x-markdown-tokens").is_none());
+        let vary = response.headers().get(VARY).unwrap().to_str().unwrap();
+        assert!(vary.contains("Accept"));
+
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_empty_body_error() {
+        let app = empty_html_app(EmptyBodyBehavior::Error);
+
+        let response = app.oneshot(markdown_request()).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("empty"));
+    }
+
+    async fn convert_with(config: MarkdownConfig, html: &'static str) -> Response<Body> {
+        let app = Router::new()
+            .route("/", get(move || async move { axum::response::Html(html) }))
+            .layer(MarkdownLayer::with_config(config));
+        app.oneshot(markdown_request()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_on_error() {
+        const HTML: &str = "<html><body><p>Unconvertible</p></body></html>";
+        let (sender, receiver) = std::sync::mpsc::sync_channel(4);
+        let config = || {
+            let config = MarkdownConfig::new()
+                .original_content_length(true)
+                .error_sender(sender.clone());
+            stub_converter(config, |_| None)
+        };
+
+        let response = convert_with(config(), HTML).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            body_string(response).await,
+            "Markdown conversion failed: unable to convert HTML to markdown"
+        );
+        assert_eq!(
+            receiver.try_recv().map(|e| e.kind),
+            Ok(ConversionErrorKind::ConversionFailed)
+        );
+
+        let layer = MarkdownLayer::with_config(config().on_error(ErrorPolicy::PassthroughHtml));
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async { ([("x-custom", "kept")], axum::response::Html(HTML)) }),
+            )
+            .layer(layer.clone());
+        let response = app.oneshot(markdown_request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(headers[CONTENT_LENGTH], HTML.len().to_string().as_str());
+        assert_eq!(headers["x-custom"], "kept");
+        assert_eq!(headers[VARY], "Accept");
+        assert!(!headers.contains_key("x-original-content-length"));
+        assert!(!headers.contains_key("content-signal"));
+        assert_eq!(body_string(response).await, HTML);
+        assert_eq!(
+            receiver.try_recv().map(|e| e.kind),
+            Ok(ConversionErrorKind::ConversionFailed)
+        );
+        assert_eq!(layer.stats().failures, 1);
+
+        // A body too large has nothing left to pass through
+        let config = config()
+            .on_error(ErrorPolicy::PassthroughHtml)
+            .max_body_size(8);
+        let response = convert_with(config, HTML).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_line_ending() {
+        let html = "<h1>Title</h1><p>One</p><pre><code>a\nb</code></pre><p>Two</p>";
+
+        let response = convert_with(MarkdownConfig::new(), html).await;
+        assert!(!body_string(response).await.contains('\r'));
+
+        let config = MarkdownConfig::new().line_ending(LineEnding::CrLf);
+        let response = convert_with(config, html).await;
+        #[cfg(feature = "tokens")]
+        let tokens = response.headers()["x-markdown-tokens"].clone();
+        let body = body_string(response).await;
+        assert_eq!(
+            body,
+            "# Title\r\n\r\nOne\r\n\r\n```\r\na\r\nb\r\n```\r\n\r\nTwo"
+        );
+        #[cfg(feature = "tokens")]
+        assert_eq!(
+            tokens,
+            TokenizerModel::O200kBase.count(&body).to_string().as_str()
+        );
+
+        // The truncation notice follows it too
+        #[cfg(feature = "tokens")]
+        {
+            let config = MarkdownConfig::new()
+                .line_ending(LineEnding::CrLf)
+                .max_tokens(6);
+            let response = convert_with(config, html).await;
+            let body = body_string(response).await;
+            assert!(body.ends_with("\r\n\r\n[Truncated]"), "{body:?}");
+            assert!(!body.replace("\r\n", "").contains('\n'), "{body:?}");
+        }
+    }
+
+    #[test]
+    fn test_line_ending_apply() {
+        assert_eq!(LineEnding::CrLf.apply("a\nb\r\nc\n"), "a\r\nb\r\nc\r\n");
+        assert_eq!(LineEnding::Lf.apply("a\nb\r\n"), "a\nb\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_external_base_path() {
+        let html = r#"<p><a href="/about">About</a> <a href="https://example.com/x">X</a>
+            <img src="/logo.png" alt="Logo"></p>"#;
+        let config = MarkdownConfig::new().external_base_path("/proxy");
+        let response = convert_with(config, html).await;
+        assert_eq!(
+            body_string(response).await,
+            "[About](/proxy/about) [X](https://example.com/x) ![Logo](/proxy/logo.png)"
+        );
+
+        let config = MarkdownConfig::new()
+            .external_base_path("/proxy")
+            .inline_link_threshold(0);
+        let response = convert_with(config, html).await;
+        assert!(body_string(response).await.contains("]: /proxy/about"));
+    }
+
+    #[tokio::test]
+    async fn test_conversion_timeout() {
+        const HTML: &str = "<html><body><p>Slow</p></body></html>";
+        struct Slow;
+        impl TokenCounter for Slow {
+            fn count(&self, _text: &str) -> usize {
+                std::thread::sleep(Duration::from_millis(300));
+                1
+            }
+        }
+
+        let slow_converter = |config| {
+            stub_converter(config, |_| {
+                std::thread::sleep(Duration::from_millis(300));
+                Some("Slow".to_string())
+            })
+        };
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(4);
+        let config = || {
+            let config = MarkdownConfig::new()
+                .conversion_timeout(Duration::from_millis(50))
+                .error_sender(sender.clone());
+            slow_converter(config)
+        };
+        let started = Instant::now();
+        let response = convert_with(config(), HTML).await;
+        assert!(started.elapsed() < Duration::from_millis(250));
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            body_string(response).await,
+            "Markdown conversion failed: conversion timed out"
+        );
+        assert_eq!(
+            receiver.try_recv().map(|e| e.kind),
+            Ok(ConversionErrorKind::Timeout {
+                limit: Duration::from_millis(50)
+            })
+        );
+
+        let passthrough = config().on_error(ErrorPolicy::PassthroughHtml);
+        let response = convert_with(passthrough, HTML).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(body_string(response).await, HTML);
+
+        // Token counting is bounded too
+        let slow = MarkdownConfig::new()
+            .conversion_timeout(Duration::from_millis(50))
+            .token_counter(Arc::new(Slow));
+        let response = convert_with(slow, html_response()).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        // Within the timeout, the conversion is served
+        let config =
+            slow_converter(MarkdownConfig::new().conversion_timeout(Duration::from_secs(5)));
+        let response = convert_with(config, HTML).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "Slow");
+    }
+
+    async fn body_string(response: Response<Body>) -> String {
+        let body = to_bytes(response.into_body(), 1024 * 1024).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_front_matter_title_and_description() {
+        let config = MarkdownConfig::new().front_matter(true);
+        let response = convert_with(
+            config,
+            r#"<html><head><title>Guide: "Getting Started"</title>
+            <meta name="description" content="How to begin"></head>
+            <body><p>Body text</p></body></html>"#,
+        )
+        .await;
+
+        let tokens = response.headers().get("x-markdown-tokens").cloned();
+        let md = body_string(response).await;
+        assert_eq!(
+            md,
+            "---\ntitle: \"Guide: \\\"Getting Started\\\"\"\ndescription: \"How to begin\"\n---\n\nBody text"
+        );
+        #[cfg(feature = "tokens")]
+        assert_eq!(
+            tokens.unwrap().to_str().unwrap(),
+            TokenizerModel::O200kBase.count(&md).to_string()
+        );
+        #[cfg(not(feature = "tokens"))]
+        assert!(tokens.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_front_matter_unicode_and_long_title() {
+        let config = MarkdownConfig::new().front_matter(true);
+        let response = convert_with(
+            config,
+            "<html><head><title>Ünïcödé — 日本語: a very long title that goes on and on and on \
+             well past any sensible line length limit for a YAML scalar value</title></head>\
+             <body><p>x</p></body></html>",
+        )
+        .await;
+
+        let md = body_string(response).await;
+        assert!(md.starts_with(
+            "---\ntitle: \"Ünïcödé — 日本語: a very long title that goes on and on and on \
+             well past any sensible line length limit for a YAML scalar value\"\n---\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_front_matter_strips_duplicate_h1() {
+        let html = "<html><head><title>Hello</title></head>\
+                    <body><h1>Hello</h1><p>World</p><h1>Hello</h1></body></html>";
+
+        let md =
+            body_string(convert_with(MarkdownConfig::new().front_matter(true), html).await).await;
+        assert_eq!(md, "---\ntitle: \"Hello\"\n---\n\nWorld\n\n# Hello");
+
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .strip_duplicate_title(false);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(
+            md,
+            "---\ntitle: \"Hello\"\n---\n\n# Hello\n\nWorld\n\n# Hello"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_front_matter_without_metadata() {
+        let html = "<html><body><h1>Hello</h1></body></html>";
+
+        let md =
+            body_string(convert_with(MarkdownConfig::new().front_matter(true), html).await).await;
+        assert_eq!(md, "# Hello");
+
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .empty_front_matter(true);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(md, "---\n---\n\n# Hello");
+    }
+
+    #[tokio::test]
+    async fn test_trim_output() {
+        let html = "\u{feff}<br><br><p>&nbsp;</p><p>Hello</p><br>\u{feff}";
+
+        let response = convert_with(MarkdownConfig::new(), html).await;
+        #[cfg(feature = "tokens")]
+        assert_eq!(response.headers()["x-markdown-tokens"], "1");
+        assert_eq!(body_string(response).await, "Hello");
+
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .empty_front_matter(true);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(md, "---\n---\n\nHello");
+
+        let md =
+            body_string(convert_with(MarkdownConfig::new().trim_output(false), html).await).await;
+        assert!(md.starts_with(' ') && md.ends_with('\u{feff}'), "{md:?}");
+    }
+
+    #[tokio::test]
+    async fn test_dialect() {
+        const HTML: &str = "<table>\n  <caption>Plans</caption>\n  <thead>\n                <tr><th>Plan</th><th align=\"right\">Price</th><th style=\"text-align: center\">Notes</th></tr>\n              </thead>\n\n  <tbody>\n    <tr><td><b>Free</b></td><td>0</td><td>a | b</td></tr>\n                <tr><td>Pro</td><td>10</td></tr>\n  </tbody>\n</table>\n            <p><del>Old</del> price</p>\n            <ul><li><input type=\"checkbox\" checked> Done</li><li><input type=\"checkbox\"> Todo</li></ul>";
+        let convert = |config| async move { body_string(convert_with(config, HTML).await).await };
+
+        let gfm = convert(MarkdownConfig::new().dialect(MarkdownDialect::Gfm)).await;
+        assert!(
+            gfm.starts_with(
+                "Plans\n\n\
+                 | Plan | Price | Notes |\n\
+                 | --- | ---: | :---: |\n\
+                 | **Free** | 0 | a \\| b |\n\
+                 | Pro | 10 |  |\n\n"
+            ),
+            "{gfm}"
+        );
+        assert!(gfm.contains("~~Old~~ price"), "{gfm}");
+        assert!(
+            gfm.contains("[x] Done") && gfm.contains("[ ] Todo"),
+            "{gfm}"
+        );
+
+        let commonmark = convert(MarkdownConfig::new().dialect(MarkdownDialect::CommonMark)).await;
+        let table = commonmark.split("\n\n").next().unwrap();
+        assert!(
+            table.starts_with("<table>") && table.ends_with("</table>"),
+            "{commonmark}"
+        );
+        assert!(table.contains("<th align=\"right\">Price</th>"));
+        assert!(!commonmark.contains("| Plan |") && !commonmark.contains("~~"));
+        assert!(commonmark.contains("<del>Old</del> price"), "{commonmark}");
+        assert!(commonmark.contains("\\[x\\] Done"), "{commonmark}");
+
+        let default = convert(MarkdownConfig::new()).await;
+        assert!(!default.contains("| ---") && !default.contains("<table>"));
+    }
+
+    #[tokio::test]
+    async fn test_duration_header() {
+        let layer = MarkdownLayer::with_config(MarkdownConfig::new().duration_header(true));
+        let app = Router::new()
+            .route("/", get(|| async { axum::response::Html(html_response()) }))
+            .layer(layer.clone());
+
+        let started = Instant::now();
+        let response = app.clone().oneshot(markdown_request()).await.unwrap();
+        let elapsed = started.elapsed();
+        let millis: f64 = response.headers()["x-markdown-duration-ms"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(millis > 0.0 && millis <= elapsed.as_secs_f64() * 1000.0);
+
+        let stats = layer.stats();
+        assert!(stats.conversion_time > Duration::ZERO);
+        assert!(stats.read_time + stats.conversion_time + stats.token_time <= elapsed);
+
+        // Steps accumulate across conversions
+        app.clone().oneshot(markdown_request()).await.unwrap();
+        let after = layer.stats();
+        assert!(after.conversion_time > stats.conversion_time);
+        assert!(after.read_time >= stats.read_time && after.token_time >= stats.token_time);
+
+        let response = app_with(MarkdownConfig::new())
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key("x-markdown-duration-ms"));
+    }
+
+    #[tokio::test]
+    async fn test_extra_headers() {
+        let header = |name: &'static str, value: &'static str| {
+            (
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            )
+        };
+        let config = MarkdownConfig::new().extra_headers([
+            header("x-served-by", "markdown-layer"),
+            header("x-experiment", "a"),
+            header("x-experiment", "b"),
+            header("content-type", "text/plain"),
+            header("content-signal", "ai-train=no"),
+        ]);
+        assert_eq!(config.extra_headers.len(), 4);
+        assert!(!config.extra_headers.contains_key(CONTENT_TYPE));
+        let app = Router::new()
+            .route("/", get(|| async { axum::response::Html(html_response()) }))
+            .route("/data", get(|| async { "plain text" }))
+            .layer(MarkdownLayer::with_config(config));
+
+        let response = app.clone().oneshot(markdown_request()).await.unwrap();
+        let headers = response.headers();
+        assert_eq!(headers["x-served-by"], "markdown-layer");
+        let experiments: Vec<_> = headers.get_all("x-experiment").iter().collect();
+        assert_eq!(experiments, ["a", "b"]);
+        assert_eq!(headers[CONTENT_TYPE], "text/markdown; charset=utf-8");
+        assert_eq!(headers["content-signal"], "ai-train=no");
+
+        let request = |uri: &str, accept: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap()
+        };
+        for (uri, accept) in [("/", "text/html"), ("/data", "text/markdown")] {
+            let response = app.clone().oneshot(request(uri, accept)).await.unwrap();
+            assert!(!response.headers().contains_key("x-served-by"));
+            assert!(!response.headers().contains_key("x-experiment"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extra_headers_all_responses() {
+        let config = || {
+            MarkdownConfig::new()
+                .extra_header("x-robots-tag", "noindex")
+                .unwrap()
+        };
+        assert!(MarkdownConfig::new().extra_header("bad name", "x").is_err());
+        assert!(MarkdownConfig::new().extra_header("x-ok", "bad\n").is_err());
+
+        let app = |config: MarkdownConfig| {
+            Router::new()
+                .route("/", get(|| async { axum::response::Html(html_response()) }))
+                .layer(MarkdownLayer::with_config(config))
+        };
+        let html = || {
+            Request::builder()
+                .uri("/")
+                .header(ACCEPT, "text/html")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app(config()).oneshot(markdown_request()).await.unwrap();
+        assert_eq!(response.headers()["x-robots-tag"], "noindex");
+        let response = app(config()).oneshot(html()).await.unwrap();
+        assert!(!response.headers().contains_key("x-robots-tag"));
+
+        let all = || config().extra_headers_all_responses(true);
+        let response = app(all()).oneshot(html()).await.unwrap();
+        assert_eq!(response.headers()["x-robots-tag"], "noindex");
+        let response = app(all()).oneshot(markdown_request()).await.unwrap();
+        let tags: Vec<_> = response.headers().get_all("x-robots-tag").iter().collect();
+        assert_eq!(tags, ["noindex"]);
+    }
+
+    #[tokio::test]
+    async fn test_strip_headers() {
+        let app = |config: MarkdownConfig| {
+            Router::new()
+                .route(
+                    "/",
+                    get(|| async {
+                        (
+                            [
+                                (CONTENT_SECURITY_POLICY, "default-src 'self'"),
+                                (X_FRAME_OPTIONS, "DENY"),
+                                (X_XSS_PROTECTION, "1; mode=block"),
+                                (HeaderName::from_static("permissions-policy"), "camera=()"),
+                                (HeaderName::from_static("x-custom"), "kept"),
+                                (http::header::CACHE_CONTROL, "max-age=60"),
+                            ],
+                            axum::response::Html(html_response()),
+                        )
+                    }),
+                )
+                .layer(MarkdownLayer::with_config(config))
+        };
+        let html_only = [
+            "content-security-policy",
+            "x-frame-options",
+            "x-xss-protection",
+            "permissions-policy",
+        ];
+        let names = |response: &Response<Body>| {
+            response
+                .headers()
+                .keys()
+                .map(HeaderName::as_str)
+                .filter(|name| html_only.contains(name) || name.starts_with("x-custom"))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        let passthrough = || Request::builder().uri("/").body(Body::empty()).unwrap();
+        let all = [html_only.as_slice(), &["x-custom"]].concat();
+
+        // Kept by default, and on passthrough
+        let response = app(MarkdownConfig::new())
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        assert_eq!(names(&response), all);
+        let config = || MarkdownConfig::new().strip_html_only_headers();
+        let response = app(config()).oneshot(passthrough()).await.unwrap();
+        assert_eq!(names(&response), all);
+
+        let response = app(config()).oneshot(markdown_request()).await.unwrap();
+        assert_eq!(names(&response), ["x-custom"]);
+        assert_eq!(
+            response.headers()[http::header::CACHE_CONTROL],
+            "max-age=60"
+        );
+
+        let config = MarkdownConfig::new().strip_headers([X_FRAME_OPTIONS]);
+        let response = app(config).oneshot(markdown_request()).await.unwrap();
+        assert_eq!(
+            names(&response),
+            [
+                "content-security-policy",
+                "x-xss-protection",
+                "permissions-policy",
+                "x-custom"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_propagate_language() {
+        let language = |html: &'static str, config: MarkdownConfig| async move {
+            convert_with(config, html)
+                .await
+                .headers()
+                .get(CONTENT_LANGUAGE)
+                .cloned()
+        };
+        let config = || MarkdownConfig::new().propagate_language(true);
+
+        let french = "<html lang=\"fr\"><body><p>Bonjour</p></body></html>";
+        assert_eq!(language(french, config()).await.unwrap(), "fr");
+        assert_eq!(
+            language("<html lang=\" pt-BR \"><p>Olá</p></html>", config())
+                .await
+                .unwrap(),
+            "pt-BR"
+        );
+        assert_eq!(language(french, MarkdownConfig::new()).await, None);
+        for html in [
+            "<p>No language</p>",
+            "<html lang=\"\"><p>Empty</p></html>",
+            "<html lang=\"en; q=1\"><p>Malformed</p></html>",
+        ] {
+            assert_eq!(language(html, config()).await, None, "{html}");
+        }
+
+        // The inner service's header wins
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    (
+                        [(CONTENT_LANGUAGE, "de")],
+                        axum::response::Html("<html lang=\"fr\"><p>Hallo</p></html>"),
+                    )
+                }),
+            )
+            .layer(MarkdownLayer::with_config(config()));
+        let response = app.oneshot(markdown_request()).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_LANGUAGE], "de");
+    }
+
+    #[tokio::test]
+    async fn test_transformation_warning() {
+        let app = |config: MarkdownConfig| {
+            Router::new()
+                .route("/", get(|| async { axum::response::Html(html_response()) }))
+                .route(
+                    "/stale",
+                    get(|| async {
+                        (
+                            [(WARNING, "110 cdn.example \"Response is Stale\"")],
+                            axum::response::Html(html_response()),
+                        )
+                    }),
+                )
+                .layer(MarkdownLayer::with_config(config))
+        };
+        let warnings = |response: &Response<Body>| {
+            response
+                .headers()
+                .get_all(WARNING)
+                .iter()
+                .map(|v| v.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let config = || MarkdownConfig::new().transformation_warning(true);
+        let applied = "214 axum-markdown \"Transformation Applied\"";
+
+        let response = app(config()).oneshot(markdown_request()).await.unwrap();
+        assert_eq!(warnings(&response), [applied]);
+        let response = app(config())
+            .oneshot(markdown_request_to("/stale"))
+            .await
+            .unwrap();
+        assert_eq!(
+            warnings(&response),
+            ["110 cdn.example \"Response is Stale\"", applied]
+        );
+
+        // Not on passthrough, errors, or without the option
+        let response = app(config())
+            .oneshot(
+                Request::builder()
+                    .uri("/stale")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            warnings(&response),
+            ["110 cdn.example \"Response is Stale\""]
+        );
+        let response = app(config().max_body_size(10))
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 502);
+        assert!(!response.headers().contains_key(WARNING));
+        let response = app(MarkdownConfig::new())
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(WARNING));
+    }
+
+    #[tokio::test]
+    async fn test_normalize_html_whitespace() {
+        const INDENTED: &str = "<html>\n  <body>\n    <div>\n      <div>\n        <h1>\n          Title\n        </h1>\n        <p>\n          Some <b>bold</b>\n          text,\n          <a href=\"/x\">\n            a link\n          </a>\n        </p>\n        <ul>\n          <li>\n            One\n          </li>\n          <li>\n            <p>\n              Two\n            </p>\n          </li>\n        </ul>\n        <blockquote>\n          <p>\n            Quoted\n          </p>\n        </blockquote>\n        <pre><code>fn main() {\n    run();\n}</code></pre>\n      </div>\n    </div>\n  </body>\n</html>\n";
+        const MINIFIED: &str = "<html><body><div><div><h1>Title</h1><p>Some <b>bold</b> text, <a href=\"/x\">a link</a></p><ul><li>One</li><li><p>Two</p></li></ul><blockquote><p>Quoted</p></blockquote><pre><code>fn main() {\n    run();\n}</code></pre></div></div></body></html>";
+
+        let convert = |html| async move {
+            let config = MarkdownConfig::new().normalize_html_whitespace(true);
+            body_string(convert_with(config, html).await).await
+        };
+        let minified = convert(MINIFIED).await;
+        assert_eq!(convert(INDENTED).await, minified);
+        assert!(minified.contains("```\nfn main() {\n    run();\n}\n```"));
+        assert_eq!(
+            body_string(convert_with(MarkdownConfig::new(), MINIFIED).await).await,
+            minified
+        );
+    }
+
+    #[tokio::test]
+    async fn test_conversion_stats() {
+        struct Fixed;
+        impl TokenCounter for Fixed {
+            fn count(&self, _text: &str) -> usize {
+                4
+            }
+        }
+
+        let layer = MarkdownLayer::with_token_counter(
+            MarkdownConfig::new().max_body_size(64),
+            Arc::new(Fixed),
+        );
+        let app = Router::new()
+            .route("/", get(|| async { axum::response::Html(html_response()) }))
+            .route(
+                "/large",
+                get(|| async { axum::response::Html("<p>large</p>".repeat(10)) }),
+            )
+            .layer(layer.clone());
+
+        for _ in 0..3 {
+            app.clone().oneshot(markdown_request()).await.unwrap();
+        }
+        app.clone()
+            .oneshot(markdown_request_to("/large"))
+            .await
+            .unwrap();
+        // Passthrough isn't counted
+        app.clone()
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        let stats = layer.stats();
+        assert_eq!(
+            (stats.conversions, stats.failures, stats.tokens),
+            (3, 1, 12)
+        );
+
+        let router =
+            Router::new().route("/", get(|| async { axum::response::Html(html_response()) }));
+        let mut service = MarkdownLayer::new().layer(router);
+        service.call(markdown_request()).await.unwrap();
+        assert_eq!(service.stats().conversions, 1);
+    }
+
+    #[test]
+    fn test_strip_markdown_suffix() {
+        let strip = |uri: &str| {
+            let mut req = Request::builder().uri(uri).body(()).unwrap();
+            strip_markdown_suffix(&mut req, ".md").then(|| req.uri().to_string())
+        };
+        assert_eq!(
+            strip("/docs/intro.md?lang=en").as_deref(),
+            Some("/docs/intro?lang=en")
+        );
+        assert_eq!(strip("/docs/index.md").as_deref(), Some("/docs/"));
+        assert_eq!(strip("/index.md").as_deref(), Some("/"));
+        assert_eq!(strip("/docs/reindex.md").as_deref(), Some("/docs/reindex"));
+        assert_eq!(
+            strip("http://example.com/a.md").as_deref(),
+            Some("http://example.com/a")
+        );
+        assert_eq!(strip("/docs/intro"), None);
+        assert_eq!(strip("/docs/.md"), None);
+        assert_eq!(strip("/docs/intro?file=a.md"), None);
+    }
+
+    #[tokio::test]
+    async fn test_markdown_suffix_precedence() {
+        let app = |config: MarkdownConfig| {
+            let router = Router::new().route(
+                "/docs/intro",
+                get(|| async { axum::response::Html(html_response()) }),
+            );
+            MarkdownLayer::with_config(config).layer(router)
+        };
+        let request = |uri: &str, accept: Option<&str>| {
+            let mut builder = Request::builder().uri(uri);
+            if let Some(accept) = accept {
+                builder = builder.header(ACCEPT, accept);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let content_type = |response: &Response<Body>| response.headers()[CONTENT_TYPE].clone();
+        let browser = Some("text/html,application/xhtml+xml,*/*;q=0.8");
+
+        // Without the option, the suffixed path is the inner service's to route
+        let response = app(MarkdownConfig::new())
+            .oneshot(request("/docs/intro.md", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+
+        let suffix = || MarkdownConfig::new().markdown_suffix(".md");
+        for accept in [None, Some("*/*"), Some("text/html"), browser] {
+            let response = app(suffix())
+                .oneshot(request("/docs/intro.md", accept))
+                .await
+                .unwrap();
+            assert_eq!(content_type(&response), "text/markdown; charset=utf-8");
+            assert_eq!(body_string(response).await, "# Hello\n\nWorld");
+        }
+        // The unsuffixed path still negotiates on Accept
+        let response = app(suffix())
+            .oneshot(request("/docs/intro", browser))
+            .await
+            .unwrap();
+        assert_eq!(content_type(&response), "text/html; charset=utf-8");
+
+        let accept_wins = || suffix().suffix_precedence(SuffixPrecedence::AcceptWins);
+        for (accept, expected) in [
+            (Some("text/html"), "text/html; charset=utf-8"),
+            (browser, "text/html; charset=utf-8"),
+            (
+                Some("text/markdown;q=0.5, text/html"),
+                "text/html; charset=utf-8",
+            ),
+            (None, "text/markdown; charset=utf-8"),
+            (Some("*/*"), "text/markdown; charset=utf-8"),
+            (Some("text/markdown"), "text/markdown; charset=utf-8"),
+        ] {
+            let response = app(accept_wins())
+                .oneshot(request("/docs/intro.md", accept))
+                .await
+                .unwrap();
+            assert_eq!(content_type(&response), expected, "{accept:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_x_robots_tag() {
+        let app = |config: MarkdownConfig| {
+            Router::new()
+                .route("/", get(|| async { axum::response::Html(html_response()) }))
+                .route(
+                    "/json",
+                    get(|| async { axum::Json(serde_json::json!({"key": "value"})) }),
+                )
+                .route(
+                    "/tagged",
+                    get(|| async {
+                        (
+                            [("x-robots-tag", "googlebot: nofollow")],
+                            axum::response::Html("<p>Tagged</p>"),
+                        )
+                    }),
+                )
+                .layer(MarkdownLayer::with_config(config))
+        };
+        let get_to = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let tags = |response: &Response<Body>| {
+            response
+                .headers()
+                .get_all("x-robots-tag")
+                .iter()
+                .map(|v| v.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let config = || MarkdownConfig::new().x_robots_tag("noarchive").unwrap();
+
+        // Converted responses only, by default
+        let response = app(config()).oneshot(markdown_request()).await.unwrap();
+        assert_eq!(tags(&response), ["noarchive"]);
+        for uri in ["/", "/json"] {
+            let response = app(config()).oneshot(get_to(uri)).await.unwrap();
+            assert!(!response.headers().contains_key("x-robots-tag"));
+        }
+        let response = app(MarkdownConfig::new())
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key("x-robots-tag"));
+
+        // The handler's header lines are kept, ahead of the layer's
+        let response = app(config())
+            .oneshot(markdown_request_to("/tagged"))
+            .await
+            .unwrap();
+        assert_eq!(tags(&response), ["googlebot: nofollow", "noarchive"]);
+        let response = app(config()).oneshot(get_to("/tagged")).await.unwrap();
+        assert_eq!(tags(&response), ["googlebot: nofollow"]);
+
+        let all = || config().robots_tag_all_responses(true);
+        for request in [get_to("/"), get_to("/json"), markdown_request()] {
+            let response = app(all()).oneshot(request).await.unwrap();
+            assert_eq!(tags(&response), ["noarchive"]);
+        }
+        for request in [get_to("/tagged"), markdown_request_to("/tagged")] {
+            let response = app(all()).oneshot(request).await.unwrap();
+            assert_eq!(tags(&response), ["googlebot: nofollow", "noarchive"]);
+        }
+
+        assert!(MarkdownConfig::new().x_robots_tag("no\nindex").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_signal_all_responses() {
+        let app = |config: MarkdownConfig| {
+            Router::new()
+                .route(
+                    "/json",
+                    get(|| async { axum::Json(serde_json::json!({"key": "value"})) }),
+                )
+                .route("/", get(|| async { axum::response::Html(html_response()) }))
+                .route(
+                    "/signed",
+                    get(|| async {
+                        (
+                            [("content-signal", "ai-train=no")],
+                            axum::response::Html("<p>Signed</p>"),
+                        )
+                    }),
+                )
+                .layer(MarkdownLayer::with_config(config))
+        };
+        let get_to = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let signal = |response: &Response<Body>| response.headers().get("content-signal").cloned();
+
+        // By default only converted responses carry it
+        let response = app(MarkdownConfig::new())
+            .oneshot(get_to("/json"))
+            .await
+            .unwrap();
+        assert_eq!(signal(&response), None);
+
+        let config = || MarkdownConfig::new().signal_all_responses(true);
+        for request in [get_to("/json"), get_to("/"), markdown_request()] {
+            let response = app(config()).oneshot(request).await.unwrap();
+            assert_eq!(
+                signal(&response).unwrap(),
+                "ai-train=yes, search=yes, ai-input=yes"
+            );
+        }
+
+        // The handler's own signal wins, converted or not, unless the layer's
+        // takes precedence
+        for request in [get_to("/signed"), markdown_request_to("/signed")] {
+            let response = app(config()).oneshot(request).await.unwrap();
+            assert_eq!(signal(&response).unwrap(), "ai-train=no");
+        }
+        let config = config().content_signal_precedence(SignalPrecedence::Layer);
+        for request in [get_to("/signed"), markdown_request_to("/signed")] {
+            let response = app(config.clone()).oneshot(request).await.unwrap();
+            assert_eq!(
+                signal(&response).unwrap(),
+                "ai-train=yes, search=yes, ai-input=yes"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nested_layers_process_once() {
+        let app = Router::new()
+            .route("/", get(|| async { axum::response::Html(html_response()) }))
+            .layer(MarkdownLayer::with_config(
+                MarkdownConfig::new().content_signal("search=yes"),
+            ))
+            .layer(MarkdownLayer::with_config(
+                MarkdownConfig::new().content_signal("search=no"),
+            ));
+
+        let response = app.oneshot(markdown_request()).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.extensions().get::<AlreadyProcessed>().is_some());
+        assert_eq!(response.headers()["content-signal"], "search=yes");
+        let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+        assert_eq!(vary, ["Accept"]);
+
+        let md = body_string(response).await;
+        assert_eq!(md, "# Hello\n\nWorld");
+    }
+
+    #[tokio::test]
+    async fn test_nested_layers_passthrough_once() {
+        let app = Router::new()
+            .route("/", get(|| async { axum::response::Html(html_response()) }))
+            .layer(MarkdownLayer::new())
+            .layer(MarkdownLayer::new());
+
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = app.oneshot(req).await.unwrap();
+
+        let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+        assert_eq!(vary, ["Accept"]);
+        assert!(response.extensions().get::<AlreadyProcessed>().is_some());
+    }
+
+    const SOCIAL_HEAD: &str = r#"<html><head>
+        <title>Post</title>
+        <meta property="og:type" content="article">
+        <meta property="og:image" content="https://example.com/1.png">
+        <meta property="og:image" content="https://example.com/2.png">
+        <meta property="og:description">
+        <meta property="article:author" content="Ada">
+        <meta name="twitter:card" content="summary_large_image">
+        </head><body><p>Text</p></body></html>"#;
+
+    #[tokio::test]
+    async fn test_front_matter_social_metadata() {
+        let config = MarkdownConfig::new().front_matter(true);
+        let md = body_string(convert_with(config, SOCIAL_HEAD).await).await;
+        assert_eq!(
+            md,
+            "---\ntitle: \"Post\"\nog.type: \"article\"\nog.image:\n  - \"https://example.com/1.png\"\n  - \"https://example.com/2.png\"\narticle.author: \"Ada\"\ntwitter.card: \"summary_large_image\"\n---\n\nText"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_front_matter_social_allowlist() {
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .social_properties(["og:image", "og:locale"]);
+        let md = body_string(convert_with(config, SOCIAL_HEAD).await).await;
+        assert_eq!(
+            md,
+            "---\ntitle: \"Post\"\nog.image:\n  - \"https://example.com/1.png\"\n  - \"https://example.com/2.png\"\n---\n\nText"
+        );
+
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .social_properties(Vec::<String>::new());
+        let md = body_string(convert_with(config, SOCIAL_HEAD).await).await;
+        assert_eq!(md, "---\ntitle: \"Post\"\n---\n\nText");
+    }
+
+    #[tokio::test]
+    async fn test_front_matter_custom_fields() {
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .front_matter_fields(Arc::new(|context: &FrontMatterContext<'_>| {
+                let mut fields = vec![(
+                    "path".to_string(),
+                    FrontMatterValue::String(context.uri().path().to_string()),
+                )];
+                if let Some(sku) = context.meta("sku") {
+                    fields.push(("sku".to_string(), FrontMatterValue::String(sku.to_string())));
+                }
+                if let Some(lang) = context.lang() {
+                    fields.push((
+                        "title".to_string(),
+                        FrontMatterValue::String(lang.to_string()),
+                    ));
+                }
+                fields
+            }));
+        let md = body_string(
+            convert_request(
+                config,
+                r#"<html lang="en"><head><title>Widget</title><meta name="sku" content="W-42"></head>
+                <body><p>Buy it</p></body></html>"#,
+                markdown_request_to("/shop/widget"),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(
+            md,
+            "---\ntitle: \"en\"\npath: \"/shop/widget\"\nsku: \"W-42\"\n---\n\nBuy it"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_front_matter_dates() {
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .social_properties(Vec::<String>::new());
+        let md = body_string(
+            convert_with(
+                config,
+                r#"<html><head><title>Post</title>
+                <meta property="article:modified_time" content="2024-06-01 08:00:00+0000">
+                <meta name="date" content="2024-05-01">
+                </head><body><article><time datetime="2024-04-01">April</time></article></body></html>"#,
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(
+            md,
+            "---\ntitle: \"Post\"\npublished: \"2024-05-01\"\nmodified: \"2024-06-01T08:00:00+00:00\"\n---\n\nApril"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_canonical_comment_first_line() {
+        let config = MarkdownConfig::new()
+            .canonical_comment(true)
+            .front_matter(true);
+        let md = body_string(
+            convert_request(
+                config,
+                "<title>Intro</title><h1>Welcome</h1>",
+                markdown_request_to("/docs/intro?lang=en"),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(
+            md.lines().next(),
+            Some("<!-- canonical: http://example.com/docs/intro?lang=en -->")
+        );
+        assert_eq!(
+            md,
+            "<!-- canonical: http://example.com/docs/intro?lang=en -->\n---\ntitle: \"Intro\"\n---\n\n# Welcome"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_embed_source_html_round_trip() {
+        let html = "<h1>Caf\u{e9}</h1><p>a --> b</p><!-- note -->";
+        let md = body_string(
+            convert_request(
+                MarkdownConfig::new().embed_source_html(true),
+                html,
+                markdown_request_to("/page"),
+            )
+            .await,
+        )
+        .await;
+        let (markdown, comment) = md.split_once("\n\n<!-- source-html-base64: ").unwrap();
+        assert!(markdown.starts_with("# Café"));
+        let encoded = comment.strip_suffix(" -->").unwrap();
+        let decoded = BASE64_STANDARD.decode(encoded).unwrap();
+        assert_eq!(String::from_utf8(decoded).unwrap(), html);
+
+        // Encoded, the HTML no longer fits the body size limit
+        let config = MarkdownConfig::new()
+            .embed_source_html(true)
+            .max_body_size(html.len());
+        let md =
+            body_string(convert_request(config, html, markdown_request_to("/page")).await).await;
+        assert!(md.starts_with("# Café"));
+        assert!(!md.contains("source-html-base64"));
+    }
+
+    #[tokio::test]
+    async fn test_table_of_contents() {
+        let html = "<html><head><title>Guide</title></head><body>\
+            <h1>Guide</h1><p>Intro</p>\
+            <h2>Install</h2><p>Run it.</p>\
+            <h3>From <code>source</code></h3><p>Build it.</p>\
+            <h2>Configure <a href=\"/ref\">options</a></h2><p>Tune it.</p>\
+            </body></html>";
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .strip_duplicate_title(false)
+            .table_of_contents(TocOptions::default());
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(
+            md,
+            "---\ntitle: \"Guide\"\n---\n\n# Guide\n\n\
+             - [Install](#install)\n  - [From source](#from-source)\n\
+             - [Configure options](#configure-options)\n\n\
+             Intro\n\n## Install\n\nRun it.\n\n### From `source`\n\nBuild it.\n\n\
+             ## Configure [options](/ref)\n\nTune it."
+        );
+
+        // Two `##` entries are below `min_headings` once `###` is excluded
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .table_of_contents(TocOptions {
+                max_depth: 2,
+                min_headings: 3,
+            });
+        let md = body_string(convert_with(config, html).await).await;
+        assert!(!md.contains("](#"), "{md}");
+    }
+
+    #[tokio::test]
+    async fn test_heading_anchors() {
+        let html = "<h1>Guide</h1><h2>Install</h2><p>a</p><h2>Install</h2><p>b</p>\
+                    <h3>Install</h3><pre><code># comment</code></pre>";
+        let config = MarkdownConfig::new().heading_anchors(true);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(
+            md,
+            "# Guide {#guide}\n\n## Install {#install}\n\na\n\n## Install {#install-1}\n\nb\n\n\
+             ### Install {#install-2}\n\n```\n# comment\n```"
+        );
+    }
+
+    const BREADCRUMB_PAGE: &str = r#"<html><head><title>Getting Started</title></head><body>
+        <nav aria-label="breadcrumb"><ol><li><a href="/">Home</a></li><li><a href="/docs">Docs</a></li>
+        <li>Getting Started</li></ol></nav><h1>Getting Started</h1></body></html>"#;
+
+    #[tokio::test]
+    async fn test_breadcrumbs_line() {
+        let config = MarkdownConfig::new().breadcrumbs(BreadcrumbPlacement::Line);
+        let md = body_string(convert_with(config, BREADCRUMB_PAGE).await).await;
+        assert!(md.starts_with("Home › Docs › Getting Started\n\n"), "{md}");
+
+        let md = body_string(
+            convert_with(
+                MarkdownConfig::new().breadcrumbs(BreadcrumbPlacement::Line),
+                "<h1>Lost</h1>",
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(md, "# Lost");
+    }
+
+    #[tokio::test]
+    async fn test_breadcrumbs_in_front_matter() {
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .breadcrumbs(BreadcrumbPlacement::FrontMatter);
+        let md = body_string(convert_with(config, BREADCRUMB_PAGE).await).await;
+        assert!(
+            md.starts_with("---\ntitle: \"Getting Started\"\nbreadcrumbs:\n  - \"Home\"\n  - \"Docs\"\n  - \"Getting Started\"\n---\n"),
+            "{md}"
+        );
+    }
+
+    fn range_request(range: &str) -> Request<Body> {
+        Request::builder()
+            .uri("/")
+            .header(ACCEPT, "text/markdown")
+            .header(RANGE, range)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_range_request_returns_partial_markdown() {
+        let full = body_string(app().oneshot(markdown_request()).await.unwrap()).await;
+        assert!(full.len() > 10);
+
+        let response = app().oneshot(range_request("bytes=0-9")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers()["content-range"],
+            format!("bytes 0-9/{}", full.len())
+        );
+        assert_eq!(response.headers()[ACCEPT_RANGES], "bytes");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, full.as_bytes()[..10]);
+
+        let response = app().oneshot(range_request("bytes=100000-")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            response.headers()["content-range"],
+            format!("bytes */{}", full.len())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_range_ignored_unless_requested() {
+        let response = app().oneshot(markdown_request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("content-range").is_none());
+
+        let mut req = range_request("bytes=0-9");
+        req.headers_mut()
+            .insert(IF_RANGE, HeaderValue::from_static("\"etag\""));
+        let response = app().oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The inner service never sees the range
+        let app = Router::new()
+            .route(
+                "/",
+                get(|headers: HeaderMap| async move {
+                    assert!(!headers.contains_key(RANGE));
+                    axum::response::Html("<p>Hello, world</p>")
+                }),
+            )
+            .layer(MarkdownLayer::new());
+        let response = app.oneshot(range_request("bytes=-5")).await.unwrap();
+        assert_eq!(body_string(response).await, "world");
+    }
+
+    async fn dry_run(uri: &str, accept: &str) -> Response<Body> {
+        let app = app_with(MarkdownConfig::new().dry_run_header(true));
+        let req = Request::builder()
+            .uri(uri)
+            .header(ACCEPT, accept)
+            .header("x-markdown-dry-run", "true")
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_redirect_passes_through() {
+        let html = "<p>Moved to <a href=\"/new\">/new</a></p>";
+        let redirect_app = |config: MarkdownConfig| {
+            Router::new()
+                .route(
+                    "/",
+                    get(move || async move {
+                        (
+                            StatusCode::FOUND,
+                            [(http::header::LOCATION, "/new")],
+                            axum::response::Html(html),
+                        )
+                    }),
+                )
+                .layer(MarkdownLayer::with_config(config))
+        };
+
+        let response = redirect_app(MarkdownConfig::new())
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[http::header::LOCATION], "/new");
+        assert!(response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        assert!(!response.headers().contains_key("x-markdown-tokens"));
+        assert_eq!(body_string(response).await, html);
+
+        let config = MarkdownConfig::new().pass_through_redirects(false);
+        let response = redirect_app(config)
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(body_string(response).await, "Moved to [/new](/new)");
+    }
+
+    #[tokio::test]
+    async fn test_etag_replaced_with_markdown_hash() {
+        use http::header::ETAG;
+        async fn etag_of(
+            config: MarkdownConfig,
+            html: &'static str,
+            upstream: bool,
+        ) -> Option<String> {
+            let app = Router::new()
+                .route(
+                    "/",
+                    get(move || async move {
+                        let mut response =
+                            axum::response::IntoResponse::into_response(axum::response::Html(html));
+                        if upstream {
+                            response
+                                .headers_mut()
+                                .insert(ETAG, HeaderValue::from_static("\"html-v1\""));
+                        }
+                        response
+                    }),
+                )
+                .layer(MarkdownLayer::with_config(config));
+            let response = app.oneshot(markdown_request()).await.unwrap();
+            response
+                .headers()
+                .get(ETAG)
+                .map(|etag| etag.to_str().unwrap().to_string())
+        }
+
+        let etag = etag_of(MarkdownConfig::new(), "<h1>One</h1>", true)
+            .await
+            .unwrap();
+        assert_ne!(etag, "\"html-v1\"");
+        assert!(etag.starts_with('"') && !etag.starts_with("W/"));
+        // Stable across identical conversions, and changes with the markdown
+        let again = etag_of(MarkdownConfig::new(), "<h1>One</h1>", true).await;
+        assert_eq!(again.as_deref(), Some(&*etag));
+        let changed = etag_of(MarkdownConfig::new(), "<h1>Two</h1>", true).await;
+        assert_ne!(changed.as_deref(), Some(&*etag));
+        let config = MarkdownConfig::new()
+            .normalize_blockquotes(false)
+            .trim_output(false);
+        let html = "<h1>One</h1><blockquote><blockquote><p>x</p></blockquote></blockquote>";
+        assert_ne!(
+            etag_of(config, html, true).await,
+            etag_of(MarkdownConfig::new(), html, true).await
+        );
+
+        // Without an upstream ETag, only `Always` adds one
+        assert_eq!(
+            etag_of(MarkdownConfig::new(), "<h1>One</h1>", false).await,
+            None
+        );
+        let config = MarkdownConfig::new().etag(ETagPolicy::Always);
+        assert_eq!(etag_of(config, "<h1>One</h1>", false).await, Some(etag));
+        let config = MarkdownConfig::new().etag(ETagPolicy::Keep);
+        assert_eq!(
+            etag_of(config, "<h1>One</h1>", true).await.as_deref(),
+            Some("\"html-v1\"")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_original_validators_preserved() {
+        use http::header::{ETAG, LAST_MODIFIED};
+        let app = |config: MarkdownConfig| {
+            Router::new()
+                .route(
+                    "/",
+                    get(|| async {
+                        (
+                            [
+                                (ETAG, "W/\"html-v1\""),
+                                (LAST_MODIFIED, "Tue, 13 Oct 2026 08:00:00 GMT"),
+                            ],
+                            axum::response::Html("<h1>One</h1>"),
+                        )
+                    }),
+                )
+                .layer(MarkdownLayer::with_config(config))
+        };
+
+        let response = app(MarkdownConfig::new())
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        let headers = response.headers();
+        assert_eq!(headers["x-original-etag"], "W/\"html-v1\"");
+        assert_ne!(headers[ETAG], "W/\"html-v1\"");
+        assert_eq!(
+            headers["x-original-last-modified"],
+            "Tue, 13 Oct 2026 08:00:00 GMT"
+        );
+
+        let config = MarkdownConfig::new()
+            .etag(ETagPolicy::Keep)
+            .original_etag_header(Some(HeaderName::from_static("x-html-etag")))
+            .original_last_modified_header(None);
+        let response = app(config).oneshot(markdown_request()).await.unwrap();
+        let headers = response.headers();
+        assert_eq!(headers["x-html-etag"], "W/\"html-v1\"");
+        assert!(!headers.contains_key("x-original-etag"));
+        assert!(!headers.contains_key("x-original-last-modified"));
+
+        // Nothing to preserve without upstream validators
+        let response = convert_with(MarkdownConfig::new(), "<h1>One</h1>").await;
+        assert!(!response.headers().contains_key("x-original-etag"));
+    }
+
+    #[tokio::test]
+    async fn test_if_none_match_not_modified() {
+        use http::header::{ETAG, IF_NONE_MATCH};
+        let config = MarkdownConfig::new().etag(ETagPolicy::Always);
+        let response = app_with(config.clone())
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+
+        let conditional = |if_none_match: String| {
+            Request::builder()
+                .uri("/")
+                .header(ACCEPT, "text/markdown")
+                .header(IF_NONE_MATCH, if_none_match)
+                .body(Body::empty())
+                .unwrap()
+        };
+        for matching in [
+            etag.clone(),
+            format!("W/{etag}"),
+            format!("\"x\", {etag}"),
+            "*".into(),
+        ] {
+            let response = app_with(config.clone())
+                .oneshot(conditional(matching.clone()))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{matching}");
+            assert_eq!(response.headers()[ETAG], etag.as_str());
+            assert!(!response.headers().contains_key(CONTENT_TYPE));
+            assert!(response.headers()[VARY]
+                .to_str()
+                .unwrap()
+                .contains("Accept"));
+            assert_eq!(body_string(response).await, "");
+        }
+
+        let response = app_with(config)
+            .oneshot(conditional("\"other\"".into()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "# Hello\n\nWorld");
+    }
+
+    fn conditional_request(headers: &[(HeaderName, &str)]) -> Request<Body> {
+        let mut request = Request::builder().uri("/").header(ACCEPT, "text/markdown");
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_if_modified_since_forwarded() {
+        use http::header::{IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+        const MODIFIED: &str = "Tue, 13 Oct 2026 08:00:00 GMT";
+        // An inner service answering `If-Modified-Since` itself
+        let honoring = |config: MarkdownConfig| {
+            Router::new()
+                .route(
+                    "/",
+                    get(|headers: HeaderMap| async move {
+                        let status = if headers.contains_key(IF_MODIFIED_SINCE) {
+                            StatusCode::NOT_MODIFIED
+                        } else {
+                            StatusCode::OK
+                        };
+                        (
+                            status,
+                            [(LAST_MODIFIED, MODIFIED)],
+                            axum::response::Html("<h1>Hello</h1><p>World</p>"),
+                        )
+                    }),
+                )
+                .layer(MarkdownLayer::with_config(config))
+        };
+
+        let response = honoring(MarkdownConfig::new())
+            .oneshot(conditional_request(&[]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[LAST_MODIFIED], MODIFIED);
+        assert_eq!(body_string(response).await, "# Hello\n\nWorld");
+
+        // The inner service's 304 passes through unconverted
+        let response = honoring(MarkdownConfig::new())
+            .oneshot(conditional_request(&[(IF_MODIFIED_SINCE, MODIFIED)]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(!response.headers().contains_key("x-markdown-tokens"));
+        assert!(response.headers()[VARY]
+            .to_str()
+            .unwrap()
+            .contains("Accept"));
+
+        // `If-None-Match` takes precedence, so the inner service never sees
+        // `If-Modified-Since`
+        let response = honoring(MarkdownConfig::new())
+            .oneshot(conditional_request(&[
+                (IF_MODIFIED_SINCE, MODIFIED),
+                (IF_NONE_MATCH, "\"other\""),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "# Hello\n\nWorld");
+    }
+
+    #[tokio::test]
+    async fn test_if_modified_since_answered() {
+        use http::header::{IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+        const MODIFIED: &str = "Tue, 13 Oct 2026 08:00:00 GMT";
+        // Converted responses served from the cache without asking the
+        // inner service
+        let ignoring = |config: MarkdownConfig| {
+            Router::new()
+                .route(
+                    "/",
+                    get(|| async {
+                        (
+                            [(LAST_MODIFIED, MODIFIED)],
+                            axum::response::Html("<h1>Hello</h1><p>World</p>"),
+                        )
+                    }),
+                )
+                .layer(MarkdownLayer::with_config(config))
+        };
+        let config = MarkdownConfig::new().cache_ttl(Duration::from_secs(60));
+        let app = ignoring(config.clone());
+        app.clone().oneshot(conditional_request(&[])).await.unwrap();
+        let response = app
+            .oneshot(conditional_request(&[(IF_MODIFIED_SINCE, MODIFIED)]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let app = ignoring(config.if_modified_since(true));
+        app.clone().oneshot(conditional_request(&[])).await.unwrap();
+        for (since, status) in [
+            (MODIFIED, StatusCode::NOT_MODIFIED),
+            ("Wed, 14 Oct 2026 08:00:00 GMT", StatusCode::NOT_MODIFIED),
+            ("Mon, 12 Oct 2026 08:00:00 GMT", StatusCode::OK),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(conditional_request(&[(IF_MODIFIED_SINCE, since)]))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{since}");
+            assert_eq!(response.headers()[LAST_MODIFIED], MODIFIED);
+        }
+        let response = app
+            .oneshot(conditional_request(&[
+                (IF_MODIFIED_SINCE, MODIFIED),
+                (IF_NONE_MATCH, "\"other\""),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Without a cache, the middleware answers for services that ignore it
+        let response = ignoring(MarkdownConfig::new().if_modified_since(true))
+            .oneshot(conditional_request(&[(IF_MODIFIED_SINCE, MODIFIED)]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(body_string(response).await, "");
+    }
+
+    #[tokio::test]
+    async fn test_content_digest() {
+        use http::header::RANGE;
+        let digested_app = |config: MarkdownConfig| {
+            Router::new()
+                .route(
+                    "/",
+                    get(|| async {
+                        (
+                            [("content-digest", "sha-256=:html:"), ("digest", "md5=html")],
+                            axum::response::Html("<h1>Hello</h1><p>World</p>"),
+                        )
+                    }),
+                )
+                .layer(MarkdownLayer::with_config(config))
+        };
+        let request = |accept: &str, range: Option<&str>| {
+            let mut request = Request::builder().uri("/").header(ACCEPT, accept);
+            if let Some(range) = range {
+                request = request.header(RANGE, range);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        // Digests of "# Hello\n\nWorld" and its first five bytes, from
+        // Python's hashlib
+        let config = MarkdownConfig::new().content_digest(DigestAlgorithm::Sha256);
+        let response = digested_app(config.clone())
+            .oneshot(request("text/markdown", None))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()["content-digest"],
+            "sha-256=:rW4L+Ijalkq1eZLobG+JSq7DMl17GDVauSyBur6BxKM=:"
+        );
+        assert!(!response.headers().contains_key("digest"));
+        let response = digested_app(config.clone())
+            .oneshot(request("text/markdown", Some("bytes=0-4")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers()["content-digest"],
+            "sha-256=:0NHGZ7sYss4zKhtnJ0uLDJ97yfhY/2w+9JrItMUs5WY=:"
+        );
+
+        let config = MarkdownConfig::new().content_digest(DigestAlgorithm::Sha512);
+        let response = digested_app(config)
+            .oneshot(request("text/markdown", None))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()["content-digest"],
+            "sha-512=:5DPecbdkuJRZQTI9MEFlg/q9ivp/3YWh/TCjXi2j4RNIhTVqxGZx4idQqEbOkybwIjYfswbruVeYfYgwstuDUQ==:"
+        );
+
+        // Without the flag, converted responses just lose the HTML's digests
+        let response = digested_app(MarkdownConfig::new())
+            .oneshot(request("text/markdown", None))
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key("content-digest"));
+        assert!(!response.headers().contains_key("digest"));
+
+        let response = digested_app(MarkdownConfig::new().content_digest(DigestAlgorithm::Sha256))
+            .oneshot(request("text/html", None))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["content-digest"], "sha-256=:html:");
+        assert_eq!(response.headers()["digest"], "md5=html");
+    }
+
+    #[tokio::test]
+    async fn test_digest() {
+        use http::header::RANGE;
+        let app = |config: MarkdownConfig| {
+            Router::new()
+                .route(
+                    "/",
+                    get(|| async {
+                        (
+                            [("digest", "md5=html")],
+                            axum::response::Html("<h1>Hello</h1><p>World</p>"),
+                        )
+                    }),
+                )
+                .layer(MarkdownLayer::with_config(config))
+        };
+
+        // sha-256 of "# Hello\n\nWorld", from Python's hashlib
+        let response = app(MarkdownConfig::new().digest(DigestAlgorithm::Sha256))
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()["digest"],
+            "sha-256=rW4L+Ijalkq1eZLobG+JSq7DMl17GDVauSyBur6BxKM="
+        );
+        assert!(!response.headers().contains_key("content-digest"));
+
+        let mut request = markdown_request();
+        request
+            .headers_mut()
+            .insert(RANGE, HeaderValue::from_static("bytes=0-4"));
+        let response = app(MarkdownConfig::new().digest(DigestAlgorithm::Sha256))
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(!response.headers().contains_key("digest"));
+    }
+
+    #[tokio::test]
+    async fn test_page_summary() {
+        let html = "<html lang=\"en\"><head><title>Guide</title>\
+            <meta name=\"description\" content=\"How to\">\
+            <meta property=\"og:title\" content=\"The Guide\">\
+            <meta property=\"og:description\" content=\"All about it\">\
+            <link rel=\"canonical\" href=\"/guide\"></head><body><h1>Guide</h1></body></html>";
+        let summary_request = |accept: &str| {
+            Request::builder()
+                .uri("/doc")
+                .header(ACCEPT, accept)
+                .header(HOST, "example.com")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let config = || MarkdownConfig::new().page_summary(true);
+
+        let response = convert_request(
+            config(),
+            html,
+            summary_request("application/vnd.page-summary+json"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "application/vnd.page-summary+json"
+        );
+        assert!(response.headers()[VARY]
+            .to_str()
+            .unwrap()
+            .contains("Accept"));
+        let summary: serde_json::Value =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(
+            summary,
+            serde_json::json!({
+                "url": "http://example.com/doc",
+                "title": "Guide",
+                "description": "How to",
+                "canonical": "http://example.com/guide",
+                "lang": "en",
+                "properties": {"og:title": "The Guide", "og:description": "All about it"},
+            })
+        );
+
+        // Markdown still wins when preferred, and gets the same fields as front matter
+        let accept = "text/markdown, application/vnd.page-summary+json;q=0.5";
+        let response =
+            convert_request(config().front_matter(true), html, summary_request(accept)).await;
+        let md = body_string(response).await;
+        assert!(md.contains("og.title: \"The Guide\"\nog.description: \"All about it\""));
+
+        // Without page_summary, the media type isn't offered
+        let response = convert_request(
+            MarkdownConfig::new(),
+            html,
+            summary_request("application/vnd.page-summary+json"),
+        )
+        .await;
+        assert!(response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_reports_decisions() {
+        for (uri, accept, would_convert, decision) in [
+            ("/", "text/markdown", "true", "accept-header"),
+            ("/", "text/html", "false", "not-requested"),
+            ("/json", "text/markdown", "false", "not-html"),
+        ] {
+            let response = dry_run(uri, accept).await;
+            assert_eq!(
+                response.headers()["x-markdown-would-convert"],
+                would_convert,
+                "{uri} {accept}"
+            );
+            assert_eq!(response.headers()["x-markdown-decision"], decision);
+            assert!(response.headers()[VARY]
+                .to_str()
+                .unwrap()
+                .contains("X-Markdown-Dry-Run"));
+            assert_ne!(
+                response.headers()[CONTENT_TYPE],
+                "text/markdown; charset=utf-8"
+            );
+        }
+
+        let body = body_string(dry_run("/", "text/markdown").await).await;
+        assert!(body.contains("<h1>"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_header_ignored_when_disabled() {
+        let req = Request::builder()
+            .uri("/")
+            .header(ACCEPT, "text/markdown")
+            .header("x-markdown-dry-run", "true")
+            .body(Body::empty())
+            .unwrap();
+        let response = app().oneshot(req).await.unwrap();
+        assert!(response.headers().get("x-markdown-decision").is_none());
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/markdown; charset=utf-8"
+        );
+    }
+
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_tokenizer_models_count_differently() {
+        // Whitespace runs and non-English text split differently across encodings
+        let html = "<pre>    indented    code    here</pre><p>Привет, как дела? こんにちは世界</p>";
+        let count = |response: &Response<Body>| {
+            response.headers()["x-markdown-tokens"]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let o200k = count(&convert_with(MarkdownConfig::new(), html).await);
+        let config = MarkdownConfig::new().tokenizer(TokenizerModel::Cl100kBase);
+        let cl100k = count(&convert_with(config, html).await);
+        let config = MarkdownConfig::new().tokenizer(TokenizerModel::R50kBase);
+        let r50k = count(&convert_with(config, html).await);
+        assert_ne!(o200k, cl100k);
+        assert_ne!(cl100k, r50k);
+
+        let config = MarkdownConfig::new()
+            .tokenizer(TokenizerModel::Cl100kBase)
+            .token_header_model(true);
+        let response = convert_with(config, html).await;
+        assert_eq!(count(&response), format!("{cl100k}; model=cl100k_base"));
+    }
+
+    #[tokio::test]
+    async fn test_count_tokens_disabled() {
+        let config = MarkdownConfig::new().count_tokens(false);
+        let response = convert_with(config, "<h1>Hello</h1>").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("x-markdown-tokens").is_none());
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/markdown; charset=utf-8"
+        );
+        assert_eq!(body_string(response).await, "# Hello");
+    }
+
+    #[tokio::test]
+    async fn test_custom_token_counter() {
+        struct Fixed;
+        impl TokenCounter for Fixed {
+            fn count(&self, _text: &str) -> usize {
+                42
+            }
+        }
+
+        let config = MarkdownConfig::new()
+            .token_counter(Arc::new(Fixed))
+            .token_header_model(true);
+        assert!(format!("{config:?}").contains("token_counter: Some(\"..\")"));
+        let response = convert_with(config, "<h1>Hello</h1>").await;
+        assert_eq!(response.headers()["x-markdown-tokens"], "42");
+    }
+
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_additional_tokenizers() {
+        struct Words;
+        impl TokenCounter for Words {
+            fn count(&self, text: &str) -> usize {
+                text.split_whitespace().count()
+            }
+        }
+
+        let config = || {
+            MarkdownConfig::new()
+                .additional_tokenizers([TokenizerModel::Cl100kBase])
+                .additional_token_counter("words", Arc::new(Words))
+        };
+        assert!(format!("{:?}", config())
+            .contains("additional_tokenizers: [\"cl100k_base\", \"words\"]"));
+        let response = convert_with(config(), "<h1>Hello</h1><p>World</p>").await;
+        assert_eq!(response.headers()["x-markdown-tokens"], "4");
+        assert_eq!(
+            response.headers()["x-markdown-tokens-by-model"],
+            "cl100k_base=4, words=3"
+        );
+
+        // Pages report only their own count
+        let config = config().page_tokens(16);
+        let response = convert_request(config, PAGED, page_request("/doc", "1")).await;
+        assert_eq!(response.headers()["x-markdown-tokens"], "14");
+        assert!(response
+            .headers()
+            .get("x-markdown-tokens-by-model")
+            .is_none());
+
+        let response = convert_with(MarkdownConfig::new(), "<h1>Hello</h1>").await;
+        assert!(response
+            .headers()
+            .get("x-markdown-tokens-by-model")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_layer_from_shared_config() {
+        let shared = SharedMarkdownConfig::from(MarkdownConfig::new().count_tokens(false));
+        let layer = MarkdownLayer::with_shared_config(shared.clone());
+        assert!(Arc::ptr_eq(&layer.config, &Arc::from(shared.clone())));
+        assert!(!layer.config().count_tokens);
+
+        let app = Router::new()
+            .route("/", get(|| async { axum::response::Html(html_response()) }))
+            .layer(layer);
+        let response = app.oneshot(markdown_request()).await.unwrap();
+        assert!(!response.headers().contains_key("x-markdown-tokens"));
+        assert_eq!(body_string(response).await, "# Hello\n\nWorld");
+
+        let arc = Arc::new(MarkdownConfig::new());
+        let layer = MarkdownLayer::with_shared_config(Arc::clone(&arc));
+        assert!(Arc::ptr_eq(&layer.config, &arc));
+    }
+
+    #[tokio::test]
+    async fn test_post_process() {
+        struct Uppercase;
+        impl TokenCounter for Uppercase {
+            fn count(&self, text: &str) -> usize {
+                text.chars().filter(char::is_ascii_uppercase).count()
+            }
+        }
+
+        let config = MarkdownConfig::new()
+            .token_counter(Arc::new(Uppercase))
+            .post_process(Arc::new(|markdown| markdown.to_uppercase()));
+        assert!(format!("{config:?}").contains("post_process: Some(\"..\")"));
+        let response = convert_with(config, "<h1>Hello</h1><p>World</p>").await;
+        assert_eq!(response.headers()["x-markdown-tokens"], "10");
+        assert_eq!(body_string(response).await, "# HELLO\n\nWORLD");
+    }
+
+    #[tokio::test]
+    async fn test_token_counter_shared_across_layers() {
+        use std::sync::atomic::AtomicUsize;
+        static BUILT: AtomicUsize = AtomicUsize::new(0);
+        struct Loaded {
+            counted: AtomicUsize,
+        }
+        impl Loaded {
+            fn load() -> Self {
+                BUILT.fetch_add(1, Ordering::SeqCst);
+                Self {
+                    counted: AtomicUsize::new(0),
+                }
+            }
+        }
+        impl TokenCounter for Loaded {
+            fn count(&self, text: &str) -> usize {
+                self.counted.fetch_add(1, Ordering::SeqCst);
+                text.len()
+            }
+        }
+
+        let counter = Arc::new(Loaded::load());
+        let app = |config: MarkdownConfig| {
+            let shared: Arc<dyn TokenCounter> = counter.clone();
+            Router::new()
+                .route("/", get(|| async { axum::response::Html("<p>Hi</p>") }))
+                .layer(MarkdownLayer::with_token_counter(config, shared))
+        };
+        let (first, second) = (app(MarkdownConfig::new()), app(MarkdownConfig::new()));
+        for app in [first.clone(), second, first] {
+            let response = app.oneshot(markdown_request()).await.unwrap();
+            assert_eq!(response.headers()["x-markdown-tokens"], "2");
+        }
+        assert_eq!(BUILT.load(Ordering::SeqCst), 1);
+        assert_eq!(counter.counted.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_approximate_token_estimation() {
+        let config = MarkdownConfig::new()
+            .token_estimation(Estimation::Approximate)
+            .token_header_model(true);
+        let response = convert_with(config, "<h1>Hello</h1><p>World</p>").await;
+        // `# Hello\n\nWorld`: `#`, `Hello`, `\n\n`, `World` make 4 pieces
+        assert_eq!(response.headers()["x-markdown-tokens"], "3");
+        assert_eq!(response.headers()["x-markdown-tokens-estimated"], "true");
+
+        let response = convert_with(MarkdownConfig::new(), "<h1>Hello</h1>").await;
+        assert!(!response
+            .headers()
+            .contains_key("x-markdown-tokens-estimated"));
+    }
+
+    #[tokio::test]
+    async fn test_section_tokens() {
+        let html =
+            "<p>Lead</p><h1>Install</h1><pre><code># not a heading\n## nor this</code></pre>\
+                    <h3>Deep</h3><p>Still install</p><h2>Use <em>it</em></h2><p>Run it.</p>";
+        let app_with = |config: MarkdownConfig| {
+            Router::new()
+                .route("/", get(move || async move { axum::response::Html(html) }))
+                .layer(MarkdownLayer::with_config(config))
+        };
+        // The default prefix differences, and tiktoken's one-pass split
+        let approximate = MarkdownConfig::new().token_counter(Arc::new(estimate::Approximate));
+        let tiktoken = cfg!(feature = "tokens").then(MarkdownConfig::new);
+        for config in [Some(approximate), tiktoken].into_iter().flatten() {
+            let config = config
+                .section_tokens_header(1024)
+                .cache_ttl(Duration::from_secs(60));
+            let app = app_with(config);
+            for _ in 0..2 {
+                let response = app.clone().oneshot(markdown_request()).await.unwrap();
+                let sections = response
+                    .extensions()
+                    .get::<Vec<SectionTokens>>()
+                    .unwrap()
+                    .clone();
+                let total: usize = response.headers()["x-markdown-tokens"]
+                    .to_str()
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let header = response.headers()["x-markdown-section-tokens"].clone();
+                let md = body_string(response).await;
+
+                let headings: Vec<_> = sections.iter().map(|s| s.heading.as_deref()).collect();
+                assert_eq!(headings, [None, Some("Install"), Some("Use it")]);
+                assert!(md[sections[1].byte_range.clone()].contains("# not a heading"));
+                assert_eq!(sections.iter().map(|s| s.tokens).sum::<usize>(), total);
+                assert_eq!(sections[2].byte_range.end, md.len());
+                let pairs: serde_json::Value = serde_json::from_slice(header.as_bytes()).unwrap();
+                assert_eq!(pairs[1][0], "Install");
+                assert_eq!(pairs[1][1], sections[1].tokens);
+            }
+        }
+
+        let config = MarkdownConfig::new().section_tokens_header(8);
+        let config = config.token_counter(Arc::new(estimate::Approximate));
+        let response = app_with(config).oneshot(markdown_request()).await.unwrap();
+        assert!(response.extensions().get::<Vec<SectionTokens>>().is_some());
+        assert!(!response.headers().contains_key("x-markdown-section-tokens"));
+    }
+
+    #[tokio::test]
+    async fn test_inline_link_threshold() {
+        let html = "<p><a href=\"/a\">A</a>, <a href=\"/b\">B</a>, and <a href=\"/c\">C</a></p>";
+        let config = MarkdownConfig::new().inline_link_threshold(3);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(md, "[A](/a), [B](/b), and [C](/c)");
+
+        let config = MarkdownConfig::new().inline_link_threshold(2);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(
+            md,
+            "[A][1], [B][2], and [C][3]\n\n[1]: /a\n[2]: /b\n[3]: /c"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_code_fence_styles() {
+        let html = "<pre><code class=\"language-md\">Use\n```\nfences\n```</code></pre>\
+                    <pre><code>plain</code></pre>";
+
+        let md = body_string(convert_with(MarkdownConfig::new(), html).await).await;
+        assert_eq!(md, "````md\nUse\n```\nfences\n```\n````\n\n```\nplain\n```");
+
+        let config = MarkdownConfig::new().code_fence(CodeFence::Tilde);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(md, "~~~md\nUse\n```\nfences\n```\n~~~\n\n~~~\nplain\n~~~");
+
+        let config = MarkdownConfig::new().code_fence(CodeFence::Auto);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(md, "~~~md\nUse\n```\nfences\n```\n~~~\n\n```\nplain\n```");
+    }
+
+    #[tokio::test]
+    async fn test_thematic_break() {
+        let html = "<p>Above</p><hr><p>Below</p>";
+        let md = body_string(convert_with(MarkdownConfig::new(), html).await).await;
+        assert_eq!(md, "Above\n\n* * *\n\nBelow");
+
+        for (style, marker) in [
+            (ThematicBreak::Asterisks, "***"),
+            (ThematicBreak::Dashes, "---"),
+            (ThematicBreak::Underscores, "___"),
+        ] {
+            let config = MarkdownConfig::new().thematic_break(style);
+            let md = body_string(convert_with(config, html).await).await;
+            assert_eq!(md, format!("Above\n\n{marker}\n\nBelow"));
+        }
+    }
+
+    fn fixed_clock() -> RetrievedAt {
+        Arc::new(|| "2024-06-01T12:00:00Z".to_string())
+    }
+
+    #[tokio::test]
+    async fn test_source_footer() {
+        let config = MarkdownConfig::new()
+            .source_attribution(DEFAULT_SOURCE_TEMPLATE)
+            .retrieved_at(fixed_clock());
+        let md = body_string(
+            convert_request(config, "<p>Intro</p>", markdown_request_to("/docs/intro")).await,
+        )
+        .await;
+        assert_eq!(
+            md,
+            "Intro\n\n---\nSource: http://example.com/docs/intro (retrieved 2024-06-01T12:00:00Z)"
+        );
+
+        let config = MarkdownConfig::new()
+            .base_url("https://docs.example.com")
+            .source_attribution("From \"{title}\" at {url}")
+            .retrieved_at(fixed_clock());
+        let response = convert_request(
+            config,
+            "<title>Intro</title><p>Intro</p>",
+            markdown_request_to("/intro"),
+        )
+        .await;
+        // The footer is counted
+        #[cfg(feature = "tokens")]
+        assert_ne!(response.headers()["x-markdown-tokens"], "1");
+        assert!(body_string(response)
+            .await
+            .ends_with("\n\n---\nFrom \"Intro\" at https://docs.example.com/intro"));
+    }
+
+    #[tokio::test]
+    async fn test_source_in_front_matter() {
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .source_attribution(DEFAULT_SOURCE_TEMPLATE)
+            .source_placement(SourcePlacement::FrontMatter)
+            .retrieved_at(fixed_clock());
+        let md = body_string(
+            convert_request(config, "<p>Intro</p>", markdown_request_to("/docs/intro")).await,
+        )
+        .await;
+        assert_eq!(
+            md,
+            "---\nsource: \"http://example.com/docs/intro\"\nretrieved_at: \"2024-06-01T12:00:00Z\"\n---\n\nIntro"
+        );
+    }
+
+    const JSON_LD_PAGE: &str = r#"<html><head><title>Post</title>
+        <script type="application/ld+json">
+          {"@context": "https://schema.org", "@type": "Article", "headline": "Post"}
+        </script>
+        <script type="application/ld+json">{"@type": "Article", </script>
+        <script type="application/ld+json">{"@type": "BreadcrumbList"}</script>
+        </head><body><p>Text</p></body></html>"#;
+
+    #[tokio::test]
+    async fn test_json_ld_in_front_matter() {
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .json_ld_types(["Article"]);
+        let md = body_string(convert_with(config, JSON_LD_PAGE).await).await;
+        assert_eq!(
+            md,
+            "---\ntitle: \"Post\"\njson_ld:\n  - {\"@type\":\"Article\",\"headline\":\"Post\"}\n---\n\nText"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_ld_disallowed_types_omitted() {
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .json_ld_types(["Product"]);
+        let md = body_string(convert_with(config, JSON_LD_PAGE).await).await;
+        assert_eq!(md, "---\ntitle: \"Post\"\n---\n\nText");
+    }
+
+    #[tokio::test]
+    async fn test_json_ld_code_block_without_front_matter() {
+        let config = MarkdownConfig::new()
+            .json_ld_types(["Article", "BreadcrumbList"])
+            .json_ld_placement(JsonLdPlacement::CodeBlock);
+        let md = body_string(convert_with(config, JSON_LD_PAGE).await).await;
+        assert!(md.starts_with("Post\n\nText\n\n```json\n["), "{md}");
+        assert!(md.ends_with("\n```"), "{md}");
+        let json = md
+            .split_once("```json\n")
+            .and_then(|(_, rest)| rest.strip_suffix("\n```"))
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(json).unwrap(),
+            serde_json::json!([
+                {"@type": "Article", "headline": "Post"},
+                {"@type": "BreadcrumbList"},
+            ])
+        );
+    }
+
+    async fn convert_request(
+        config: MarkdownConfig,
+        html: &'static str,
+        req: Request<Body>,
+    ) -> Response<Body> {
+        let app = Router::new()
+            .route(
+                "/{*path}",
+                get(move || async move { axum::response::Html(html) }),
+            )
+            .layer(MarkdownLayer::with_config(config));
+        app.oneshot(req).await.unwrap()
+    }
+
+    fn markdown_request_to(uri: &str) -> Request<Body> {
+        Request::builder()
+            .uri(uri)
+            .header(ACCEPT, "text/markdown")
+            .header(HOST, "example.com")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_canonical_absolute_in_front_matter_and_link() {
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .canonical_header(CanonicalHeader::Link);
+        let html = r#"<html><head><link rel="canonical" href="https://example.org/post"></head>
+            <body><p>Text</p></body></html>"#;
+        let response = convert_request(config, html, markdown_request_to("/post?utm=1")).await;
+
+        assert_eq!(
+            response.headers()[LINK],
+            "<https://example.org/post>; rel=\"canonical\""
+        );
+        let md = body_string(response).await;
+        assert_eq!(
+            md,
+            "---\ncanonical: \"https://example.org/post\"\n---\n\nText"
+        );
+    }
+
+    #[test]
+    fn test_content_location_values() {
+        let query = "/docs/intro?lang=en&markdown-page=2&q=a+b";
+        let reserved = "/a%2Fb/c;v=1/@x:y";
+        for (location, uri, expected) in [
+            (
+                ContentLocation::RequestUri,
+                query,
+                "/docs/intro?lang=en&q=a+b",
+            ),
+            (ContentLocation::RequestUri, reserved, reserved),
+            (
+                ContentLocation::PathSuffix(".md".into()),
+                query,
+                "/docs/intro.md?lang=en&q=a+b",
+            ),
+            (
+                ContentLocation::PathSuffix(".md".into()),
+                "/docs/",
+                "/docs/index.md",
+            ),
+            (
+                ContentLocation::PathSuffix(".md".into()),
+                reserved,
+                "/a%2Fb/c;v=1/@x:y.md",
+            ),
+            (
+                ContentLocation::PathSuffix(" (md)".into()),
+                "/é",
+                "/%C3%A9%20(md)",
+            ),
+            (
+                ContentLocation::QueryParam("format=md".into()),
+                query,
+                "/docs/intro?lang=en&q=a+b&format=md",
+            ),
+            (
+                ContentLocation::QueryParam("format=md".into()),
+                "/x?format=md",
+                "/x?format=md",
+            ),
+            (
+                ContentLocation::QueryParam("format=md".into()),
+                reserved,
+                "/a%2Fb/c;v=1/@x:y?format=md",
+            ),
+        ] {
+            assert_eq!(location.resolve(uri), expected, "{location:?} {uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_content_location() {
+        let config =
+            || MarkdownConfig::new().content_location(ContentLocation::PathSuffix(".md".into()));
+        let request = markdown_request_to("/docs/setup?lang=en");
+        let response = convert_request(config(), "<p>Text</p>", request).await;
+        assert_eq!(
+            response.headers()[CONTENT_LOCATION],
+            "/docs/setup.md?lang=en"
+        );
+
+        // Not on passthrough, and the canonical URL takes precedence
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = app_with(config()).oneshot(req).await.unwrap();
+        assert!(!response.headers().contains_key(CONTENT_LOCATION));
+        let html =
+            r#"<html><head><link rel="canonical" href="/intro"></head><body>Text</body></html>"#;
+        let config = config().canonical_header(CanonicalHeader::ContentLocation);
+        let response = convert_request(config, html, markdown_request_to("/docs/setup")).await;
+        assert_eq!(
+            response.headers()[CONTENT_LOCATION],
+            "http://example.com/intro"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_alternate_link() {
+        let app = Router::new()
+            .route(
+                "/docs/intro",
+                get(|| async {
+                    (
+                        [(LINK, "</style.css>; rel=\"preload\"")],
+                        axum::response::Html("<p>Text</p>"),
+                    )
+                }),
+            )
+            .route("/json", get(|| async { axum::Json(serde_json::json!({})) }))
+            .layer(MarkdownLayer::with_config(
+                MarkdownConfig::new().alternate_link(ContentLocation::PathSuffix(".md".into())),
+            ));
+        let links = |response: &Response<Body>| -> Vec<String> {
+            let links = response.headers().get_all(LINK).iter();
+            links.map(|v| v.to_str().unwrap().to_string()).collect()
+        };
+
+        let req = Request::builder()
+            .uri("/docs/intro?v=2&markdown-page=1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(
+            links(&response),
+            [
+                "</style.css>; rel=\"preload\"",
+                "</docs/intro.md?v=2>; rel=\"alternate\"; type=\"text/markdown\""
+            ]
+        );
+
+        let response = app
+            .clone()
+            .oneshot(markdown_request_to("/docs/intro?v=2"))
+            .await
+            .unwrap();
+        assert_eq!(
+            links(&response),
+            [
+                "</style.css>; rel=\"preload\"",
+                "</docs/intro?v=2>; rel=\"alternate\"; type=\"text/html\""
+            ]
+        );
+
+        let req = Request::builder().uri("/json").body(Body::empty()).unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        assert!(!response.headers().contains_key(LINK));
+    }
+
+    #[tokio::test]
+    async fn test_canonical_relative_header_without_front_matter() {
+        let config = MarkdownConfig::new().canonical_header(CanonicalHeader::ContentLocation);
+        let html = r#"<html><head><link rel="canonical" href="../intro"></head>
+            <body><p>Text</p></body></html>"#;
+        let response = convert_request(config, html, markdown_request_to("/docs/v2/setup")).await;
+
+        assert_eq!(
+            response.headers()[CONTENT_LOCATION],
+            "http://example.com/docs/intro"
+        );
+        assert_eq!(body_string(response).await, "Text");
+    }
+
+    #[tokio::test]
+    async fn test_canonical_relative_with_base_url_appends_link() {
+        let config = MarkdownConfig::new()
+            .canonical_header(CanonicalHeader::Link)
+            .base_url("https://public.example.com/");
+        let html = r#"<html><head><link rel="canonical" href="/docs/intro"></head></html>"#;
+        let app = Router::new()
+            .route(
+                "/{*path}",
+                get(move || async move {
+                    (
+                        [(LINK, "</style.css>; rel=preload")],
+                        axum::response::Html(html),
+                    )
+                }),
+            )
+            .layer(MarkdownLayer::with_config(config));
+        let response = app
+            .oneshot(markdown_request_to("/docs/intro?x=1"))
+            .await
+            .unwrap();
+
+        let links: Vec<_> = response.headers().get_all(LINK).iter().collect();
+        assert_eq!(
+            links,
+            [
+                "</style.css>; rel=preload",
+                "<https://public.example.com/docs/intro>; rel=\"canonical\""
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_canonical_missing() {
+        let config = MarkdownConfig::new()
+            .front_matter(true)
+            .canonical_header(CanonicalHeader::Link);
+        let html = "<html><head><title>T</title></head><body><p>Text</p></body></html>";
+        let response = convert_request(config, html, markdown_request_to("/page")).await;
+
+        assert!(response.headers().get(LINK).is_none());
+        let md = body_string(response).await;
+        assert_eq!(md, "---\ntitle: \"T\"\n---\n\nText");
+    }
+
+    #[test]
+    fn test_prefers_markdown() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "prefer",
+            HeaderValue::from_static("wait=10, return=markdown; x=1"),
+        );
+        assert!(prefers_markdown(&headers));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("prefer", HeaderValue::from_static("RETURN=\"Markdown\""));
+        assert!(prefers_markdown(&headers));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("prefer", HeaderValue::from_static("return=minimal"));
+        assert!(!prefers_markdown(&headers));
+    }
+
+    fn prefer_request() -> Request<Body> {
+        Request::builder()
+            .uri("/")
+            .header("prefer", "return=markdown")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_prefer_header_triggers_conversion() {
+        let app = Router::new()
+            .route("/", get(|| async { axum::response::Html(html_response()) }))
+            .layer(MarkdownLayer::with_config(
+                MarkdownConfig::new().prefer_header(true),
+            ));
+
+        let response = app.oneshot(prefer_request()).await.unwrap();
+
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/markdown; charset=utf-8"
+        );
+        assert_eq!(response.headers()["preference-applied"], "return=markdown");
+        assert_eq!(response.headers()[VARY], "Accept, Prefer");
+        assert_eq!(body_string(response).await, "# Hello\n\nWorld");
+    }
+
+    #[tokio::test]
+    async fn test_prefer_header_ignored_when_disabled() {
+        let response = app().oneshot(prefer_request()).await.unwrap();
+
+        let ct = response.headers()[CONTENT_TYPE].to_str().unwrap();
+        assert!(ct.contains("text/html"));
+        assert!(response.headers().get("preference-applied").is_none());
+        assert_eq!(response.headers()[VARY], "Accept");
+    }
+
+    #[tokio::test]
+    async fn test_accept_without_prefer_has_no_preference_applied() {
+        let app = Router::new()
+            .route("/", get(|| async { axum::response::Html(html_response()) }))
+            .layer(MarkdownLayer::with_config(
+                MarkdownConfig::new().prefer_header(true),
+            ));
+
+        let response = app.oneshot(markdown_request()).await.unwrap();
+
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/markdown; charset=utf-8"
+        );
+        assert!(response.headers().get("preference-applied").is_none());
+    }
+
+    fn counting_app(config: MarkdownConfig) -> (Router, Arc<std::sync::atomic::AtomicUsize>) {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let app = Router::new()
+            .route(
+                "/",
+                get(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    async { axum::response::Html(html_response()) }
+                }),
+            )
+            .layer(MarkdownLayer::with_config(config));
+        (app, calls)
+    }
+
+    #[tokio::test]
+    async fn test_require_same_origin() {
+        let app = app_with(MarkdownConfig::new().require_same_origin(true));
+        let request = |headers: &[(HeaderName, &str)]| {
+            let mut builder = Request::builder()
+                .uri("/")
+                .header(HOST, "example.com")
+                .header(ACCEPT, "text/markdown");
+            for (name, value) in headers {
+                builder = builder.header(name, *value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let content_type = |response: &Response<Body>| response.headers()[CONTENT_TYPE].clone();
+
+        let response = app
+            .clone()
+            .oneshot(request(&[(REFERER, "http://EXAMPLE.com/docs?a=1")]))
+            .await
+            .unwrap();
+        assert_eq!(content_type(&response), "text/markdown; charset=utf-8");
+        assert_eq!(response.headers()[VARY], "Accept, Origin, Referer");
+
+        // A cross-origin Origin wins over a matching Referer
+        for headers in [
+            &[(REFERER, "http://evil.example/page")][..],
+            &[(REFERER, "https://example.com/")],
+            &[(ORIGIN, "null"), (REFERER, "http://example.com/")],
+            &[
+                (ORIGIN, "http://evil.example"),
+                (REFERER, "http://example.com/"),
+            ],
+            &[],
+        ] {
+            let response = app.clone().oneshot(request(headers)).await.unwrap();
+            assert_eq!(
+                content_type(&response),
+                "text/html; charset=utf-8",
+                "{headers:?}"
+            );
+        }
+
+        let response = app
+            .oneshot(request(&[(ORIGIN, "http://example.com")]))
+            .await
+            .unwrap();
+        assert_eq!(content_type(&response), "text/markdown; charset=utf-8");
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_charset() {
+        use http::header::ACCEPT_CHARSET;
+        let request = |accept: &str, accept_charset: Option<&str>| {
+            let mut request = Request::builder().uri("/").header(ACCEPT, accept);
+            if let Some(charset) = accept_charset {
+                request = request.header(ACCEPT_CHARSET, charset);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        let refused = [
+            ("text/markdown;charset=bogus", None),
+            ("text/markdown", Some("x-bogus")),
+        ];
 
-    fn html_response() -> &'static str {
-        "<html><body><h1>Hello</h1><p>World</p></body></html>"
+        // Served as UTF-8 anyway
+        for (accept, accept_charset) in refused {
+            let (app, _) = counting_app(MarkdownConfig::new());
+            let response = app.oneshot(request(accept, accept_charset)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{accept}");
+            assert_eq!(
+                response.headers()[CONTENT_TYPE],
+                "text/markdown; charset=utf-8"
+            );
+            assert_eq!(body_string(response).await, "# Hello\n\nWorld");
+        }
+
+        for (accept, accept_charset) in refused {
+            let (app, calls) = counting_app(MarkdownConfig::new().strict_negotiation(true));
+            let response = app.oneshot(request(accept, accept_charset)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE, "{accept}");
+            let vary = response.headers()[VARY].to_str().unwrap();
+            assert!(vary.contains("Accept-Charset") && vary.contains("Accept,"));
+            assert_eq!(calls.load(Ordering::SeqCst), 0);
+        }
+
+        let (app, _) = counting_app(MarkdownConfig::new().strict_negotiation(true));
+        let response = app
+            .oneshot(request("text/markdown", Some("iso-8859-1, utf-8;q=0.5")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
     }
 
-    fn app() -> Router {
-        Router::new()
-            .route("/", get(|| async { axum::response::Html(html_response()) }))
+    #[tokio::test]
+    async fn test_url_cache_hit_within_ttl() {
+        let (app, calls) = counting_app(MarkdownConfig::new().cache_ttl(Duration::from_secs(60)));
+
+        let first = app.clone().oneshot(markdown_request()).await.unwrap();
+        let second = app.clone().oneshot(markdown_request()).await.unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            second.headers()[CONTENT_TYPE],
+            "text/markdown; charset=utf-8"
+        );
+        assert_eq!(
+            second.headers().get("x-markdown-tokens"),
+            first.headers().get("x-markdown-tokens")
+        );
+        assert_eq!(second.headers()[VARY], "Accept");
+        assert_eq!(body_string(second).await, body_string(first).await);
+
+        // HTML requests are never served from the cache
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let html = app.oneshot(req).await.unwrap();
+        assert!(html.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .contains("text/html"));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_url_cache_only_shares_safe_requests() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (counter, private_counter) = (Arc::clone(&calls), Arc::clone(&calls));
+        let app = Router::new()
             .route(
-                "/json",
-                get(|| async { axum::Json(serde_json::json!({"key": "value"})) }),
+                "/",
+                axum::routing::any(move |method: Method, headers: HeaderMap| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let host = headers.get(HOST).map_or("none", |h| h.to_str().unwrap());
+                    let html = format!("<p>{method} on {host}</p>");
+                    async move { ([(SET_COOKIE, "session=abc")], axum::response::Html(html)) }
+                }),
             )
-            .layer(MarkdownLayer::new())
+            .route(
+                "/private",
+                get(move || {
+                    private_counter.fetch_add(1, Ordering::SeqCst);
+                    async {
+                        (
+                            [(CACHE_CONTROL, "max-age=60, Private")],
+                            axum::response::Html("<p>Yours</p>"),
+                        )
+                    }
+                }),
+            )
+            .layer(MarkdownLayer::with_config(
+                MarkdownConfig::new().cache_ttl(Duration::from_secs(60)),
+            ));
+        let request = |method: Method, uri: &str, headers: &[(HeaderName, &str)]| {
+            let mut builder = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(ACCEPT, "text/markdown");
+            for (name, value) in headers {
+                builder = builder.header(name, *value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let send = |req: Request<Body>| {
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(req).await.unwrap();
+                let cookie = response.headers().contains_key(SET_COOKIE);
+                (body_string(response).await, cookie)
+            }
+        };
+
+        let a = [(HOST, "a.example")];
+        assert_eq!(
+            send(request(Method::GET, "/", &a)).await,
+            ("GET on a.example".to_string(), true)
+        );
+        // Served from the cache, without the first response's cookie
+        assert_eq!(
+            send(request(Method::GET, "/", &a)).await,
+            ("GET on a.example".to_string(), false)
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Writes reach the handler, and aren't stored
+        let (body, _) = send(request(Method::POST, "/", &a)).await;
+        assert_eq!(body, "POST on a.example");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let (body, _) = send(request(Method::GET, "/", &a)).await;
+        assert_eq!(body, "GET on a.example");
+
+        // Each host has its own entry
+        let (body, _) = send(request(Method::GET, "/", &[(HOST, "b.example")])).await;
+        assert_eq!(body, "GET on b.example");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Requests with credentials bypass the cache
+        let with_cookie = [(HOST, "a.example"), (COOKIE, "session=abc")];
+        send(request(Method::GET, "/", &with_cookie)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // As do responses marked private
+        let private = || request(Method::GET, "/private", &[]);
+        assert_eq!(send(private()).await.0, "Yours");
+        assert_eq!(send(private()).await.0, "Yours");
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn test_conversion_cache() {
+        let version = Arc::new(std::sync::atomic::AtomicUsize::new(1));
+        let calls = Arc::clone(&version);
+        let app = Router::new()
+            .route(
+                "/",
+                get(move || {
+                    let version = calls.fetch_add(1, Ordering::SeqCst) / 3 + 1;
+                    let cookie = format!("visit={version}");
+                    async move {
+                        (
+                            [(http::header::SET_COOKIE, cookie)],
+                            axum::response::Html(format!("<h1>Version {version}</h1>")),
+                        )
+                    }
+                }),
+            )
+            .layer(MarkdownLayer::with_config(MarkdownConfig::new().cache(
+                CacheConfig {
+                    max_entries: 8,
+                    max_bytes: 4096,
+                    ttl: None,
+                },
+            )));
+
+        // Calls 1 and 2 serve version 1, 3 onwards version 2
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            let response = app.clone().oneshot(markdown_request()).await.unwrap();
+            let headers = response.headers().clone();
+            assert_eq!(headers[CONTENT_TYPE], "text/markdown; charset=utf-8");
+            seen.push((
+                headers["x-markdown-cache"].to_str().unwrap().to_string(),
+                headers["set-cookie"].to_str().unwrap().to_string(),
+                body_string(response).await,
+            ));
+        }
+        let expected = [
+            ("MISS", "visit=1", "# Version 1"),
+            ("HIT", "visit=1", "# Version 1"),
+            ("MISS", "visit=2", "# Version 2"),
+            ("HIT", "visit=2", "# Version 2"),
+        ];
+        for (seen, expected) in seen.iter().zip(expected) {
+            assert_eq!(
+                (seen.0.as_str(), seen.1.as_str(), seen.2.as_str()),
+                expected
+            );
+        }
+
+        // Disabled by default
+        let response = app_with(MarkdownConfig::new())
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key("x-markdown-cache"));
+    }
+
+    /// An app with the conversion cache whose pages change on every call,
+    /// without an `ETag`, and its cache handle.
+    fn changing_app(cache: CacheConfig) -> (Router, MarkdownCacheHandle) {
+        changing_app_with(MarkdownConfig::new().cache(cache))
+    }
+
+    fn changing_app_with(config: MarkdownConfig) -> (Router, MarkdownCacheHandle) {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let layer = MarkdownLayer::with_config(config);
+        let handle = layer.cache_handle();
+        let app = Router::new()
+            .route(
+                "/{*path}",
+                get(move |uri: Uri| {
+                    let call = calls.fetch_add(1, Ordering::SeqCst);
+                    async move { axum::response::Html(format!("<p>{} {call}</p>", uri.path())) }
+                }),
+            )
+            .layer(layer);
+        (app, handle)
+    }
+
+    async fn cache_status(app: &Router, uri: &str) -> String {
+        let response = app.clone().oneshot(markdown_request_to(uri)).await.unwrap();
+        response.headers()["x-markdown-cache"]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_conversion_cache_ttl() {
+        let ttl = Duration::from_millis(50);
+        let (app, _) = changing_app(CacheConfig::default().ttl(ttl));
+        assert_eq!(cache_status(&app, "/a").await, "MISS");
+        // Trusted while fresh, although the page changed
+        let response = app
+            .clone()
+            .oneshot(markdown_request_to("/a"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-markdown-cache"], "HIT");
+        assert_eq!(body_string(response).await, "/a 0");
+
+        tokio::time::sleep(ttl * 2).await;
+        let response = app
+            .clone()
+            .oneshot(markdown_request_to("/a"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-markdown-cache"], "MISS");
+        assert_eq!(body_string(response).await, "/a 2");
+
+        // Without a TTL, the changed hash is a miss every time
+        let (app, _) = changing_app(CacheConfig::default());
+        assert_eq!(cache_status(&app, "/a").await, "MISS");
+        assert_eq!(cache_status(&app, "/a").await, "MISS");
+    }
+
+    #[tokio::test]
+    async fn test_conversion_cache_skips_private_responses() {
+        let config =
+            MarkdownConfig::new().cache(CacheConfig::default().ttl(Duration::from_secs(60)));
+        let app = Router::new()
+            .route(
+                "/account",
+                get(|headers: HeaderMap| async move {
+                    let cookie = headers[http::header::COOKIE].to_str().unwrap().to_string();
+                    (
+                        [(http::header::CACHE_CONTROL, "private")],
+                        axum::response::Html(format!("<p>Secret for {cookie}</p>")),
+                    )
+                }),
+            )
+            .layer(MarkdownLayer::with_config(config));
+        for user in ["user=alice", "user=bob"] {
+            let mut request = markdown_request_to("/account");
+            request
+                .headers_mut()
+                .insert(http::header::COOKIE, HeaderValue::from_static(user));
+            let response = app.clone().oneshot(request).await.unwrap();
+            // Neither looked up nor stored: always a miss
+            assert!(response.headers().get("x-markdown-cache").is_none());
+            assert_eq!(body_string(response).await, format!("Secret for {user}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_handle_invalidation() {
+        let cache = CacheConfig::default().ttl(Duration::from_secs(60));
+        let config = MarkdownConfig::new().cache(cache);
+        cache_handle_invalidation(config.clone()).await;
+        #[cfg(feature = "moka")]
+        cache_handle_invalidation(config.cache_backend(Arc::new(MokaCache::new(cache)))).await;
+    }
+
+    async fn cache_handle_invalidation(config: MarkdownConfig) {
+        let (app, handle) = changing_app_with(config);
+        let paths = ["/docs/a", "/docs/a?v=1", "/docs/b", "/docsets", "/blog"];
+        for path in paths {
+            assert_eq!(cache_status(&app, path).await, "MISS");
+        }
+
+        handle.invalidate("/docs/a");
+        let statuses = [
+            cache_status(&app, "/docs/a").await,
+            cache_status(&app, "/docs/a?v=1").await,
+            cache_status(&app, "/docs/b").await,
+            cache_status(&app, "/blog").await,
+        ];
+        assert_eq!(statuses, ["MISS", "MISS", "HIT", "HIT"]);
+
+        handle.clone().invalidate_prefix("/docs/");
+        let statuses = [
+            cache_status(&app, "/docs/a").await,
+            cache_status(&app, "/docs/b").await,
+            cache_status(&app, "/docsets").await,
+            cache_status(&app, "/blog").await,
+        ];
+        assert_eq!(statuses, ["MISS", "MISS", "HIT", "HIT"]);
+
+        handle.clear();
+        assert_eq!(cache_status(&app, "/blog").await, "MISS");
+        assert_eq!(cache_status(&app, "/blog").await, "HIT");
+    }
+
+    #[tokio::test]
+    async fn test_url_cache_miss_after_expiry() {
+        let (app, calls) = counting_app(MarkdownConfig::new().cache_ttl(Duration::from_millis(20)));
+
+        app.clone().oneshot(markdown_request()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        let response = app.oneshot(markdown_request()).await.unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(body_string(response).await, "# Hello\n\nWorld");
+    }
+
+    #[tokio::test]
+    async fn test_svg_handling() {
+        let html = "<p>Logo: <svg viewBox=\"0 0 10 10\"><title>Acme logo</title>\
+                    <desc>A red circle</desc><circle r=\"5\"/><text>hi</text></svg> end</p>\
+                    <p><svg><path d=\"M0 0\"/></svg>Done</p>";
+
+        let md = body_string(convert_with(MarkdownConfig::new(), html).await).await;
+        assert_eq!(md, "Logo: Acme logoA red circlehi end\n\nDone");
+
+        let config = MarkdownConfig::new().svg_handling(SvgHandling::TitleAsText);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(md, "Logo: Acme logo: A red circle end\n\nDone");
+
+        let config = MarkdownConfig::new().svg_handling(SvgHandling::Drop);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(md, "Logo: end\n\nDone");
+    }
+
+    /// Serves fixed HTML for `/docs/*` links and records every request.
+    #[derive(Default)]
+    struct MockResolver {
+        requested: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl LinkResolver for MockResolver {
+        fn resolve<'a>(&'a self, url: &'a str) -> ResolveFuture<'a> {
+            Box::pin(async move {
+                self.requested.lock().unwrap().push(url.to_string());
+                match url.rsplit('/').next() {
+                    Some("a") => Some("<p>Page <em>A</em></p>".to_string()),
+                    Some("big") => Some(format!("<p>{}</p>", "x".repeat(100))),
+                    _ => None,
+                }
+            })
+        }
+    }
+
+    const LINKING_PAGE: &str = r##"<p>See <a href="a">A</a>, <a href="/docs/a#top">A again</a>,
+        <a href="big">Big</a>, <a href="missing">Missing</a>,
+        <a href="https://other.org/a">elsewhere</a> and <a href="#self">here</a>.</p>"##;
+
+    #[tokio::test]
+    async fn test_link_resolver_inlines_same_origin_pages() {
+        let resolver = Arc::new(MockResolver::default());
+        let config = MarkdownConfig::new()
+            .link_resolver(resolver.clone())
+            .link_limits(LinkLimits {
+                max_links: 5,
+                max_page_bytes: 64,
+            });
+        let response =
+            convert_request(config, LINKING_PAGE, markdown_request_to("/docs/page")).await;
+        let md = body_string(response).await;
+        assert!(
+            md.ends_with("## Linked pages\n\n### <http://example.com/docs/a>\n\nPage _A_"),
+            "{md}"
+        );
+        assert_eq!(
+            *resolver.requested.lock().unwrap(),
+            [
+                "http://example.com/docs/a",
+                "http://example.com/docs/big",
+                "http://example.com/docs/missing",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_link_resolver_respects_max_links() {
+        let resolver = Arc::new(MockResolver::default());
+        let config = MarkdownConfig::new()
+            .link_resolver(resolver.clone())
+            .link_limits(LinkLimits {
+                max_links: 1,
+                ..LinkLimits::default()
+            });
+        let page = r#"<p><a href="missing">Missing</a> <a href="a">A</a></p>"#;
+        let md =
+            body_string(convert_request(config, page, markdown_request_to("/docs/page")).await)
+                .await;
+        assert!(!md.contains("Linked pages"), "{md}");
+        assert_eq!(resolver.requested.lock().unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "tokens")]
+    fn token_model_request(model: &str) -> Request<Body> {
+        Request::builder()
+            .uri("/")
+            .header(ACCEPT, "text/markdown")
+            .header("x-token-model", model)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_token_model_selected_per_request() {
+        let html = "<p>Hello, world! Ünïcödé text tokenizes differently.</p>";
+        let config = MarkdownConfig::new().token_models([TokenizerModel::R50kBase]);
+        let router = Router::new()
+            .route("/", get(move || async move { axum::response::Html(html) }))
+            .layer(MarkdownLayer::with_config(config));
+
+        let response = router
+            .clone()
+            .oneshot(token_model_request("R50K_BASE"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-markdown-tokens-model"], "r50k_base");
+        let md = body_string(response).await;
+        let expected = TokenizerModel::R50kBase.count(&md);
+        assert_ne!(expected, TokenizerModel::O200kBase.count(&md));
+
+        let response = router
+            .oneshot(token_model_request("r50k_base"))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()["x-markdown-tokens"],
+            expected.to_string().as_str()
+        );
+    }
+
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_token_model_unknown_falls_back() {
+        let config = MarkdownConfig::new().token_models([TokenizerModel::R50kBase]);
+        for model in ["gpt-9", "cl100k_base"] {
+            let response = app_with(config.clone())
+                .oneshot(token_model_request(model))
+                .await
+                .unwrap();
+            assert_eq!(response.headers()["x-markdown-tokens-model"], "o200k_base");
+            let tokens: usize = response.headers()["x-markdown-tokens"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            let md = body_string(response).await;
+            assert_eq!(tokens, TokenizerModel::O200kBase.count(&md), "{model}");
+        }
+    }
+
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_token_model_vary() {
+        let response = app()
+            .oneshot(token_model_request("r50k_base"))
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key("x-markdown-tokens-model"));
+        let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+        assert!(!vary
+            .iter()
+            .any(|v| v.to_str().unwrap().contains("X-Token-Model")));
+
+        let config = MarkdownConfig::new().token_models([TokenizerModel::R50kBase]);
+        for request in [token_model_request("r50k_base"), markdown_request()] {
+            let response = app_with(config.clone()).oneshot(request).await.unwrap();
+            let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+            assert!(
+                vary.iter()
+                    .any(|v| v.to_str().unwrap().contains("X-Token-Model")),
+                "{vary:?}"
+            );
+        }
+    }
+
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_html_tokens() {
+        let response = convert_with(MarkdownConfig::new(), html_response()).await;
+        assert!(!response.headers().contains_key("x-html-tokens"));
+
+        let response = convert_with(MarkdownConfig::new().html_tokens(true), html_response()).await;
+        let count =
+            |name: &str| -> usize { response.headers()[name].to_str().unwrap().parse().unwrap() };
+        assert_eq!(
+            count("x-html-tokens"),
+            TokenizerModel::O200kBase.count(html_response())
+        );
+        assert!(count("x-markdown-tokens") < count("x-html-tokens"));
+    }
+
+    const DEFINITION_LIST: &str = "<p>Glossary:</p><dl>\n  <dt>Crate</dt>\n  <dd>A <em>compilation</em> unit.</dd>\n  \
+        <dt>Trait</dt><dt>Interface</dt><dd><p>Shared behavior.</p><p>Like a typeclass.</p></dd>\n  \
+        <div><dt>Lifetime</dt><dd>How long a borrow lives.</dd><dd>Annotated with <code>\'a</code>.</dd></div>\n</dl><p>End</p>";
+
+    #[tokio::test]
+    async fn test_definition_list_styles() {
+        let config = MarkdownConfig::new().definition_lists(DefinitionListStyle::BoldTermParagraph);
+        let md = body_string(convert_with(config, DEFINITION_LIST).await).await;
+        assert_eq!(
+            md,
+            "Glossary:\n\n\
+             **Crate**\n: A _compilation_ unit.\n\n\
+             **Trait**\n**Interface**\n: Shared behavior.\n\n  Like a typeclass.\n\n\
+             **Lifetime**\n: How long a borrow lives.\n: Annotated with `\'a`.\n\n\
+             End"
+        );
+
+        let config = MarkdownConfig::new().definition_lists(DefinitionListStyle::Indented);
+        let md = body_string(convert_with(config, DEFINITION_LIST).await).await;
+        assert_eq!(
+            md,
+            "Glossary:\n\n\
+             **Crate**\n  A _compilation_ unit.\n\n\
+             **Trait**\n**Interface**\n  Shared behavior.\n\n  Like a typeclass.\n\n\
+             **Lifetime**\n  How long a borrow lives.\n  Annotated with `\'a`.\n\n\
+             End"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_definition_list_stray_entries() {
+        let config = MarkdownConfig::new().definition_lists(DefinitionListStyle::BoldTermParagraph);
+        let md = body_string(convert_with(config, "<dt>Loose</dt><dd>entry</dd>").await).await;
+        assert_eq!(md, "Loose\n\nentry");
+    }
+
+    #[tokio::test]
+    async fn test_math() {
+        let html = "<p>Let <math><msup><mi>x</mi><mn>2</mn></msup><mo>+</mo>\
+                    <mfrac><mn>1</mn><msub><mi>y</mi><mi>i</mi></msub></mfrac></math> \
+                    and \\(a_i * b\\).</p>\
+                    <math display=\"block\"><semantics><mrow><mi>e</mi></mrow>\
+                    <annotation encoding=\"application/x-tex\">\\sum_{n=0}^\\infty \\frac{1}{n!}</annotation>\
+                    </semantics></math><p>\\[E = mc^2\\]</p>";
+        let md = body_string(convert_with(MarkdownConfig::new(), html).await).await;
+        assert!(!md.contains('$'), "{md}");
+
+        let config = MarkdownConfig::new().math(MathNotation::Dollars);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(
+            md,
+            "Let $x^2 + \\frac{1}{y_i}$ and $a_i * b$.\n\n\
+             $$\n\\sum_{n=0}^\\infty \\frac{1}{n!}\n$$\n\n\
+             $$\nE = mc^2\n$$"
+        );
+
+        let config = MarkdownConfig::new()
+            .math(MathNotation::Fenced)
+            .code_fence(CodeFence::Tilde);
+        let md = body_string(convert_with(config, html).await).await;
+        assert!(
+            md.ends_with(
+                "~~~math\n\\sum_{n=0}^\\infty \\frac{1}{n!}\n~~~\n\n~~~math\nE = mc^2\n~~~"
+            ),
+            "{md}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_details_expand() {
+        let config = MarkdownConfig::new().details_handling(DetailsHandling::Expand);
+        let html = "<details><summary>More</summary><p>Hidden</p></details>";
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(md, "**More**\n\nHidden");
+    }
+
+    #[tokio::test]
+    async fn test_details_handling() {
+        let html = "<p>Intro</p><details open><summary>FAQ <em>1</em></summary>\n                    <p>Answer</p><details><summary>Nested</summary><p>Deeper</p></details>\n                    </details><summary>Stray</summary><details><p>No summary</p></details>";
+
+        let config = MarkdownConfig::new().details_handling(DetailsHandling::Expand);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(
+            md,
+            "Intro\n\n**FAQ _1_**\n\nAnswer\n\n**Nested**\n\nDeeper\n\nStray\n\nNo summary"
+        );
+
+        let config = MarkdownConfig::new().details_handling(DetailsHandling::SummaryOnly);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(md, "Intro\n\n**FAQ _1_**\n\nStray");
+
+        let config = MarkdownConfig::new().details_handling(DetailsHandling::KeepHtml);
+        let md = body_string(
+            convert_with(
+                config,
+                "<details><summary>More</summary><p>Hidden &amp; *</p></details>",
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(
+            md,
+            "<details><summary>More</summary><p>Hidden &amp; *</p></details>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_figure_captions() {
+        let html = "<p>Intro</p><figure><img src=\"/a.png\" alt=\"A\">\
+                    <figcaption>The <b>architecture</b>\n  overview</figcaption></figure>\
+                    <figure><figcaption>Listing 1</figcaption><pre><code>x</code></pre></figure>\
+                    <figure><img src=\"/b.png\"><figcaption> </figcaption></figure>\
+                    <figure><figcaption>Caption only</figcaption></figure>\
+                    <figcaption>Stray</figcaption><p>End</p>";
+        let md = body_string(convert_with(MarkdownConfig::new(), html).await).await;
+        assert_eq!(
+            md,
+            "Intro\n\n![A](/a.png)\n\n*The **architecture** overview*\n\n\
+             ```\nx\n```\n\n*Listing 1*\n\n![](/b.png)\n\n*Caption only*\n\n\
+             Stray\n\nEnd"
+        );
+    }
+
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_blocking_token_count_matches_inline() {
+        let html = "<h1>Large</h1><p>Counted on the blocking pool.</p>";
+        let inline = convert_with(MarkdownConfig::new().html_tokens(true), html).await;
+        let blocking = convert_with(
+            MarkdownConfig::new()
+                .html_tokens(true)
+                .blocking_token_threshold(0),
+            html,
+        )
+        .await;
+        for header in ["x-markdown-tokens", "x-html-tokens"] {
+            assert_eq!(inline.headers()[header], blocking.headers()[header]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocking_token_count_failure_omits_header() {
+        struct Failing;
+        impl TokenCounter for Failing {
+            #[allow(clippy::panic)]
+            fn count(&self, _text: &str) -> usize {
+                panic!("tokenizer failure");
+            }
+        }
+
+        let config = MarkdownConfig::new()
+            .token_counter(Arc::new(Failing))
+            .blocking_token_threshold(0);
+        let response = convert_with(config, "<h1>Hello</h1>").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key("x-markdown-tokens"));
+        assert_eq!(body_string(response).await, "# Hello");
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_token_counter_degrades() {
+        struct Uninitialized;
+        impl TokenCounter for Uninitialized {
+            #[allow(clippy::panic)]
+            fn count(&self, _text: &str) -> usize {
+                panic!("counted with an unavailable tokenizer");
+            }
+
+            fn is_available(&self) -> bool {
+                false
+            }
+        }
+
+        let config = MarkdownConfig::new()
+            .token_counter(Arc::new(Uninitialized))
+            .max_tokens(1);
+        let response = convert_with(config, "<h1>Hello</h1><p>World</p>").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key("x-markdown-tokens"));
+        assert!(!response.headers().contains_key("x-markdown-truncated"));
+        assert_eq!(response.headers()["x-markdown-tokens-unavailable"], "true");
+        assert_eq!(body_string(response).await, "# Hello\n\nWorld");
+    }
+
+    #[tokio::test]
+    async fn test_ensure_ready() {
+        struct Uninitialized;
+        impl TokenCounter for Uninitialized {
+            fn count(&self, _text: &str) -> usize {
+                0
+            }
+
+            fn is_available(&self) -> bool {
+                false
+            }
+        }
+
+        let mut config = MarkdownConfig::new();
+        config.token_models.push(TokenizerModel::Cl100kBase);
+        assert_eq!(ensure_ready(&config).await, Ok(()));
+        #[cfg(feature = "tokens")]
+        assert!(CL100K_BASE.is_some() && O200K_BASE.is_some());
+        let mut config = MarkdownConfig::new();
+        config
+            .additional_tokenizers
+            .push(("llama".to_string(), Arc::new(Uninitialized)));
+        assert_eq!(
+            ensure_ready(&config).await.map_err(|e| e.to_string()),
+            Err("token counter initialization failed: unavailable: llama".to_string())
+        );
+        let config = MarkdownConfig::new()
+            .token_counter(Arc::new(Uninitialized))
+            .count_tokens(false);
+        assert_eq!(ensure_ready(&config).await, Ok(()));
     }
 
+    #[cfg(feature = "tokens")]
     #[test]
-    fn test_wants_markdown_basic() {
+    fn test_tokenizer_load_failure_is_none() {
+        let failed: Result<tiktoken_rs::CoreBPE, _> = Err("vocabulary missing");
+        assert!(TokenizerModel::Cl100kBase.loaded(failed).is_none());
+        assert!(TokenizerModel::Cl100kBase.is_available());
+    }
+
+    #[tokio::test]
+    async fn test_original_content_length() {
+        let html = "<h1>Hello</h1><p>Wörld</p>";
+        let config = MarkdownConfig::new().original_content_length(true);
+        let response = convert_with(config, html).await;
+        assert_eq!(
+            response.headers()["x-original-content-length"],
+            html.len().to_string().as_str()
+        );
+
+        // The measured length wins over a wrong declared one
         let mut headers = HeaderMap::new();
-        headers.insert(ACCEPT, HeaderValue::from_static("text/markdown"));
-        assert!(wants_markdown(&headers));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("3"));
+        set_original_length(&mut headers, 27);
+        assert_eq!(headers["x-original-content-length"], "27");
+
+        let response = convert_with(MarkdownConfig::new(), html).await;
+        assert!(!response.headers().contains_key("x-original-content-length"));
+    }
+
+    #[tokio::test]
+    async fn test_compression_ratio() {
+        let config = MarkdownConfig::new()
+            .original_content_length(true)
+            .compression_ratio(true);
+        let response = convert_with(config, html_response()).await;
+        let header =
+            |name: &str| -> f64 { response.headers()[name].to_str().unwrap().parse().unwrap() };
+        let (ratio, original) = (
+            header("x-markdown-ratio"),
+            header("x-original-content-length"),
+        );
+        let body = to_bytes(response.into_body(), 1024 * 1024).await.unwrap();
+        assert!(ratio > 0.0 && ratio < 1.0);
+        #[allow(clippy::cast_precision_loss)]
+        let expected = body.len() as f64 / original;
+        assert!((ratio - expected).abs() < 0.0005);
+
+        let mut headers = HeaderMap::new();
+        set_compression_ratio(&mut headers, 12, 0);
+        assert_eq!(headers["x-markdown-ratio"], "inf");
+        set_compression_ratio(&mut headers, 1, 3);
+        assert_eq!(headers["x-markdown-ratio"], "0.333");
+
+        let response = convert_with(MarkdownConfig::new(), html_response()).await;
+        assert!(!response.headers().contains_key("x-markdown-ratio"));
+    }
+
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_header_names() {
+        let config = || {
+            MarkdownConfig::new()
+                .token_header_name("x-llm-token-count")
+                .and_then(|config| config.content_signal_header_name("x-content-signal"))
+                .unwrap()
+        };
+        let response = convert_with(config(), html_response()).await;
+        assert_eq!(response.headers()["x-llm-token-count"], "4");
+        assert!(!response.headers().contains_key("x-markdown-tokens"));
+        assert_eq!(
+            response.headers()["x-content-signal"],
+            "ai-train=yes, search=yes, ai-input=yes"
+        );
+        assert!(!response.headers().contains_key("content-signal"));
+
+        let config = config().tokens_as_trailer(true);
+        let response = app_with(config).oneshot(trailers_request()).await.unwrap();
+        assert_eq!(response.headers()[TRAILER], "x-llm-token-count");
+        let collected = response.into_body().collect().await.unwrap();
+        assert_eq!(collected.trailers().unwrap()["x-llm-token-count"], "4");
     }
 
     #[test]
-    fn test_wants_markdown_with_params() {
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            ACCEPT,
-            HeaderValue::from_static("text/markdown; charset=utf-8"),
-        );
-        assert!(wants_markdown(&headers));
+    fn test_invalid_header_names_rejected() {
+        assert!(MarkdownConfig::new()
+            .token_header_name("token count")
+            .is_err());
+        assert!(MarkdownConfig::new().token_header_name("").is_err());
+        assert!(MarkdownConfig::new()
+            .content_signal_header_name("content-signal:")
+            .is_err());
+        let name = HeaderName::from_static("x-tokens");
+        let config = MarkdownConfig::new().token_header_name(name).unwrap();
+        assert_eq!(config.token_header_name, "x-tokens");
     }
 
-    #[test]
-    fn test_wants_markdown_multiple_types() {
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            ACCEPT,
-            HeaderValue::from_static("text/html, text/markdown, application/json"),
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_tokens_as_trailer() {
+        let config = || MarkdownConfig::new().tokens_as_trailer(true);
+        let response = app_with(config())
+            .oneshot(trailers_request())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[TRAILER], "x-markdown-tokens");
+        assert!(!response.headers().contains_key("x-markdown-tokens"));
+
+        let collected = response.into_body().collect().await.unwrap();
+        let trailers = collected.trailers().unwrap().clone();
+        let body = collected.to_bytes();
+        let tokens: usize = trailers["x-markdown-tokens"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(
+            tokens,
+            TokenizerModel::O200kBase.count(std::str::from_utf8(&body).unwrap())
         );
-        assert!(wants_markdown(&headers));
-    }
 
-    #[test]
-    fn test_does_not_want_markdown_html() {
-        let mut headers = HeaderMap::new();
-        headers.insert(ACCEPT, HeaderValue::from_static("text/html"));
-        assert!(!wants_markdown(&headers));
+        // An HTTP/1.1 client that doesn't read trailers keeps the header
+        let response = convert_with(config(), html_response()).await;
+        assert!(!response.headers().contains_key(TRAILER));
+        assert_eq!(response.headers()["x-markdown-tokens"], tokens.to_string());
     }
 
-    #[test]
-    fn test_does_not_want_markdown_wildcard() {
-        let mut headers = HeaderMap::new();
-        headers.insert(ACCEPT, HeaderValue::from_static("*/*"));
-        assert!(!wants_markdown(&headers));
+    /// A markdown request from a client that reads trailers.
+    #[cfg(feature = "tokens")]
+    fn trailers_request() -> Request<Body> {
+        let mut req = markdown_request();
+        req.headers_mut()
+            .insert(TE, HeaderValue::from_static("trailers"));
+        req
     }
 
-    #[test]
-    fn test_does_not_want_markdown_text_wildcard() {
-        let mut headers = HeaderMap::new();
-        headers.insert(ACCEPT, HeaderValue::from_static("text/*"));
-        assert!(!wants_markdown(&headers));
+    /// Send `app`, served on a local port, a markdown request with hyper's
+    /// client over HTTP/1.1 or (with `http2`) HTTP/2, returning the
+    /// response head, body, and trailers.
+    #[cfg(feature = "tokens")]
+    async fn round_trip(
+        app: Router,
+        http2: bool,
+        te: Option<&str>,
+    ) -> (http::response::Parts, Bytes, Option<HeaderMap>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let io = hyper_util::rt::TokioIo::new(stream);
+        let mut req = Request::builder()
+            .uri("/")
+            .header(HOST, addr.to_string())
+            .header(ACCEPT, "text/markdown");
+        if let Some(te) = te {
+            req = req.header(TE, te);
+        }
+        let req = req.body(http_body_util::Empty::<Bytes>::new()).unwrap();
+        let response = if http2 {
+            let executor = hyper_util::rt::TokioExecutor::new();
+            let (mut sender, connection) = hyper::client::conn::http2::handshake(executor, io)
+                .await
+                .unwrap();
+            tokio::spawn(connection);
+            sender.send_request(req).await
+        } else {
+            let (mut sender, connection) = hyper::client::conn::http1::handshake(io).await.unwrap();
+            tokio::spawn(connection);
+            sender.send_request(req).await
+        };
+        let (parts, body) = response.unwrap().into_parts();
+        let collected = body.collect().await.unwrap();
+        let trailers = collected.trailers().cloned();
+        (parts, collected.to_bytes(), trailers)
     }
 
+    #[cfg(feature = "tokens")]
     #[tokio::test]
-    async fn test_html_passthrough_without_accept() {
-        let app = app();
+    async fn test_tokens_as_trailer_over_http1() {
+        let app = || app_with(MarkdownConfig::new().tokens_as_trailer(true));
 
-        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let (parts, body, trailers) = round_trip(app(), false, Some("trailers")).await;
+        assert_eq!(parts.headers[TRANSFER_ENCODING], "chunked");
+        assert!(!parts.headers.contains_key(CONTENT_LENGTH));
+        assert_eq!(parts.headers[TRAILER], "x-markdown-tokens");
+        assert!(!parts.headers.contains_key("x-markdown-tokens"));
+        let count = TokenizerModel::O200kBase.count(std::str::from_utf8(&body).unwrap());
+        assert_eq!(trailers.unwrap()["x-markdown-tokens"], count.to_string());
 
-        let response = app.oneshot(req).await.unwrap();
+        // Without `TE: trailers` the count stays a header
+        let (parts, _, trailers) = round_trip(app(), false, None).await;
+        assert_eq!(parts.headers["x-markdown-tokens"], count.to_string());
+        assert!(!parts.headers.contains_key(TRAILER));
+        assert!(trailers.is_none());
+    }
 
-        assert_eq!(response.status(), StatusCode::OK);
-        let ct = response
-            .headers()
-            .get(CONTENT_TYPE)
-            .unwrap()
-            .to_str()
-            .unwrap();
-        assert!(ct.contains("text/html"));
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_tokens_as_trailer_over_http2() {
+        let app = app_with(MarkdownConfig::new().tokens_as_trailer(true));
+        // HTTP/2 clients always read trailers, and needn't say so
+        let (parts, body, trailers) = round_trip(app, true, None).await;
+        assert_eq!(parts.version, http::Version::HTTP_2);
+        assert_eq!(parts.headers[TRAILER], "x-markdown-tokens");
+        assert!(!parts.headers.contains_key("x-markdown-tokens"));
+        let count = TokenizerModel::O200kBase.count(std::str::from_utf8(&body).unwrap());
+        assert_eq!(trailers.unwrap()["x-markdown-tokens"], count.to_string());
+    }
 
-        // Should still have Vary: Accept
-        let vary = response.headers().get(VARY).unwrap().to_str().unwrap();
-        assert!(vary.contains("Accept"));
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_tokens_as_trailer_from_cache() {
+        let config = MarkdownConfig::new()
+            .tokens_as_trailer(true)
+            .cache_ttl(Duration::from_secs(60));
+        let app = app_with(config);
+        for _ in 0..2 {
+            let response = app.clone().oneshot(trailers_request()).await.unwrap();
+            assert_eq!(response.headers()[TRAILER], "x-markdown-tokens");
+            let collected = response.into_body().collect().await.unwrap();
+            assert!(collected
+                .trailers()
+                .unwrap()
+                .contains_key("x-markdown-tokens"));
+        }
     }
 
     #[tokio::test]
-    async fn test_converts_html_to_markdown() {
-        let app = app();
+    async fn test_convertible_types() {
+        let config =
+            MarkdownConfig::new().convertible_types(["text/html", "application/vnd.docs+html"]);
+        let app = Router::new()
+            .route(
+                "/custom",
+                get(|| async {
+                    (
+                        [(CONTENT_TYPE, "Application/VND.docs+html; charset=utf-8")],
+                        "<h1>Custom</h1>",
+                    )
+                }),
+            )
+            .route(
+                "/other",
+                get(|| async { ([(CONTENT_TYPE, "application/xhtml+xml")], "<h1>Other</h1>") }),
+            )
+            .layer(MarkdownLayer::with_config(config));
 
-        let req = Request::builder()
-            .uri("/")
-            .header(ACCEPT, "text/markdown")
-            .body(Body::empty())
+        let response = app
+            .clone()
+            .oneshot(markdown_request_to("/custom"))
+            .await
             .unwrap();
+        assert!(response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/markdown"));
+        assert_eq!(body_string(response).await, "# Custom");
 
-        let response = app.oneshot(req).await.unwrap();
+        let response = app.oneshot(markdown_request_to("/other")).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/xhtml+xml");
+        assert_eq!(body_string(response).await, "<h1>Other</h1>");
+    }
 
-        assert_eq!(response.status(), StatusCode::OK);
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_max_tokens_truncation() {
+        let html = "<h1>Budget</h1><p>Agents read every word of this page, one token at a time, \
+                    until the budget runs out.</p>";
+        let full = body_string(convert_with(MarkdownConfig::new(), html).await).await;
+        let total = TokenizerModel::O200kBase.count(&full);
 
-        let ct = response
-            .headers()
-            .get(CONTENT_TYPE)
-            .unwrap()
-            .to_str()
-            .unwrap();
-        assert_eq!(ct, "text/markdown; charset=utf-8");
+        let response = convert_with(MarkdownConfig::new().max_tokens(total), html).await;
+        assert!(!response.headers().contains_key("x-markdown-truncated"));
+        assert_eq!(
+            response.headers()["x-markdown-tokens"],
+            total.to_string().as_str()
+        );
+        assert_eq!(body_string(response).await, full);
 
-        // Should have token count header
-        assert!(response.headers().get("x-markdown-tokens").is_some());
-        let tokens: usize = response
-            .headers()
-            .get("x-markdown-tokens")
-            .unwrap()
+        let config = MarkdownConfig::new()
+            .max_tokens(total - 1)
+            .truncation_notice(" …");
+        let response = convert_with(config, html).await;
+        assert_eq!(response.headers()["x-markdown-truncated"], "true");
+        assert_eq!(
+            response.headers()["x-markdown-original-tokens"],
+            total.to_string().as_str()
+        );
+        let tokens: usize = response.headers()["x-markdown-tokens"]
             .to_str()
             .unwrap()
             .parse()
             .unwrap();
-        assert!(tokens > 0);
+        let md = body_string(response).await;
+        assert!(md.ends_with(" …"), "{md}");
+        assert!(full.starts_with(md.strip_suffix(" …").unwrap()));
+        assert_eq!(tokens, TokenizerModel::O200kBase.count(&md));
+        assert!(tokens < total);
+    }
 
-        // Should have Content-Signal header
-        let signal = response
-            .headers()
-            .get("content-signal")
-            .unwrap()
-            .to_str()
-            .unwrap();
-        assert_eq!(signal, "ai-train=yes, search=yes, ai-input=yes");
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_warm_up_initializes_tokenizer() {
+        // No other test counts with p50k_edit, so its load count is this test's alone
+        let loads = || TOKENIZER_LOADS[TokenizerModel::P50kEdit as usize].load(Ordering::Relaxed);
+        let config = MarkdownConfig::new().tokenizer(TokenizerModel::P50kEdit);
+        assert_eq!(loads(), 0);
 
-        // Should have Vary: Accept
-        let vary = response.headers().get(VARY).unwrap().to_str().unwrap();
-        assert!(vary.contains("Accept"));
+        warm_up(&config);
+        assert_eq!(loads(), 1);
 
-        // Body should be markdown
-        let body = to_bytes(response.into_body(), 1024 * 1024).await.unwrap();
-        let md = String::from_utf8(body.to_vec()).unwrap();
-        assert!(md.contains("# Hello"));
-        assert!(md.contains("World"));
+        let response = convert_with(config, html_response()).await;
+        assert!(response.headers().contains_key("x-markdown-tokens"));
+        assert_eq!(loads(), 1);
+    }
+
+    #[cfg(feature = "tokens")]
+    const PAGED: &str =
+        "<h1>Guide</h1><p>one two three four five</p><p>six seven eight nine ten</p>\
+                         <pre><code>let a = 1;\n\nlet b = 2;</code></pre><p>end</p>";
+
+    #[cfg(feature = "tokens")]
+    fn page_request(uri: &str, page: &str) -> Request<Body> {
+        let mut req = markdown_request_to(uri);
+        req.headers_mut()
+            .insert("x-markdown-page", HeaderValue::from_str(page).unwrap());
+        req
     }
 
+    #[cfg(feature = "tokens")]
     #[tokio::test]
-    async fn test_non_html_passthrough() {
-        let app = app();
+    async fn test_page_tokens_pages() {
+        let config = || MarkdownConfig::new().count_tokens(true).page_tokens(16);
+        let response = convert_request(config(), PAGED, page_request("/doc?a=1", "1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-markdown-page"], "1");
+        assert_eq!(response.headers()["x-markdown-total-pages"], "3");
+        assert_eq!(response.headers()["x-markdown-tokens"], "14");
+        assert_eq!(
+            response.headers()[LINK],
+            "<http://example.com/doc?a=1&markdown-page=2>; rel=\"next\""
+        );
+        assert_eq!(
+            body_string(response).await,
+            "# Guide\n\none two three four five\n\nsix seven eight nine ten"
+        );
 
-        let req = Request::builder()
-            .uri("/json")
-            .header(ACCEPT, "text/markdown")
-            .body(Body::empty())
-            .unwrap();
+        // The last page is partial and has no next link
+        let req = markdown_request_to("/doc?markdown-page=3");
+        let response = convert_request(config(), PAGED, req).await;
+        assert_eq!(response.headers()["x-markdown-page"], "3");
+        assert!(response.headers().get(LINK).is_none());
+        assert_eq!(body_string(response).await, "end");
+    }
 
-        let response = app.oneshot(req).await.unwrap();
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_page_tokens_out_of_range() {
+        let config = MarkdownConfig::new().count_tokens(true).page_tokens(16);
+        let response = convert_request(config, PAGED, page_request("/doc", "4")).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()["x-markdown-total-pages"], "3");
+        assert!(response.headers().get("x-markdown-page").is_none());
+        assert!(response.headers().get("x-markdown-tokens").is_none());
+        let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+        assert!(
+            vary.iter()
+                .any(|v| v.to_str().unwrap().contains("X-Markdown-Page")),
+            "{vary:?}"
+        );
+        assert_eq!(body_string(response).await, "");
 
+        // Without page_tokens the header is ignored
+        let response =
+            convert_request(MarkdownConfig::new(), PAGED, page_request("/doc", "4")).await;
         assert_eq!(response.status(), StatusCode::OK);
-        let ct = response
-            .headers()
-            .get(CONTENT_TYPE)
-            .unwrap()
-            .to_str()
-            .unwrap();
-        assert!(ct.contains("application/json"));
+        assert!(response.headers().get("x-markdown-total-pages").is_none());
     }
 
+    #[cfg(feature = "tokens")]
     #[tokio::test]
-    async fn test_body_too_large_returns_502() {
-        let config = MarkdownConfig::new().max_body_size(10); // 10 bytes max
-        let app = Router::new()
-            .route("/", get(|| async {
-                axum::response::Html("<html><body><h1>This body is definitely larger than 10 bytes</h1></body></html>")
-            }))
-            .layer(MarkdownLayer::with_config(config));
+    async fn test_page_tokens_share_cached_conversion() {
+        let config = MarkdownConfig::new()
+            .cache_ttl(Duration::from_secs(60))
+            .page_tokens(1);
+        let (app, calls) = counting_app(config);
+        let first = app.clone().oneshot(page_request("/", "1")).await.unwrap();
+        let second = app.clone().oneshot(page_request("/", "2")).await.unwrap();
+        let whole = app.oneshot(markdown_request_to("/")).await.unwrap();
 
-        let req = Request::builder()
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(body_string(first).await, "# Hello");
+        assert_eq!(second.headers()["x-markdown-page"], "2");
+        assert_eq!(body_string(second).await, "World");
+        assert_eq!(body_string(whole).await, "# Hello\n\nWorld");
+    }
+
+    #[cfg(feature = "tokens")]
+    fn max_tokens_request(tokens: &str) -> Request<Body> {
+        Request::builder()
             .uri("/")
             .header(ACCEPT, "text/markdown")
+            .header("x-max-tokens", tokens)
             .body(Body::empty())
-            .unwrap();
-
-        let response = app.oneshot(req).await.unwrap();
-
-        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
-        let ct = response
-            .headers()
-            .get(CONTENT_TYPE)
             .unwrap()
-            .to_str()
-            .unwrap();
-        assert!(ct.contains("text/plain"));
-        let vary = response.headers().get(VARY).unwrap().to_str().unwrap();
-        assert!(vary.contains("Accept"));
-
-        let body = to_bytes(response.into_body(), 1024).await.unwrap();
-        let text = String::from_utf8(body.to_vec()).unwrap();
-        assert!(text.contains("too large"));
     }
 
+    #[cfg(feature = "tokens")]
     #[tokio::test]
-    async fn test_custom_config_no_signal() {
-        let config = MarkdownConfig::new().no_content_signal();
-        let app = Router::new()
-            .route("/", get(|| async { axum::response::Html(html_response()) }))
+    async fn test_max_tokens_header_clamped_to_ceiling() {
+        let html = "<p>one two three four five six seven eight nine ten eleven twelve</p>";
+        let config = MarkdownConfig::new()
+            .max_tokens(10)
+            .max_tokens_header(6)
+            .truncation_notice("");
+        let router = Router::new()
+            .route("/", get(move || async move { axum::response::Html(html) }))
             .layer(MarkdownLayer::with_config(config));
+        let tokens = |response: &Response<Body>| -> usize {
+            response.headers()["x-markdown-tokens"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
 
-        let req = Request::builder()
-            .uri("/")
-            .header(ACCEPT, "text/markdown")
-            .body(Body::empty())
+        for (header, expected) in [("3", 3), ("1000", 6), ("0", 10), ("lots", 10)] {
+            let response = router
+                .clone()
+                .oneshot(max_tokens_request(header))
+                .await
+                .unwrap();
+            assert_eq!(tokens(&response), expected, "X-Max-Tokens: {header}");
+        }
+    }
+
+    #[cfg(feature = "tokens")]
+    #[tokio::test]
+    async fn test_max_tokens_header_vary() {
+        let response = app().oneshot(max_tokens_request("2")).await.unwrap();
+        assert!(!response.headers().contains_key("x-markdown-truncated"));
+        let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+        assert!(!vary
+            .iter()
+            .any(|v| v.to_str().unwrap().contains("X-Max-Tokens")));
+
+        let config = MarkdownConfig::new().max_tokens_header(100);
+        let response = app_with(config)
+            .oneshot(max_tokens_request("2"))
+            .await
             .unwrap();
+        assert_eq!(response.headers()["x-markdown-truncated"], "true");
+        let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+        assert!(
+            vary.iter()
+                .any(|v| v.to_str().unwrap().contains("X-Max-Tokens")),
+            "{vary:?}"
+        );
+    }
 
-        let response = app.oneshot(req).await.unwrap();
+    #[tokio::test]
+    async fn test_list_indent() {
+        let html = "<ul><li>One<ol><li>Nested</li><li>Also</li></ol></li><li>Two</li></ul>";
+        let md = body_string(convert_with(MarkdownConfig::new(), html).await).await;
+        assert_eq!(md, "* One\n  1. Nested\n  2. Also\n* Two");
 
-        assert_eq!(response.status(), StatusCode::OK);
-        assert!(response.headers().get("content-signal").is_none());
+        let md = body_string(convert_with(MarkdownConfig::new().list_indent(4), html).await).await;
+        assert_eq!(md, "* One\n    1. Nested\n    2. Also\n* Two");
     }
 
-    #[test]
-    fn test_append_vary_preserves_multiple_vary_headers() {
-        let mut response = Response::builder()
-            .status(StatusCode::OK)
-            .body(Body::empty())
-            .unwrap();
-        response
-            .headers_mut()
-            .append(VARY, HeaderValue::from_static("Cookie"));
-        response
-            .headers_mut()
-            .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    #[tokio::test]
+    async fn test_list_indent_round_trip() {
+        let html = "<ol><li><p>One</p><ul><li>Sub</li></ul><pre><code>code\n</code></pre></li>\
+                    <li><p>Two</p></li></ol>";
+        for spaces in [2, 4] {
+            let config = MarkdownConfig::new().list_indent(spaces);
+            let md = body_string(convert_with(config, html).await).await;
+            let mut rendered = String::new();
+            pulldown_cmark::html::push_html(&mut rendered, pulldown_cmark::Parser::new(&md));
+            assert_eq!(rendered.replace('\n', ""), html.replace('\n', ""), "{md}");
+        }
+    }
 
-        let response = append_vary(response);
+    #[tokio::test]
+    async fn test_normalize_blockquotes() {
+        let html = "<blockquote><p>Outer</p><blockquote><p>Inner</p><p>Inner 2</p>\
+                    </blockquote><p>Back</p></blockquote>";
+        let md = body_string(convert_with(MarkdownConfig::new(), html).await).await;
+        assert_eq!(md, "> Outer\n>\n>> Inner\n>>\n>> Inner 2\n>\n> Back");
 
-        let vary = response.headers().get(VARY).unwrap().to_str().unwrap();
-        assert!(
-            vary.contains("Cookie"),
-            "Vary should contain Cookie, got: {vary}"
-        );
-        assert!(
-            vary.contains("Accept-Encoding"),
-            "Vary should contain Accept-Encoding, got: {vary}"
-        );
-        assert!(
-            vary.contains("Accept"),
-            "Vary should contain Accept, got: {vary}"
-        );
+        let config = MarkdownConfig::new().normalize_blockquotes(false);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(md, "> Outer\n> \n> > Inner\n> > \n> > Inner 2\n> \n> Back");
     }
 
-    #[test]
-    fn test_append_vary_multiple_headers_already_has_accept() {
-        let mut response = Response::builder()
-            .status(StatusCode::OK)
-            .body(Body::empty())
-            .unwrap();
-        response
-            .headers_mut()
-            .append(VARY, HeaderValue::from_static("Cookie"));
-        response
-            .headers_mut()
-            .append(VARY, HeaderValue::from_static("Accept"));
+    #[tokio::test]
+    async fn test_decode_entities() {
+        let html = "<p>Fish &amp;amp; chips&nbsp;today</p><pre><code>a&nbsp;&amp;amp;</code></pre>";
+        let md = body_string(convert_with(MarkdownConfig::new(), html).await).await;
+        assert_eq!(md, "Fish & chips\u{a0}today\n\n```\na\u{a0}&amp;\n```");
 
-        let response = append_vary(response);
+        let config = MarkdownConfig::new().nbsp_to_space(true);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(md, "Fish & chips today\n\n```\na\u{a0}&amp;\n```");
 
-        let vary = response.headers().get(VARY).unwrap().to_str().unwrap();
-        assert!(
-            vary.contains("Cookie"),
-            "Vary should contain Cookie, got: {vary}"
-        );
-        // Should not duplicate Accept — check that the consolidated value
-        // has exactly one "Accept" token (not inside another word)
-        let accept_count = vary
-            .split(',')
-            .filter(|p| p.trim().eq_ignore_ascii_case("accept"))
-            .count();
+        let config = MarkdownConfig::new().decode_entities(false);
+        let md = body_string(convert_with(config, html).await).await;
+        assert_eq!(md, "Fish &amp; chips\u{a0}today\n\n```\na\u{a0}&amp;\n```");
+    }
+
+    #[tokio::test]
+    async fn test_shadow_mode() {
+        let response = app_with(MarkdownConfig::new().shadow_mode(true))
+            .oneshot(markdown_request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        assert_eq!(response.headers()[VARY], "Accept");
+        let tokens = response.headers().get("x-markdown-tokens").cloned();
+        assert_eq!(body_string(response).await, html_response());
+
+        let converted = app().oneshot(markdown_request()).await.unwrap();
         assert_eq!(
-            accept_count, 1,
-            "Accept should appear exactly once, got: {vary}"
+            tokens.as_ref(),
+            converted.headers().get("x-markdown-tokens")
         );
+        #[cfg(feature = "tokens")]
+        assert!(tokens.is_some());
+
+        // HTML requests are untouched
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = app_with(MarkdownConfig::new().shadow_mode(true))
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key("x-markdown-tokens"));
     }
 }
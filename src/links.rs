@@ -0,0 +1,85 @@
+//! Inlining of same-origin linked pages through a [`LinkResolver`].
+
+use crate::{html_converter, metadata::PageMetadata, url, LinkResolver, MarkdownConfig};
+
+/// Append the converted content of the same-origin pages linked from the
+/// page at `page_url` to `markdown`, one `###` section per page under a
+/// `## Linked pages` heading. Nothing is added if no link resolves.
+pub async fn inline(
+    markdown: &mut String,
+    resolver: &dyn LinkResolver,
+    metadata: &PageMetadata,
+    page_url: &str,
+    config: &MarkdownConfig,
+) {
+    let mut sections = Vec::new();
+    for link in candidates(metadata, page_url, config.link_limits.max_links) {
+        let Some(html) = resolver.resolve(&link).await else {
+            continue;
+        };
+        if html.len() > config.link_limits.max_page_bytes {
+            continue;
+        }
+        // Linked pages are converted plainly: no metadata, and their own
+        // links are not followed
+        let Ok(content) = html_converter(config, None).convert(&html) else {
+            continue;
+        };
+        let content = content.trim();
+        if !content.is_empty() {
+            sections.push(format!("### <{}>\n\n{content}", url::encode(&link)));
+        }
+    }
+    if sections.is_empty() {
+        return;
+    }
+    if !markdown.is_empty() {
+        markdown.push_str("\n\n");
+    }
+    markdown.push_str("## Linked pages\n\n");
+    markdown.push_str(&sections.join("\n\n"));
+}
+
+/// The first `max_links` distinct same-origin links, without fragments,
+/// excluding the page itself.
+fn candidates(metadata: &PageMetadata, page_url: &str, max_links: usize) -> Vec<String> {
+    let page = url::strip_fragment(page_url);
+    let mut links: Vec<String> = Vec::new();
+    for link in &metadata.links {
+        let link = url::strip_fragment(link);
+        if links.len() == max_links {
+            break;
+        }
+        if link != page && url::same_origin(link, page) && !links.iter().any(|l| l == link) {
+            links.push(link.to_string());
+        }
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates_same_origin_deduplicated() {
+        let metadata = PageMetadata {
+            links: [
+                "https://example.com/a#top",
+                "https://example.com/page",
+                "https://other.org/b",
+                "mailto:me@example.com",
+                "https://example.com/a",
+                "https://EXAMPLE.com/c?x=1",
+                "https://example.com/d",
+            ]
+            .map(String::from)
+            .to_vec(),
+            ..PageMetadata::default()
+        };
+        assert_eq!(
+            candidates(&metadata, "https://example.com/page", 2),
+            ["https://example.com/a", "https://EXAMPLE.com/c?x=1"]
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! Post-pass normalizing the indentation of nested lists.
+
+/// An open list item.
+struct Item {
+    /// Column of the item's marker in the input.
+    marker: usize,
+    /// Column of the item's content in the input, capped at four past the
+    /// marker: htmd indents nested blocks by four even after wide markers
+    /// such as `10.`.
+    content: usize,
+    /// Column its continuation lines and nested blocks get in the output.
+    output: usize,
+}
+
+/// Re-indent list items so each nesting level is indented by `indent`
+/// spaces, with one space after each marker. Children of an item with a
+/// wider marker, such as `10.`, go to its content column instead, so they
+/// stay inside it. Continuation lines and code blocks inside items keep
+/// their position relative to the item.
+pub fn normalize(markdown: &str, indent: usize) -> String {
+    let mut stack: Vec<Item> = Vec::new();
+    // Fence character and length of the open fenced code block
+    let mut fence: Option<(char, usize)> = None;
+    let mut out: Vec<String> = Vec::new();
+
+    for line in markdown.split('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let col = line.len() - trimmed.len();
+
+        if let Some((ch, len)) = fence {
+            if fence_run(trimmed).is_some_and(|(c, l)| c == ch && l >= len)
+                && trimmed.trim_start_matches(ch).trim().is_empty()
+            {
+                fence = None;
+            }
+            out.push(reindent(trimmed, col, stack.last()));
+            continue;
+        }
+
+        if trimmed.trim().is_empty() {
+            out.push(if stack.is_empty() { line } else { "" }.to_string());
+            continue;
+        }
+
+        // Anything indented past an item's marker belongs to the item
+        while stack.last().is_some_and(|item| item.marker >= col) {
+            stack.pop();
+        }
+
+        if let Some((marker, content_col)) = list_marker(trimmed) {
+            let start = stack.last().map_or(0, |item| item.output);
+            let content = &trimmed[content_col..];
+            let line = format!("{}{marker} {content}", " ".repeat(start));
+            out.push(line.trim_end().to_string());
+            stack.push(Item {
+                marker: col,
+                content: col + content_col.min(4),
+                output: start + indent.max(marker.len() + 1),
+            });
+            continue;
+        }
+
+        fence = fence_run(trimmed).filter(|(_, len)| *len >= 3);
+        out.push(reindent(trimmed, col, stack.last()));
+    }
+    out.join("\n")
+}
+
+/// `trimmed` moved from column `col` to the same offset within `item`'s
+/// output content column (unchanged outside any list).
+fn reindent(trimmed: &str, col: usize, item: Option<&Item>) -> String {
+    let col = item.map_or(col, |item| col.saturating_sub(item.content) + item.output);
+    format!("{}{trimmed}", " ".repeat(col))
+}
+
+/// The list marker starting `line` and the offset of the item's content.
+fn list_marker(line: &str) -> Option<(&str, usize)> {
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let marker_len = match line.as_bytes().get(digits)? {
+        b'*' | b'-' | b'+' if digits == 0 => 1,
+        b'.' | b')' if (1..=9).contains(&digits) => digits + 1,
+        _ => return None,
+    };
+    let rest = &line[marker_len..];
+    let spaces = rest.len() - rest.trim_start_matches(' ').len();
+    if spaces == 0 && !rest.is_empty() {
+        return None;
+    }
+    // `* * *` and `- - -` are thematic breaks, not items
+    let marker = &line[..marker_len];
+    if digits == 0 && line.chars().all(|c| c == ' ' || line.starts_with(c)) {
+        return None;
+    }
+    Some((marker, marker_len + spaces))
+}
+
+/// The character and length of a leading run of backticks or tildes.
+fn fence_run(trimmed: &str) -> Option<(char, usize)> {
+    let ch = trimmed.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    Some((ch, trimmed.len() - trimmed.trim_start_matches(ch).len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NESTED: &str = "*   One\n    *   Nested\n        1.  Deep\n        10.  Deeper\n    \n            ```\n            fn main() {\n                * x\n            }\n            ```\n    \n*   Two\n    \n    para\n\n* * *\n\nAfter";
+
+    #[test]
+    fn test_two_space_indent() {
+        assert_eq!(
+            normalize(NESTED, 2),
+            "* One\n  * Nested\n    1. Deep\n    10. Deeper\n\n        ```\n        fn main() {\n            * x\n        }\n        ```\n\n* Two\n\n  para\n\n* * *\n\nAfter"
+        );
+    }
+
+    #[test]
+    fn test_four_space_indent() {
+        assert_eq!(
+            normalize(NESTED, 4),
+            "* One\n    * Nested\n        1. Deep\n        10. Deeper\n\n            ```\n            fn main() {\n                * x\n            }\n            ```\n\n* Two\n\n    para\n\n* * *\n\nAfter"
+        );
+    }
+
+    #[test]
+    fn test_children_clear_wide_markers() {
+        let markdown = "1.  One\n    *   Sub\n    \n    ```\n    code\n    ```\n2.  Two";
+        assert_eq!(
+            normalize(markdown, 2),
+            "1. One\n   * Sub\n\n   ```\n   code\n   ```\n2. Two"
+        );
+        assert_eq!(
+            normalize(markdown, 4),
+            "1. One\n    * Sub\n\n    ```\n    code\n    ```\n2. Two"
+        );
+    }
+
+    #[test]
+    fn test_leaves_other_content_alone() {
+        let markdown = "\\* not a list\n\n**bold**\n\n```\n*   code\n```\n\n- - -";
+        assert_eq!(normalize(markdown, 2), markdown);
+    }
+}
@@ -0,0 +1,331 @@
+//! Rendering of `MathML` `<math>` elements and TeX `\(..\)`/`\[..\]`
+//! delimiters as math markdown renderers understand: `$..$` inline, and
+//! `$$` or ```` ```math ```` blocks for display math.
+//!
+//! A `<math>` element's TeX source is taken from its
+//! `application/x-tex` annotation when it has one; otherwise the
+//! presentation `MathML` is written out as TeX, covering scripts, fractions,
+//! roots, and tables. TeX delimiters in the page's text are found in the
+//! converted markdown, where htmd has escaped them (`\\(`, `\\\[`), and the
+//! escaping is undone inside them.
+
+use crate::{budget, entities, MathNotation};
+use markup5ever_rcdom::{Handle, NodeData};
+
+/// Output of the `<math>` handler; `fence` opens fenced math blocks.
+pub fn element(node: &Handle, notation: MathNotation, fence: &str) -> String {
+    let tex = tex(node);
+    if is_block(node) {
+        block(tex.trim(), notation, fence)
+    } else {
+        let tex = tex.split_whitespace().collect::<Vec<_>>().join(" ");
+        if tex.is_empty() {
+            String::new()
+        } else {
+            format!("${tex}$")
+        }
+    }
+}
+
+/// Rewrite the TeX delimiters in `markdown`'s text, outside code.
+pub fn delimiters(markdown: &str, notation: MathNotation, fence: &str) -> String {
+    let mut out = String::with_capacity(markdown.len());
+    for (i, line) in budget::scan(markdown).into_iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        if line.fence.is_some() || line.closes_fence {
+            out.push_str(line.text);
+            continue;
+        }
+        // Display math making up a whole line becomes a block of its own
+        let trimmed = line.text.trim();
+        if let Some(tex) = trimmed
+            .strip_prefix(DISPLAY.0)
+            .and_then(|rest| rest.strip_suffix(DISPLAY.1))
+            .filter(|tex| !tex.contains(DISPLAY.1))
+        {
+            out.push_str(block(&unescape(tex), notation, fence).trim());
+            continue;
+        }
+        entities::split_code_spans(line.text, |text, code| {
+            if code {
+                out.push_str(text);
+            } else {
+                rewrite(text, &mut out);
+            }
+        });
+    }
+    out
+}
+
+/// The `\[` and `\]` delimiters, as escaped by htmd.
+const DISPLAY: (&str, &str) = ("\\\\\\[", "\\\\\\]");
+/// The `\(` and `\)` delimiters, as escaped by htmd.
+const INLINE: (&str, &str) = ("\\\\(", "\\\\)");
+
+/// Rewrite the delimited math in a stretch of text.
+fn rewrite(text: &str, out: &mut String) {
+    let mut rest = text;
+    loop {
+        let next = [(INLINE, "$"), (DISPLAY, "$$")]
+            .into_iter()
+            .filter_map(|((open, close), dollars)| {
+                let start = rest.find(open)?;
+                let end = rest[start + open.len()..].find(close)?;
+                Some((start, open.len(), end, close.len(), dollars))
+            })
+            .min_by_key(|&(start, ..)| start);
+        let Some((start, open, end, close, dollars)) = next else {
+            break;
+        };
+        let tex = &rest[start + open..start + open + end];
+        out.push_str(&rest[..start]);
+        out.push_str(dollars);
+        out.push_str(unescape(tex).trim());
+        out.push_str(dollars);
+        rest = &rest[start + open + end + close..];
+    }
+    out.push_str(rest);
+}
+
+/// Undo htmd's escaping of text.
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(next @ ('\\' | '*' | '_' | '`' | '[' | ']')) => out.push(next),
+            Some(next) => {
+                out.push(c);
+                out.push(next);
+            }
+            None => out.push(c),
+        }
+    }
+    out
+}
+
+/// Display math `tex` as a block.
+fn block(tex: &str, notation: MathNotation, fence: &str) -> String {
+    if tex.is_empty() {
+        return String::new();
+    }
+    match notation {
+        MathNotation::Dollars => format!("\n\n$$\n{tex}\n$$\n\n"),
+        MathNotation::Fenced => format!("\n\n{fence}math\n{tex}\n{fence}\n\n"),
+    }
+}
+
+/// Whether a `<math>` element is display math.
+fn is_block(node: &Handle) -> bool {
+    attr(node, "display").is_some_and(|display| display.eq_ignore_ascii_case("block"))
+        || attr(node, "mode").is_some_and(|mode| mode.eq_ignore_ascii_case("display"))
+}
+
+/// TeX for a `MathML` element.
+fn tex(node: &Handle) -> String {
+    let NodeData::Element { name, .. } = &node.data else {
+        return String::new();
+    };
+    let args: Vec<Handle> = node
+        .children
+        .borrow()
+        .iter()
+        .filter(|child| matches!(child.data, NodeData::Element { .. }))
+        .cloned()
+        .collect();
+    let arg = |i: usize| args.get(i).map(tex).unwrap_or_default();
+    let all = |separator: &str| args.iter().map(tex).collect::<Vec<_>>().join(separator);
+    match name.local.as_ref() {
+        "mi" | "mn" => token(&text(node)),
+        "mo" => operator(&text(node)),
+        "mtext" | "ms" => format!("\\text{{{}}}", escape(&text(node))),
+        "mspace" => "\\ ".to_string(),
+        "msup" => format!("{}^{}", group(&arg(0)), group(&arg(1))),
+        "mover" => format!("\\overset{{{}}}{{{}}}", arg(1).trim(), arg(0).trim()),
+        "msub" => format!("{}_{}", group(&arg(0)), group(&arg(1))),
+        "munder" => format!("\\underset{{{}}}{{{}}}", arg(1).trim(), arg(0).trim()),
+        "msubsup" | "munderover" => {
+            format!("{}_{}^{}", group(&arg(0)), group(&arg(1)), group(&arg(2)))
+        }
+        "mfrac" => format!("\\frac{{{}}}{{{}}}", arg(0).trim(), arg(1).trim()),
+        "msqrt" => format!("\\sqrt{{{}}}", all("").trim()),
+        "mroot" => format!("\\sqrt[{}]{{{}}}", arg(1).trim(), arg(0).trim()),
+        "mfenced" => {
+            let open = attr(node, "open").unwrap_or_else(|| "(".to_string());
+            let close = attr(node, "close").unwrap_or_else(|| ")".to_string());
+            let separator = attr(node, "separators").unwrap_or_else(|| ",".to_string());
+            format!(
+                "{}{}{}",
+                operator(&open),
+                all(separator.trim()),
+                operator(&close)
+            )
+        }
+        "mtable" => format!("\\begin{{matrix}} {} \\end{{matrix}}", all(" \\\\ ")),
+        "mtr" | "mlabeledtr" => all(" & "),
+        "semantics" => annotation(&args).unwrap_or_else(|| arg(0)),
+        "annotation" | "annotation-xml" => String::new(),
+        _ => all(""),
+    }
+}
+
+/// The TeX annotation among a `<semantics>` element's children.
+fn annotation(children: &[Handle]) -> Option<String> {
+    children
+        .iter()
+        .find(|child| {
+            matches!(&child.data, NodeData::Element { name, .. }
+                if name.local.as_ref() == "annotation")
+                && attr(child, "encoding").is_some_and(|encoding| {
+                    encoding.eq_ignore_ascii_case("application/x-tex")
+                        || encoding.eq_ignore_ascii_case("tex")
+                })
+        })
+        .map(text)
+        .filter(|tex| !tex.is_empty())
+}
+
+/// A script argument, braced unless it is a single character.
+fn group(tex: &str) -> String {
+    let tex = tex.trim();
+    if tex.chars().count() == 1 {
+        tex.to_string()
+    } else {
+        format!("{{{tex}}}")
+    }
+}
+
+/// TeX for the text of an `<mi>` or `<mn>`.
+fn token(text: &str) -> String {
+    text.chars().map(symbol).collect()
+}
+
+/// TeX for the text of an `<mo>`: spaced out unless it is a bracket or
+/// punctuation.
+fn operator(text: &str) -> String {
+    let tex = token(text);
+    if text.chars().all(|c| "()[]{}|,.;:!'′⟨⟩".contains(c)) {
+        tex
+    } else {
+        format!(" {} ", tex.trim())
+    }
+}
+
+/// TeX for a character of math text; commands end with a space, so they
+/// never run into a following letter.
+fn symbol(c: char) -> String {
+    let command = match c {
+        '∑' => "\\sum",
+        '∏' => "\\prod",
+        '∫' => "\\int",
+        '∞' => "\\infty",
+        '±' => "\\pm",
+        '×' => "\\times",
+        '÷' => "\\div",
+        '·' | '⋅' => "\\cdot",
+        '≤' => "\\le",
+        '≥' => "\\ge",
+        '≠' => "\\ne",
+        '≈' => "\\approx",
+        '→' => "\\to",
+        '∈' => "\\in",
+        '∂' => "\\partial",
+        '∇' => "\\nabla",
+        '′' => "\\prime",
+        '⟨' => "\\langle",
+        '⟩' => "\\rangle",
+        'π' => "\\pi",
+        'α' => "\\alpha",
+        'β' => "\\beta",
+        'γ' => "\\gamma",
+        'δ' => "\\delta",
+        'θ' => "\\theta",
+        'λ' => "\\lambda",
+        'μ' => "\\mu",
+        'σ' => "\\sigma",
+        'ω' => "\\omega",
+        '\\' => "\\backslash",
+        '{' | '}' | '#' | '$' | '%' | '&' | '_' => return format!("\\{c}"),
+        _ => return c.to_string(),
+    };
+    format!("{command} ")
+}
+
+/// Escape TeX's special characters in `\text{}`.
+fn escape(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '{' | '}' | '#' | '$' | '%' | '&' | '_' => format!("\\{c}"),
+            '\\' => "\\textbackslash ".to_string(),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+/// The text of an element and its descendants, with whitespace collapsed.
+fn text(node: &Handle) -> String {
+    fn collect(node: &Handle, out: &mut String) {
+        for child in node.children.borrow().iter() {
+            match &child.data {
+                NodeData::Text { contents } => out.push_str(&contents.borrow()),
+                NodeData::Element { .. } => collect(child, out),
+                _ => {}
+            }
+        }
+    }
+    let mut out = String::new();
+    collect(node, &mut out);
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn attr(node: &Handle, name: &str) -> Option<String> {
+    match &node.data {
+        NodeData::Element { attrs, .. } => attrs
+            .borrow()
+            .iter()
+            .find(|a| a.name.local.as_ref() == name)
+            .map(|a| a.value.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delimiters() {
+        let md = "Inline \\\\(x\\_1^2 + y\\*z\\\\) and \\\\\\[a\\_i\\\\\\] then `\\\\(code\\\\)`\n\n\
+                  \\\\\\[\\\\sum\\_{i=1}^n a\\_i\\\\\\]\n\n```\n\\\\(kept\\\\)\n```\n\nUnclosed \\\\(x";
+        assert_eq!(
+            delimiters(md, MathNotation::Dollars, "```"),
+            "Inline $x_1^2 + y*z$ and $$a_i$$ then `\\\\(code\\\\)`\n\n\
+             $$\n\\sum_{i=1}^n a_i\n$$\n\n```\n\\\\(kept\\\\)\n```\n\nUnclosed \\\\(x"
+        );
+        assert_eq!(
+            delimiters("\\\\\\[x\\\\\\]", MathNotation::Fenced, "~~~"),
+            "~~~math\nx\n~~~"
+        );
+    }
+
+    #[test]
+    fn test_unescape_keeps_other_backslashes() {
+        assert_eq!(unescape("\\\\alpha \\_ \\# \\"), "\\alpha _ \\# \\");
+    }
+
+    #[test]
+    fn test_symbols_and_groups() {
+        assert_eq!(token("∞x"), "\\infty x");
+        assert_eq!(operator("="), " = ");
+        assert_eq!(operator("("), "(");
+        assert_eq!(group(" x "), "x");
+        assert_eq!(group("10"), "{10}");
+        assert_eq!(escape("a_b {c}"), "a\\_b \\{c\\}");
+    }
+}
@@ -0,0 +1,581 @@
+//! Page metadata extraction from the HTML document.
+//!
+//! The document is parsed once and walked once; every extractor reads from
+//! the resulting [`PageMetadata`] rather than re-parsing the HTML.
+
+use crate::{date, json_ld, url};
+use html5ever::{namespace_url, ns, parse_document, tendril::TendrilSink};
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+
+/// Metadata collected from a single pass over the parsed document.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PageMetadata {
+    /// Text of the first `<title>` element.
+    pub title: Option<String>,
+    /// Content of `<meta name="description">`.
+    pub description: Option<String>,
+    /// Text of the first `<h1>` element in the document.
+    pub first_h1: Option<String>,
+    /// Open Graph (`og:`, `article:`, …) and Twitter card `<meta>` properties,
+    /// in document order. Repeated properties appear once per tag.
+    pub social: Vec<(String, String)>,
+    /// `href` of the first `<link rel="canonical">`; absolute after [`Self::resolve_urls`].
+    pub canonical: Option<String>,
+    /// `href` of the first `<base>` element.
+    pub base_href: Option<String>,
+    /// Items from every well-formed `<script type="application/ld+json">` block.
+    pub json_ld: Vec<serde_json::Value>,
+    /// Content of `<meta name="date">`.
+    pub date: Option<String>,
+    /// `datetime` of the first `<time>` inside the first `<article>` that has one.
+    pub article_time: Option<String>,
+    /// `lang` attribute of the `<html>` element.
+    pub lang: Option<String>,
+    /// Every `<meta>` tag's `name` (or `property`) and `content`, in document order.
+    pub meta: Vec<(String, String)>,
+    /// Trail from the first `<nav aria-label="breadcrumb">` or schema.org
+    /// `BreadcrumbList` microdata.
+    pub html_breadcrumbs: Vec<String>,
+    /// `href` of every `<a>`, in document order; absolute after [`Self::resolve_urls`].
+    pub links: Vec<String>,
+}
+
+/// Open Graph namespaces (plus `twitter`) whose `<meta>` tags are collected.
+const SOCIAL_PREFIXES: &[&str] = &[
+    "og", "article", "book", "profile", "music", "video", "twitter",
+];
+
+impl PageMetadata {
+    /// Parse `html` and extract its metadata.
+    pub fn extract(html: &str) -> Self {
+        let dom = parse_document(RcDom::default(), html5ever::ParseOpts::default()).one(html);
+        let mut metadata = Self::default();
+
+        // Iterative pre-order walk so pathological nesting cannot overflow the stack.
+        // `dom` must outlive the walk: dropping the last handle to a node
+        // detaches its whole subtree.
+        let mut stack = vec![dom.document.clone()];
+        while let Some(node) = stack.pop() {
+            metadata.visit(&node);
+            stack.extend(node.children.borrow().iter().rev().cloned());
+        }
+        drop(dom);
+
+        metadata
+    }
+
+    /// Resolve relative URLs against the document base: the page URL, as
+    /// overridden by `<base href>`. Results are percent-encoded for use in headers.
+    pub fn resolve_urls(&mut self, page_url: &str) {
+        let base = self
+            .base_href
+            .as_deref()
+            .map_or_else(|| page_url.to_string(), |href| url::resolve(page_url, href));
+        if let Some(canonical) = &mut self.canonical {
+            *canonical = url::encode(&url::resolve(&base, canonical));
+        }
+        for link in &mut self.links {
+            *link = url::resolve(&base, link);
+        }
+    }
+
+    /// Publication date, normalized to RFC 3339 where possible. In priority
+    /// order: `article:published_time`, `<meta name="date">`, JSON-LD
+    /// `datePublished`, and the first `<time datetime>` in an `<article>`.
+    pub fn published(&self) -> Option<String> {
+        self.social_property("article:published_time")
+            .or(self.date.as_deref())
+            .or_else(|| self.json_ld_string("datePublished"))
+            .or(self.article_time.as_deref())
+            .map(date::normalize)
+    }
+
+    /// Modification date: `article:modified_time`, then JSON-LD `dateModified`.
+    pub fn modified(&self) -> Option<String> {
+        self.social_property("article:modified_time")
+            .or_else(|| self.json_ld_string("dateModified"))
+            .map(date::normalize)
+    }
+
+    fn social_property(&self, property: &str) -> Option<&str> {
+        self.social
+            .iter()
+            .find(|(p, _)| p == property)
+            .map(|(_, content)| content.as_str())
+    }
+
+    /// The first non-empty string value of `key` among the JSON-LD items.
+    fn json_ld_string(&self, key: &str) -> Option<&str> {
+        self.json_ld
+            .iter()
+            .filter_map(|item| item.get(key)?.as_str())
+            .find(|s| !s.trim().is_empty())
+    }
+
+    /// The breadcrumb trail, preferring JSON-LD `BreadcrumbList` over markup.
+    pub fn breadcrumbs(&self) -> Option<Vec<String>> {
+        json_ld::breadcrumbs(&self.json_ld)
+            .or_else(|| (!self.html_breadcrumbs.is_empty()).then(|| self.html_breadcrumbs.clone()))
+    }
+
+    /// Whether the first `<h1>` repeats the page title verbatim.
+    pub fn h1_duplicates_title(&self) -> bool {
+        matches!((&self.title, &self.first_h1), (Some(t), Some(h)) if t == h)
+    }
+
+    fn visit(&mut self, node: &Handle) {
+        let name = html_element_name(node);
+        if name == Some("meta") {
+            let key = attr(node, "name").or_else(|| attr(node, "property"));
+            if let (Some(key), Some(content)) = (key, attr(node, "content")) {
+                self.meta.push((key, content));
+            }
+        }
+
+        if self.html_breadcrumbs.is_empty() {
+            let is_breadcrumb_nav = name == Some("nav")
+                && attr(node, "aria-label")
+                    .is_some_and(|label| label.to_ascii_lowercase().contains("breadcrumb"));
+            let is_breadcrumb_list = attr(node, "itemtype").is_some_and(|t| {
+                t.trim()
+                    .trim_end_matches('/')
+                    .ends_with("schema.org/BreadcrumbList")
+            });
+            if is_breadcrumb_nav {
+                self.html_breadcrumbs = nav_breadcrumbs(node);
+            } else if is_breadcrumb_list {
+                self.html_breadcrumbs = microdata_breadcrumbs(node);
+            }
+        }
+
+        match name {
+            Some("html") if self.lang.is_none() => {
+                self.lang = attr(node, "lang").and_then(|l| non_empty(l.trim().to_string()));
+            }
+            Some("title") if self.title.is_none() => {
+                self.title = non_empty(text_content(node));
+            }
+            Some("h1") if self.first_h1.is_none() => {
+                self.first_h1 = non_empty(text_content(node));
+            }
+            Some("link") if self.canonical.is_none() && has_rel(node, "canonical") => {
+                self.canonical = attr(node, "href").and_then(|h| non_empty(h.trim().to_string()));
+            }
+            Some("script")
+                if attr(node, "type")
+                    .is_some_and(|t| t.trim().eq_ignore_ascii_case("application/ld+json")) =>
+            {
+                json_ld::parse_block(&raw_text(node), &mut self.json_ld);
+            }
+            Some("article") if self.article_time.is_none() => {
+                self.article_time = first_time_datetime(node);
+            }
+            Some("a") => {
+                if let Some(href) = attr(node, "href").and_then(|h| non_empty(h.trim().to_string()))
+                {
+                    self.links.push(href);
+                }
+            }
+            Some("base") if self.base_href.is_none() => {
+                self.base_href = attr(node, "href").and_then(|h| non_empty(h.trim().to_string()));
+            }
+            Some("meta")
+                if self.description.is_none()
+                    && attr(node, "name")
+                        .is_some_and(|n| n.eq_ignore_ascii_case("description")) =>
+            {
+                self.description = attr(node, "content").and_then(|c| non_empty(collapse(&c)));
+            }
+            Some("meta")
+                if self.date.is_none()
+                    && attr(node, "name").is_some_and(|n| n.eq_ignore_ascii_case("date")) =>
+            {
+                self.date = attr(node, "content").and_then(|c| non_empty(collapse(&c)));
+            }
+            Some("meta") => {
+                // Open Graph uses `property`, Twitter cards conventionally use `name`
+                let property = attr(node, "property").or_else(|| attr(node, "name"));
+                if let Some(property) = property.filter(|p| is_social_property(p)) {
+                    if let Some(content) =
+                        attr(node, "content").and_then(|c| non_empty(collapse(&c)))
+                    {
+                        self.social.push((property, content));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_social_property(property: &str) -> bool {
+    property
+        .split_once(':')
+        .is_some_and(|(prefix, rest)| !rest.is_empty() && SOCIAL_PREFIXES.contains(&prefix))
+}
+
+/// Whether the element's space-separated `rel` attribute contains `value`.
+fn has_rel(node: &Handle, value: &str) -> bool {
+    attr(node, "rel").is_some_and(|rel| {
+        rel.split_ascii_whitespace()
+            .any(|token| token.eq_ignore_ascii_case(value))
+    })
+}
+
+/// Local name of an element in the HTML namespace (so SVG `<title>` is ignored).
+fn html_element_name(node: &Handle) -> Option<&str> {
+    match &node.data {
+        NodeData::Element { name, .. } if name.ns == ns!(html) => Some(&name.local),
+        _ => None,
+    }
+}
+
+/// Value of the attribute `name` on an element.
+fn attr(node: &Handle, name: &str) -> Option<String> {
+    match &node.data {
+        NodeData::Element { attrs, .. } => attrs
+            .borrow()
+            .iter()
+            .find(|a| a.name.local.as_ref() == name)
+            .map(|a| a.value.to_string()),
+        _ => None,
+    }
+}
+
+/// Descendant elements of `node` (excluding `node`) matching `predicate`, in
+/// document order, without descending into matches.
+fn find_all(node: &Handle, predicate: impl Fn(&Handle) -> bool) -> Vec<Handle> {
+    let mut found = Vec::new();
+    let mut stack: Vec<Handle> = node.children.borrow().iter().rev().cloned().collect();
+    while let Some(node) = stack.pop() {
+        if predicate(&node) {
+            found.push(node);
+        } else {
+            stack.extend(node.children.borrow().iter().rev().cloned());
+        }
+    }
+    found
+}
+
+/// Breadcrumb names in a `<nav>`: the text of each `<li>`, or of each `<a>`
+/// when the trail isn't a list, without any separator characters around it.
+fn nav_breadcrumbs(nav: &Handle) -> Vec<String> {
+    let mut items = find_all(nav, |n| html_element_name(n) == Some("li"));
+    if items.is_empty() {
+        items = find_all(nav, |n| html_element_name(n) == Some("a"));
+    }
+    items
+        .iter()
+        .filter_map(|item| {
+            let text = text_content(item);
+            let name = text.trim_matches(|c: char| {
+                c.is_whitespace() || matches!(c, '›' | '»' | '>' | '/' | '|' | '→' | '·')
+            });
+            non_empty(name.to_string())
+        })
+        .collect()
+}
+
+/// Breadcrumb names in schema.org microdata: each `itemListElement`'s
+/// `itemprop="name"` (its `content` or text), falling back to its whole text.
+fn microdata_breadcrumbs(list: &Handle) -> Vec<String> {
+    let has_itemprop = |node: &Handle, prop: &str| {
+        attr(node, "itemprop").is_some_and(|p| p.split_ascii_whitespace().any(|p| p == prop))
+    };
+    find_all(list, |n| has_itemprop(n, "itemListElement"))
+        .iter()
+        .filter_map(|element| {
+            let name = find_all(element, |n| has_itemprop(n, "name"))
+                .first()
+                .map_or_else(
+                    || text_content(element),
+                    |name| {
+                        attr(name, "content").map_or_else(|| text_content(name), |c| collapse(&c))
+                    },
+                );
+            non_empty(name)
+        })
+        .collect()
+}
+
+/// `datetime` of the first descendant `<time>` element that has one.
+fn first_time_datetime(node: &Handle) -> Option<String> {
+    let mut stack = vec![node.clone()];
+    while let Some(node) = stack.pop() {
+        if html_element_name(&node) == Some("time") {
+            if let Some(datetime) = attr(&node, "datetime").and_then(|d| non_empty(collapse(&d))) {
+                return Some(datetime);
+            }
+        }
+        stack.extend(node.children.borrow().iter().rev().cloned());
+    }
+    None
+}
+
+/// Concatenated descendant text of a node, with whitespace collapsed.
+fn text_content(node: &Handle) -> String {
+    let mut text = String::new();
+    let mut stack = vec![node.clone()];
+    while let Some(node) = stack.pop() {
+        if let NodeData::Text { contents } = &node.data {
+            text.push_str(&contents.borrow());
+        }
+        stack.extend(node.children.borrow().iter().rev().cloned());
+    }
+    collapse(&text)
+}
+
+/// Text of a node's direct text children, verbatim (for `<script>` bodies).
+fn raw_text(node: &Handle) -> String {
+    node.children
+        .borrow()
+        .iter()
+        .filter_map(|child| match &child.data {
+            NodeData::Text { contents } => Some(contents.borrow().to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Text of an `<svg>` element's `<title>` and `<desc>` children, joined
+/// with `: `, or `None` if it has neither.
+pub fn svg_text(svg: &Handle) -> Option<String> {
+    let parts: Vec<String> = ["title", "desc"]
+        .iter()
+        .filter_map(|name| {
+            svg.children
+                .borrow()
+                .iter()
+                .find(|child| {
+                    matches!(&child.data, NodeData::Element { name: tag, .. }
+                        if tag.ns == ns!(svg) && tag.local.as_ref() == *name)
+                })
+                .map(|child| collapse(&raw_text(child)))
+                .and_then(non_empty)
+        })
+        .collect();
+    non_empty(parts.join(": "))
+}
+
+/// Collapse runs of whitespace into single spaces and trim the ends.
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn non_empty(text: String) -> Option<String> {
+    (!text.is_empty()).then_some(text)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extracts_title_and_description() {
+        let metadata = PageMetadata::extract(
+            r#"<html><head><title> Hello
+              World </title><meta name="Description" content="A page"></head>
+              <body><h1>Hello World</h1></body></html>"#,
+        );
+        assert_eq!(metadata.title.as_deref(), Some("Hello World"));
+        assert_eq!(metadata.description.as_deref(), Some("A page"));
+        assert!(metadata.h1_duplicates_title());
+    }
+
+    #[test]
+    fn test_ignores_svg_title() {
+        let metadata =
+            PageMetadata::extract("<body><svg><title>Icon</title></svg><h1>Heading</h1></body>");
+        assert_eq!(metadata.title, None);
+        assert_eq!(metadata.first_h1.as_deref(), Some("Heading"));
+        assert!(!metadata.h1_duplicates_title());
+    }
+
+    #[test]
+    fn test_extracts_social_properties_in_order() {
+        let metadata = PageMetadata::extract(
+            r#"<head>
+              <meta property="og:image" content="https://example.com/a.png">
+              <meta name="twitter:card" content="summary">
+              <meta property="og:image" content="https://example.com/b.png">
+              <meta property="og:type">
+              <meta property="fb:app_id" content="123">
+              <meta name="viewport" content="width=device-width">
+            </head>"#,
+        );
+        assert_eq!(
+            metadata.social,
+            [
+                (
+                    "og:image".to_string(),
+                    "https://example.com/a.png".to_string()
+                ),
+                ("twitter:card".to_string(), "summary".to_string()),
+                (
+                    "og:image".to_string(),
+                    "https://example.com/b.png".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolves_canonical_against_base() {
+        let html = r#"<head><link rel="alternate canonical" href="../guide/"></head>"#;
+
+        let mut metadata = PageMetadata::extract(html);
+        metadata.resolve_urls("https://example.com/docs/intro/page");
+        assert_eq!(
+            metadata.canonical.as_deref(),
+            Some("https://example.com/docs/guide/")
+        );
+
+        let html = r#"<head><base href="https://cdn.example.org/root/">
+            <link rel="canonical" href="page 1"></head>"#;
+        let mut metadata = PageMetadata::extract(html);
+        metadata.resolve_urls("https://example.com/docs/intro");
+        assert_eq!(
+            metadata.canonical.as_deref(),
+            Some("https://cdn.example.org/root/page%201")
+        );
+    }
+
+    #[test]
+    fn test_extracts_json_ld_verbatim() {
+        let metadata = PageMetadata::extract(
+            r#"<head><script type="application/ld+json">
+              {"@type": "Article", "headline": "Two  spaces"}
+            </script><script>var x = 1;</script></head>"#,
+        );
+        assert_eq!(
+            metadata.json_ld,
+            [serde_json::json!({"@type": "Article", "headline": "Two  spaces"})]
+        );
+    }
+
+    #[test]
+    fn test_published_from_each_source() {
+        for (html, expected) in [
+            (
+                r#"<meta property="article:published_time" content="2024-01-02T03:04:05+0000">"#,
+                "2024-01-02T03:04:05+00:00",
+            ),
+            (r#"<meta name="date" content="2024-01-03">"#, "2024-01-03"),
+            (
+                r#"<script type="application/ld+json">{"@type": "Article", "datePublished": "2024-01-04T00:00:00Z"}</script>"#,
+                "2024-01-04T00:00:00Z",
+            ),
+            (
+                r#"<time datetime="2024-01-01">outside</time>
+                <article><p>By <time>someone</time> on <time datetime="Fri, 5 Jan 2024 12:00 GMT">Friday</time></p></article>"#,
+                "2024-01-05T12:00:00Z",
+            ),
+            (
+                r#"<meta name="date" content="last Tuesday">"#,
+                "last Tuesday",
+            ),
+        ] {
+            let metadata = PageMetadata::extract(html);
+            assert_eq!(metadata.published().as_deref(), Some(expected), "{html}");
+        }
+    }
+
+    #[test]
+    fn test_published_precedence() {
+        let sources = [
+            r#"<meta property="article:published_time" content="2024-01-01T00:00:00Z">"#,
+            r#"<meta name="date" content="2024-01-02">"#,
+            r#"<script type="application/ld+json">{"datePublished": "2024-01-03", "dateModified": "2024-02-03"}</script>"#,
+            r#"<article><time datetime="2024-01-04"></time></article>"#,
+        ];
+        let expected = [
+            "2024-01-01T00:00:00Z",
+            "2024-01-02",
+            "2024-01-03",
+            "2024-01-04",
+        ];
+        for i in 0..sources.len() {
+            // Sources in reverse document order, so precedence isn't just "first seen"
+            let html: String = sources[i..].iter().rev().copied().collect();
+            let metadata = PageMetadata::extract(&html);
+            assert_eq!(metadata.published().as_deref(), Some(expected[i]), "{html}");
+        }
+
+        let html = format!(
+            r#"<meta property="article:modified_time" content="2024-02-01T00:00:00Z">{}"#,
+            sources[2]
+        );
+        let metadata = PageMetadata::extract(&html);
+        assert_eq!(metadata.modified().as_deref(), Some("2024-02-01T00:00:00Z"));
+        let metadata = PageMetadata::extract(sources[2]);
+        assert_eq!(metadata.modified().as_deref(), Some("2024-02-03"));
+    }
+
+    #[test]
+    fn test_extracts_lang_and_all_meta() {
+        let metadata = PageMetadata::extract(
+            r#"<html lang=" en-GB "><head><meta name="sku" content="A-1">
+              <meta property="og:type" content="article"><meta charset="utf-8"></head></html>"#,
+        );
+        assert_eq!(metadata.lang.as_deref(), Some("en-GB"));
+        assert_eq!(
+            metadata.meta,
+            [
+                ("sku".to_string(), "A-1".to_string()),
+                ("og:type".to_string(), "article".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_breadcrumbs_from_aria_nav() {
+        let metadata = PageMetadata::extract(
+            r#"<nav aria-label="Primary"><a href="/">Site</a></nav>
+            <nav aria-label="Breadcrumb"><ol>
+              <li><a href="/">Home</a> ›</li><li>/</li><li><a href="/docs">Docs</a></li>
+              <li aria-current="page">Getting Started</li>
+            </ol></nav>"#,
+        );
+        assert_eq!(
+            metadata.breadcrumbs().unwrap(),
+            ["Home", "Docs", "Getting Started"]
+        );
+    }
+
+    #[test]
+    fn test_breadcrumbs_from_microdata() {
+        let metadata = PageMetadata::extract(
+            r#"<ol itemscope itemtype="https://schema.org/BreadcrumbList">
+              <li itemprop="itemListElement" itemscope itemtype="https://schema.org/ListItem">
+                <a itemprop="item" href="/"><span itemprop="name">Home</span></a>
+                <meta itemprop="position" content="1"></li>
+              <li itemprop="itemListElement" itemscope itemtype="https://schema.org/ListItem">
+                <meta itemprop="name" content="Docs"><meta itemprop="position" content="2"></li>
+            </ol>"#,
+        );
+        assert_eq!(metadata.breadcrumbs().unwrap(), ["Home", "Docs"]);
+    }
+
+    #[test]
+    fn test_breadcrumbs_prefer_json_ld() {
+        let metadata = PageMetadata::extract(
+            r#"<script type="application/ld+json">{"@type": "BreadcrumbList",
+              "itemListElement": [{"position": 1, "name": "From JSON-LD"}]}</script>
+            <nav aria-label="breadcrumb"><a href="/">From markup</a></nav>"#,
+        );
+        assert_eq!(metadata.breadcrumbs().unwrap(), ["From JSON-LD"]);
+        assert_eq!(metadata.html_breadcrumbs, ["From markup"]);
+
+        assert_eq!(
+            PageMetadata::extract("<nav><a>Home</a></nav>").breadcrumbs(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_missing_metadata() {
+        let metadata = PageMetadata::extract("<p>No head here</p>");
+        assert_eq!(metadata, PageMetadata::default());
+    }
+}
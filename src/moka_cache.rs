@@ -0,0 +1,97 @@
+//! A [`ConversionCache`] backed by moka, with the `moka` feature.
+
+use crate::{CacheConfig, CacheFuture, CachedConversion, ConversionCache, Invalidation};
+use moka::future::Cache;
+
+/// A [`ConversionCache`] in a [`moka::future::Cache`], weighing each entry
+/// by the bytes of its markdown.
+#[derive(Debug, Clone)]
+pub struct MokaCache {
+    cache: Cache<String, CachedConversion>,
+}
+
+impl MokaCache {
+    /// A cache holding up to [`CacheConfig::max_bytes`] of markdown, and
+    /// expiring entries after [`CacheConfig::ttl`]. moka bounds by weight
+    /// alone, so [`CacheConfig::max_entries`] is not used.
+    #[must_use]
+    pub fn new(limits: CacheConfig) -> Self {
+        let mut builder = Cache::builder()
+            .max_capacity(u64::try_from(limits.max_bytes).unwrap_or(u64::MAX))
+            .weigher(|_, conversion: &CachedConversion| {
+                u32::try_from(conversion.markdown().len()).unwrap_or(u32::MAX)
+            })
+            .support_invalidation_closures();
+        if let Some(ttl) = limits.ttl {
+            builder = builder.time_to_live(ttl);
+        }
+        Self::from_cache(builder.build())
+    }
+
+    /// Store conversions in `cache`, e.g. one built with the application's
+    /// own eviction listener. Without
+    /// [`support_invalidation_closures`](moka::future::CacheBuilder::support_invalidation_closures),
+    /// invalidating a path or prefix clears the whole cache.
+    #[must_use]
+    pub const fn from_cache(cache: Cache<String, CachedConversion>) -> Self {
+        Self { cache }
+    }
+
+    /// The underlying cache, e.g. for its entry count and weighted size.
+    #[must_use]
+    pub const fn cache(&self) -> &Cache<String, CachedConversion> {
+        &self.cache
+    }
+}
+
+impl ConversionCache for MokaCache {
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a, Option<CachedConversion>> {
+        Box::pin(self.cache.get(key))
+    }
+
+    fn insert(&self, key: String, conversion: CachedConversion) -> CacheFuture<'_, ()> {
+        Box::pin(self.cache.insert(key, conversion))
+    }
+
+    fn invalidate(&self, entries: &Invalidation) {
+        if *entries == Invalidation::All {
+            self.cache.invalidate_all();
+            return;
+        }
+        let entries = entries.clone();
+        let registered = self
+            .cache
+            .invalidate_entries_if(move |key, _| entries.matches(key));
+        if registered.is_err() {
+            self.cache.invalidate_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::tests::backend_suite;
+
+    #[tokio::test]
+    async fn test_moka_backend() {
+        backend_suite(&MokaCache::new(CacheConfig::default())).await;
+    }
+
+    #[tokio::test]
+    async fn test_moka_without_invalidation_closures_clears() {
+        let cache = MokaCache::from_cache(Cache::new(16));
+        let conversion = || {
+            CachedConversion::new(
+                String::new(),
+                crate::cache::HeaderChanges::default(),
+                http::Extensions::new(),
+                bytes::Bytes::from("# A"),
+            )
+        };
+        cache.insert("/a".into(), conversion()).await;
+        cache.insert("/b".into(), conversion()).await;
+        cache.invalidate(&Invalidation::Path("/a".into()));
+        assert!(cache.get("/b").await.is_none());
+    }
+}
@@ -0,0 +1,380 @@
+//! `Accept` header content negotiation (RFC 7231 §5.3.2), and whether
+//! UTF-8 is among the acceptable charsets.
+
+use http::{
+    header::{ACCEPT, ACCEPT_CHARSET},
+    HeaderMap,
+};
+use std::cmp::Reverse;
+
+/// A media type a resource can be served as, with any parameters, e.g.
+/// `text/markdown; charset=utf-8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MediaType(&'static str);
+
+impl MediaType {
+    /// `text/html`.
+    pub const HTML: Self = Self("text/html");
+    /// `text/markdown; charset=utf-8`, as the middleware serves it.
+    pub const MARKDOWN: Self = Self("text/markdown; charset=utf-8");
+    /// `application/vnd.page-summary+json`, for
+    /// [`MarkdownConfig::page_summary`](crate::MarkdownConfig::page_summary).
+    pub const PAGE_SUMMARY: Self = Self("application/vnd.page-summary+json");
+
+    /// A media type from its `type/subtype[; name=value]*` form.
+    #[must_use]
+    pub const fn new(media_type: &'static str) -> Self {
+        Self(media_type)
+    }
+
+    /// The media type as given to [`new`](Self::new).
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        self.0
+    }
+}
+
+/// A parsed `Accept` media range.
+#[derive(Debug, Clone, PartialEq)]
+struct MediaRange<'a> {
+    kind: &'a str,
+    subtype: &'a str,
+    /// Parameters before `q`; accept-extensions after it are dropped.
+    params: Vec<(&'a str, &'a str)>,
+    q: f32,
+}
+
+impl MediaRange<'_> {
+    /// How specific the range is, if it matches `media_type`: parameters
+    /// beat `type/subtype`, which beats `type/*`, which beats `*/*`.
+    ///
+    /// A `charset` the type doesn't have counts for nothing but doesn't
+    /// prevent the match: each type is served in one charset, and serving
+    /// it beats refusing (RFC 9110 §12.5.1). [`utf8_acceptable`] checks it.
+    fn specificity(&self, media_type: &ParsedType<'_>) -> Option<usize> {
+        if self.kind == "*" {
+            return (self.subtype == "*").then_some(0);
+        }
+        if !self.kind.eq_ignore_ascii_case(media_type.kind) {
+            return None;
+        }
+        if self.subtype == "*" {
+            return Some(1);
+        }
+        if !self.subtype.eq_ignore_ascii_case(media_type.subtype) {
+            return None;
+        }
+        let mut matched = 0;
+        for (name, value) in &self.params {
+            let has = media_type
+                .params
+                .iter()
+                .any(|(n, v)| n.eq_ignore_ascii_case(name) && v.eq_ignore_ascii_case(value));
+            if has {
+                matched += 1;
+            } else if !name.eq_ignore_ascii_case("charset") {
+                return None;
+            }
+        }
+        Some(2 + matched)
+    }
+}
+
+struct ParsedType<'a> {
+    kind: &'a str,
+    subtype: &'a str,
+    params: Vec<(&'a str, &'a str)>,
+}
+
+/// `type/subtype` and its parameters, or `None` if malformed.
+fn parse_type(s: &str) -> Option<ParsedType<'_>> {
+    let mut parts = s.split(';');
+    let (kind, subtype) = parts.next()?.trim().split_once('/')?;
+    let (kind, subtype) = (kind.trim(), subtype.trim());
+    if kind.is_empty() || subtype.is_empty() || (kind == "*" && subtype != "*") {
+        return None;
+    }
+    let params = parts
+        .filter_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            Some((name.trim(), value.trim().trim_matches('"')))
+        })
+        .collect();
+    Some(ParsedType {
+        kind,
+        subtype,
+        params,
+    })
+}
+
+/// The media ranges of every `Accept` header, in order. Malformed ranges,
+/// and ranges with an invalid `q`, are skipped.
+fn media_ranges(headers: &HeaderMap) -> Vec<MediaRange<'_>> {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|range| {
+            let ParsedType {
+                kind,
+                subtype,
+                params,
+            } = parse_type(range)?;
+            let q_at = params
+                .iter()
+                .position(|(name, _)| name.eq_ignore_ascii_case("q"));
+            let q = match q_at {
+                Some(i) => parse_q(params[i].1)?,
+                None => 1.0,
+            };
+            let params = params[..q_at.unwrap_or(params.len())].to_vec();
+            Some(MediaRange {
+                kind,
+                subtype,
+                params,
+                q,
+            })
+        })
+        .collect()
+}
+
+/// A qvalue: `0`–`1` with at most three decimals.
+fn parse_q(value: &str) -> Option<f32> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let valid = matches!(whole, "0" | "1")
+        && fraction.len() <= 3
+        && fraction.bytes().all(|b| b.is_ascii_digit())
+        && (whole == "0" || fraction.bytes().all(|b| b == b'0'));
+    valid.then(|| value.parse().ok()).flatten()
+}
+
+/// The best of `available` for the request's `Accept` headers, or `None` if
+/// none is acceptable.
+///
+/// Each media type takes the quality of the most specific range matching it
+/// (a range with parameters only matches a type that has them all, though
+/// a `charset` the type lacks is disregarded rather than refused). The
+/// highest quality wins; ties go to the type matched by the more specific
+/// range, then by the range listed first, then to the type listed first in
+/// `available`. Without an `Accept` header, every type is acceptable, so
+/// the first is returned.
+#[must_use]
+pub fn negotiate(headers: &HeaderMap, available: &[MediaType]) -> Option<MediaType> {
+    if !headers.contains_key(ACCEPT) {
+        return available.first().copied();
+    }
+    let ranges = media_ranges(headers);
+    available
+        .iter()
+        .enumerate()
+        .filter_map(|(position, &media_type)| {
+            let (q, specificity, index) = best_range(&ranges, media_type)?;
+            (q > 0.0).then_some((
+                q,
+                (specificity, Reverse(index), Reverse(position)),
+                media_type,
+            ))
+        })
+        .max_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)))
+        .map(|(.., media_type)| media_type)
+}
+
+/// Whether the request allows markdown's UTF-8: its `Accept-Charset`
+/// gives `utf-8`, or failing that `*`, a nonzero quality (RFC 9110
+/// §12.5.2), and its `text/markdown` ranges naming a `charset` include
+/// `utf-8`. A request that names no charsets allows any.
+#[must_use]
+pub fn utf8_acceptable(headers: &HeaderMap) -> bool {
+    let charsets: Vec<(&str, f32)> = headers
+        .get_all(ACCEPT_CHARSET)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|member| {
+            let mut parts = member.split(';');
+            let name = parts.next()?.trim();
+            let q = match parts.find_map(|p| p.trim().strip_prefix("q=")) {
+                Some(q) => parse_q(q)?,
+                None => 1.0,
+            };
+            (!name.is_empty()).then_some((name, q))
+        })
+        .collect();
+    let quality = |name: &str| {
+        charsets
+            .iter()
+            .find(|(charset, _)| charset.eq_ignore_ascii_case(name))
+            .map(|&(_, q)| q)
+    };
+    let charset_ok = charsets.is_empty()
+        || quality("utf-8")
+            .or_else(|| quality("*"))
+            .is_some_and(|q| q > 0.0);
+
+    let markdown_charsets: Vec<&str> = media_ranges(headers)
+        .iter()
+        .filter(|range| {
+            range.q > 0.0
+                && range.kind.eq_ignore_ascii_case("text")
+                && range.subtype.eq_ignore_ascii_case("markdown")
+        })
+        .filter_map(|range| {
+            range
+                .params
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("charset"))
+                .map(|&(_, value)| value)
+        })
+        .collect();
+    let range_ok = markdown_charsets.is_empty()
+        || markdown_charsets
+            .iter()
+            .any(|charset| charset.eq_ignore_ascii_case("utf-8"));
+
+    charset_ok && range_ok
+}
+
+/// The quality, specificity, and index of the most specific range matching
+/// `media_type` (the earliest, among equally specific ones).
+fn best_range(ranges: &[MediaRange<'_>], media_type: MediaType) -> Option<(f32, usize, usize)> {
+    let parsed = parse_type(media_type.0)?;
+    ranges
+        .iter()
+        .enumerate()
+        .filter_map(|(index, range)| Some((range.q, range.specificity(&parsed)?, index)))
+        .max_by_key(|&(_, specificity, index)| (specificity, Reverse(index)))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn accept(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(ACCEPT, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    const OFFERED: [MediaType; 2] = [MediaType::HTML, MediaType::MARKDOWN];
+
+    #[test]
+    fn test_negotiate_precedence() {
+        for (header, expected) in [
+            ("text/markdown", Some(MediaType::MARKDOWN)),
+            ("text/html", Some(MediaType::HTML)),
+            ("*/*", Some(MediaType::HTML)),
+            ("text/*", Some(MediaType::HTML)),
+            ("application/json", None),
+            // Quality decides
+            ("text/html;q=0.9, text/markdown", Some(MediaType::MARKDOWN)),
+            ("text/markdown;q=0.5, */*", Some(MediaType::HTML)),
+            // A browser: markdown only through `*/*;q=0.8`
+            (
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+                Some(MediaType::HTML),
+            ),
+            // Equal quality: the range listed first
+            ("text/markdown, text/html", Some(MediaType::MARKDOWN)),
+            ("text/html, text/markdown", Some(MediaType::HTML)),
+            // The most specific range applies, whatever its quality
+            ("text/markdown;q=0, text/*", Some(MediaType::HTML)),
+            ("text/*;q=0, text/markdown", Some(MediaType::MARKDOWN)),
+            (
+                "*/*;q=0.1, text/*;q=0.2, text/markdown;q=0.3",
+                Some(MediaType::MARKDOWN),
+            ),
+            ("text/markdown;charset=UTF-8", Some(MediaType::MARKDOWN)),
+            // An unavailable charset is served UTF-8 anyway
+            ("text/markdown;charset=latin1", Some(MediaType::MARKDOWN)),
+            (
+                "text/markdown;charset=bogus;q=0.5, text/html;q=0.4",
+                Some(MediaType::MARKDOWN),
+            ),
+            // Malformed ranges and qualities are skipped
+            ("text/markdown;q=2, text/html;q=0.1", Some(MediaType::HTML)),
+            ("*/markdown, text", None),
+        ] {
+            assert_eq!(
+                negotiate(&accept(&[header]), &OFFERED),
+                expected,
+                "{header}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_negotiate_rfc_7231_example() {
+        // RFC 7231 §5.3.2
+        let headers = accept(&[
+            "text/*;q=0.3, text/html;q=0.7, text/html;level=1, text/html;level=2;q=0.4, */*;q=0.5",
+        ]);
+        let ranges = media_ranges(&headers);
+        for (media_type, q) in [
+            ("text/html;level=1", 1.0),
+            ("text/html", 0.7),
+            ("text/plain", 0.3),
+            ("image/jpeg", 0.5),
+            ("text/html;level=2", 0.4),
+            ("text/html;level=3", 0.7),
+        ] {
+            let (quality, ..) = best_range(&ranges, MediaType::new(media_type)).unwrap();
+            assert!((quality - q).abs() < f32::EPSILON, "{media_type}");
+        }
+    }
+
+    #[test]
+    fn test_negotiate_without_accept() {
+        assert_eq!(
+            negotiate(&HeaderMap::new(), &OFFERED),
+            Some(MediaType::HTML)
+        );
+        assert_eq!(negotiate(&HeaderMap::new(), &[]), None);
+    }
+
+    #[test]
+    fn test_negotiate_across_header_lines() {
+        let headers = accept(&["text/html;q=0.5", "text/markdown"]);
+        assert_eq!(negotiate(&headers, &OFFERED), Some(MediaType::MARKDOWN));
+    }
+
+    #[test]
+    fn test_utf8_acceptable() {
+        let headers = |accept: &'static str, accept_charset: Option<&'static str>| {
+            let mut headers = self::accept(&[accept]);
+            if let Some(value) = accept_charset {
+                headers.insert(ACCEPT_CHARSET, HeaderValue::from_static(value));
+            }
+            headers
+        };
+        for (accept, accept_charset, expected) in [
+            ("text/markdown", None, true),
+            ("text/markdown", Some("utf-8"), true),
+            ("text/markdown", Some("iso-8859-1, UTF-8;q=0.1"), true),
+            ("text/markdown", Some("iso-8859-1, *;q=0.5"), true),
+            ("text/markdown", Some("bogus"), false),
+            ("text/markdown", Some("*, utf-8;q=0"), false),
+            ("text/markdown", Some("*;q=0"), false),
+            ("text/markdown", Some("utf-8;q=2"), true),
+            ("text/markdown;charset=UTF-8", None, true),
+            ("text/markdown;charset=bogus", None, false),
+            (
+                "text/markdown;charset=bogus, text/markdown;charset=utf-8;q=0.1",
+                None,
+                true,
+            ),
+            ("text/markdown;charset=utf-8;q=0, text/html", None, true),
+            ("text/html;charset=bogus, text/markdown", None, true),
+        ] {
+            assert_eq!(
+                utf8_acceptable(&headers(accept, accept_charset)),
+                expected,
+                "{accept} / {accept_charset:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Token-window pagination of converted documents, selected with an
+//! `X-Markdown-Page` header or a `markdown-page` query parameter.
+
+use crate::{budget, etag, MarkdownConfig, RequestContext, TokenCounter};
+use bytes::Bytes;
+use http::{
+    header::{ETAG, LINK},
+    HeaderValue, Response, StatusCode,
+};
+use std::ops::Range;
+
+/// Query parameter selecting a page, also used in `Link: rel="next"`.
+const PARAM: &str = "markdown-page";
+
+/// The page number (from 1) in a `markdown-page` query parameter.
+pub fn from_query(query: &str) -> Option<usize> {
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(PARAM)?.strip_prefix('='))
+        .and_then(parse)
+}
+
+/// A page number, from 1.
+pub fn parse(value: &str) -> Option<usize> {
+    value.trim().parse().ok().filter(|&page| page > 0)
+}
+
+/// `path_and_query` without its `markdown-page` parameter, so that every
+/// page of a document shares one cached conversion.
+pub fn strip_param(path_and_query: &str) -> String {
+    let Some((path, query)) = path_and_query.split_once('?') else {
+        return path_and_query.to_string();
+    };
+    let query: Vec<&str> = query
+        .split('&')
+        .filter(|pair| pair.split('=').next() != Some(PARAM))
+        .collect();
+    if query.is_empty() {
+        path.to_string()
+    } else {
+        format!("{path}?{}", query.join("&"))
+    }
+}
+
+/// Group the blocks of `markdown` into pages of at most `window` tokens,
+/// returning each page's byte range and token count. A block larger than
+/// the window gets a page to itself.
+fn split(counter: &dyn TokenCounter, markdown: &str, window: usize) -> Vec<(Range<usize>, usize)> {
+    let mut pages: Vec<(Range<usize>, usize)> = Vec::new();
+    for block in budget::blocks(markdown) {
+        if let Some((page, tokens)) = pages.last_mut() {
+            let extended = counter.count(&markdown[page.start..block.end]);
+            if extended <= window {
+                page.end = block.end;
+                *tokens = extended;
+                continue;
+            }
+        }
+        let tokens = counter.count(&markdown[block.clone()]);
+        pages.push((block, tokens));
+    }
+    pages
+}
+
+/// Cut a converted `200 OK` response down to the page the request asked
+/// for, with `x-markdown-page`, `x-markdown-total-pages`, and (unless it is
+/// the last page) a `Link: rel="next"` header. `x-markdown-tokens` is
+/// replaced with the page's count, and `x-markdown-tokens-by-model`
+/// removed.
+///
+/// A page past the end gets an empty `416 Range Not Satisfiable` response,
+/// which still carries `x-markdown-total-pages`. An empty document has one,
+/// empty, page.
+pub async fn apply(
+    response: Response<Bytes>,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+) -> Response<Bytes> {
+    let (Some(page), Some(window)) = (request.page, config.page_tokens) else {
+        return response;
+    };
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Some(pages) = config
+        .with_counter(body.clone(), request.tokenizer, move |counter, text| {
+            split(counter, text, window)
+        })
+        .await
+    else {
+        return Response::from_parts(parts, body);
+    };
+
+    let total = pages.len().max(1);
+    parts.headers.remove("x-markdown-tokens-by-model");
+    parts
+        .headers
+        .insert("x-markdown-total-pages", HeaderValue::from(total));
+    let body = match pages.get(page - 1) {
+        Some((range, tokens)) => {
+            parts
+                .headers
+                .insert("x-markdown-page", HeaderValue::from(page));
+            if let Ok(hv) = HeaderValue::from_str(&crate::tokens_value(*tokens, config, request)) {
+                parts.headers.insert(config.token_header_name.clone(), hv);
+            }
+            if page < total {
+                let next = format!(
+                    "<{}>; rel=\"next\"",
+                    next_url(&request.url(config), page + 1)
+                );
+                if let Ok(hv) = HeaderValue::from_str(&next) {
+                    parts.headers.append(LINK, hv);
+                }
+            }
+            let body = body.slice(range.clone());
+            etag::refresh(&mut parts.headers, &body, config.etag);
+            body
+        }
+        None if page == 1 => {
+            parts
+                .headers
+                .insert("x-markdown-page", HeaderValue::from(page));
+            Bytes::new()
+        }
+        None => {
+            parts.status = StatusCode::RANGE_NOT_SATISFIABLE;
+            parts.headers.remove(&config.token_header_name);
+            parts.headers.remove(ETAG);
+            Bytes::new()
+        }
+    };
+    Response::from_parts(parts, body)
+}
+
+/// `url` with its `markdown-page` parameter set to `page`.
+fn next_url(url: &str, page: usize) -> String {
+    let url = strip_param(url);
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{url}{separator}{PARAM}={page}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(from_query("a=1&markdown-page=3"), Some(3));
+        assert_eq!(from_query("markdown-pages=3&markdown-page=0"), None);
+        assert_eq!(strip_param("/doc?a=1&markdown-page=2&b"), "/doc?a=1&b");
+        assert_eq!(strip_param("/doc?markdown-page=2"), "/doc");
+        assert_eq!(
+            next_url("https://x.test/doc?markdown-page=2", 3),
+            "https://x.test/doc?markdown-page=3"
+        );
+        assert_eq!(next_url("/doc?a=1", 2), "/doc?a=1&markdown-page=2");
+    }
+}
@@ -0,0 +1,108 @@
+//! Single byte-range (`Range: bytes=…`) support for converted responses.
+
+use bytes::Bytes;
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_RANGE},
+    HeaderValue, Response, StatusCode,
+};
+use std::ops::Range;
+
+/// The outcome of evaluating a `Range` header against a body length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ByteRange {
+    Satisfiable(Range<usize>),
+    Unsatisfiable,
+}
+
+/// Evaluate a `Range` header value against a body of `len` bytes.
+///
+/// Returns `None` when the header should be ignored: other units, multiple
+/// ranges, or malformed syntax.
+fn parse(header: &str, len: usize) -> Option<ByteRange> {
+    let spec = header.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // Suffix range: the last `end` bytes
+        let suffix: usize = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        len.saturating_sub(suffix)..len
+    } else {
+        let start: usize = start.parse().ok()?;
+        let end = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            let end: usize = end.parse().ok()?;
+            if end < start {
+                return None;
+            }
+            end.min(len.saturating_sub(1))
+        };
+        if start >= len {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        start..end + 1
+    };
+    Some(ByteRange::Satisfiable(range))
+}
+
+/// Turn a complete `200 OK` response into a `206 Partial Content` (or
+/// `416 Range Not Satisfiable`) one when `range` asks for part of it.
+/// Other responses, and ranges that should be ignored, pass through whole.
+pub fn apply(response: Response<Bytes>, range: Option<&HeaderValue>) -> Response<Bytes> {
+    let (mut parts, body) = response.into_parts();
+    let range = range
+        .filter(|_| parts.status == StatusCode::OK)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| parse(h, body.len()));
+
+    let body = match range {
+        None => body,
+        Some(ByteRange::Satisfiable(range)) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, body.len());
+            if let Ok(hv) = HeaderValue::from_str(&content_range) {
+                parts.headers.insert(CONTENT_RANGE, hv);
+            }
+            parts.status = StatusCode::PARTIAL_CONTENT;
+            body.slice(range)
+        }
+        Some(ByteRange::Unsatisfiable) => {
+            if let Ok(hv) = HeaderValue::from_str(&format!("bytes */{}", body.len())) {
+                parts.headers.insert(CONTENT_RANGE, hv);
+            }
+            parts.status = StatusCode::RANGE_NOT_SATISFIABLE;
+            Bytes::new()
+        }
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for (header, expected) in [
+            ("bytes=0-9", Some(ByteRange::Satisfiable(0..10))),
+            ("bytes=5-", Some(ByteRange::Satisfiable(5..20))),
+            ("bytes=-5", Some(ByteRange::Satisfiable(15..20))),
+            ("bytes=-50", Some(ByteRange::Satisfiable(0..20))),
+            ("bytes=10-100", Some(ByteRange::Satisfiable(10..20))),
+            ("bytes=20-", Some(ByteRange::Unsatisfiable)),
+            ("bytes=-0", Some(ByteRange::Unsatisfiable)),
+            ("bytes=0-1,4-5", None),
+            ("bytes=9-3", None),
+            ("items=0-9", None),
+            ("bytes=x-9", None),
+        ] {
+            assert_eq!(parse(header, 20), expected, "header {header:?}");
+        }
+    }
+}
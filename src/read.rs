@@ -0,0 +1,160 @@
+//! Buffering of HTML bodies up to a size limit, keeping a body over the
+//! limit whole so it can still be passed through.
+
+use axum::body::Body;
+use bytes::{Bytes, BytesMut};
+use http_body::{Body as _, Frame, SizeHint};
+use http_body_util::BodyExt;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A body that couldn't be buffered.
+pub enum Unread {
+    /// The body exceeded the limit: the whole of it, the bytes read so far
+    /// followed by the rest of the stream.
+    TooLarge(Body),
+    /// Reading the body failed partway; what was read is lost.
+    Unreadable,
+}
+
+/// Buffer `body`, up to `limit` bytes.
+///
+/// Trailers are dropped, as the converted response can't carry them. A
+/// body whose size hint is already over the limit isn't read at all.
+pub async fn read_body(mut body: Body, limit: usize) -> Result<Bytes, Unread> {
+    if usize::try_from(body.size_hint().lower()).map_or(true, |lower| lower > limit) {
+        return Err(Unread::TooLarge(body));
+    }
+    let mut chunks = VecDeque::new();
+    let mut len = 0;
+    while let Some(frame) = body.frame().await {
+        let Ok(frame) = frame else {
+            return Err(Unread::Unreadable);
+        };
+        let Ok(data) = frame.into_data() else {
+            continue;
+        };
+        len += data.len();
+        chunks.push_back(data);
+        if len > limit {
+            return Err(Unread::TooLarge(Body::new(Replay { chunks, rest: body })));
+        }
+    }
+    Ok(match chunks.len() {
+        0 => Bytes::new(),
+        1 => chunks.pop_front().unwrap_or_default(),
+        _ => {
+            let mut bytes = BytesMut::with_capacity(len);
+            for chunk in &chunks {
+                bytes.extend_from_slice(chunk);
+            }
+            bytes.freeze()
+        }
+    })
+}
+
+/// The chunks read from a body, then the rest of it.
+struct Replay {
+    chunks: VecDeque<Bytes>,
+    rest: Body,
+}
+
+impl http_body::Body for Replay {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some(chunk) = this.chunks.pop_front() {
+            return Poll::Ready(Some(Ok(Frame::data(chunk))));
+        }
+        Pin::new(&mut this.rest).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.chunks.is_empty() && self.rest.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let buffered: u64 = self.chunks.iter().map(|chunk| chunk.len() as u64).sum();
+        let rest = self.rest.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(rest.lower() + buffered);
+        if let Some(upper) = rest.upper() {
+            hint.set_upper(upper + buffered);
+        }
+        hint
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    /// A body of `chunks`, with no size hint.
+    pub struct Chunks(pub VecDeque<Bytes>);
+
+    impl http_body::Body for Chunks {
+        type Data = Bytes;
+        type Error = axum::Error;
+
+        fn poll_frame(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Poll::Ready(
+                self.get_mut()
+                    .0
+                    .pop_front()
+                    .map(|chunk| Ok(Frame::data(chunk))),
+            )
+        }
+    }
+
+    fn chunked(chunks: &[&'static str]) -> Body {
+        Body::new(Chunks(
+            chunks
+                .iter()
+                .map(|c| Bytes::from_static(c.as_bytes()))
+                .collect(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_read_within_limit() {
+        let bytes = read_body(chunked(&["<p>", "a", "</p>"]), 8)
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(bytes, "<p>a</p>");
+        let bytes = read_body(Body::from("<p>a</p>"), 8).await.ok().unwrap();
+        assert_eq!(bytes, "<p>a</p>");
+        let bytes = read_body(Body::empty(), 0).await.ok().unwrap();
+        assert!(bytes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_too_large_keeps_body() {
+        for body in [
+            chunked(&["<p>", "ab", "</p>", "<p>c</p>"]),
+            Body::from("<p>ab</p><p>c</p>"),
+        ] {
+            let unread = read_body(body, 8).await.err();
+            let body = unread
+                .and_then(|unread| match unread {
+                    Unread::TooLarge(body) => Some(body),
+                    Unread::Unreadable => None,
+                })
+                .unwrap();
+            let bytes = body.collect().await.unwrap().to_bytes();
+            assert_eq!(bytes, "<p>ab</p><p>c</p>");
+        }
+    }
+}
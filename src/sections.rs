@@ -0,0 +1,140 @@
+//! Per-section token counts for [`MarkdownConfig::section_tokens`].
+
+use crate::{budget, toc, MarkdownConfig, RequestContext, SectionTokens};
+use bytes::Bytes;
+use std::{fmt::Write, ops::Range};
+
+/// The sections of `markdown`: the text before the first `#` or `##`
+/// heading outside a code fence (if any), then each such heading up to the
+/// next. Between them, the sections cover every byte.
+fn split(markdown: &str) -> Vec<(Option<String>, Range<usize>)> {
+    let mut sections: Vec<(Option<String>, Range<usize>)> = Vec::new();
+    let mut start = 0;
+    let mut heading = None;
+    for line in budget::scan(markdown) {
+        if line.fence.is_some() || line.closes_fence {
+            continue;
+        }
+        let Some((_, text)) = toc::atx_heading(line.text).filter(|(level, _)| *level <= 2) else {
+            continue;
+        };
+        let line_start = line.end - line.text.len();
+        if line_start > 0 {
+            sections.push((heading.take(), start..line_start));
+        }
+        start = line_start;
+        heading = Some(toc::flatten(text));
+    }
+    if start < markdown.len() || heading.is_some() {
+        sections.push((heading, start..markdown.len()));
+    }
+    sections
+}
+
+/// Count the tokens of each section of `markdown` with the request's
+/// counter; `None` if there is none.
+pub async fn count(
+    markdown: Bytes,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+) -> Option<Vec<SectionTokens>> {
+    config
+        .with_counter(markdown, request.tokenizer, |counter, text| {
+            let sections = split(text);
+            let ends: Vec<usize> = sections.iter().map(|(_, range)| range.end).collect();
+            let counts = counter.count_parts(text, &ends);
+            sections
+                .into_iter()
+                .zip(counts)
+                .map(|((heading, byte_range), tokens)| SectionTokens {
+                    heading,
+                    tokens,
+                    byte_range,
+                })
+                .collect()
+        })
+        .await
+}
+
+/// The `x-markdown-section-tokens` value: a JSON array of `[heading,
+/// tokens]` pairs (`null` for text before the first heading), with
+/// non-ASCII characters escaped. `None` if it is longer than `max_len`.
+pub fn header(sections: &[SectionTokens], max_len: usize) -> Option<String> {
+    let pairs: Vec<_> = sections
+        .iter()
+        .map(|section| serde_json::json!([section.heading, section.tokens]))
+        .collect();
+    let json = serde_json::Value::Array(pairs).to_string();
+    let mut value = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            value.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                let _ = write!(value, "\\u{unit:04x}");
+            }
+        }
+    }
+    (value.len() <= max_len).then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headings(markdown: &str) -> Vec<(Option<String>, &str)> {
+        split(markdown)
+            .into_iter()
+            .map(|(heading, range)| (heading, &markdown[range]))
+            .collect()
+    }
+
+    #[test]
+    fn test_split_on_top_level_headings() {
+        let md = "intro\n\n# One\n\ntext\n\n### Deep\n\nmore\n\n## Two *b*\n\nend";
+        assert_eq!(
+            headings(md),
+            [
+                (None, "intro\n\n"),
+                (Some("One".into()), "# One\n\ntext\n\n### Deep\n\nmore\n\n"),
+                (Some("Two b".into()), "## Two *b*\n\nend"),
+            ]
+        );
+        assert_eq!(headings("# Only"), [(Some("Only".into()), "# Only")]);
+        assert_eq!(split(""), Vec::new());
+    }
+
+    #[test]
+    fn test_split_ignores_headings_in_fences() {
+        let md = "# Shell\n\n```sh\n# not a heading\n## nor this\n```\n\n~~~~\n```\n# still code\n~~~~\n\n## Next";
+        assert_eq!(
+            headings(md),
+            [
+                (
+                    Some("Shell".into()),
+                    "# Shell\n\n```sh\n# not a heading\n## nor this\n```\n\n~~~~\n```\n# still code\n~~~~\n\n"
+                ),
+                (Some("Next".into()), "## Next"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_header_escapes_and_caps() {
+        let sections = [
+            SectionTokens {
+                heading: None,
+                tokens: 2,
+                byte_range: 0..4,
+            },
+            SectionTokens {
+                heading: Some("Café \"1\"".to_string()),
+                tokens: 7,
+                byte_range: 4..20,
+            },
+        ];
+        let value = header(&sections, 100).unwrap_or_default();
+        assert_eq!(value, r#"[[null,2],["Caf\u00e9 \"1\"",7]]"#);
+        assert_eq!(header(&sections, value.len() - 1), None);
+    }
+}
@@ -0,0 +1,110 @@
+//! Static file serving with markdown conversion.
+
+use crate::{MarkdownConfig, MarkdownLayer};
+use axum::Router;
+use std::path::Path;
+use tower_http::services::ServeDir;
+
+/// Serve the files under `path`, converting HTML files to markdown for
+/// requests that ask for it.
+///
+/// This is a [`Router`] with a [`ServeDir`] fallback wrapped in a
+/// [`MarkdownLayer`], so `index.html` resolution, MIME detection, and
+/// `Last-Modified` handling are `ServeDir`'s; non-HTML files are served
+/// unchanged. Mount it with [`Router::nest_service`] or [`Router::fallback_service`]:
+///
+/// ```no_run
+/// # use axum::Router;
+/// # use axum_markdown::{serve_dir, MarkdownConfig};
+/// let app: Router = Router::new().nest_service("/docs", serve_dir("site/docs", MarkdownConfig::new()));
+/// ```
+pub fn serve_dir(path: impl AsRef<Path>, config: MarkdownConfig) -> Router {
+    Router::new()
+        .fallback_service(ServeDir::new(path))
+        .layer(MarkdownLayer::with_config(config))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        Request, StatusCode,
+    };
+    use std::{fs, path::PathBuf};
+    use tower::ServiceExt;
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("axum-markdown-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("docs")).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn get(router: Router, uri: &str, accept: &str) -> (StatusCode, String, String) {
+        let request = Request::builder()
+            .uri(uri)
+            .header(ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        let status = response.status();
+        let content_type = response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_serve_dir_converts_html_files() {
+        let dir = TempDir::new("serve-dir");
+        fs::write(
+            dir.0.join("docs/index.html"),
+            "<html><body><h1>Docs</h1><p>Start <a href=\"intro.html\">here</a>.</p></body></html>",
+        )
+        .unwrap();
+        fs::write(dir.0.join("docs/notes.txt"), "plain notes").unwrap();
+        let router = serve_dir(&dir.0, MarkdownConfig::new());
+
+        let (status, content_type, body) = get(router.clone(), "/docs/", "text/markdown").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "text/markdown; charset=utf-8");
+        assert_eq!(body, "# Docs\n\nStart [here](intro.html).");
+
+        let (_, content_type, body) = get(router.clone(), "/docs/index.html", "text/html").await;
+        assert_eq!(content_type, "text/html");
+        assert!(body.starts_with("<html>"));
+
+        let (_, content_type, body) = get(router.clone(), "/docs/notes.txt", "text/markdown").await;
+        assert!(content_type.starts_with("text/plain"));
+        assert_eq!(body, "plain notes");
+
+        let request = Request::builder()
+            .uri("/docs/missing.html")
+            .header(ACCEPT, "text/markdown")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,145 @@
+//! An XML sitemap of the markdown variants of pages, for crawlers that
+//! want the markdown without negotiating for it.
+
+use crate::{url, ContentLocation};
+use axum::routing::{get, MethodRouter};
+use bytes::Bytes;
+use http::header::CONTENT_TYPE;
+use std::fmt::Write as _;
+
+/// A `GET` route serving a sitemap of the `.md` form of each of `urls`.
+///
+/// The sitemap (sitemaps.org 0.9) lists `https://example.com/docs/intro.md`
+/// for `https://example.com/docs/intro`, and `index.md` for paths ending in
+/// `/`, as with [`ContentLocation::PathSuffix`].
+///
+/// Sitemaps take absolute URLs. They are percent-encoded where needed, and
+/// any fragment dropped. Mount the route wherever crawlers are told to
+/// look, e.g. with a `Sitemap:` line in `robots.txt`; pair it with
+/// [`MarkdownConfig::markdown_suffix`] so the `.md` URLs resolve.
+///
+/// ```rust
+/// # use axum::Router;
+/// # use axum_markdown::markdown_sitemap_route;
+/// let app: Router = Router::new().route(
+///     "/sitemap-markdown.xml",
+///     markdown_sitemap_route(["https://example.com/", "https://example.com/docs/intro"]),
+/// );
+/// ```
+///
+/// [`MarkdownConfig::markdown_suffix`]: crate::MarkdownConfig::markdown_suffix
+pub fn markdown_sitemap_route<S>(urls: impl IntoIterator<Item = impl AsRef<str>>) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let body = Bytes::from(sitemap(urls));
+    get(move || {
+        let body = body.clone();
+        async move { ([(CONTENT_TYPE, "application/xml; charset=utf-8")], body) }
+    })
+}
+
+/// The sitemap document.
+fn sitemap(urls: impl IntoIterator<Item = impl AsRef<str>>) -> String {
+    let suffix = ContentLocation::PathSuffix(".md".into());
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for url in urls {
+        let url = url::with_root_path(url::strip_fragment(url.as_ref()));
+        let _ = writeln!(
+            out,
+            "  <url><loc>{}</loc></url>",
+            escape(&suffix.resolve(&url))
+        );
+    }
+    out.push_str("</urlset>\n");
+    out
+}
+
+/// Escape `text` for XML character data.
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, Router};
+    use http::Request;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_markdown_sitemap_route() {
+        let app: Router = Router::new().route(
+            "/sitemap-markdown.xml",
+            markdown_sitemap_route([
+                "https://example.com",
+                "https://example.com/docs/",
+                "https://example.com/docs/intro#setup",
+                "https://example.com/search?q=a&lang=en",
+                "https://example.com/caf\u{e9}",
+            ]),
+        );
+        let request = Request::builder()
+            .uri("/sitemap-markdown.xml")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "application/xml; charset=utf-8"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let xml = String::from_utf8(body.to_vec()).unwrap();
+
+        let mut lines = xml.lines();
+        assert_eq!(
+            lines.next(),
+            Some("<?xml version=\"1.0\" encoding=\"UTF-8\"?>")
+        );
+        assert_eq!(
+            lines.next(),
+            Some("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">")
+        );
+        assert_eq!(lines.next_back(), Some("</urlset>"));
+        let locs: Vec<&str> = lines
+            .map(|line| {
+                line.trim()
+                    .strip_prefix("<url><loc>")
+                    .and_then(|line| line.strip_suffix("</loc></url>"))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            locs,
+            [
+                "https://example.com/index.md",
+                "https://example.com/docs/index.md",
+                "https://example.com/docs/intro.md",
+                "https://example.com/search.md?q=a&amp;lang=en",
+                "https://example.com/caf%C3%A9.md",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a&b<c>'\""), "a&amp;b&lt;c&gt;&apos;&quot;");
+    }
+}
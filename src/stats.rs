@@ -0,0 +1,91 @@
+//! Counts of the conversions a layer has made, for
+//! [`MarkdownLayer::stats`](crate::MarkdownLayer::stats).
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// A snapshot of a layer's conversion counts, from
+/// [`MarkdownLayer::stats`](crate::MarkdownLayer::stats) or
+/// [`MarkdownService::stats`](crate::MarkdownService::stats).
+///
+/// Responses served from a cache aren't converted again, so they aren't
+/// counted; nor are the conversions [`shadow_mode`] measures.
+///
+/// [`shadow_mode`]: crate::MarkdownConfig::shadow_mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ConversionStats {
+    /// HTML responses converted to markdown.
+    pub conversions: u64,
+    /// Conversions that failed, and were answered with `502 Bad Gateway`
+    /// or passed through as HTML.
+    pub failures: u64,
+    /// The `x-markdown-tokens` of the conversions, summed; conversions
+    /// without a count add nothing.
+    pub tokens: u64,
+    /// Time spent reading the HTML bodies of the conversions.
+    pub read_time: Duration,
+    /// Time spent converting the HTML to markdown, including post-passes.
+    pub conversion_time: Duration,
+    /// Time spent counting tokens, including waits for the blocking pool.
+    pub token_time: Duration,
+}
+
+/// How long the steps of one conversion took.
+#[derive(Debug, Clone, Copy)]
+pub struct Timings {
+    pub read: Duration,
+    pub conversion: Duration,
+    pub tokenization: Duration,
+}
+
+/// The counters behind [`ConversionStats`], shared by every service built
+/// from one layer.
+#[derive(Debug, Default)]
+pub struct Stats {
+    conversions: AtomicU64,
+    failures: AtomicU64,
+    tokens: AtomicU64,
+    /// Nanoseconds of each [`Timings`] step, summed.
+    read: AtomicU64,
+    conversion: AtomicU64,
+    tokenization: AtomicU64,
+}
+
+impl Stats {
+    /// Count a conversion of `tokens` tokens, which took `timings`.
+    pub fn converted(&self, tokens: Option<usize>, timings: Timings) {
+        self.conversions.fetch_add(1, Ordering::Relaxed);
+        if let Some(tokens) = tokens {
+            self.tokens
+                .fetch_add(u64::try_from(tokens).unwrap_or(u64::MAX), Ordering::Relaxed);
+        }
+        for (total, time) in [
+            (&self.read, timings.read),
+            (&self.conversion, timings.conversion),
+            (&self.tokenization, timings.tokenization),
+        ] {
+            let nanos = u64::try_from(time.as_nanos()).unwrap_or(u64::MAX);
+            total.fetch_add(nanos, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a failed conversion.
+    pub fn failed(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counts so far. Each is read on its own, so a snapshot taken
+    /// during a conversion may include some of its counts but not others.
+    pub fn snapshot(&self) -> ConversionStats {
+        ConversionStats {
+            conversions: self.conversions.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            tokens: self.tokens.load(Ordering::Relaxed),
+            read_time: Duration::from_nanos(self.read.load(Ordering::Relaxed)),
+            conversion_time: Duration::from_nanos(self.conversion.load(Ordering::Relaxed)),
+            token_time: Duration::from_nanos(self.tokenization.load(Ordering::Relaxed)),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Page metadata summaries, served for `Accept: application/vnd.page-summary+json`
+//! when [`MarkdownConfig::page_summary`] is enabled.
+
+use crate::{
+    append_vary, body_limit, digest, metadata::PageMetadata, read::read_body, unread,
+    MarkdownConfig, MediaType, RequestContext,
+};
+use axum::body::Body;
+use http::{header::CONTENT_TYPE, HeaderValue, Response};
+use serde_json::{json, Map, Value};
+
+/// The summary of `metadata` for the page at `url`: its title,
+/// description, canonical URL, and language, with the Open Graph and
+/// Twitter card properties allowed by
+/// [`MarkdownConfig::social_properties`]. A property repeated in the page
+/// (e.g. several `og:image`s) becomes an array.
+fn summarize(metadata: &PageMetadata, url: &str, config: &MarkdownConfig) -> Value {
+    let mut properties = Map::new();
+    for (property, content) in &metadata.social {
+        let allowed = config
+            .social_properties
+            .as_ref()
+            .is_none_or(|allow| allow.iter().any(|p| p == property));
+        if !allowed {
+            continue;
+        }
+        let content = Value::String(content.clone());
+        match properties.get_mut(property) {
+            Some(Value::Array(values)) => values.push(content),
+            Some(value) => *value = Value::Array(vec![value.take(), content]),
+            None => {
+                properties.insert(property.clone(), content);
+            }
+        }
+    }
+    json!({
+        "url": url,
+        "title": metadata.title,
+        "description": metadata.description,
+        "canonical": metadata.canonical,
+        "lang": metadata.lang,
+        "properties": properties,
+    })
+}
+
+/// Replace an HTML response with the summary of its metadata.
+pub async fn respond(
+    response: Response<Body>,
+    config: &MarkdownConfig,
+    request: &RequestContext,
+) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    let limit = body_limit(&parts, config);
+    let body = match read_body(body, limit).await {
+        Ok(body) => body,
+        Err(error) => return unread(error, parts, limit, config, request),
+    };
+
+    let url = request.url(config);
+    let mut metadata = PageMetadata::extract(&String::from_utf8_lossy(&body));
+    metadata.resolve_urls(&url);
+    let summary = summarize(&metadata, &url, config).to_string();
+
+    parts.headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(MediaType::PAGE_SUMMARY.as_str()),
+    );
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    parts.headers.remove(http::header::ETAG);
+    digest::strip(&mut parts.headers);
+    append_vary(Response::from_parts(parts, Body::from(summary)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize_repeated_properties() {
+        let metadata = PageMetadata::extract(
+            "<head><title>T</title>\
+             <meta property=\"og:image\" content=\"/a.png\">\
+             <meta property=\"og:image\" content=\"/b.png\">\
+             <meta property=\"og:type\" content=\"article\"></head>",
+        );
+        let summary = summarize(&metadata, "http://x.test/", &MarkdownConfig::new());
+        assert_eq!(summary["title"], "T");
+        assert_eq!(summary["description"], Value::Null);
+        assert_eq!(
+            summary["properties"]["og:image"],
+            json!(["/a.png", "/b.png"])
+        );
+        assert_eq!(summary["properties"]["og:type"], "article");
+
+        let config = MarkdownConfig::new().social_properties(["og:type"]);
+        let summary = summarize(&metadata, "http://x.test/", &config);
+        assert_eq!(summary["properties"], json!({"og:type": "article"}));
+    }
+}
@@ -0,0 +1,43 @@
+//! `{placeholder}` substitution for user-supplied templates.
+
+/// Replace each `{name}` in `template` with its value from `values`, in a
+/// single pass (so substituted text is never re-expanded). Unknown
+/// placeholders and unmatched braces are kept as written.
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (*value, end))
+        });
+        if let Some((value, end)) = value {
+            out.push_str(value);
+            rest = &rest[end + 1..];
+        } else {
+            out.push('{');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let values = [("url", "https://x.org/{title}"), ("title", "T")];
+        assert_eq!(
+            render("{title} <{url}> {other} {", &values),
+            "T <https://x.org/{title}> {other} {"
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Table of contents generation from converted markdown headings.
+
+use crate::{budget, TocOptions};
+use std::collections::HashMap;
+
+/// An ATX heading found in the markdown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Heading {
+    level: usize,
+    text: String,
+}
+
+/// Insert a table of contents into `markdown`: right after the first line if
+/// the document opens with a heading (which is left out of the list),
+/// otherwise at the top. Does nothing below `options.min_headings` entries.
+pub fn insert(markdown: &mut String, options: &TocOptions) {
+    let headings = headings(markdown);
+    let opens_with_heading = markdown
+        .lines()
+        .next()
+        .is_some_and(|line| atx_heading(line).is_some());
+
+    // Slugs are assigned over every heading, so duplicates are numbered
+    // the way a renderer would number them.
+    let mut seen = HashMap::new();
+    let entries: Vec<(usize, String, String)> = headings
+        .into_iter()
+        .map(|heading| {
+            let slug = unique_slug(&heading.text, &mut seen);
+            (heading.level, heading.text, slug)
+        })
+        .skip(usize::from(opens_with_heading))
+        .filter(|(level, _, _)| *level <= usize::from(options.max_depth))
+        .collect();
+    if entries.is_empty() || entries.len() < options.min_headings {
+        return;
+    }
+
+    let min_level = entries
+        .iter()
+        .map(|(level, _, _)| *level)
+        .min()
+        .unwrap_or(1);
+    let toc = entries
+        .iter()
+        .map(|(level, text, slug)| {
+            format!(
+                "{}- [{}](#{slug})",
+                "  ".repeat(level - min_level),
+                escape_link_text(text)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if opens_with_heading {
+        let end = markdown.find('\n').unwrap_or(markdown.len());
+        markdown.insert_str(end, &format!("\n\n{toc}"));
+    } else if markdown.is_empty() {
+        *markdown = toc;
+    } else {
+        markdown.insert_str(0, &format!("{toc}\n\n"));
+    }
+}
+
+/// End each ATX heading outside fenced code with its slug as an attribute,
+/// `## Section {#section}`, numbering repeated slugs as [`insert`] does.
+pub fn anchor(markdown: &str) -> String {
+    let mut seen = HashMap::new();
+    let mut out = String::with_capacity(markdown.len());
+    for (i, line) in budget::scan(markdown).into_iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        out.push_str(line.text);
+        if line.fence.is_some() || line.closes_fence {
+            continue;
+        }
+        let Some((_, text)) = atx_heading(line.text) else {
+            continue;
+        };
+        let text = flatten(text);
+        if !text.is_empty() {
+            let slug = unique_slug(&text, &mut seen);
+            out.truncate(out.trim_end().len());
+            out.push_str(" {#");
+            out.push_str(&slug);
+            out.push('}');
+        }
+    }
+    out
+}
+
+/// ATX headings outside fenced code blocks, with their text flattened.
+fn headings(markdown: &str) -> Vec<Heading> {
+    let mut headings = Vec::new();
+    let mut fence: Option<&str> = None;
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        if let Some(open) = fence {
+            if trimmed.starts_with(open) {
+                fence = None;
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") {
+            fence = Some("```");
+        } else if trimmed.starts_with("~~~") {
+            fence = Some("~~~");
+        } else if let Some((level, text)) = atx_heading(line) {
+            let text = flatten(text);
+            if !text.is_empty() {
+                headings.push(Heading { level, text });
+            }
+        }
+    }
+    headings
+}
+
+/// Level and raw text of an ATX heading line (up to three spaces of indent).
+pub fn atx_heading(line: &str) -> Option<(usize, &str)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let line = &line[indent..];
+    let level = line.len() - line.trim_start_matches('#').len();
+    let rest = &line[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        return None;
+    }
+    // Drop an attribute block and an optional closing sequence of `#`s
+    let text = rest.trim();
+    let text = text
+        .strip_suffix('}')
+        .and_then(|rest| rest.rfind(" {#").map(|at| rest[..at].trim_end()))
+        .unwrap_or(text);
+    let text = text
+        .trim_end_matches('#')
+        .strip_suffix([' ', '\t'])
+        .map_or(text, str::trim_end);
+    Some((level, text))
+}
+
+/// Reduce inline markdown to plain text: link and image syntax keeps only
+/// its text, emphasis and code markers are dropped, and escapes are resolved.
+pub fn flatten(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    out.push(escaped);
+                }
+            }
+            '!' if chars.peek() == Some(&'[') => {}
+            '[' | '*' | '_' | '`' | '~' => {}
+            ']' => {
+                // Skip the destination of `[text](url)` or the label of `[text][ref]`
+                if let Some(&open @ ('(' | '[')) = chars.peek() {
+                    let close = if open == '(' { ')' } else { ']' };
+                    let mut depth = 0usize;
+                    for ch in chars.by_ref() {
+                        if ch == open {
+                            depth += 1;
+                        } else if ch == close {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            ch => out.push(ch),
+        }
+    }
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// GitHub-style anchor slug, numbered (`-1`, `-2`, …) when repeated.
+fn unique_slug(text: &str, seen: &mut HashMap<String, usize>) -> String {
+    let slug: String = text
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            _ => None,
+        })
+        .collect();
+    let count = seen.entry(slug.clone()).or_insert(0);
+    let unique = if *count == 0 {
+        slug
+    } else {
+        format!("{slug}-{count}")
+    };
+    *count += 1;
+    unique
+}
+
+/// Escape the characters that would be read as markup inside link text.
+fn escape_link_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if matches!(ch, '\\' | '[' | ']' | '*' | '_' | '`') {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: TocOptions = TocOptions {
+        max_depth: 3,
+        min_headings: 2,
+    };
+
+    #[test]
+    fn test_toc_after_title() {
+        let mut markdown = "# Guide\n\nIntro\n\n## Getting _started_ with [links](/a \"t\")\n\n\
+                            ### Sub\\_part \\*star\\*\n\n```\n## not a heading\n```\n\n\
+                            #### Too deep\n\n## FAQ ##\n\n## FAQ"
+            .to_string();
+        insert(&mut markdown, &OPTIONS);
+        assert_eq!(
+            markdown,
+            "# Guide\n\n\
+             - [Getting started with links](#getting-started-with-links)\n  \
+             - [Sub\\_part \\*star\\*](#sub_part-star)\n\
+             - [FAQ](#faq)\n\
+             - [FAQ](#faq-1)\n\n\
+             Intro\n\n## Getting _started_ with [links](/a \"t\")\n\n\
+             ### Sub\\_part \\*star\\*\n\n```\n## not a heading\n```\n\n\
+             #### Too deep\n\n## FAQ ##\n\n## FAQ"
+        );
+    }
+
+    #[test]
+    fn test_toc_at_top_without_title() {
+        let mut markdown = "Intro\n\n## A\n\n### B [x][ref] ![img](i.png)".to_string();
+        insert(&mut markdown, &OPTIONS);
+        assert_eq!(
+            markdown,
+            "- [A](#a)\n  - [B x img](#b-x-img)\n\nIntro\n\n## A\n\n### B [x][ref] ![img](i.png)"
+        );
+    }
+
+    #[test]
+    fn test_toc_skipped_below_min_headings() {
+        let mut markdown = "# Title\n\n## Only one\n\n#hashtag".to_string();
+        insert(&mut markdown, &OPTIONS);
+        assert_eq!(markdown, "# Title\n\n## Only one\n\n#hashtag");
+    }
+
+    #[test]
+    fn test_anchor_numbers_duplicates() {
+        let markdown = "# Guide\n\n## FAQ\n\ntext\n\n## FAQ ##\n\n```\n## FAQ\n```\n\n\
+                        ### FAQ\n\n## *Set up* the [app](/a)\n\n#\n\n#hashtag";
+        assert_eq!(
+            anchor(markdown),
+            "# Guide {#guide}\n\n## FAQ {#faq}\n\ntext\n\n## FAQ ## {#faq-1}\n\n\
+             ```\n## FAQ\n```\n\n### FAQ {#faq-2}\n\n\
+             ## *Set up* the [app](/a) {#set-up-the-app}\n\n#\n\n#hashtag"
+        );
+    }
+
+    #[test]
+    fn test_toc_of_anchored_headings() {
+        let mut markdown = anchor("# Guide\n\n## A b\n\n## A b");
+        insert(&mut markdown, &OPTIONS);
+        assert_eq!(
+            markdown,
+            "# Guide {#guide}\n\n- [A b](#a-b)\n- [A b](#a-b-1)\n\n\
+             ## A b {#a-b}\n\n## A b {#a-b-1}"
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Minimal URL handling: RFC 3986 reference resolution and percent-encoding
+//! for header values.
+
+use std::fmt::Write;
+
+/// The five components of a URI reference (RFC 3986 §3).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Parts<'a> {
+    scheme: Option<&'a str>,
+    authority: Option<&'a str>,
+    path: &'a str,
+    query: Option<&'a str>,
+    fragment: Option<&'a str>,
+}
+
+fn parse(s: &str) -> Parts<'_> {
+    let mut parts = Parts::default();
+    let mut rest = s;
+
+    if let Some((fragment_start, fragment)) = rest.split_once('#') {
+        parts.fragment = Some(fragment);
+        rest = fragment_start;
+    }
+    if let Some((before, query)) = rest.split_once('?') {
+        parts.query = Some(query);
+        rest = before;
+    }
+    if let Some(colon) = rest.find(':') {
+        let scheme = &rest[..colon];
+        let valid = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+        if valid && !scheme.contains('/') {
+            parts.scheme = Some(scheme);
+            rest = &rest[colon + 1..];
+        }
+    }
+    if let Some(after) = rest.strip_prefix("//") {
+        let end = after.find('/').unwrap_or(after.len());
+        parts.authority = Some(&after[..end]);
+        rest = &after[end..];
+    }
+    parts.path = rest;
+    parts
+}
+
+/// Resolve `reference` against `base` (RFC 3986 §5.2).
+///
+/// `base` need not be absolute; a path-only base yields a path-only result.
+pub fn resolve(base: &str, reference: &str) -> String {
+    let b = parse(base);
+    let r = parse(reference.trim());
+
+    let (scheme, authority, path, query) = if r.scheme.is_some() {
+        (r.scheme, r.authority, remove_dot_segments(r.path), r.query)
+    } else if r.authority.is_some() {
+        (b.scheme, r.authority, remove_dot_segments(r.path), r.query)
+    } else if r.path.is_empty() {
+        (
+            b.scheme,
+            b.authority,
+            b.path.to_string(),
+            r.query.or(b.query),
+        )
+    } else if r.path.starts_with('/') {
+        (b.scheme, b.authority, remove_dot_segments(r.path), r.query)
+    } else {
+        let merged = if b.authority.is_some() && b.path.is_empty() {
+            format!("/{}", r.path)
+        } else {
+            let dir = b.path.rfind('/').map_or("", |i| &b.path[..=i]);
+            format!("{dir}{}", r.path)
+        };
+        (b.scheme, b.authority, remove_dot_segments(&merged), r.query)
+    };
+
+    let mut out = String::new();
+    if let Some(scheme) = scheme {
+        out.push_str(scheme);
+        out.push(':');
+    }
+    if let Some(authority) = authority {
+        out.push_str("//");
+        out.push_str(authority);
+    }
+    out.push_str(&path);
+    if let Some(query) = query {
+        out.push('?');
+        out.push_str(query);
+    }
+    if let Some(fragment) = r.fragment {
+        out.push('#');
+        out.push_str(fragment);
+    }
+    out
+}
+
+/// Whether `a` and `b` share a scheme and authority (compared
+/// case-insensitively). Two path-only URLs count as the same origin.
+pub fn same_origin(a: &str, b: &str) -> bool {
+    let (a, b) = (parse(a), parse(b));
+    let eq = |x: Option<&str>, y: Option<&str>| match (x, y) {
+        (Some(x), Some(y)) => x.eq_ignore_ascii_case(y),
+        (x, y) => x == y,
+    };
+    eq(a.scheme, b.scheme) && eq(a.authority, b.authority)
+}
+
+/// Whether `s` names a host, as an absolute URL or origin does.
+pub fn has_authority(s: &str) -> bool {
+    parse(s)
+        .authority
+        .is_some_and(|authority| !authority.is_empty())
+}
+
+/// `uri` with the `/` path an empty one is equivalent to after an
+/// authority (RFC 3986 §6.2.3): `https://example.com/` for
+/// `https://example.com`.
+pub fn with_root_path(uri: &str) -> String {
+    let parts = parse(uri);
+    if parts.authority.is_none() || !parts.path.is_empty() {
+        return uri.to_string();
+    }
+    let at = uri.len()
+        - parts.query.map_or(0, |query| query.len() + 1)
+        - parts.fragment.map_or(0, |fragment| fragment.len() + 1);
+    format!("{}/{}", &uri[..at], &uri[at..])
+}
+
+/// `uri` without its fragment.
+pub fn strip_fragment(uri: &str) -> &str {
+    uri.split_once('#').map_or(uri, |(before, _)| before)
+}
+
+/// RFC 3986 §5.2.4.
+fn remove_dot_segments(path: &str) -> String {
+    let mut output: Vec<&str> = Vec::new();
+    let mut input = path;
+
+    while !input.is_empty() {
+        if let Some(rest) = input.strip_prefix("../") {
+            input = rest;
+        } else if let Some(rest) = input.strip_prefix("./") {
+            input = rest;
+        } else if input.starts_with("/./") {
+            input = &input[2..];
+        } else if input == "/." {
+            input = "/";
+        } else if input.starts_with("/../") || input == "/.." {
+            input = if input == "/.." { "/" } else { &input[3..] };
+            output.pop();
+        } else if input == "." || input == ".." {
+            input = "";
+        } else {
+            let start = usize::from(input.starts_with('/'));
+            let end = input[start..].find('/').map_or(input.len(), |i| i + start);
+            output.push(&input[..end]);
+            input = &input[end..];
+        }
+    }
+
+    output.concat()
+}
+
+/// Percent-encode the characters that may not appear in a URI (or in an
+/// HTTP header value): non-ASCII, controls, space, and ``"<>\^`{|}``.
+/// Existing `%XX` escapes and reserved characters are left alone.
+pub fn encode(uri: &str) -> String {
+    let mut out = String::with_capacity(uri.len());
+    for ch in uri.chars() {
+        let needs_encoding = !ch.is_ascii()
+            || ch.is_ascii_control()
+            || matches!(
+                ch,
+                ' ' | '"' | '<' | '>' | '\\' | '^' | '`' | '{' | '|' | '}'
+            );
+        if needs_encoding {
+            let mut buf = [0; 4];
+            for byte in ch.encode_utf8(&mut buf).bytes() {
+                let _ = write!(out, "%{byte:02X}");
+            }
+        } else {
+            out.push(ch);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "http://a/b/c/d;p?q";
+
+    #[test]
+    fn test_resolve_rfc3986_normal_examples() {
+        for (reference, expected) in [
+            ("g:h", "g:h"),
+            ("g", "http://a/b/c/g"),
+            ("./g", "http://a/b/c/g"),
+            ("g/", "http://a/b/c/g/"),
+            ("/g", "http://a/g"),
+            ("//g", "http://g"),
+            ("?y", "http://a/b/c/d;p?y"),
+            ("g?y", "http://a/b/c/g?y"),
+            ("#s", "http://a/b/c/d;p?q#s"),
+            ("g#s", "http://a/b/c/g#s"),
+            (";x", "http://a/b/c/;x"),
+            ("", "http://a/b/c/d;p?q"),
+            (".", "http://a/b/c/"),
+            ("..", "http://a/b/"),
+            ("../g", "http://a/b/g"),
+            ("../..", "http://a/"),
+            ("../../g", "http://a/g"),
+        ] {
+            assert_eq!(
+                resolve(BASE, reference),
+                expected,
+                "reference {reference:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolve_rfc3986_abnormal_examples() {
+        for (reference, expected) in [
+            ("../../../g", "http://a/g"),
+            ("/./g", "http://a/g"),
+            ("/../g", "http://a/g"),
+            ("g.", "http://a/b/c/g."),
+            ("..g", "http://a/b/c/..g"),
+            ("./g/.", "http://a/b/c/g/"),
+            ("g/../h", "http://a/b/c/h"),
+        ] {
+            assert_eq!(
+                resolve(BASE, reference),
+                expected,
+                "reference {reference:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolve_against_path_only_base() {
+        assert_eq!(resolve("/docs/intro", "setup"), "/docs/setup");
+        assert_eq!(resolve("/docs/intro", "https://x.org/"), "https://x.org/");
+    }
+
+    #[test]
+    fn test_same_origin() {
+        assert!(same_origin(
+            "https://Example.com/a",
+            "HTTPS://example.com/b?q"
+        ));
+        assert!(same_origin("/a", "/b"));
+        assert!(!same_origin(
+            "https://example.com/a",
+            "http://example.com/a"
+        ));
+        assert!(!same_origin(
+            "https://example.com/a",
+            "https://example.org/a"
+        ));
+        assert!(!same_origin("/a", "mailto:me@example.com"));
+        assert!(has_authority("https://example.com"));
+        assert!(!has_authority("null"));
+        assert!(!has_authority("file:///etc"));
+    }
+
+    #[test]
+    fn test_with_root_path() {
+        assert_eq!(with_root_path("https://a"), "https://a/");
+        assert_eq!(with_root_path("https://a?q#f"), "https://a/?q#f");
+        assert_eq!(with_root_path("https://a/b"), "https://a/b");
+        assert_eq!(with_root_path("b?q"), "b?q");
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode("/a b/ü?q=\"x\""), "/a%20b/%C3%BC?q=%22x%22");
+        assert_eq!(
+            encode("/already%20encoded?a=1&b=2"),
+            "/already%20encoded?a=1&b=2"
+        );
+    }
+}
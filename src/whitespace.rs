@@ -0,0 +1,247 @@
+//! Pre-pass collapsing the insignificant whitespace of HTML, for
+//! [`MarkdownConfig::normalize_html_whitespace`](crate::MarkdownConfig::normalize_html_whitespace).
+
+/// Elements whose contents are kept verbatim.
+const VERBATIM: &[&str] = &["pre", "textarea", "script", "style", "xmp", "listing"];
+
+/// Elements around which whitespace doesn't render.
+const BLOCKS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "body",
+    "br",
+    "caption",
+    "col",
+    "colgroup",
+    "dd",
+    "details",
+    "dialog",
+    "div",
+    "dl",
+    "dt",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "header",
+    "hgroup",
+    "hr",
+    "html",
+    "li",
+    "link",
+    "main",
+    "menu",
+    "meta",
+    "nav",
+    "ol",
+    "option",
+    "p",
+    "pre",
+    "section",
+    "summary",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "title",
+    "tr",
+    "ul",
+];
+
+/// Collapse each run of whitespace in the text of `html` to a space, and
+/// drop it entirely next to a block-level tag, as rendering does.
+///
+/// Tags, comments, and the contents of `<pre>`, `<textarea>`, `<script>`,
+/// and `<style>` are left verbatim.
+pub fn normalize(html: &str) -> String {
+    let mut out = Output {
+        html: String::with_capacity(html.len()),
+        trim: true,
+        space: None,
+    };
+    let mut rest = html;
+    loop {
+        let at = next_markup(rest);
+        let (text, markup) = rest.split_at(at);
+        let tag = tag(markup);
+        out.text(text);
+        if tag
+            .as_ref()
+            .map_or(markup.is_empty(), |tag| is_block(&tag.name))
+        {
+            out.block();
+        }
+        if markup.is_empty() {
+            return out.html;
+        }
+        let out = &mut out.html;
+        let Some(tag) = tag else {
+            // A comment, doctype, or processing instruction
+            let end = if markup.starts_with("<!--") {
+                markup.find("-->").map_or(markup.len(), |end| end + 3)
+            } else {
+                markup.find('>').map_or(markup.len(), |end| end + 1)
+            };
+            out.push_str(&markup[..end]);
+            rest = &markup[end..];
+            continue;
+        };
+        out.push_str(&markup[..tag.len]);
+        rest = &markup[tag.len..];
+        if !tag.closing && VERBATIM.contains(&tag.name.as_str()) {
+            let end = find_closing(rest, &tag.name).unwrap_or(rest.len());
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+        }
+    }
+}
+
+/// The normalized HTML, as it is written.
+struct Output {
+    html: String,
+    /// Whether whitespace is dropped here: at a block boundary, or after a
+    /// space.
+    trim: bool,
+    /// Offset of the last space written, while only inline tags follow it.
+    space: Option<usize>,
+}
+
+impl Output {
+    /// Write `text` with its whitespace collapsed.
+    fn text(&mut self, text: &str) {
+        for c in text.chars() {
+            if !is_space(c) {
+                self.trim = false;
+                self.space = None;
+                self.html.push(c);
+            } else if !self.trim {
+                self.trim = true;
+                self.space = Some(self.html.len());
+                self.html.push(' ');
+            }
+        }
+    }
+
+    /// Mark a block boundary: a space before it, even ahead of inline
+    /// tags, doesn't render.
+    fn block(&mut self) {
+        if let Some(at) = self.space.take() {
+            self.html.remove(at);
+        }
+        self.trim = true;
+    }
+}
+
+/// HTML's ASCII whitespace; a non-breaking space is text.
+const fn is_space(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r' | '\u{c}')
+}
+
+fn is_block(name: &str) -> bool {
+    BLOCKS.contains(&name)
+}
+
+/// A start or end tag at the start of some markup.
+struct Tag {
+    /// Lowercase element name.
+    name: String,
+    closing: bool,
+    /// Length of the tag, up to and including its `>`.
+    len: usize,
+}
+
+/// The tag `markup` starts with, if it is one rather than a comment or the
+/// like. Quoted attribute values may contain `>`.
+fn tag(markup: &str) -> Option<Tag> {
+    let after = markup.strip_prefix('<')?;
+    let (closing, after) = after
+        .strip_prefix('/')
+        .map_or((false, after), |after| (true, after));
+    let name_len = after
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '-')
+        .unwrap_or(after.len());
+    if name_len == 0 {
+        return None;
+    }
+    let mut quote = None;
+    let end = after.char_indices().skip(name_len).find_map(|(i, c)| {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, '>') => return Some(i),
+            _ => {}
+        }
+        None
+    });
+    let len = end.map_or(markup.len(), |end| markup.len() - after.len() + end + 1);
+    Some(Tag {
+        name: after[..name_len].to_ascii_lowercase(),
+        closing,
+        len,
+    })
+}
+
+/// Offset of the next `<` in `html` that opens markup: a tag, comment,
+/// doctype, or processing instruction. Others are text.
+fn next_markup(html: &str) -> usize {
+    html.match_indices('<')
+        .find(|&(at, _)| {
+            html[at + 1..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || matches!(c, '/' | '!' | '?'))
+        })
+        .map_or(html.len(), |(at, _)| at)
+}
+
+/// Offset of the `</name` closing a verbatim element, matched without
+/// regard to case.
+fn find_closing(html: &str, name: &str) -> Option<usize> {
+    let needle = format!("</{name}");
+    html.as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collapses_text_whitespace() {
+        assert_eq!(
+            normalize("<div>\n  <p>\n    Some <b>bold</b>\n    text\n  </p>\n</div>"),
+            "<div><p>Some <b>bold</b> text</p></div>"
+        );
+        assert_eq!(
+            normalize("<p><b>a</b>\n  <i>b</i>&nbsp;\u{a0} c</p>"),
+            "<p><b>a</b> <i>b</i>&nbsp;\u{a0} c</p>"
+        );
+        assert_eq!(normalize("  a < b  "), "a < b");
+        assert_eq!(normalize("a <b> b </b> c"), "a <b>b </b>c");
+        assert_eq!(normalize("a <!-- c --> b"), "a <!-- c -->b");
+    }
+
+    #[test]
+    fn test_keeps_markup_and_preformatted_text() {
+        let html = "<div>\n<!-- a\n  comment -->\n<pre>\n  keep\n    <b>this</b>\n</PRE>\n\
+                    <a title=\"x >  y\"  href=x>\n  link\n</a></div>";
+        assert_eq!(
+            normalize(html),
+            "<div><!-- a\n  comment --><pre>\n  keep\n    <b>this</b>\n</PRE>\
+             <a title=\"x >  y\"  href=x>link</a></div>"
+        );
+    }
+}